@@ -1,19 +1,45 @@
 //! Configuration module for differ
 //!
 //! Loads user configuration from ~/.config/differ/config.toml
+//!
+//! Diff settings git also has fall back to git config when the file leaves
+//! them out; command line flags override both.
 
+use crate::diff::{DiffLimits, DiffMode, Whitespace};
 use anyhow::{Context, Result};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use toml::Value;
 
+/// Keys read from git config (`diff.*`) when config.toml does not set them.
+/// They are never written into the file, so leaving one out keeps deferring.
+const GIT_CONFIG_KEYS: [&str; 6] = [
+    "context_lines",
+    "diff_algorithm",
+    "renames",
+    "no_prefix",
+    "mnemonic_prefix",
+    "ignore_submodules",
+];
+
+/// Written into config.toml once the values earlier versions filled in for
+/// git config keys have been dropped
+const CONFIG_VERSION: i64 = 2;
+
+/// Values earlier versions wrote into every config.toml for keys git config
+/// now supplies. In a file older than [`CONFIG_VERSION`] they are taken as
+/// unset, since nobody chose them.
+const AUTO_WRITTEN: [(&str, &str); 2] = [("context_lines", "3"), ("diff_algorithm", "\"myers\"")];
+
 /// Application configuration
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default)]
 pub struct Config {
     /// Enable side-by-side diff view
     pub side_by_side: bool,
-    /// Number of context lines around changes (default 3)
+    /// Number of context lines around changes (default 3, or `diff.context`)
     pub context_lines: u32,
     /// Show annotation content inline (default true)
     pub show_annotations: bool,
@@ -21,20 +47,212 @@ pub struct Config {
     /// Syntax theme name (syntect/bat theme)
     pub syntax_theme: Option<String>,
     pub ai_target: AiTarget,
+    /// Compare syntax tokens instead of lines, hiding layout-only changes
+    pub structural_diff: bool,
+    /// Diff backend: "libgit2" (in-process) or "git" (shell out, honours diff drivers)
+    pub diff_backend: DiffBackend,
+    /// Diff algorithm: "myers", "patience", "histogram" or "minimal" (or `diff.algorithm`)
+    pub diff_algorithm: DiffAlgorithm,
+    /// Rename detection: "off", "renames" or "copies" (or `diff.renames`)
+    pub renames: RenameDetection,
+    /// Show paths without the a/ and b/ prefixes (or `diff.noprefix`)
+    pub no_prefix: bool,
+    /// Prefixes naming the compared sides, e.g. i/ and w/ (or `diff.mnemonicPrefix`)
+    pub mnemonic_prefix: bool,
+    /// Submodule changes to leave out: "none", "untracked", "dirty" or "all"
+    /// (or `diff.ignoreSubmodules`)
+    pub ignore_submodules: IgnoreSubmodules,
+    /// Ignore whitespace when comparing lines (`-w`)
+    pub ignore_all_space: bool,
+    /// Ignore changes in amount of whitespace (`-b`)
+    pub ignore_space_change: bool,
+    /// Ignore changes whose lines are all blank
+    pub ignore_blank_lines: bool,
+    /// Changed lines past which a file is shown as a stub until loaded (0: no limit)
+    pub max_file_lines: usize,
+    /// Changed lines across the diff past which further files are stubs (0: no limit)
+    pub max_total_lines: usize,
+    /// Untracked files larger than this many bytes are not read (0: no limit)
+    pub max_untracked_bytes: u64,
+    /// Files whose two sides hold more bytes than this are not diffed until
+    /// loaded (0: no limit)
+    pub max_file_bytes: u64,
+    /// Untracked files in the unstaged view: "show", "names", "group" or "hide"
+    pub untracked_files: UntrackedFiles,
     /// Ignore paths for filesystem watcher
     pub watch_ignore_paths: Vec<String>,
+    /// Keys config.toml sets itself, which git config must not override
+    #[serde(skip)]
+    file_keys: HashSet<String>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum AiTarget {
+    #[default]
     Claude,
     Codex,
 }
 
-impl Default for AiTarget {
-    fn default() -> Self {
-        AiTarget::Claude
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffBackend {
+    #[default]
+    Libgit2,
+    Git,
+}
+
+impl DiffBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiffBackend::Libgit2 => "libgit2",
+            DiffBackend::Git => "git",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    #[default]
+    // git also accepts "default" for myers
+    #[value(alias = "default")]
+    Myers,
+    Patience,
+    Histogram,
+    Minimal,
+}
+
+impl DiffAlgorithm {
+    pub const ALL: [DiffAlgorithm; 4] = [
+        DiffAlgorithm::Myers,
+        DiffAlgorithm::Patience,
+        DiffAlgorithm::Histogram,
+        DiffAlgorithm::Minimal,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiffAlgorithm::Myers => "myers",
+            DiffAlgorithm::Patience => "patience",
+            DiffAlgorithm::Histogram => "histogram",
+            DiffAlgorithm::Minimal => "minimal",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_lowercase();
+        // git also accepts "default" for myers
+        if name == "default" {
+            return Some(DiffAlgorithm::Myers);
+        }
+        Self::ALL.into_iter().find(|a| a.as_str() == name)
+    }
+
+    /// Next algorithm in the cycle used by the TUI
+    pub fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|a| *a == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+}
+
+/// Which moved files `git diff` pairs up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenameDetection {
+    /// Every file is added or deleted on its own
+    Off,
+    Renames,
+    /// Renames, and new files copied from a changed one
+    #[default]
+    Copies,
+}
+
+impl RenameDetection {
+    /// `diff.renames` takes a boolean or "copies"/"copy"
+    fn from_git(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "copies" | "copy" => Some(RenameDetection::Copies),
+            "true" | "yes" | "on" | "1" => Some(RenameDetection::Renames),
+            "false" | "no" | "off" | "0" => Some(RenameDetection::Off),
+            _ => None,
+        }
+    }
+}
+
+/// Submodule changes left out of the diff - `--ignore-submodules=<when>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IgnoreSubmodules {
+    #[default]
+    None,
+    /// Untracked files inside a submodule
+    Untracked,
+    /// Any change to a submodule's worktree, but not a new commit
+    Dirty,
+    /// Every submodule change
+    All,
+}
+
+impl IgnoreSubmodules {
+    const ALL: [IgnoreSubmodules; 4] = [
+        IgnoreSubmodules::None,
+        IgnoreSubmodules::Untracked,
+        IgnoreSubmodules::Dirty,
+        IgnoreSubmodules::All,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IgnoreSubmodules::None => "none",
+            IgnoreSubmodules::Untracked => "untracked",
+            IgnoreSubmodules::Dirty => "dirty",
+            IgnoreSubmodules::All => "all",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_lowercase();
+        Self::ALL.into_iter().find(|i| i.as_str() == name)
+    }
+}
+
+/// How untracked files appear in the unstaged view
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UntrackedFiles {
+    /// In full, as added files
+    #[default]
+    Show,
+    /// Names only; x loads a file's content
+    Names,
+    /// Names only, folded into one "N untracked files" group
+    Group,
+    /// Left out
+    Hide,
+}
+
+impl UntrackedFiles {
+    pub const ALL: [UntrackedFiles; 4] = [
+        UntrackedFiles::Show,
+        UntrackedFiles::Names,
+        UntrackedFiles::Group,
+        UntrackedFiles::Hide,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UntrackedFiles::Show => "show",
+            UntrackedFiles::Names => "names",
+            UntrackedFiles::Group => "group",
+            UntrackedFiles::Hide => "hide",
+        }
+    }
+
+    /// Next policy in the cycle used by the TUI
+    pub fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|u| *u == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
     }
 }
 
@@ -47,12 +265,28 @@ impl Default for Config {
             syntax_highlighting: true,
             syntax_theme: None,
             ai_target: AiTarget::default(),
+            structural_diff: false,
+            diff_backend: DiffBackend::default(),
+            diff_algorithm: DiffAlgorithm::default(),
+            renames: RenameDetection::default(),
+            no_prefix: false,
+            mnemonic_prefix: false,
+            ignore_submodules: IgnoreSubmodules::default(),
+            ignore_all_space: false,
+            ignore_space_change: false,
+            ignore_blank_lines: false,
+            max_file_lines: 5_000,
+            max_total_lines: 50_000,
+            max_untracked_bytes: 1024 * 1024,
+            max_file_bytes: 16 * 1024 * 1024,
+            untracked_files: UntrackedFiles::default(),
             watch_ignore_paths: vec![
                 ".git".to_string(),
                 "target".to_string(),
                 "_build".to_string(),
                 "deps".to_string(),
             ],
+            file_keys: HashSet::new(),
         }
     }
 }
@@ -64,7 +298,7 @@ impl Config {
 
         if config_path.exists() {
             let contents = std::fs::read_to_string(&config_path)?;
-            let config: Config = toml::from_str(&contents)?;
+            let config = Self::from_toml(&contents)?;
             Self::sync_missing_fields(&config_path, &contents);
             Ok(config)
         } else {
@@ -72,6 +306,40 @@ impl Config {
         }
     }
 
+    /// Parse config.toml, remembering which keys it sets
+    fn from_toml(contents: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(contents)?;
+        if let Ok(Value::Table(table)) = toml::from_str::<Value>(contents) {
+            config.file_keys = table
+                .keys()
+                .filter(|key| !Self::auto_written(&table, key))
+                .cloned()
+                .collect();
+        }
+        Ok(config)
+    }
+
+    /// Version of the release that wrote the file; files from before
+    /// `config_version` existed are version 1
+    fn file_version(table: &toml::map::Map<String, Value>) -> i64 {
+        table
+            .get("config_version")
+            .and_then(Value::as_integer)
+            .unwrap_or(1)
+    }
+
+    /// Whether `key` still holds the value an earlier version wrote for it
+    fn auto_written(table: &toml::map::Map<String, Value>, key: &str) -> bool {
+        // From version 2 on nothing is written for these keys, so a value
+        // there was set by hand even when it matches the old default
+        if Self::file_version(table) >= CONFIG_VERSION {
+            return false;
+        }
+        AUTO_WRITTEN
+            .iter()
+            .any(|(name, value)| *name == key && table[key].to_string() == *value)
+    }
+
     /// Get the default config file path
     pub fn default_path() -> PathBuf {
         dirs::home_dir()
@@ -80,6 +348,65 @@ impl Config {
             .join("config.toml")
     }
 
+    /// Fill in the diff settings config.toml leaves out from git config
+    /// (`diff.context`, `diff.algorithm`, ...), repository scope included
+    pub fn with_git_config(mut self, repo_path: &Path) -> Self {
+        let git_config = git2::Repository::open(repo_path)
+            .and_then(|repo| repo.config())
+            .or_else(|_| git2::Config::open_default());
+        let Ok(git_config) = git_config else {
+            return self;
+        };
+        let unset = |key: &str| !self.file_keys.contains(key);
+        let string = |name: &str| git_config.get_string(name).ok();
+        let boolean = |name: &str| git_config.get_bool(name).ok();
+
+        if unset("context_lines") {
+            if let Some(context) = git_config
+                .get_i64("diff.context")
+                .ok()
+                .and_then(|n| u32::try_from(n).ok())
+            {
+                self.context_lines = context;
+            }
+        }
+        if unset("diff_algorithm") {
+            if let Some(algorithm) = string("diff.algorithm")
+                .as_deref()
+                .and_then(DiffAlgorithm::from_name)
+            {
+                self.diff_algorithm = algorithm;
+            }
+        }
+        if unset("renames") {
+            if let Some(renames) = string("diff.renames")
+                .as_deref()
+                .and_then(RenameDetection::from_git)
+            {
+                self.renames = renames;
+            }
+        }
+        if unset("no_prefix") {
+            if let Some(no_prefix) = boolean("diff.noprefix") {
+                self.no_prefix = no_prefix;
+            }
+        }
+        if unset("mnemonic_prefix") {
+            if let Some(mnemonic) = boolean("diff.mnemonicPrefix") {
+                self.mnemonic_prefix = mnemonic;
+            }
+        }
+        if unset("ignore_submodules") {
+            if let Some(ignore) = string("diff.ignoreSubmodules")
+                .as_deref()
+                .and_then(IgnoreSubmodules::from_name)
+            {
+                self.ignore_submodules = ignore;
+            }
+        }
+        self
+    }
+
     /// Merge CLI overrides into config
     pub fn with_overrides(
         mut self,
@@ -95,6 +422,60 @@ impl Config {
         self
     }
 
+    /// Merge CLI diff option overrides into config
+    pub fn with_diff_overrides(
+        mut self,
+        diff_algorithm: Option<DiffAlgorithm>,
+        ignore_all_space: bool,
+        ignore_space_change: bool,
+        ignore_blank_lines: bool,
+    ) -> Self {
+        if let Some(algorithm) = diff_algorithm {
+            self.diff_algorithm = algorithm;
+        }
+        self.ignore_all_space |= ignore_all_space;
+        self.ignore_space_change |= ignore_space_change;
+        self.ignore_blank_lines |= ignore_blank_lines;
+        self
+    }
+
+    /// Whitespace handling for the diff engine
+    pub fn whitespace(&self) -> Whitespace {
+        Whitespace {
+            ignore_all_space: self.ignore_all_space,
+            ignore_space_change: self.ignore_space_change,
+            ignore_blank_lines: self.ignore_blank_lines,
+        }
+    }
+
+    /// Prefixes of the old and new path, as `git diff` would print them
+    pub fn path_prefixes(&self, mode: &DiffMode) -> (&'static str, &'static str) {
+        if self.no_prefix {
+            return ("", "");
+        }
+        if !self.mnemonic_prefix {
+            return ("a/", "b/");
+        }
+        // (c)ommit, (i)ndex, (w)orktree, or two files outside git
+        match mode {
+            DiffMode::Unstaged => ("i/", "w/"),
+            DiffMode::Staged => ("c/", "i/"),
+            DiffMode::WorkingTree { .. } => ("c/", "w/"),
+            DiffMode::NoIndex { .. } => ("1/", "2/"),
+            _ => ("a/", "b/"),
+        }
+    }
+
+    /// Size budgets for the diff engine
+    pub fn limits(&self) -> DiffLimits {
+        DiffLimits {
+            file_lines: self.max_file_lines,
+            total_lines: self.max_total_lines,
+            untracked_bytes: self.max_untracked_bytes,
+            file_bytes: self.max_file_bytes,
+        }
+    }
+
     /// Create a default config file
     pub fn create_default() -> Result<()> {
         let config_path = Self::default_path();
@@ -107,7 +488,15 @@ impl Config {
             })?;
         }
 
-        let contents = toml::to_string_pretty(&config).context("Failed to serialize config")?;
+        // Keys git config can supply stay out, so they keep deferring to it
+        let mut value = Value::try_from(&config).context("Failed to serialize config")?;
+        if let Value::Table(ref mut table) = value {
+            for key in GIT_CONFIG_KEYS {
+                table.remove(key);
+            }
+            table.insert("config_version".to_string(), Value::Integer(CONFIG_VERSION));
+        }
+        let contents = toml::to_string_pretty(&value).context("Failed to serialize config")?;
 
         std::fs::write(&config_path, contents)
             .with_context(|| format!("Failed to write config file: {}", config_path.display()))?;
@@ -124,6 +513,22 @@ impl Config {
         };
         let mut updated = false;
 
+        // Drop the git config keys versions before 2 filled in, so git
+        // config applies and a value set from now on is kept. Files already
+        // at version 2 are left alone: `context_lines = 3` there is a choice.
+        if Self::file_version(table) < CONFIG_VERSION {
+            let stale: Vec<String> = table
+                .keys()
+                .filter(|key| Self::auto_written(table, key))
+                .cloned()
+                .collect();
+            for key in stale {
+                table.remove(&key);
+            }
+            table.insert("config_version".to_string(), Value::Integer(CONFIG_VERSION));
+            updated = true;
+        }
+
         let defaults = Config::default();
 
         let mut insert_if_missing = |key: &str, value: Value| {
@@ -133,11 +538,9 @@ impl Config {
             }
         };
 
+        // Keys in GIT_CONFIG_KEYS are left out: once written they would
+        // shadow git config for good
         insert_if_missing("side_by_side", Value::Boolean(defaults.side_by_side));
-        insert_if_missing(
-            "context_lines",
-            Value::Integer(defaults.context_lines as i64),
-        );
         insert_if_missing(
             "show_annotations",
             Value::Boolean(defaults.show_annotations),
@@ -154,6 +557,43 @@ impl Config {
             AiTarget::Codex => "codex",
         };
         insert_if_missing("ai_target", Value::String(ai_target.to_string()));
+        insert_if_missing("structural_diff", Value::Boolean(defaults.structural_diff));
+        insert_if_missing(
+            "diff_backend",
+            Value::String(defaults.diff_backend.as_str().to_string()),
+        );
+        insert_if_missing(
+            "ignore_all_space",
+            Value::Boolean(defaults.ignore_all_space),
+        );
+        insert_if_missing(
+            "ignore_space_change",
+            Value::Boolean(defaults.ignore_space_change),
+        );
+        insert_if_missing(
+            "ignore_blank_lines",
+            Value::Boolean(defaults.ignore_blank_lines),
+        );
+        insert_if_missing(
+            "max_file_lines",
+            Value::Integer(defaults.max_file_lines as i64),
+        );
+        insert_if_missing(
+            "max_total_lines",
+            Value::Integer(defaults.max_total_lines as i64),
+        );
+        insert_if_missing(
+            "max_untracked_bytes",
+            Value::Integer(defaults.max_untracked_bytes as i64),
+        );
+        insert_if_missing(
+            "max_file_bytes",
+            Value::Integer(defaults.max_file_bytes as i64),
+        );
+        insert_if_missing(
+            "untracked_files",
+            Value::String(defaults.untracked_files.as_str().to_string()),
+        );
         insert_if_missing(
             "watch_ignore_paths",
             Value::Array(
@@ -172,3 +612,117 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_git_config_fills_what_the_file_leaves_out() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(dir.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        git(&["config", "diff.context", "7"]);
+        git(&["config", "diff.algorithm", "histogram"]);
+        git(&["config", "diff.renames", "false"]);
+        git(&["config", "diff.mnemonicPrefix", "true"]);
+        git(&["config", "diff.ignoreSubmodules", "dirty"]);
+
+        let config = Config::default().with_git_config(dir.path());
+        assert_eq!(config.context_lines, 7);
+        assert_eq!(config.diff_algorithm, DiffAlgorithm::Histogram);
+        assert_eq!(config.renames, RenameDetection::Off);
+        assert_eq!(config.ignore_submodules, IgnoreSubmodules::Dirty);
+        assert_eq!(config.path_prefixes(&DiffMode::Unstaged), ("i/", "w/"));
+        assert_eq!(config.path_prefixes(&DiffMode::Staged), ("c/", "i/"));
+
+        // config.toml wins over git config, and the command line over both
+        let config = Config::from_toml("context_lines = 2\nno_prefix = true\n")
+            .unwrap()
+            .with_git_config(dir.path());
+        assert_eq!(config.context_lines, 2);
+        assert_eq!(config.diff_algorithm, DiffAlgorithm::Histogram);
+        assert_eq!(config.path_prefixes(&DiffMode::Unstaged), ("", ""));
+        let config = config.with_overrides(None, Some(5)).with_diff_overrides(
+            Some(DiffAlgorithm::Patience),
+            false,
+            false,
+            false,
+        );
+        assert_eq!(config.context_lines, 5);
+        assert_eq!(config.diff_algorithm, DiffAlgorithm::Patience);
+
+        git(&["config", "diff.renames", "copies"]);
+        let config = Config::default().with_git_config(dir.path());
+        assert_eq!(config.renames, RenameDetection::Copies);
+    }
+
+    #[test]
+    fn test_values_written_by_earlier_versions_defer_to_git() {
+        let dir = tempdir().unwrap();
+        let status = Command::new("git")
+            .args(["init", "-q"])
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        for (key, value) in [("diff.context", "7"), ("diff.algorithm", "histogram")] {
+            let status = Command::new("git")
+                .args(["config", key, value])
+                .current_dir(dir.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        // What earlier versions left in config.toml
+        let path = dir.path().join("config.toml");
+        let written = "side_by_side = false\ncontext_lines = 3\ndiff_algorithm = \"myers\"\n";
+        let config = Config::from_toml(written)
+            .unwrap()
+            .with_git_config(dir.path());
+        assert_eq!(config.context_lines, 7);
+        assert_eq!(config.diff_algorithm, DiffAlgorithm::Histogram);
+
+        Config::sync_missing_fields(&path, written);
+        let migrated = std::fs::read_to_string(&path).unwrap();
+        let table: Value = toml::from_str(&migrated).unwrap();
+        assert!(table.get("context_lines").is_none());
+        assert!(table.get("diff_algorithm").is_none());
+
+        // Once migrated, the same values are the user's own
+        let chosen = format!("{}context_lines = 3\n", migrated);
+        let config = Config::from_toml(&chosen)
+            .unwrap()
+            .with_git_config(dir.path());
+        assert_eq!(config.context_lines, 3);
+        assert_eq!(config.diff_algorithm, DiffAlgorithm::Histogram);
+
+        // Only files from before version 2 are migrated
+        let older = format!("config_version = 1\n{}", written);
+        Config::sync_missing_fields(&path, &older);
+        let table: Value = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(table.get("context_lines").is_none());
+        assert_eq!(table["config_version"].as_integer(), Some(CONFIG_VERSION));
+        let current = format!("config_version = {}\n{}", CONFIG_VERSION, written);
+        Config::sync_missing_fields(&path, &current);
+        let table: Value = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(table["context_lines"].as_integer(), Some(3));
+        assert_eq!(table["diff_algorithm"].as_str(), Some("myers"));
+
+        // Values nobody wrote automatically stay in charge
+        let config = Config::from_toml("context_lines = 4\n")
+            .unwrap()
+            .with_git_config(dir.path());
+        assert_eq!(config.context_lines, 4);
+    }
+}
@@ -1,12 +1,30 @@
-//! Diff engine using git diff command
+//! Diff engine
 //!
-//! Uses `git diff` command directly to handle custom diff drivers properly.
+//! Diffs are produced in-process via libgit2 by default. The `git diff` command
+//! path is kept as a fallback; files with a textconv driver are re-diffed
+//! through it whichever backend is chosen.
 
+use crate::config::{
+    DiffAlgorithm, DiffBackend, IgnoreSubmodules, RenameDetection, UntrackedFiles,
+};
+use crate::structural::structural_hunks;
+use crate::syntax::SyntaxHighlighter;
 use anyhow::{Context, Result};
-use git2::Repository;
-use std::io::{BufRead, BufReader, Read};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use git2::{Delta, DiffFindOptions, DiffOptions, Patch, Repository, Tree};
+use similar::{capture_diff_slices, group_diff_ops, Algorithm, ChangeTag, DiffOp, TextDiff};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Config for `git diff` runs whose output is parsed: non-ASCII and control
+/// bytes in paths always come C-quoted, so names survive any local setting
+const GIT_PATH_CONFIG: [&str; 2] = ["-c", "core.quotePath=true"];
 
 /// Diff mode - what to compare
 #[derive(Debug, Clone)]
@@ -21,14 +39,191 @@ pub enum DiffMode {
     Commits { from: String, to: String },
     /// Changes since merge-base - `git diff <from>...<to>`
     MergeBase { from: String, to: String },
-    /// External diff mode - git passes old and new file paths directly
-    ExternalDiff {
-        path: String,
-        old_file: String,
-        new_file: String,
+    /// A single commit against its first parent - `git show <rev>`
+    Commit { rev: String },
+    /// A stash entry - `git stash show -p`. `staged` shows the stashed index
+    /// against the commit it was made on, otherwise the worktree changes are
+    /// shown against that index, as `git stash` keeps them apart.
+    Stash { index: usize, staged: bool },
+    /// External diff mode - git passes old and new file paths directly, one
+    /// file per run; a session gathers every run's files
+    ExternalDiff { files: Vec<ExternalFile> },
+    /// A unified diff read from a file or stdin (pager mode, read-only)
+    Patch { source: String, text: Arc<Vec<u8>> },
+    /// Two files or directory trees outside any repository - `git diff --no-index`
+    NoIndex { old: PathBuf, new: PathBuf },
+    /// Two versions of a patch series, one file per commit pair holding the
+    /// diff of their patches - `git range-diff`
+    RangeDiff {
+        old_from: String,
+        old_to: String,
+        new_from: String,
+        new_to: String,
     },
 }
 
+/// One file pair git hands to an external diff program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalFile {
+    /// Path in the repository, on the old side when renamed
+    pub path: String,
+    /// Path on the new side when git detected a rename or copy
+    pub new_path: Option<String>,
+    /// Old contents, `/dev/null` when the file was added
+    pub old_file: String,
+    /// New contents, `/dev/null` when the file was deleted
+    pub new_file: String,
+    /// An unmerged path, which git names without any contents
+    pub unmerged: bool,
+}
+
+impl ExternalFile {
+    /// The file git describes with the arguments of one external diff run:
+    /// `path old-file old-hex old-mode new-file new-hex new-mode`, followed
+    /// by `new-path rename-message` for renames and copies
+    pub fn from_git_args(args: &[String]) -> Option<Self> {
+        // An object id, or `.` for the missing side of an added or deleted file
+        let oid = |arg: &String| {
+            arg == "." || (arg.len() >= 40 && arg.chars().all(|c| c.is_ascii_hexdigit()))
+        };
+        if !matches!(args.len(), 7 | 9) || !oid(&args[2]) || !oid(&args[5]) {
+            return None;
+        }
+        Some(Self {
+            path: args[0].clone(),
+            new_path: args.get(7).cloned(),
+            old_file: args[1].clone(),
+            new_file: args[4].clone(),
+            unmerged: false,
+        })
+    }
+
+    /// An unmerged path, for which git passes the name alone
+    pub fn unmerged(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            new_path: None,
+            old_file: "/dev/null".to_string(),
+            new_file: "/dev/null".to_string(),
+            unmerged: true,
+        }
+    }
+}
+
+/// The empty tree, which a root commit is compared against
+const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+/// Metadata of a single commit, shown above its diff
+#[derive(Debug, Clone)]
+pub struct CommitInfo {
+    pub sha: String,
+    pub author: String,
+    pub date: String,
+    pub message: String,
+    pub parents: Vec<String>,
+}
+
+impl CommitInfo {
+    fn from_commit(commit: &git2::Commit) -> Self {
+        let author = commit.author();
+        Self {
+            sha: commit.id().to_string(),
+            author: format!(
+                "{} <{}>",
+                String::from_utf8_lossy(author.name_bytes()),
+                String::from_utf8_lossy(author.email_bytes())
+            ),
+            date: format_git_time(author.when()),
+            message: String::from_utf8_lossy(commit.message_bytes())
+                .trim_end()
+                .to_string(),
+            parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    /// First line of the message
+    pub fn summary(&self) -> &str {
+        self.message.lines().next().unwrap_or("")
+    }
+}
+
+/// An entry of `git stash list`
+#[derive(Debug, Clone)]
+pub struct StashEntry {
+    pub index: usize,
+    pub message: String,
+}
+
+/// Revision naming a stash entry
+pub fn stash_rev(index: usize) -> String {
+    format!("stash@{{{}}}", index)
+}
+
+/// Line of `new` that holds line `line_no` (1-based) of `old` unchanged,
+/// by a line diff of the two texts
+pub fn map_line(old: &str, new: &str, line_no: u32) -> Option<u32> {
+    let idx = line_no.checked_sub(1)? as usize;
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines)
+        .into_iter()
+        .find_map(|op| match op {
+            DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } if (old_index..old_index + len).contains(&idx) => {
+                Some((new_index + idx - old_index) as u32 + 1)
+            }
+            _ => None,
+        })
+}
+
+/// Lowest patch similarity at which commits of two series are paired
+/// without sharing a subject
+const RANGE_MATCH_RATIO: f32 = 0.5;
+
+/// A commit of the old series matched with one of the new series; either
+/// side is missing for a dropped or an added commit
+#[derive(Debug, Clone)]
+pub struct RangePair {
+    /// 1-based position in the old series and the commit
+    pub old: Option<(usize, CommitInfo)>,
+    /// 1-based position in the new series and the commit
+    pub new: Option<(usize, CommitInfo)>,
+    old_patch: String,
+    new_patch: String,
+}
+
+impl RangePair {
+    /// `git range-diff` style summary: `1: abc1234 ! 1: def5678 subject`
+    pub fn label(&self) -> String {
+        let side = |commit: &Option<(usize, CommitInfo)>| match commit {
+            Some((pos, info)) => format!("{}: {}", pos, &info.sha[..7.min(info.sha.len())]),
+            None => "-: -------".to_string(),
+        };
+        let marker = match (&self.old, &self.new) {
+            (Some(_), None) => '<',
+            (None, Some(_)) => '>',
+            _ if self.old_patch == self.new_patch => '=',
+            _ => '!',
+        };
+        let subject = self
+            .new
+            .as_ref()
+            .or(self.old.as_ref())
+            .map(|(_, info)| info.summary().to_string())
+            .unwrap_or_default();
+        format!(
+            "{} {} {} {}",
+            side(&self.old),
+            marker,
+            side(&self.new),
+            subject
+        )
+    }
+}
+
 /// Represents a changed file in a diff
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
@@ -37,6 +232,170 @@ pub struct DiffFile {
     pub new_path: Option<PathBuf>,
     pub status: FileStatus,
     pub hunks: Vec<DiffHunk>,
+    /// Binary content: no hunks, only sizes
+    pub binary: bool,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    /// Git file modes (e.g. 0o100644), when known
+    pub old_mode: Option<u32>,
+    pub new_mode: Option<u32>,
+    /// Similarity percentage of a rename or copy
+    pub similarity: Option<u8>,
+    /// Encoding of the file's text when it is not UTF-8
+    pub encoding: Option<&'static Encoding>,
+    /// Set when the hunks were left out for being over the size limits
+    pub deferred: Option<Deferred>,
+    /// Not tracked by git: listed from the worktree rather than diffed
+    pub untracked: bool,
+    /// Hunks show the output of a textconv driver, so they cannot be applied
+    pub textconv: bool,
+}
+
+/// What a file held when its hunks were left out of the diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deferred {
+    /// Changed lines of the file
+    Lines(usize),
+    /// Size of a file that was not read or diffed
+    Bytes(u64),
+}
+
+impl DiffFile {
+    pub fn new(old_path: Option<PathBuf>, new_path: Option<PathBuf>, status: FileStatus) -> Self {
+        Self {
+            old_path,
+            new_path,
+            status,
+            hunks: Vec::new(),
+            binary: false,
+            old_size: None,
+            new_size: None,
+            old_mode: None,
+            new_mode: None,
+            similarity: None,
+            encoding: None,
+            deferred: None,
+            untracked: false,
+            textconv: false,
+        }
+    }
+
+    /// A rename with identical content and mode
+    pub fn is_pure_rename(&self) -> bool {
+        self.status == FileStatus::Renamed
+            && self.similarity == Some(100)
+            && self.hunks.is_empty()
+            && self.deferred.is_none()
+            && !self.binary
+            && self.old_mode == self.new_mode
+    }
+
+    /// Whether the permission bits changed on an object of the same type
+    pub fn mode_changed(&self) -> bool {
+        match (self.old_mode, self.new_mode) {
+            (Some(old), Some(new)) => old != new && !self.type_changed(),
+            _ => false,
+        }
+    }
+
+    fn type_changed(&self) -> bool {
+        let is_blob = |m: u32| m & 0o170000 == 0o100000;
+        match (self.old_mode, self.new_mode) {
+            (Some(old), Some(new)) => old != new && !(is_blob(old) && is_blob(new)),
+            _ => false,
+        }
+    }
+
+    /// Whether there is anything to show for this file
+    pub fn has_changes(&self) -> bool {
+        !self.hunks.is_empty()
+            || self.binary
+            || self.deferred.is_some()
+            || self.old_mode != self.new_mode
+            || matches!(self.status, FileStatus::Renamed | FileStatus::Copied)
+    }
+
+    /// Files that can only be staged or discarded as a whole
+    pub fn whole_file_only(&self) -> bool {
+        self.binary
+            || self.textconv
+            || self.deferred.is_some()
+            || matches!(
+                self.status,
+                FileStatus::TypeChanged | FileStatus::Submodule | FileStatus::Conflicted
+            )
+    }
+
+    /// Added and deleted lines across all hunks
+    pub fn changed_lines(&self) -> usize {
+        self.hunks
+            .iter()
+            .flat_map(|h| &h.lines)
+            .filter(|l| l.kind != LineKind::Context)
+            .count()
+    }
+
+    /// Old and new commit of a submodule, read from its "Subproject commit" lines
+    pub fn submodule_commits(&self) -> (Option<String>, Option<String>) {
+        let mut old = None;
+        let mut new = None;
+        for line in self.hunks.iter().flat_map(|h| &h.lines) {
+            let Some(sha) = line.content.strip_prefix("Subproject commit ") else {
+                continue;
+            };
+            match line.kind {
+                LineKind::Deletion => old = Some(sha.to_string()),
+                LineKind::Addition => new = Some(sha.to_string()),
+                LineKind::Context => {
+                    old = Some(sha.to_string());
+                    new = Some(sha.to_string());
+                }
+            }
+        }
+        (old, new)
+    }
+
+    /// Refine a plain modification using the recorded modes
+    fn classify(&mut self) {
+        if self.status != FileStatus::Modified {
+            return;
+        }
+        // git prints no index line, so no modes, for a dirty submodule checkout
+        let lines = || self.hunks.iter().flat_map(|h| &h.lines);
+        if self.old_mode.is_none()
+            && self.new_mode.is_none()
+            && lines().next().is_some()
+            && lines().all(|l| l.content.starts_with("Subproject commit "))
+        {
+            self.old_mode = Some(MODE_SUBMODULE);
+            self.new_mode = Some(MODE_SUBMODULE);
+        }
+        if self.type_changed() {
+            self.status = FileStatus::TypeChanged;
+        } else if self.new_mode == Some(MODE_SUBMODULE) {
+            self.status = FileStatus::Submodule;
+        } else if self.new_mode == Some(MODE_SYMLINK) {
+            self.status = FileStatus::Symlink;
+        } else if self.mode_changed()
+            && self.hunks.is_empty()
+            && !self.binary
+            && self.deferred.is_none()
+        {
+            self.status = FileStatus::ModeChanged;
+        }
+    }
+
+    /// Record the fallback encoding when any line had to be decoded with it
+    fn note_legacy_lines(&mut self) {
+        let legacy = self
+            .hunks
+            .iter()
+            .flat_map(|h| &h.lines)
+            .any(|l| l.raw.is_some());
+        if legacy && self.encoding.is_none() {
+            self.encoding = Some(LEGACY_ENCODING);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -45,6 +404,33 @@ pub enum FileStatus {
     Deleted,
     Modified,
     Renamed,
+    /// New file copied from another one (`copy from`)
+    Copied,
+    /// Only the file mode changed (e.g. chmod +x)
+    ModeChanged,
+    /// Object type changed (e.g. regular file to symlink)
+    TypeChanged,
+    /// Symlink target changed
+    Symlink,
+    /// Submodule pointer moved
+    Submodule,
+    /// Unmerged path of a stopped merge or rebase
+    Conflicted,
+}
+
+pub const MODE_FILE: u32 = 0o100644;
+pub const MODE_EXECUTABLE: u32 = 0o100755;
+pub const MODE_SYMLINK: u32 = 0o120000;
+pub const MODE_SUBMODULE: u32 = 0o160000;
+
+/// Human-readable object type for a git file mode
+pub fn mode_kind(mode: u32) -> &'static str {
+    match mode {
+        MODE_SYMLINK => "symlink",
+        MODE_SUBMODULE => "submodule",
+        MODE_EXECUTABLE => "executable",
+        _ => "file",
+    }
 }
 
 /// A hunk of changes within a file
@@ -71,188 +457,1729 @@ pub struct DiffLine {
     pub highlights: Vec<HighlightRange>,
     /// Intraline diff ranges for additions/deletions
     pub inline_ranges: Vec<InlineRange>,
+    /// Part of a conflict region the line belongs to, in conflicted files
+    pub conflict: Option<ConflictSide>,
+    /// Per-parent columns of a combined (`--cc`) diff: Addition where the
+    /// line is new relative to that parent, Deletion where it came from that
+    /// parent only, Context where that parent has it. Empty for plain diffs.
+    pub combined: Vec<LineKind>,
+    /// Other end of a moved block, for deletions that reappear as additions
+    /// elsewhere in the diff and the reverse
+    pub moved: Option<MovedLine>,
+    /// Terminator the line had in its file, so rebuilt patches keep it
+    pub ending: LineEnding,
+    /// Bytes of `content` in the file when they are not UTF-8; `content`
+    /// then holds them decoded for display and patches use these instead
+    pub raw: Option<Vec<u8>>,
 }
 
+/// How a line ended in its file
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum LineKind {
-    Context,
-    Addition,
-    Deletion,
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    /// Last line of the file, without a newline - `\ No newline at end of file`
+    Missing,
 }
 
-use crate::syntax::TextStyle;
+impl LineEnding {
+    /// Text written after the line in a unified diff
+    pub fn patch_suffix(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Missing => "\n\\ No newline at end of file\n",
+        }
+    }
+}
 
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
-pub struct HighlightRange {
-    pub start: usize,
-    pub end: usize,
-    pub style: TextStyle,
+/// Split a line read with its terminator into its content and ending
+pub fn split_line_ending(line: &str) -> (&str, LineEnding) {
+    if let Some(content) = line.strip_suffix("\r\n") {
+        (content, LineEnding::CrLf)
+    } else if let Some(content) = line.strip_suffix('\n') {
+        (content, LineEnding::Lf)
+    } else {
+        (line, LineEnding::Missing)
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct InlineRange {
-    pub start: usize,
-    pub end: usize,
+/// Lines of a text with their endings; unlike `str::lines` nothing is lost
+fn lines_with_endings(text: &str) -> Vec<(&str, LineEnding)> {
+    text.split_inclusive('\n').map(split_line_ending).collect()
 }
 
-/// Diff engine using git diff command
-#[derive(Clone)]
-pub struct DiffEngine {
-    repo_path: PathBuf,
-    context_lines: u32,
+/// Encoding assumed for text that is not UTF-8 and declares nothing. Each
+/// byte decodes to a character of its own, so decoded text encodes back to
+/// exactly the original bytes.
+const LEGACY_ENCODING: &Encoding = WINDOWS_1252;
+
+/// Decode file content for display: a declared encoding (the
+/// `working-tree-encoding` attribute) wins, then a byte order mark, then
+/// UTF-8, then the legacy fallback. Returns the encoding used unless UTF-8.
+pub fn decode_text(
+    bytes: &[u8],
+    declared: Option<&'static Encoding>,
+) -> (String, Option<&'static Encoding>) {
+    let encoding = match declared {
+        Some(encoding) => encoding,
+        None if Encoding::for_bom(bytes).is_some() || std::str::from_utf8(bytes).is_ok() => UTF_8,
+        None => LEGACY_ENCODING,
+    };
+    // decode() follows a byte order mark over the guess
+    let (text, used, _) = encoding.decode(bytes);
+    (text.into_owned(), Some(used).filter(|e| *e != UTF_8))
 }
 
-impl DiffEngine {
-    pub fn new(repo_path: PathBuf, context_lines: u32) -> Self {
-        Self {
-            repo_path,
-            context_lines,
+/// A line of diff output as text, and whether it was decoded with the
+/// legacy encoding because it is not UTF-8
+fn decode_line(bytes: &[u8]) -> (Cow<'_, str>, bool) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (Cow::Borrowed(text), false),
+        Err(_) => (LEGACY_ENCODING.decode_without_bom_handling(bytes).0, true),
+    }
+}
+
+/// Original bytes of a line decoded with the legacy encoding
+fn legacy_bytes(text: &str) -> Vec<u8> {
+    LEGACY_ENCODING.encode(text).0.into_owned()
+}
+
+/// A working tree file decoded for display, honouring its
+/// `working-tree-encoding` attribute and textconv driver
+pub fn read_worktree_text(repo_path: &Path, path: &Path) -> Option<String> {
+    let bytes = std::fs::read(repo_path.join(path)).ok()?;
+    let Ok(repo) = Repository::open(repo_path) else {
+        return Some(decode_text(&bytes, None).0);
+    };
+    if let DiffDriver::Textconv(command) = diff_driver(&repo, path) {
+        // A failing driver leaves the file as it is
+        if let Ok(text) = run_textconv(repo_path, &command, &bytes) {
+            return Some(decode_text(&text, None).0);
         }
     }
+    Some(decode_text(&bytes, working_tree_encoding(&repo, path)).0)
+}
 
-    /// Main diff method - handles all diff modes
-    #[allow(dead_code)]
-    pub fn diff(&self, mode: &DiffMode, paths: &[String]) -> Result<Vec<DiffFile>> {
-        match mode {
-            DiffMode::Unstaged => {
-                let mut files = self.diff_via_git_cmd(&[], paths)?;
-                let mut untracked = self.diff_untracked_files(paths)?;
-                files.append(&mut untracked);
-                Ok(files)
-            }
-            DiffMode::Staged => self.diff_via_git_cmd(&["--staged"], paths),
-            DiffMode::WorkingTree { base } => self.diff_via_git_cmd(&[base.as_str()], paths),
-            DiffMode::Commits { from, to } => {
-                let range = format!("{}..{}", from, to);
-                self.diff_via_git_cmd(&[&range], paths)
+/// What the `diff` attribute of a path makes of its content
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DiffDriver {
+    /// Shown as text unless it looks binary
+    #[default]
+    Default,
+    /// `-diff`, `binary`, or a driver with `binary = true`: never shown as text
+    Binary,
+    /// A driver whose `textconv` command turns the content into text
+    Textconv(String),
+}
+
+/// The diff driver gitattributes assign to a path, with its
+/// `diff.<driver>.*` settings from git config
+pub fn diff_driver(repo: &Repository, path: &Path) -> DiffDriver {
+    let value = repo
+        .get_attr(path, "diff", git2::AttrCheckFlags::FILE_THEN_INDEX)
+        .ok()
+        .flatten();
+    match git2::AttrValue::from_string(value) {
+        git2::AttrValue::False => DiffDriver::Binary,
+        git2::AttrValue::String(driver) => {
+            let Ok(config) = repo.config() else {
+                return DiffDriver::Default;
+            };
+            if config.get_bool(&format!("diff.{}.binary", driver)) == Ok(true) {
+                return DiffDriver::Binary;
             }
-            DiffMode::MergeBase { from, to } => {
-                let range = format!("{}...{}", from, to);
-                self.diff_via_git_cmd(&[&range], paths)
+            match config.get_string(&format!("diff.{}.textconv", driver)) {
+                Ok(command) => DiffDriver::Textconv(command),
+                Err(_) => DiffDriver::Default,
             }
-            DiffMode::ExternalDiff {
-                path,
-                old_file,
-                new_file,
-            } => self.diff_external_files(path, old_file, new_file),
         }
+        _ => DiffDriver::Default,
     }
+}
 
-    /// Stream diff results file-by-file.
-    pub fn diff_stream<F>(&self, mode: &DiffMode, paths: &[String], mut on_file: F) -> Result<()>
-    where
-        F: FnMut(DiffFile) -> Result<()>,
-    {
-        match mode {
-            DiffMode::Unstaged => {
-                self.diff_via_git_cmd_stream(&[], paths, &mut on_file)?;
-                let untracked = self.diff_untracked_files(paths)?;
-                for file in untracked {
-                    on_file(file)?;
+/// Run a textconv command the way git does: through the shell, with the
+/// name of a file holding the content as its argument
+pub fn run_textconv(repo_path: &Path, command: &str, bytes: &[u8]) -> Result<Vec<u8>> {
+    // Created exclusively and readable by us alone, as git does, and
+    // removed when dropped
+    let mut input = tempfile::Builder::new()
+        .prefix("differ-textconv-")
+        .tempfile()
+        .context("Failed to create textconv input")?;
+    input
+        .write_all(bytes)
+        .and_then(|()| input.flush())
+        .context("Failed to write textconv input")?;
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", command))
+        .arg(command)
+        .arg(input.path())
+        .current_dir(repo_path)
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run textconv {}", command))?;
+    if !output.status.success() {
+        anyhow::bail!("textconv {} failed", command);
+    }
+    Ok(output.stdout)
+}
+
+/// The `working-tree-encoding` attribute of a path, when it names an
+/// encoding other than UTF-8. Git keeps such files as UTF-8 in the index
+/// and converts them on checkout.
+pub fn working_tree_encoding(repo: &Repository, path: &Path) -> Option<&'static Encoding> {
+    let value = repo
+        .get_attr(
+            path,
+            "working-tree-encoding",
+            git2::AttrCheckFlags::FILE_THEN_INDEX,
+        )
+        .ok()??;
+    Encoding::for_label(value.as_bytes()).filter(|e| *e != UTF_8)
+}
+
+/// Position of a line in a list of files: file, hunk and line index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MovedLine {
+    pub file: usize,
+    pub hunk: usize,
+    pub line: usize,
+}
+
+/// Fewest alphanumeric characters in a block for it to count as moved, as in
+/// git, so that braces and blank lines are not reported as moves
+const MOVED_MIN_ALNUM: usize = 20;
+
+/// Fewest alphanumeric characters in a line a moved block is looked up by.
+/// Plainer lines, blank ones included, only join a block found through
+/// its other lines.
+const MOVED_ANCHOR_ALNUM: usize = 4;
+
+/// Lines added more often than this are not looked up either, so that
+/// repeated boilerplate does not compare every deletion with every addition
+const MOVED_MAX_CANDIDATES: usize = 16;
+
+fn line_hash(content: &str) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Mark blocks of deleted lines that reappear as added lines anywhere in the
+/// diff, within or across files - `git diff --color-moved`. Each moved line
+/// points at its counterpart. Returns the number of moved line pairs.
+pub fn detect_moves(files: &mut [DiffFile]) -> usize {
+    let runs = |files: &[DiffFile], kind: LineKind| {
+        let mut runs: Vec<Vec<MovedLine>> = Vec::new();
+        for (file_idx, file) in files.iter().enumerate() {
+            for (hunk_idx, hunk) in file.hunks.iter().enumerate() {
+                let mut run: Vec<MovedLine> = Vec::new();
+                for (line_idx, line) in hunk.lines.iter().enumerate() {
+                    if line.kind == kind {
+                        run.push(MovedLine {
+                            file: file_idx,
+                            hunk: hunk_idx,
+                            line: line_idx,
+                        });
+                    } else if !run.is_empty() {
+                        runs.push(std::mem::take(&mut run));
+                    }
                 }
-                Ok(())
-            }
-            DiffMode::Staged => self.diff_via_git_cmd_stream(&["--staged"], paths, &mut on_file),
-            DiffMode::WorkingTree { base } => {
-                self.diff_via_git_cmd_stream(&[base.as_str()], paths, &mut on_file)
-            }
-            DiffMode::Commits { from, to } => {
-                let range = format!("{}..{}", from, to);
-                self.diff_via_git_cmd_stream(&[&range], paths, &mut on_file)
-            }
-            DiffMode::MergeBase { from, to } => {
-                let range = format!("{}...{}", from, to);
-                self.diff_via_git_cmd_stream(&[&range], paths, &mut on_file)
-            }
-            DiffMode::ExternalDiff {
-                path,
-                old_file,
-                new_file,
-            } => {
-                let files = self.diff_external_files(path, old_file, new_file)?;
-                for file in files {
-                    on_file(file)?;
+                if !run.is_empty() {
+                    runs.push(run);
                 }
-                Ok(())
             }
         }
-    }
-
-    /// Use git diff command directly - handles custom diff drivers properly
-    #[allow(dead_code)]
-    fn diff_via_git_cmd(&self, args: &[&str], paths: &[String]) -> Result<Vec<DiffFile>> {
-        let mut cmd = Command::new("git");
-        cmd.arg("-C")
-            .arg(&self.repo_path)
-            .arg("diff")
-            .arg("--no-color")
-            .arg(format!("-U{}", self.context_lines))
-            .arg("--find-renames")
-            .arg("--find-copies");
+        runs
+    };
+    let deleted = runs(files, LineKind::Deletion);
+    let added = runs(files, LineKind::Addition);
 
-        for arg in args {
-            cmd.arg(arg);
+    let pairs = {
+        let content = |at: MovedLine| {
+            files[at.file].hunks[at.hunk].lines[at.line]
+                .content
+                .as_str()
+        };
+        let anchors = |at: MovedLine| {
+            content(at).chars().filter(|c| c.is_alphanumeric()).count() >= MOVED_ANCHOR_ALNUM
+        };
+        let mut index: HashMap<u64, Vec<(usize, usize)>> = HashMap::new();
+        for (run_idx, run) in added.iter().enumerate() {
+            for (offset, at) in run.iter().enumerate() {
+                if anchors(*at) {
+                    index
+                        .entry(line_hash(content(*at)))
+                        .or_default()
+                        .push((run_idx, offset));
+                }
+            }
         }
+        index.retain(|_, candidates| candidates.len() <= MOVED_MAX_CANDIDATES);
 
-        if !paths.is_empty() {
-            cmd.arg("--");
-            for path in paths {
-                if !path.is_empty() {
-                    cmd.arg(path);
+        let mut taken: HashSet<MovedLine> = HashSet::new();
+        let mut pairs = Vec::new();
+        for run in &deleted {
+            // Deleted lines from `unpaired` on are not part of a block yet
+            let mut unpaired = 0;
+            let mut i = 0;
+            while i < run.len() {
+                let candidates = if anchors(run[i]) {
+                    index.get(&line_hash(content(run[i])))
+                } else {
+                    None
+                };
+                // Longest block of added lines starting with this one
+                let mut best: Option<(usize, usize, usize)> = None;
+                for &(run_idx, offset) in candidates.into_iter().flatten() {
+                    let target = &added[run_idx];
+                    let len = (0..)
+                        .take_while(|k| {
+                            i + k < run.len()
+                                && offset + k < target.len()
+                                && !taken.contains(&target[offset + k])
+                                && content(run[i + k]) == content(target[offset + k])
+                        })
+                        .count();
+                    if len > best.map_or(0, |b| b.2) {
+                        best = Some((run_idx, offset, len));
+                    }
+                }
+                let Some((run_idx, offset, len)) = best else {
+                    i += 1;
+                    continue;
+                };
+                // Plain lines just before the one it was found by belong
+                // to the block too
+                let target = &added[run_idx];
+                let back = (1..)
+                    .take_while(|k| {
+                        i >= unpaired + k
+                            && offset >= *k
+                            && !taken.contains(&target[offset - k])
+                            && content(run[i - k]) == content(target[offset - k])
+                    })
+                    .count();
+                let (start, offset, len) = (i - back, offset - back, len + back);
+                let alnum: usize = run[start..start + len]
+                    .iter()
+                    .map(|at| content(*at).chars().filter(|c| c.is_alphanumeric()).count())
+                    .sum();
+                if alnum < MOVED_MIN_ALNUM {
+                    i += 1;
+                    continue;
+                }
+                for k in 0..len {
+                    let to = target[offset + k];
+                    taken.insert(to);
+                    pairs.push((run[start + k], to));
                 }
+                i = start + len;
+                unpaired = i;
             }
         }
+        pairs
+    };
 
-        let output = cmd.output().context("Failed to run git diff")?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("git diff failed: {}", stderr);
+    for file in files.iter_mut() {
+        for line in file.hunks.iter_mut().flat_map(|h| h.lines.iter_mut()) {
+            line.moved = None;
         }
+    }
+    for (from, to) in &pairs {
+        files[from.file].hunks[from.hunk].lines[from.line].moved = Some(*to);
+        files[to.file].hunks[to.hunk].lines[to.line].moved = Some(*from);
+    }
+    pairs.len()
+}
 
-        self.parse_full_diff(&String::from_utf8_lossy(&output.stdout))
+/// Part of a `<<<<<<<`/`>>>>>>>` conflict region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictSide {
+    Marker,
+    Ours,
+    Base,
+    Theirs,
+}
+
+/// Resolution for one conflict region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictChoice {
+    Ours,
+    Theirs,
+    Both,
+}
+
+/// A conflict region as 0-based line indices of its markers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConflictRegion {
+    /// `<<<<<<<` line
+    pub start: usize,
+    /// `|||||||` line, with `merge.conflictStyle = diff3`
+    pub base: Option<usize>,
+    /// `=======` line
+    pub separator: usize,
+    /// `>>>>>>>` line
+    pub end: usize,
+}
+
+impl ConflictRegion {
+    pub fn ours(&self) -> Range<usize> {
+        self.start + 1..self.base.unwrap_or(self.separator)
     }
 
-    fn diff_via_git_cmd_stream<F>(
-        &self,
-        args: &[&str],
-        paths: &[String],
-        on_file: &mut F,
-    ) -> Result<()>
-    where
-        F: FnMut(DiffFile) -> Result<()>,
-    {
-        let mut cmd = Command::new("git");
-        cmd.arg("-C")
-            .arg(&self.repo_path)
-            .arg("diff")
-            .arg("--no-color")
-            .arg(format!("-U{}", self.context_lines))
-            .arg("--find-renames")
-            .arg("--find-copies");
+    pub fn theirs(&self) -> Range<usize> {
+        self.separator + 1..self.end
+    }
 
-        for arg in args {
-            cmd.arg(arg);
+    /// Side of the region a line falls on, if any
+    pub fn side_of(&self, idx: usize) -> Option<ConflictSide> {
+        if idx == self.start || Some(idx) == self.base || idx == self.separator || idx == self.end {
+            Some(ConflictSide::Marker)
+        } else if self.ours().contains(&idx) {
+            Some(ConflictSide::Ours)
+        } else if self.theirs().contains(&idx) {
+            Some(ConflictSide::Theirs)
+        } else if (self.start..self.end).contains(&idx) {
+            Some(ConflictSide::Base)
+        } else {
+            None
         }
+    }
+}
 
-        if !paths.is_empty() {
-            cmd.arg("--");
-            for path in paths {
-                if !path.is_empty() {
-                    cmd.arg(path);
+/// A conflict marker: seven `marker` characters, then a label or nothing
+fn is_conflict_marker(line: &str, marker: u8) -> bool {
+    let line = line.strip_suffix('\r').unwrap_or(line).as_bytes();
+    line.len() >= 7
+        && line[..7].iter().all(|b| *b == marker)
+        && line.get(7).is_none_or(|b| *b == b' ')
+}
+
+/// Conflict regions left in a file's text
+pub fn find_conflicts(text: &str) -> Vec<ConflictRegion> {
+    let mut regions = Vec::new();
+    let mut open: Option<(usize, Option<usize>, Option<usize>)> = None;
+    for (idx, line) in text.lines().enumerate() {
+        match open.as_mut() {
+            Some((start, base, separator)) => {
+                if separator.is_none() && is_conflict_marker(line, b'<') {
+                    // An unterminated region; start over from here
+                    open = Some((idx, None, None));
+                } else if separator.is_none() && base.is_none() && is_conflict_marker(line, b'|') {
+                    *base = Some(idx);
+                } else if separator.is_none() && line.trim_end_matches('\r') == "=======" {
+                    *separator = Some(idx);
+                } else if let (Some(sep), true) = (*separator, is_conflict_marker(line, b'>')) {
+                    regions.push(ConflictRegion {
+                        start: *start,
+                        base: *base,
+                        separator: sep,
+                        end: idx,
+                    });
+                    open = None;
                 }
             }
+            None if is_conflict_marker(line, b'<') => open = Some((idx, None, None)),
+            None => {}
         }
+    }
+    regions
+}
 
-        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+/// Conflict regions left in a file's bytes, whatever their encoding: the
+/// markers are ASCII, and lossy decoding keeps every line where it was
+pub fn find_conflicts_in(bytes: &[u8]) -> Vec<ConflictRegion> {
+    find_conflicts(&String::from_utf8_lossy(bytes))
+}
 
-        let mut child = cmd.spawn().context("Failed to run git diff")?;
-        let stdout = child
-            .stdout
-            .take()
-            .context("Failed to capture git diff stdout")?;
-        let mut reader = BufReader::new(stdout);
+/// Replace one conflict region with the chosen side(s), keeping every other
+/// byte of the file as it is
+pub fn resolve_conflict(bytes: &[u8], region: &ConflictRegion, choice: ConflictChoice) -> Vec<u8> {
+    let lines: Vec<&[u8]> = bytes.split_inclusive(|b| *b == b'\n').collect();
+    let mut out = lines[..region.start].concat();
+    if matches!(choice, ConflictChoice::Ours | ConflictChoice::Both) {
+        out.extend(lines[region.ours()].concat());
+    }
+    if matches!(choice, ConflictChoice::Theirs | ConflictChoice::Both) {
+        out.extend(lines[region.theirs()].concat());
+    }
+    out.extend(lines[region.end + 1..].concat());
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    Context,
+    Addition,
+    Deletion,
+}
+
+use crate::syntax::TextStyle;
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct HighlightRange {
+    pub start: usize,
+    pub end: usize,
+    pub style: TextStyle,
+}
+
+#[derive(Debug, Clone)]
+pub struct InlineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Whitespace handling when comparing lines
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Whitespace {
+    /// `-w`: ignore all whitespace
+    pub ignore_all_space: bool,
+    /// `-b`: ignore changes in amount of whitespace
+    pub ignore_space_change: bool,
+    /// `--ignore-blank-lines`: ignore changes whose lines are all blank
+    pub ignore_blank_lines: bool,
+}
+
+impl Whitespace {
+    /// Whether any whitespace difference is hidden
+    pub fn is_ignoring(&self) -> bool {
+        self.ignore_all_space || self.ignore_space_change || self.ignore_blank_lines
+    }
+
+    fn git_args(&self) -> Vec<&'static str> {
+        let mut args = Vec::new();
+        if self.ignore_all_space {
+            args.push("--ignore-all-space");
+        }
+        if self.ignore_space_change {
+            args.push("--ignore-space-change");
+        }
+        if self.ignore_blank_lines {
+            args.push("--ignore-blank-lines");
+        }
+        args
+    }
+
+    /// Comparison key for a line
+    fn normalize(&self, line: &str) -> String {
+        if self.ignore_all_space {
+            line.split_whitespace().collect()
+        } else if self.ignore_space_change {
+            let mut key = String::with_capacity(line.len());
+            let mut in_space = false;
+            for c in line.trim_end().chars() {
+                if c.is_whitespace() {
+                    in_space = true;
+                    continue;
+                }
+                if in_space {
+                    key.push(' ');
+                    in_space = false;
+                }
+                key.push(c);
+            }
+            key
+        } else {
+            line.to_string()
+        }
+    }
+}
+
+/// Size budgets past which a file's hunks are left out until asked for.
+/// Zero means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffLimits {
+    /// Changed lines in a single file
+    pub file_lines: usize,
+    /// Changed lines across the whole diff
+    pub total_lines: usize,
+    /// Size of an untracked file, checked before it is read
+    pub untracked_bytes: u64,
+    /// Size of both sides of a file, checked before it is diffed
+    pub file_bytes: u64,
+}
+
+/// Diff engine backed by libgit2 or the git command
+#[derive(Clone)]
+pub struct DiffEngine {
+    repo_path: PathBuf,
+    context_lines: u32,
+    backend: DiffBackend,
+    structural: bool,
+    algorithm: DiffAlgorithm,
+    whitespace: Whitespace,
+    renames: RenameDetection,
+    ignore_submodules: IgnoreSubmodules,
+    limits: DiffLimits,
+    untracked: UntrackedFiles,
+    /// Files loaded in full on request, exempt from the limits
+    full_paths: HashSet<PathBuf>,
+}
+
+impl DiffEngine {
+    pub fn new(repo_path: PathBuf, context_lines: u32) -> Self {
+        Self {
+            repo_path,
+            context_lines,
+            backend: DiffBackend::default(),
+            structural: false,
+            algorithm: DiffAlgorithm::default(),
+            whitespace: Whitespace::default(),
+            renames: RenameDetection::default(),
+            ignore_submodules: IgnoreSubmodules::default(),
+            limits: DiffLimits::default(),
+            untracked: UntrackedFiles::default(),
+            full_paths: HashSet::new(),
+        }
+    }
+
+    pub fn with_backend(mut self, backend: DiffBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_structural(mut self, structural: bool) -> Self {
+        self.structural = structural;
+        self
+    }
+
+    pub fn set_structural(&mut self, structural: bool) {
+        self.structural = structural;
+    }
+
+    pub fn with_algorithm(mut self, algorithm: DiffAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn set_algorithm(&mut self, algorithm: DiffAlgorithm) {
+        self.algorithm = algorithm;
+    }
+
+    pub fn with_whitespace(mut self, whitespace: Whitespace) -> Self {
+        self.whitespace = whitespace;
+        self
+    }
+
+    pub fn set_whitespace(&mut self, whitespace: Whitespace) {
+        self.whitespace = whitespace;
+    }
+
+    pub fn whitespace(&self) -> Whitespace {
+        self.whitespace
+    }
+
+    pub fn with_renames(mut self, renames: RenameDetection) -> Self {
+        self.renames = renames;
+        self
+    }
+
+    pub fn with_ignore_submodules(mut self, ignore_submodules: IgnoreSubmodules) -> Self {
+        self.ignore_submodules = ignore_submodules;
+        self
+    }
+
+    /// Rename and submodule options for `git diff`
+    fn git_detection_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = match self.renames {
+            RenameDetection::Off => vec!["--no-renames".into()],
+            RenameDetection::Renames => vec!["--find-renames".into()],
+            RenameDetection::Copies => vec!["--find-renames".into(), "--find-copies".into()],
+        };
+        args.push(format!(
+            "--ignore-submodules={}",
+            self.ignore_submodules.as_str()
+        ));
+        args
+    }
+
+    pub fn with_limits(mut self, limits: DiffLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_untracked(mut self, untracked: UntrackedFiles) -> Self {
+        self.untracked = untracked;
+        self
+    }
+
+    pub fn set_untracked(&mut self, untracked: UntrackedFiles) {
+        self.untracked = untracked;
+    }
+
+    /// Keep an untracked file out of the diff by listing it in `info/exclude`
+    pub fn exclude_untracked(&self, path: &Path) -> Result<()> {
+        let pattern = exclude_pattern(path).context("A name with a newline cannot be excluded")?;
+        // Linked worktrees share the main repository's exclude file
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.repo_path)
+            .args(["rev-parse", "--git-path", "info/exclude"])
+            .output()
+            .context("Failed to run git rev-parse")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("git rev-parse failed: {}", stderr.trim());
+        }
+        let exclude = self
+            .repo_path
+            .join(String::from_utf8_lossy(&output.stdout).trim_end());
+        if let Some(dir) = exclude.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let mut contents = std::fs::read(&exclude).unwrap_or_default();
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            contents.push(b'\n');
+        }
+        contents.extend_from_slice(&pattern);
+        contents.push(b'\n');
+        std::fs::write(&exclude, contents)
+            .with_context(|| format!("Failed to write {}", exclude.display()))
+    }
+
+    /// Whether one side of an unmerged path deleted it: its index stage
+    /// (2 for ours, 3 for theirs) is missing
+    pub fn conflict_side_deleted(&self, path: &Path, choice: ConflictChoice) -> bool {
+        let stage = match choice {
+            ConflictChoice::Ours => 2,
+            ConflictChoice::Theirs => 3,
+            ConflictChoice::Both => return false,
+        };
+        Repository::open(&self.repo_path)
+            .and_then(|repo| repo.index())
+            .is_ok_and(|index| index.get_path(path, stage).is_none())
+    }
+
+    /// Same engine with whitespace differences shown, for the hunks that
+    /// staging and discarding apply
+    pub fn whitespace_sensitive(&self) -> Self {
+        let mut engine = self.clone();
+        engine.whitespace = Whitespace::default();
+        engine.structural = false;
+        engine.limits = DiffLimits::default();
+        engine
+    }
+
+    /// Keep a file left out over the limits in full from now on
+    pub fn keep_in_full(&mut self, file: &DiffFile) {
+        self.full_paths
+            .extend(file.old_path.iter().chain(&file.new_path).cloned());
+    }
+
+    /// Diff a file left out over the limits again in full, and keep it in
+    /// full from now on
+    pub fn load_in_full(
+        &mut self,
+        mode: &DiffMode,
+        paths: &[String],
+        file: &DiffFile,
+    ) -> Result<Option<DiffFile>> {
+        self.keep_in_full(file);
+        self.diff_file_again(mode, paths, file)
+    }
+
+    /// Diff one file of an earlier diff again. Only that file is diffed
+    /// where the mode allows it: the pathspec names it, or the nearest
+    /// directory whose name is UTF-8, and it is picked out by its path.
+    pub fn diff_file_again(
+        &self,
+        mode: &DiffMode,
+        paths: &[String],
+        file: &DiffFile,
+    ) -> Result<Option<DiffFile>> {
+        let key = |f: &DiffFile| f.new_path.clone().or_else(|| f.old_path.clone());
+        let find = |mode: &DiffMode, paths: &[String]| -> Result<Option<DiffFile>> {
+            let mut found = None;
+            self.diff_stream(mode, paths, |f| {
+                if found.is_none() && key(&f) == key(file) {
+                    found = Some(f);
+                }
+                Ok(())
+            })?;
+            Ok(found)
+        };
+        match mode {
+            DiffMode::ExternalDiff { files } => {
+                let files = files
+                    .iter()
+                    .filter(|f| {
+                        let path = f.new_path.as_ref().unwrap_or(&f.path);
+                        Some(Path::new(path)) == key(file).as_deref()
+                    })
+                    .cloned()
+                    .collect();
+                find(&DiffMode::ExternalDiff { files }, &[])
+            }
+            DiffMode::Patch { .. } | DiffMode::NoIndex { .. } | DiffMode::RangeDiff { .. } => {
+                find(mode, paths)
+            }
+            _ => {
+                let narrowed: Vec<String> = file
+                    .old_path
+                    .iter()
+                    .chain(&file.new_path)
+                    .map(|path| {
+                        path.ancestors()
+                            .find_map(Path::to_str)
+                            .unwrap_or_default()
+                            .to_string()
+                    })
+                    .collect();
+                find(mode, &narrowed)
+            }
+        }
+    }
+
+    fn loads_in_full(&self, path: &Path) -> bool {
+        self.full_paths.contains(path)
+    }
+
+    /// Whether a file with `lines` changed lines is over the budget for a
+    /// single file. It is checked while the diff is read, so the hunks of
+    /// such a file are never built.
+    fn over_file_limit(&self, paths: [Option<&Path>; 2], lines: usize) -> bool {
+        self.limits.file_lines > 0
+            && lines > self.limits.file_lines
+            && !paths.into_iter().flatten().any(|p| self.loads_in_full(p))
+    }
+
+    /// Whether a file whose sides hold `bytes` in all is too big to diff
+    /// before it is asked for
+    fn over_file_bytes(&self, paths: [Option<&Path>; 2], bytes: u64) -> bool {
+        self.limits.file_bytes > 0
+            && bytes > self.limits.file_bytes
+            && !paths.into_iter().flatten().any(|p| self.loads_in_full(p))
+    }
+
+    /// Size of one side of a libgit2 delta without reading it: the index
+    /// records it, blobs in trees have it in their header and files in the
+    /// working tree in their metadata
+    fn side_size(&self, repo: &Repository, file: &git2::DiffFile) -> u64 {
+        if file.size() > 0 || !file.exists() {
+            return file.size();
+        }
+        if !file.id().is_zero() {
+            if let Some((size, _)) = repo
+                .odb()
+                .ok()
+                .and_then(|odb| odb.read_header(file.id()).ok())
+            {
+                return size as u64;
+            }
+        }
+        file.path()
+            .and_then(|path| self.repo_path.join(path).symlink_metadata().ok())
+            .map_or(0, |meta| meta.len())
+    }
+
+    /// Leave out the hunks of a file over the line budgets, keeping their count
+    fn apply_limits(&self, file: &mut DiffFile, total: &mut usize) {
+        let exempt = file.deferred.is_some()
+            || file.status == FileStatus::Conflicted
+            || file
+                .new_path
+                .iter()
+                .chain(&file.old_path)
+                .any(|p| self.loads_in_full(p));
+        if exempt {
+            return;
+        }
+        let lines = file.changed_lines();
+        let over_file =
+            self.over_file_limit([file.old_path.as_deref(), file.new_path.as_deref()], lines);
+        let over_total = self.limits.total_lines > 0 && *total + lines > self.limits.total_lines;
+        if over_file || over_total {
+            file.hunks = Vec::new();
+            file.deferred = Some(Deferred::Lines(lines));
+        } else {
+            *total += lines;
+        }
+    }
+
+    /// Author, date, message and parents of a commit
+    pub fn commit_info(&self, rev: &str) -> Result<CommitInfo> {
+        let repo = Repository::open(&self.repo_path).context("Failed to open repository")?;
+        let commit = resolve_commit(&repo, rev)?;
+        Ok(CommitInfo::from_commit(&commit))
+    }
+
+    /// Commits reachable from `to` but not from `from`, oldest first - `git log <from>..<to>`
+    pub fn list_commits(&self, from: &str, to: &str) -> Result<Vec<CommitInfo>> {
+        let repo = Repository::open(&self.repo_path).context("Failed to open repository")?;
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        walk.push(resolve_commit(&repo, to)?.id())?;
+        walk.hide(resolve_commit(&repo, from)?.id())?;
+        let mut commits = Vec::new();
+        for id in walk {
+            commits.push(CommitInfo::from_commit(&repo.find_commit(id?)?));
+        }
+        Ok(commits)
+    }
+
+    /// Match the commits of two versions of a series: identical patches
+    /// first, then the most similar remaining pairs, like `git range-diff`.
+    /// Pairs follow the new series, dropped commits near their old position.
+    pub fn range_pairs(
+        &self,
+        old: (&str, &str),
+        new: (&str, &str),
+        paths: &[String],
+    ) -> Result<Vec<RangePair>> {
+        let old_commits = self.list_commits(old.0, old.1)?;
+        let new_commits = self.list_commits(new.0, new.1)?;
+        let patches = |commits: &[CommitInfo]| {
+            commits
+                .iter()
+                .map(|c| self.commit_patch(&c.sha, paths))
+                .collect::<Result<Vec<_>>>()
+        };
+        let old_patches = patches(&old_commits)?;
+        let new_patches = patches(&new_commits)?;
+
+        let mut new_to_old: Vec<Option<usize>> = vec![None; new_commits.len()];
+        let mut old_taken = vec![false; old_commits.len()];
+        for (j, patch) in new_patches.iter().enumerate() {
+            if let Some(i) =
+                (0..old_commits.len()).find(|&i| !old_taken[i] && old_patches[i] == *patch)
+            {
+                new_to_old[j] = Some(i);
+                old_taken[i] = true;
+            }
+        }
+        let mut candidates = Vec::new();
+        for (i, old_patch) in old_patches.iter().enumerate() {
+            for (j, new_patch) in new_patches.iter().enumerate() {
+                if old_taken[i] || new_to_old[j].is_some() {
+                    continue;
+                }
+                let mut score = TextDiff::from_lines(old_patch, new_patch).ratio();
+                if old_commits[i].summary() == new_commits[j].summary() {
+                    score += 1.0;
+                }
+                if score >= RANGE_MATCH_RATIO {
+                    candidates.push((score, i, j));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+        for (_, i, j) in candidates {
+            if !old_taken[i] && new_to_old[j].is_none() {
+                new_to_old[j] = Some(i);
+                old_taken[i] = true;
+            }
+        }
+
+        let dropped = |i: usize| RangePair {
+            old: Some((i + 1, old_commits[i].clone())),
+            new: None,
+            old_patch: old_patches[i].clone(),
+            new_patch: String::new(),
+        };
+        let mut pairs = Vec::new();
+        let mut next_old = 0;
+        for (j, matched) in new_to_old.iter().enumerate() {
+            if let Some(i) = *matched {
+                while next_old < i {
+                    if !old_taken[next_old] {
+                        pairs.push(dropped(next_old));
+                    }
+                    next_old += 1;
+                }
+                next_old = next_old.max(i + 1);
+            }
+            pairs.push(RangePair {
+                old: matched.map(|i| (i + 1, old_commits[i].clone())),
+                new: Some((j + 1, new_commits[j].clone())),
+                old_patch: matched.map(|i| old_patches[i].clone()).unwrap_or_default(),
+                new_patch: new_patches[j].clone(),
+            });
+        }
+        pairs.extend(
+            (next_old..old_commits.len())
+                .filter(|&i| !old_taken[i])
+                .map(dropped),
+        );
+        Ok(pairs)
+    }
+
+    /// A commit's patch with positions stripped, so that the same change
+    /// reads the same after a rebase: `## path ##` and `@@ context` headers
+    fn commit_patch(&self, sha: &str, paths: &[String]) -> Result<String> {
+        let mode = DiffMode::Commit {
+            rev: sha.to_string(),
+        };
+        let mut patch = String::new();
+        self.diff_stream_lines(&mode, paths, &mut |file: DiffFile| {
+            let path = |p: &Option<PathBuf>| {
+                p.as_ref()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            };
+            let header = match file.status {
+                FileStatus::Added => format!("{} (new)", path(&file.new_path)),
+                FileStatus::Deleted => format!("{} (deleted)", path(&file.old_path)),
+                FileStatus::Renamed | FileStatus::Copied => {
+                    format!("{} => {}", path(&file.old_path), path(&file.new_path))
+                }
+                _ => path(&file.new_path),
+            };
+            patch.push_str(&format!("## {} ##\n", header));
+            if file.binary {
+                patch.push_str("Binary files differ\n");
+            }
+            for hunk in &file.hunks {
+                match &hunk.header {
+                    Some(header) => patch.push_str(&format!("@@ {}\n", header)),
+                    None => patch.push_str("@@\n"),
+                }
+                for line in &hunk.lines {
+                    let prefix = match line.kind {
+                        LineKind::Addition => '+',
+                        LineKind::Deletion => '-',
+                        LineKind::Context => ' ',
+                    };
+                    patch.push(prefix);
+                    patch.push_str(&line.content);
+                    patch.push('\n');
+                }
+            }
+            Ok(())
+        })?;
+        Ok(patch)
+    }
+
+    /// Stash entries, newest first - `git stash list`
+    pub fn list_stashes(&self) -> Result<Vec<StashEntry>> {
+        let mut repo = Repository::open(&self.repo_path).context("Failed to open repository")?;
+        let mut stashes = Vec::new();
+        repo.stash_foreach(|index, message, _| {
+            stashes.push(StashEntry {
+                index,
+                message: message.to_string(),
+            });
+            true
+        })?;
+        Ok(stashes)
+    }
+
+    /// Main diff method - handles all diff modes
+    #[allow(dead_code)]
+    pub fn diff(&self, mode: &DiffMode, paths: &[String]) -> Result<Vec<DiffFile>> {
+        let mut files = Vec::new();
+        self.diff_stream(mode, paths, |file| {
+            files.push(file);
+            Ok(())
+        })?;
+        Ok(files)
+    }
+
+    /// Stream diff results file-by-file.
+    pub fn diff_stream<F>(&self, mode: &DiffMode, paths: &[String], mut on_file: F) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        let repo = Repository::open(&self.repo_path).ok();
+        let mut highlighter = if self.structural {
+            Some(SyntaxHighlighter::new(None)?)
+        } else {
+            None
+        };
+        let mut total_lines = 0;
+        self.diff_stream_lines(mode, paths, &mut |mut file| {
+            let Some(repo) = repo.as_ref() else {
+                self.apply_limits(&mut file, &mut total_lines);
+                return on_file(file);
+            };
+            let path = file.new_path.clone().or_else(|| file.old_path.clone());
+            let declared = path
+                .as_deref()
+                .and_then(|path| Some((path, working_tree_encoding(repo, path)?)));
+            // Untracked and external files have their driver applied where
+            // they are read
+            let from_git = !file.untracked
+                && !matches!(
+                    mode,
+                    DiffMode::ExternalDiff { .. }
+                        | DiffMode::Patch { .. }
+                        | DiffMode::NoIndex { .. }
+                        | DiffMode::RangeDiff { .. }
+                );
+            let driver = path
+                .as_deref()
+                .filter(|_| from_git)
+                .map(|path| diff_driver(repo, path))
+                .unwrap_or_default();
+            // A file left out over the limits is converted once it is loaded
+            let loaded = path.as_deref().filter(|_| file.deferred.is_none());
+            if let (DiffDriver::Textconv(_), Some(path)) = (&driver, loaded) {
+                if !self.diffs_in_process(mode) {
+                    file.textconv = true;
+                } else if let Ok(Some(converted)) = self.diff_path_via_git_cmd(mode, path) {
+                    // libgit2 runs no textconv drivers; git does. A failing
+                    // driver leaves the file as libgit2 saw it.
+                    file = converted;
+                    file.textconv = true;
+                }
+            }
+            if driver == DiffDriver::Binary && !file.binary && file.deferred.is_none() {
+                file.hunks = Vec::new();
+                file.binary = true;
+            }
+            // libgit2 only ignores submodules altogether; git tells a dirty
+            // checkout from an untracked file in it
+            let partly_ignored = matches!(
+                self.ignore_submodules,
+                IgnoreSubmodules::Untracked | IgnoreSubmodules::Dirty
+            );
+            if file.status == FileStatus::Submodule && partly_ignored && self.uses_libgit2() {
+                if let Some(path) = path.as_deref() {
+                    match self.diff_path_via_git_cmd(mode, path)? {
+                        Some(converted) => file = converted,
+                        None => return Ok(()),
+                    }
+                }
+            }
+            if let Some((path, encoding)) = declared {
+                // libgit2 compares the checked-out bytes with the UTF-8 in
+                // the index and sees binary; git converts them first
+                if file.binary && self.diffs_in_process(mode) && !file.untracked {
+                    if let Some(converted) = self.diff_path_via_git_cmd(mode, path)? {
+                        file = converted;
+                    }
+                }
+                file.encoding = Some(encoding);
+            }
+            if file.binary && file.old_size.is_none() && file.new_size.is_none() {
+                let (old, new) = self.file_side_sizes(repo, mode, &file);
+                if file.status != FileStatus::Added {
+                    file.old_size = old;
+                }
+                if file.status != FileStatus::Deleted {
+                    file.new_size = new;
+                }
+            }
+            self.apply_limits(&mut file, &mut total_lines);
+            if let Some(highlighter) = highlighter.as_mut() {
+                let structural = !file.binary
+                    && file.deferred.is_none()
+                    && matches!(
+                        file.status,
+                        FileStatus::Modified | FileStatus::Renamed | FileStatus::Copied
+                    );
+                if let Some((old, new)) = structural
+                    .then(|| self.file_sides(repo, mode, &file))
+                    .flatten()
+                {
+                    let path = file
+                        .new_path
+                        .as_ref()
+                        .map(|p| p.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    file.hunks =
+                        structural_hunks(highlighter, &path, &old, &new, self.context_lines);
+                    if file.hunks.is_empty() {
+                        return Ok(());
+                    }
+                }
+            }
+            on_file(file)
+        })
+    }
+
+    /// Stream the range-diff of series already matched by `range_pairs`,
+    /// one file per pair. Only the line budgets apply to them.
+    pub fn range_diff_stream<F>(&self, pairs: &[RangePair], mut on_file: F) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        let mut total_lines = 0;
+        self.range_diff_files(pairs, &mut |mut file| {
+            self.apply_limits(&mut file, &mut total_lines);
+            on_file(file)
+        })
+    }
+
+    fn range_diff_files<F>(&self, pairs: &[RangePair], on_file: &mut F) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        for pair in pairs {
+            let label = PathBuf::from(pair.label());
+            let status = match (&pair.old, &pair.new) {
+                (None, _) => FileStatus::Added,
+                (_, None) => FileStatus::Deleted,
+                _ => FileStatus::Modified,
+            };
+            let mut file = DiffFile::new(Some(label.clone()), Some(label), status);
+            file.hunks = self.create_diff_hunks(&pair.old_patch, &pair.new_patch)?;
+            on_file(file)?;
+        }
+        Ok(())
+    }
+
+    fn diff_stream_lines<F>(&self, mode: &DiffMode, paths: &[String], on_file: &mut F) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        if let DiffMode::ExternalDiff { files } = mode {
+            for external in files {
+                on_file(self.diff_external_file(external)?)?;
+            }
+            return Ok(());
+        }
+        if let DiffMode::Patch { text, .. } = mode {
+            return self.parse_stream(&mut text.as_slice(), on_file);
+        }
+        if let DiffMode::NoIndex { old, new } = mode {
+            return self.diff_no_index(old, new, on_file);
+        }
+        if let DiffMode::RangeDiff {
+            old_from,
+            old_to,
+            new_from,
+            new_to,
+        } = mode
+        {
+            let pairs = self.range_pairs((old_from, old_to), (new_from, new_to), paths)?;
+            return self.range_diff_files(&pairs, on_file);
+        }
+
+        // Unmerged paths come first, shown by their conflict regions
+        let conflicts = if matches!(mode, DiffMode::Unstaged) {
+            self.diff_conflicts(paths)?
+        } else {
+            Vec::new()
+        };
+        let conflicted: HashSet<PathBuf> = conflicts
+            .iter()
+            .filter_map(|f| f.new_path.clone())
+            .collect();
+        for file in conflicts {
+            on_file(file)?;
+        }
+        let mut on_file = |file: DiffFile| {
+            let path = file.new_path.as_ref().or(file.old_path.as_ref());
+            if path.is_some_and(|p| conflicted.contains(p)) {
+                return Ok(());
+            }
+            on_file(file)
+        };
+        let on_file = &mut on_file;
+
+        // libgit2 has no combined diff; git renders merges with --cc
+        if self.uses_libgit2() && !self.is_merge_commit(mode) {
+            self.diff_via_libgit2(mode, paths, on_file)?
+        } else {
+            let args = git_diff_args(&self.repo_path, mode)?;
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            self.diff_via_git_cmd_stream(&args, paths, on_file)?
+        }
+        match mode {
+            DiffMode::Unstaged if self.untracked != UntrackedFiles::Hide => {
+                for file in self.diff_untracked_files(paths)? {
+                    on_file(file)?;
+                }
+            }
+            DiffMode::Stash {
+                index,
+                staged: false,
+            } => self.diff_stash_untracked(*index, paths, on_file)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Unmerged index entries as conflicted files whose hunks are the
+    /// conflict regions left in the working tree
+    fn diff_conflicts(&self, paths: &[String]) -> Result<Vec<DiffFile>> {
+        let Ok(repo) = Repository::open(&self.repo_path) else {
+            return Ok(Vec::new());
+        };
+        let index = repo.index()?;
+        if !index.has_conflicts() {
+            return Ok(Vec::new());
+        }
+        let mut unmerged = BTreeSet::new();
+        for conflict in index.conflicts()? {
+            let conflict = conflict?;
+            let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+            if let Some(entry) = entry {
+                unmerged.insert(PathBuf::from(String::from_utf8_lossy(&entry.path).as_ref()));
+            }
+        }
+
+        let mut files = Vec::new();
+        for path in unmerged {
+            let selected = paths.iter().all(|p| p.is_empty())
+                || paths
+                    .iter()
+                    .any(|p| !p.is_empty() && path.starts_with(p.trim_end_matches('/')));
+            if selected {
+                files.push(self.conflicted_file(path));
+            }
+        }
+        Ok(files)
+    }
+
+    /// An unmerged file whose hunks are the conflict regions in the worktree
+    fn conflicted_file(&self, path: PathBuf) -> DiffFile {
+        let mut file = DiffFile::new(
+            Some(path.clone()),
+            Some(path.clone()),
+            FileStatus::Conflicted,
+        );
+        match std::fs::read(self.repo_path.join(&path)) {
+            Ok(content) if is_binary(&content) => file.binary = true,
+            Ok(content) => {
+                let text = String::from_utf8_lossy(&content);
+                let merged = self.merged_with_base(&path);
+                file.hunks = conflict_hunks(&text, merged.as_deref(), self.context_lines as usize);
+            }
+            // Deleted on one side: nothing to show but the status
+            Err(_) => {}
+        }
+        file
+    }
+
+    /// The three index stages of an unmerged path merged again in diff3
+    /// style, so each conflict region has its base whatever
+    /// `merge.conflictStyle` wrote to the working tree
+    fn merged_with_base(&self, path: &Path) -> Option<String> {
+        let repo = Repository::open(&self.repo_path).ok()?;
+        let index = repo.index().ok()?;
+        let mut stages = Vec::new();
+        // merge-file takes ours, base, theirs
+        for stage in [2, 1, 3] {
+            let blob = repo.find_blob(index.get_path(path, stage)?.id).ok()?;
+            let mut file = tempfile::Builder::new()
+                .prefix("differ-merge-")
+                .tempfile()
+                .ok()?;
+            file.write_all(blob.content()).ok()?;
+            stages.push(file);
+        }
+        let output = Command::new("git")
+            .args(["merge-file", "-p", "--diff3"])
+            .args(["-L", "ours", "-L", "base", "-L", "theirs"])
+            .args(stages.iter().map(|f| f.path()))
+            .current_dir(&self.repo_path)
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        // The exit status counts the conflicts; it is negative on errors
+        output.status.code().filter(|code| *code >= 0)?;
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Commit mode on a merge, shown as a combined diff against every parent
+    fn is_merge_commit(&self, mode: &DiffMode) -> bool {
+        let DiffMode::Commit { rev } = mode else {
+            return false;
+        };
+        Repository::open(&self.repo_path)
+            .ok()
+            .and_then(|repo| Some(resolve_commit(&repo, rev).ok()?.parent_count() > 1))
+            .unwrap_or(false)
+    }
+
+    /// libgit2 has no histogram algorithm; git does it instead
+    fn uses_libgit2(&self) -> bool {
+        self.backend == DiffBackend::Libgit2 && self.algorithm != DiffAlgorithm::Histogram
+    }
+
+    /// Whether the tracked files of this mode come from libgit2, which
+    /// applies no gitattributes filters
+    fn diffs_in_process(&self, mode: &DiffMode) -> bool {
+        self.uses_libgit2()
+            && !self.is_merge_commit(mode)
+            && !matches!(
+                mode,
+                DiffMode::ExternalDiff { .. }
+                    | DiffMode::Patch { .. }
+                    | DiffMode::NoIndex { .. }
+                    | DiffMode::RangeDiff { .. }
+            )
+    }
+
+    /// Untracked files a stash saved (`--include-untracked`), kept as its third parent
+    fn diff_stash_untracked<F>(&self, index: usize, paths: &[String], on_file: &mut F) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        let repo = Repository::open(&self.repo_path).context("Failed to open repository")?;
+        let Ok(untracked) = resolve_commit(&repo, &stash_rev(index))?.parent(2) else {
+            return Ok(());
+        };
+        if self.uses_libgit2() {
+            let mut opts = self.libgit2_options(paths);
+            let mut diff =
+                repo.diff_tree_to_tree(None, Some(&untracked.tree()?), Some(&mut opts))?;
+            self.emit_libgit2_diff(&repo, &mut diff, on_file)
+        } else {
+            let to = untracked.id().to_string();
+            self.diff_via_git_cmd_stream(&[EMPTY_TREE, &to], paths, on_file)
+        }
+    }
+
+    /// Full old and new text of a file for the given mode
+    fn file_sides(
+        &self,
+        repo: &Repository,
+        mode: &DiffMode,
+        file: &DiffFile,
+    ) -> Option<(String, String)> {
+        let (old, new) = self.file_side_bytes(repo, mode, file);
+        // Compared as the diff shows them, after any textconv
+        let text = |bytes: Option<Vec<u8>>, path: Option<&PathBuf>| -> Option<String> {
+            let bytes = bytes?;
+            let bytes = match path.map(|path| diff_driver(repo, path)) {
+                Some(DiffDriver::Binary) => return None,
+                Some(DiffDriver::Textconv(command)) => {
+                    run_textconv(&self.repo_path, &command, &bytes).ok()?
+                }
+                _ => bytes,
+            };
+            String::from_utf8(bytes).ok()
+        };
+        let old_path = file.old_path.as_ref().or(file.new_path.as_ref());
+        let new_path = file.new_path.as_ref().or(file.old_path.as_ref());
+        Some((text(old, old_path)?, text(new, new_path)?))
+    }
+
+    /// Raw old and new contents of a file for the given mode
+    fn file_side_bytes(
+        &self,
+        repo: &Repository,
+        mode: &DiffMode,
+        file: &DiffFile,
+    ) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        let (old, new) = self.file_side_sources(repo, mode, file);
+        let bytes = |side: Option<Side>| side?.bytes(repo);
+        (bytes(old), bytes(new))
+    }
+
+    /// Sizes of the old and new contents of a file, read from the object
+    /// headers and file metadata without loading the contents
+    fn file_side_sizes(
+        &self,
+        repo: &Repository,
+        mode: &DiffMode,
+        file: &DiffFile,
+    ) -> (Option<u64>, Option<u64>) {
+        let (old, new) = self.file_side_sources(repo, mode, file);
+        let size = |side: Option<Side>| side?.size(repo);
+        (size(old), size(new))
+    }
+
+    /// Where the old and new contents of a file come from for the given mode
+    fn file_side_sources(
+        &self,
+        repo: &Repository,
+        mode: &DiffMode,
+        file: &DiffFile,
+    ) -> (Option<Side>, Option<Side>) {
+        let (Some(old_path), Some(new_path)) = (
+            file.old_path.as_ref().or(file.new_path.as_ref()),
+            file.new_path.as_ref().or(file.old_path.as_ref()),
+        ) else {
+            return (None, None);
+        };
+        let workdir = || Some(Side::Disk(self.repo_path.join(new_path)));
+        let index = |path: &Path| {
+            let entry = repo.index().ok()?.get_path(path, 0)?;
+            Some(Side::Blob(entry.id))
+        };
+        let at_rev = |spec: &str, path: &Path| {
+            let tree = resolve_tree(repo, spec).ok()?;
+            Some(Side::Blob(tree.get_path(path).ok()?.id()))
+        };
+
+        match mode {
+            DiffMode::Unstaged => (index(old_path), workdir()),
+            DiffMode::Staged => (at_rev("HEAD", old_path), index(new_path)),
+            DiffMode::WorkingTree { base } => (at_rev(base, old_path), workdir()),
+            DiffMode::Commits { from, to } => (at_rev(from, old_path), at_rev(to, new_path)),
+            DiffMode::MergeBase { from, to } => {
+                let base = merge_base_rev(repo, from, to);
+                (
+                    base.and_then(|base| at_rev(&base, old_path)),
+                    at_rev(to, new_path),
+                )
+            }
+            DiffMode::Commit { rev } => (
+                at_rev(&format!("{}^", rev), old_path),
+                at_rev(rev, new_path),
+            ),
+            DiffMode::Stash { index, staged } => {
+                let stash = stash_rev(*index);
+                if *staged {
+                    (
+                        at_rev(&format!("{}^1", stash), old_path),
+                        at_rev(&format!("{}^2", stash), new_path),
+                    )
+                } else {
+                    (
+                        at_rev(&format!("{}^2", stash), old_path),
+                        at_rev(&stash, new_path)
+                            .or_else(|| at_rev(&format!("{}^3", stash), new_path)),
+                    )
+                }
+            }
+            DiffMode::ExternalDiff { files } => {
+                let external = files.iter().find(|f| {
+                    Path::new(f.new_path.as_ref().unwrap_or(&f.path)) == new_path.as_path()
+                });
+                match external {
+                    Some(f) => (
+                        Some(Side::Disk(PathBuf::from(&f.old_file))),
+                        Some(Side::Disk(PathBuf::from(&f.new_file))),
+                    ),
+                    None => (None, None),
+                }
+            }
+            DiffMode::Patch { .. } | DiffMode::RangeDiff { .. } => (None, None),
+            DiffMode::NoIndex { old, new } => (
+                Some(Side::Disk(no_index_path(old, old_path))),
+                Some(Side::Disk(no_index_path(new, new_path))),
+            ),
+        }
+    }
+
+    /// Diff in-process via libgit2, producing files without a text round-trip.
+    /// Untracked files are not included; callers add them separately.
+    fn diff_via_libgit2<F>(&self, mode: &DiffMode, paths: &[String], on_file: &mut F) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        let repo = Repository::open(&self.repo_path).context("Failed to open repository")?;
+        let mut opts = self.libgit2_options(paths);
+
+        let mut diff = match mode {
+            DiffMode::Unstaged => repo.diff_index_to_workdir(None, Some(&mut opts))?,
+            DiffMode::Staged => {
+                let head = repo.head().ok().and_then(|h| h.peel_to_tree().ok());
+                repo.diff_tree_to_index(head.as_ref(), None, Some(&mut opts))?
+            }
+            DiffMode::WorkingTree { base } => {
+                let tree = resolve_tree(&repo, base)?;
+                repo.diff_tree_to_workdir_with_index(Some(&tree), Some(&mut opts))?
+            }
+            DiffMode::Commits { from, to } => {
+                let old_tree = resolve_tree(&repo, from)?;
+                let new_tree = resolve_tree(&repo, to)?;
+                repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(&mut opts))?
+            }
+            DiffMode::MergeBase { from, to } => {
+                let from_commit = repo
+                    .revparse_single(from)
+                    .and_then(|obj| obj.peel_to_commit())
+                    .with_context(|| format!("Unknown revision: {}", from))?;
+                let to_commit = repo
+                    .revparse_single(to)
+                    .and_then(|obj| obj.peel_to_commit())
+                    .with_context(|| format!("Unknown revision: {}", to))?;
+                let base = repo
+                    .merge_base(from_commit.id(), to_commit.id())
+                    .with_context(|| format!("No merge base between {} and {}", from, to))?;
+                let old_tree = repo.find_commit(base)?.tree()?;
+                let new_tree = to_commit.tree()?;
+                repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(&mut opts))?
+            }
+            DiffMode::Commit { rev } => {
+                let commit = resolve_commit(&repo, rev)?;
+                let old_tree = match commit.parent(0) {
+                    Ok(parent) => Some(parent.tree()?),
+                    Err(_) => None,
+                };
+                let new_tree = commit.tree()?;
+                repo.diff_tree_to_tree(old_tree.as_ref(), Some(&new_tree), Some(&mut opts))?
+            }
+            DiffMode::Stash { index, staged } => {
+                let stash = resolve_commit(&repo, &stash_rev(*index))?;
+                let index_tree = stash.parent(1)?.tree()?;
+                let (old_tree, new_tree) = if *staged {
+                    (stash.parent(0)?.tree()?, index_tree)
+                } else {
+                    (index_tree, stash.tree()?)
+                };
+                repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(&mut opts))?
+            }
+            DiffMode::ExternalDiff { .. }
+            | DiffMode::Patch { .. }
+            | DiffMode::NoIndex { .. }
+            | DiffMode::RangeDiff { .. } => return Ok(()),
+        };
+
+        self.emit_libgit2_diff(&repo, &mut diff, on_file)
+    }
+
+    fn libgit2_options(&self, paths: &[String]) -> DiffOptions {
+        let mut opts = DiffOptions::new();
+        opts.context_lines(self.context_lines)
+            .include_typechange(true)
+            .patience(self.algorithm == DiffAlgorithm::Patience)
+            .minimal(self.algorithm == DiffAlgorithm::Minimal)
+            .ignore_whitespace(self.whitespace.ignore_all_space)
+            .ignore_whitespace_change(self.whitespace.ignore_space_change)
+            .ignore_blank_lines(self.whitespace.ignore_blank_lines)
+            .ignore_submodules(self.ignore_submodules == IgnoreSubmodules::All);
+        for path in paths {
+            if !path.is_empty() {
+                opts.pathspec(path);
+            }
+        }
+        opts
+    }
+
+    /// Detect renames and copies, then hand each changed file to `on_file`
+    fn emit_libgit2_diff<F>(
+        &self,
+        repo: &Repository,
+        diff: &mut git2::Diff,
+        on_file: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        if self.renames != RenameDetection::Off {
+            let mut find_opts = DiffFindOptions::new();
+            find_opts
+                .renames(true)
+                .copies(self.renames == RenameDetection::Copies);
+            diff.find_similar(Some(&mut find_opts))
+                .context("Failed to detect renames")?;
+        }
+
+        for idx in 0..diff.deltas().len() {
+            // Sized up from the delta, so an oversized file's patch is never built
+            let delta = diff.get_delta(idx).context("Missing diff delta")?;
+            let paths = [delta.old_file().path(), delta.new_file().path()];
+            let size =
+                self.side_size(repo, &delta.old_file()) + self.side_size(repo, &delta.new_file());
+            if self.over_file_bytes(paths, size) {
+                let mut file = diff_file_from_delta(&delta);
+                file.deferred = Some(Deferred::Bytes(size));
+                file.classify();
+                on_file(file)?;
+                continue;
+            }
+            let Some(mut patch) = Patch::from_diff(diff, idx)? else {
+                continue;
+            };
+            let (_, additions, deletions) = patch.line_stats()?;
+            let delta = patch.delta();
+            let paths = [delta.old_file().path(), delta.new_file().path()];
+            let deferred =
+                Some(additions + deletions).filter(|lines| self.over_file_limit(paths, *lines));
+            let file = diff_file_from_patch(&mut patch, deferred)?;
+            if file.has_changes() {
+                on_file(file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Use git diff command directly - handles custom diff drivers properly
+    #[allow(dead_code)]
+    fn diff_via_git_cmd(&self, args: &[&str], paths: &[String]) -> Result<Vec<DiffFile>> {
+        let mut cmd = Command::new("git");
+        cmd.arg("-C")
+            .arg(&self.repo_path)
+            .args(GIT_PATH_CONFIG)
+            .arg("diff")
+            .arg("--no-color")
+            .arg("--no-ext-diff")
+            .args(["--src-prefix=a/", "--dst-prefix=b/"])
+            .arg(format!("-U{}", self.context_lines))
+            .args(self.git_detection_args())
+            .arg(format!("--diff-algorithm={}", self.algorithm.as_str()))
+            .args(self.whitespace.git_args());
+
+        for arg in args {
+            cmd.arg(arg);
+        }
+
+        if !paths.is_empty() {
+            cmd.arg("--");
+            for path in paths {
+                if !path.is_empty() {
+                    cmd.arg(path);
+                }
+            }
+        }
+
+        let output = cmd.output().context("Failed to run git diff")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("git diff failed: {}", stderr);
+        }
+
+        self.parse_full_diff(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// One file's diff from `git diff`, which applies gitattributes filters
+    fn diff_path_via_git_cmd(&self, mode: &DiffMode, path: &Path) -> Result<Option<DiffFile>> {
+        let args = git_diff_args(&self.repo_path, mode)?;
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let pathspec = format!(":(literal){}", path.to_string_lossy());
+        Ok(self
+            .diff_via_git_cmd(&args, &[pathspec])?
+            .into_iter()
+            .next())
+    }
+
+    fn diff_via_git_cmd_stream<F>(
+        &self,
+        args: &[&str],
+        paths: &[String],
+        on_file: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        let mut cmd = Command::new("git");
+        cmd.arg("-C")
+            .arg(&self.repo_path)
+            .args(GIT_PATH_CONFIG)
+            .arg("diff")
+            .arg("--no-color")
+            .arg("--no-ext-diff")
+            .args(["--src-prefix=a/", "--dst-prefix=b/"])
+            .arg(format!("-U{}", self.context_lines))
+            .args(self.git_detection_args())
+            .arg(format!("--diff-algorithm={}", self.algorithm.as_str()))
+            .args(self.whitespace.git_args());
+
+        for arg in args {
+            cmd.arg(arg);
+        }
+
+        if !paths.is_empty() {
+            cmd.arg("--");
+            for path in paths {
+                if !path.is_empty() {
+                    cmd.arg(path);
+                }
+            }
+        }
+
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+        let mut child = cmd.spawn().context("Failed to run git diff")?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("Failed to capture git diff stdout")?;
+        let mut reader = BufReader::new(stdout);
         self.parse_stream(&mut reader, on_file)?;
 
         let mut stderr = String::new();
@@ -269,13 +2196,54 @@ impl DiffEngine {
 
     fn diff_untracked_files(&self, paths: &[String]) -> Result<Vec<DiffFile>> {
         let untracked = self.list_untracked_files(paths)?;
+        let repo = Repository::open(&self.repo_path).ok();
         let mut files = Vec::new();
         for path in untracked {
             let full_path = self.repo_path.join(&path);
-            let content = std::fs::read(&full_path).unwrap_or_default();
-            let text = String::from_utf8_lossy(&content);
+            let size = std::fs::metadata(&full_path).map_or(0, |m| m.len());
+            let names_only = matches!(
+                self.untracked,
+                UntrackedFiles::Names | UntrackedFiles::Group
+            );
+            let too_big = self.limits.untracked_bytes > 0 && size > self.limits.untracked_bytes;
+            if (names_only || too_big) && !self.loads_in_full(&path) {
+                let mut file = DiffFile::new(None, Some(path.clone()), FileStatus::Added);
+                file.new_size = Some(size);
+                file.deferred = Some(Deferred::Bytes(size));
+                file.untracked = true;
+                files.push(file);
+                continue;
+            }
+            let mut content = std::fs::read(&full_path).unwrap_or_default();
+            let mut driver = repo
+                .as_ref()
+                .map(|repo| diff_driver(repo, &path))
+                .unwrap_or_default();
+            if let DiffDriver::Textconv(command) = &driver {
+                // A failing driver leaves the file as it is
+                match run_textconv(&self.repo_path, command, &content) {
+                    Ok(text) => content = text,
+                    Err(_) => driver = DiffDriver::Default,
+                }
+            }
+            let declared = repo
+                .as_ref()
+                .filter(|_| driver == DiffDriver::Default)
+                .and_then(|repo| working_tree_encoding(repo, &path));
+            if driver == DiffDriver::Binary || (declared.is_none() && is_binary(&content)) {
+                let mut file = DiffFile::new(None, Some(path.clone()), FileStatus::Added);
+                file.binary = true;
+                file.new_size = Some(content.len() as u64);
+                file.untracked = true;
+                files.push(file);
+                continue;
+            }
+            let (text, encoding) = decode_text(&content, declared);
+            // A declared encoding is stored as UTF-8, so only undeclared
+            // legacy text is patched back in its own bytes
+            let legacy = declared.is_none() && encoding.is_some();
             let mut lines = Vec::new();
-            for (idx, line) in text.lines().enumerate() {
+            for (idx, (line, ending)) in lines_with_endings(&text).into_iter().enumerate() {
                 lines.push(DiffLine {
                     kind: LineKind::Addition,
                     old_line_no: None,
@@ -283,6 +2251,11 @@ impl DiffEngine {
                     content: line.to_string(),
                     highlights: Vec::new(),
                     inline_ranges: Vec::new(),
+                    conflict: None,
+                    combined: Vec::new(),
+                    moved: None,
+                    ending,
+                    raw: legacy.then(|| legacy_bytes(line)),
                 });
             }
             let hunk = DiffHunk {
@@ -293,16 +2266,14 @@ impl DiffEngine {
                 header: None,
                 lines,
             };
-            files.push(DiffFile {
-                old_path: None,
-                new_path: Some(path.clone()),
-                status: FileStatus::Added,
-                hunks: if hunk.lines.is_empty() {
-                    Vec::new()
-                } else {
-                    vec![hunk]
-                },
-            });
+            let mut file = DiffFile::new(None, Some(path.clone()), FileStatus::Added);
+            file.encoding = encoding;
+            file.untracked = true;
+            file.textconv = matches!(driver, DiffDriver::Textconv(_));
+            if !hunk.lines.is_empty() {
+                file.hunks.push(hunk);
+            }
+            files.push(file);
         }
         Ok(files)
     }
@@ -312,6 +2283,7 @@ impl DiffEngine {
         cmd.arg("-C")
             .arg(&self.repo_path)
             .arg("ls-files")
+            .arg("-z")
             .arg("--others")
             .arg("--exclude-standard");
 
@@ -330,121 +2302,23 @@ impl DiffEngine {
             anyhow::bail!("git ls-files failed: {}", stderr);
         }
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let mut files = Vec::new();
-        for line in stdout.lines() {
-            let path = line.trim();
-            if path.is_empty() {
-                continue;
-            }
-            files.push(PathBuf::from(path));
-        }
-        Ok(files)
+        // NUL-terminated names come out verbatim, whatever bytes they hold
+        Ok(output
+            .stdout
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| path_from_bytes(name.to_vec()))
+            .collect())
     }
 
     /// Parse full unified diff output into DiffFile structs
     #[allow(dead_code)]
     fn parse_full_diff(&self, diff_output: &str) -> Result<Vec<DiffFile>> {
         let mut files = Vec::new();
-        let mut current_file: Option<DiffFile> = None;
-        let mut current_hunk: Option<DiffHunk> = None;
-        let mut old_line = 0u32;
-        let mut new_line = 0u32;
-
-        for line in diff_output.lines() {
-            if line.starts_with("diff --git") {
-                // Save previous file
-                if let Some(mut f) = current_file.take() {
-                    if let Some(h) = current_hunk.take() {
-                        f.hunks.push(h);
-                    }
-                    files.push(f);
-                }
-                // Parse file paths from "diff --git a/path b/path"
-                let paths = parse_diff_git_line(line);
-                current_file = Some(DiffFile {
-                    old_path: paths.0.map(PathBuf::from),
-                    new_path: paths.1.map(PathBuf::from),
-                    status: FileStatus::Modified, // Will be updated by index line
-                    hunks: Vec::new(),
-                });
-            } else if line.starts_with("new file") {
-                if let Some(ref mut f) = current_file {
-                    f.status = FileStatus::Added;
-                }
-            } else if line.starts_with("deleted file") {
-                if let Some(ref mut f) = current_file {
-                    f.status = FileStatus::Deleted;
-                }
-            } else if line.starts_with("rename from") || line.starts_with("similarity index") {
-                if let Some(ref mut f) = current_file {
-                    f.status = FileStatus::Renamed;
-                }
-            } else if line.starts_with("@@") {
-                // Save previous hunk
-                if let Some(h) = current_hunk.take() {
-                    if let Some(ref mut f) = current_file {
-                        f.hunks.push(h);
-                    }
-                }
-
-                if let Some(header) = parse_hunk_header(line) {
-                    old_line = header.0;
-                    new_line = header.2;
-                    current_hunk = Some(DiffHunk {
-                        old_start: header.0,
-                        old_lines: header.1,
-                        new_start: header.2,
-                        new_lines: header.3,
-                        header: header.4,
-                        lines: Vec::new(),
-                    });
-                }
-            } else if let Some(ref mut hunk) = current_hunk {
-                let (kind, old_no, new_no) = if line.starts_with('+') {
-                    let no = new_line;
-                    new_line += 1;
-                    (LineKind::Addition, None, Some(no))
-                } else if line.starts_with('-') {
-                    let no = old_line;
-                    old_line += 1;
-                    (LineKind::Deletion, Some(no), None)
-                } else if line.starts_with(' ') {
-                    let old_no = old_line;
-                    let new_no = new_line;
-                    old_line += 1;
-                    new_line += 1;
-                    (LineKind::Context, Some(old_no), Some(new_no))
-                } else if line.starts_with('\\') {
-                    // "\ No newline at end of file"
-                    continue;
-                } else {
-                    continue;
-                };
-
-                let content = if line.len() > 1 { &line[1..] } else { "" };
-                hunk.lines.push(DiffLine {
-                    kind,
-                    old_line_no: old_no,
-                    new_line_no: new_no,
-                    content: content.to_string(),
-                    highlights: Vec::new(),
-                    inline_ranges: Vec::new(),
-                });
-            }
-        }
-
-        // Don't forget the last file/hunk
-        if let Some(mut f) = current_file {
-            if let Some(h) = current_hunk {
-                f.hunks.push(h);
-            }
-            files.push(f);
-        }
-
-        // Filter out files with no hunks
-        files.retain(|f| !f.hunks.is_empty());
-
+        self.parse_stream(&mut diff_output.as_bytes(), &mut |file| {
+            files.push(file);
+            Ok(())
+        })?;
         Ok(files)
     }
 
@@ -455,38 +2329,140 @@ impl DiffEngine {
     ) -> Result<()> {
         let mut current_file: Option<DiffFile> = None;
         let mut current_hunk: Option<DiffHunk> = None;
+        let mut pending: Option<DiffFile> = None;
         let mut old_line = 0u32;
         let mut new_line = 0u32;
+        // Lines still expected in the current hunk, per its header
+        let mut old_left = 0u32;
+        let mut new_left = 0u32;
+        // File started by a bare "---" header (plain `diff -u` output)
+        let mut plain_file = false;
+        // Parent line counters of a combined (`--cc`) hunk, one per parent;
+        // empty for ordinary two-sided hunks
+        let mut parent_lines: Vec<u32> = Vec::new();
+        // Changed lines of the current file, and whether they are over the
+        // limit so that no more are kept
+        let mut changed = 0usize;
+        let mut over = false;
 
-        for line in reader.lines() {
-            let line = line?;
-            if line.starts_with("diff --git") {
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                break;
+            }
+            let (text, legacy) = decode_line(&buf);
+            // Keep the terminator of each line so CRLF content survives; a
+            // patch whose last line lacks a newline says nothing of the file
+            let (line, ending) = match split_line_ending(&text) {
+                (line, LineEnding::Missing) => (line, LineEnding::Lf),
+                split => split,
+            };
+            if current_hunk.is_some() && old_left == 0 && new_left == 0 && !line.starts_with('\\') {
+                // Hunk complete; what follows is a header or trailing text
+                // such as a mail signature
+                if let (Some(h), Some(f)) = (current_hunk.take(), current_file.as_mut()) {
+                    f.hunks.push(h);
+                }
+            }
+            let combined_path = line
+                .strip_prefix("diff --cc ")
+                .or_else(|| line.strip_prefix("diff --combined "));
+            if line.starts_with("diff --git") || combined_path.is_some() {
                 if let Some(mut f) = current_file.take() {
                     if let Some(h) = current_hunk.take() {
                         f.hunks.push(h);
                     }
-                    if !f.hunks.is_empty() {
-                        on_file(f)?;
+                    defer_parsed(&mut f, over.then_some(changed));
+                    emit_parsed(f, &mut pending, on_file)?;
+                }
+                (changed, over) = (0, false);
+                let paths = match combined_path {
+                    // A merge result keeps one path for every parent
+                    Some(path) => (
+                        Some(header_path(path, legacy)),
+                        Some(header_path(path, legacy)),
+                    ),
+                    None => parse_diff_git_line(line, legacy),
+                };
+                current_file = Some(DiffFile::new(
+                    paths.0,
+                    paths.1,
+                    FileStatus::Modified, // Will be updated by extended headers
+                ));
+                plain_file = false;
+            } else if current_hunk.is_none() && !line.starts_with("@@") {
+                if let Some(path) = line.strip_prefix("--- ") {
+                    let starts_file = current_file
+                        .as_ref()
+                        .is_none_or(|f| over || !f.hunks.is_empty());
+                    if starts_file {
+                        if let Some(mut f) = current_file.take() {
+                            defer_parsed(&mut f, over.then_some(changed));
+                            emit_parsed(f, &mut pending, on_file)?;
+                        }
+                        (changed, over) = (0, false);
+                        let old_path = plain_diff_path(path, "a/", legacy);
+                        let status = if old_path.is_none() {
+                            FileStatus::Added
+                        } else {
+                            FileStatus::Modified
+                        };
+                        current_file = Some(DiffFile::new(old_path, None, status));
+                        plain_file = true;
+                        continue;
                     }
                 }
-                let paths = parse_diff_git_line(&line);
-                current_file = Some(DiffFile {
-                    old_path: paths.0.map(PathBuf::from),
-                    new_path: paths.1.map(PathBuf::from),
-                    status: FileStatus::Modified,
-                    hunks: Vec::new(),
-                });
-            } else if line.starts_with("new file") {
-                if let Some(ref mut f) = current_file {
+                let Some(ref mut f) = current_file else {
+                    continue;
+                };
+                if let Some(path) = line.strip_prefix("+++ ").filter(|_| plain_file) {
+                    f.new_path = plain_diff_path(path, "b/", legacy);
+                    if f.new_path.is_none() {
+                        f.status = FileStatus::Deleted;
+                    }
+                } else if let Some(path) = line.strip_prefix("--- ") {
+                    // Unlike the "diff --git" line these name each side
+                    // unambiguously
+                    if let Some(path) = plain_diff_path(path, "a/", legacy) {
+                        f.old_path = Some(path);
+                    }
+                } else if let Some(path) = line.strip_prefix("+++ ") {
+                    if let Some(path) = plain_diff_path(path, "b/", legacy) {
+                        f.new_path = Some(path);
+                    }
+                } else if let Some(mode) = line.strip_prefix("new file mode ") {
                     f.status = FileStatus::Added;
-                }
-            } else if line.starts_with("deleted file") {
-                if let Some(ref mut f) = current_file {
+                    f.new_mode = parse_mode(mode);
+                } else if let Some(mode) = line.strip_prefix("deleted file mode ") {
                     f.status = FileStatus::Deleted;
-                }
-            } else if line.starts_with("rename from") || line.starts_with("similarity index") {
-                if let Some(ref mut f) = current_file {
+                    f.old_mode = parse_mode(mode);
+                } else if let Some(mode) = line.strip_prefix("old mode ") {
+                    f.old_mode = parse_mode(mode);
+                } else if let Some(mode) = line.strip_prefix("new mode ") {
+                    f.new_mode = parse_mode(mode);
+                } else if let Some(rest) = line.strip_prefix("index ") {
+                    // "index abc..def 100644" carries the mode when it is unchanged
+                    if let Some(mode) = rest.split_whitespace().nth(1).and_then(parse_mode) {
+                        f.old_mode.get_or_insert(mode);
+                        f.new_mode.get_or_insert(mode);
+                    }
+                } else if let Some(score) = line.strip_prefix("similarity index ") {
+                    f.similarity = parse_similarity(score);
+                } else if let Some(path) = line.strip_prefix("rename from ") {
                     f.status = FileStatus::Renamed;
+                    f.old_path = Some(header_path(path, legacy));
+                } else if let Some(path) = line.strip_prefix("copy from ") {
+                    f.status = FileStatus::Copied;
+                    f.old_path = Some(header_path(path, legacy));
+                } else if let Some(path) = line
+                    .strip_prefix("rename to ")
+                    .or_else(|| line.strip_prefix("copy to "))
+                {
+                    f.new_path = Some(header_path(path, legacy));
+                } else if line.starts_with("Binary files ") || line.starts_with("GIT binary patch")
+                {
+                    f.binary = true;
                 }
             } else if line.starts_with("@@") {
                 if let Some(h) = current_hunk.take() {
@@ -494,9 +2470,25 @@ impl DiffEngine {
                         f.hunks.push(h);
                     }
                 }
-                if let Some(header) = parse_hunk_header(&line) {
+                if let Some(combined) = parse_combined_hunk_header(line) {
+                    parent_lines = combined.parents.iter().map(|r| r.0).collect();
+                    new_line = combined.result.0;
+                    old_left = combined.parents.iter().map(|r| r.1).sum();
+                    new_left = combined.result.1;
+                    current_hunk = Some(DiffHunk {
+                        old_start: combined.parents[0].0,
+                        old_lines: combined.parents[0].1,
+                        new_start: combined.result.0,
+                        new_lines: combined.result.1,
+                        header: combined.header,
+                        lines: Vec::new(),
+                    });
+                } else if let Some(header) = parse_hunk_header(line) {
+                    parent_lines.clear();
                     old_line = header.0;
                     new_line = header.2;
+                    old_left = header.1;
+                    new_left = header.3;
                     current_hunk = Some(DiffHunk {
                         old_start: header.0,
                         old_lines: header.1,
@@ -506,27 +2498,105 @@ impl DiffEngine {
                         lines: Vec::new(),
                     });
                 }
+            } else if let (Some(hunk), false) = (current_hunk.as_mut(), parent_lines.is_empty()) {
+                if line.starts_with('\\') {
+                    if let Some(last) = hunk.lines.last_mut() {
+                        last.ending = LineEnding::Missing;
+                    }
+                    continue;
+                }
+                let columns: Vec<LineKind> = (0..parent_lines.len())
+                    .map(|i| match line.as_bytes().get(i) {
+                        Some(b'+') => LineKind::Addition,
+                        Some(b'-') => LineKind::Deletion,
+                        _ => LineKind::Context,
+                    })
+                    .collect();
+                let mut old_no = None;
+                let removed = columns.contains(&LineKind::Deletion);
+                for (i, column) in columns.iter().enumerate() {
+                    // A removed line counts in the parents that had it; a
+                    // result line in the parents that already had it
+                    let counts = if removed {
+                        *column == LineKind::Deletion
+                    } else {
+                        *column == LineKind::Context
+                    };
+                    if counts {
+                        old_no = old_no.or(Some(parent_lines[i]));
+                        parent_lines[i] += 1;
+                        old_left = old_left.saturating_sub(1);
+                    }
+                }
+                let (kind, new_no) = if removed {
+                    (LineKind::Deletion, None)
+                } else {
+                    let no = new_line;
+                    new_line += 1;
+                    new_left = new_left.saturating_sub(1);
+                    if columns.contains(&LineKind::Addition) {
+                        old_no = None;
+                        (LineKind::Addition, Some(no))
+                    } else {
+                        (LineKind::Context, Some(no))
+                    }
+                };
+                if kind != LineKind::Context {
+                    changed += 1;
+                }
+                if over || self.over_parsed_limit(current_file.as_mut(), hunk, changed) {
+                    over = true;
+                    continue;
+                }
+                let content = line.get(columns.len()..).unwrap_or("");
+                hunk.lines.push(DiffLine {
+                    kind,
+                    old_line_no: old_no,
+                    new_line_no: new_no,
+                    content: content.to_string(),
+                    highlights: Vec::new(),
+                    inline_ranges: Vec::new(),
+                    conflict: None,
+                    combined: columns,
+                    moved: None,
+                    ending,
+                    raw: legacy.then(|| legacy_bytes(content)),
+                });
             } else if let Some(ref mut hunk) = current_hunk {
                 let (kind, old_no, new_no) = if line.starts_with('+') {
                     let no = new_line;
                     new_line += 1;
+                    new_left = new_left.saturating_sub(1);
                     (LineKind::Addition, None, Some(no))
                 } else if line.starts_with('-') {
                     let no = old_line;
                     old_line += 1;
+                    old_left = old_left.saturating_sub(1);
                     (LineKind::Deletion, Some(no), None)
-                } else if line.starts_with(' ') {
+                } else if line.starts_with(' ') || line.is_empty() {
+                    // Some mailers strip the space off empty context lines
                     let old_no = old_line;
                     let new_no = new_line;
                     old_line += 1;
                     new_line += 1;
+                    old_left = old_left.saturating_sub(1);
+                    new_left = new_left.saturating_sub(1);
                     (LineKind::Context, Some(old_no), Some(new_no))
-                } else if line.starts_with('\\') {
-                    continue;
                 } else {
+                    // "\ No newline at end of file" belongs to the line before
+                    if let Some(last) = hunk.lines.last_mut() {
+                        last.ending = LineEnding::Missing;
+                    }
                     continue;
                 };
 
+                if kind != LineKind::Context {
+                    changed += 1;
+                }
+                if over || self.over_parsed_limit(current_file.as_mut(), hunk, changed) {
+                    over = true;
+                    continue;
+                }
                 let content = if line.len() > 1 { &line[1..] } else { "" };
                 hunk.lines.push(DiffLine {
                     kind,
@@ -535,6 +2605,11 @@ impl DiffEngine {
                     content: content.to_string(),
                     highlights: Vec::new(),
                     inline_ranges: Vec::new(),
+                    conflict: None,
+                    combined: Vec::new(),
+                    moved: None,
+                    ending,
+                    raw: legacy.then(|| legacy_bytes(content)),
                 });
             }
         }
@@ -543,135 +2618,1173 @@ impl DiffEngine {
             if let Some(h) = current_hunk {
                 f.hunks.push(h);
             }
-            if !f.hunks.is_empty() {
-                on_file(f)?;
-            }
+            defer_parsed(&mut f, over.then_some(changed));
+            emit_parsed(f, &mut pending, on_file)?;
+        }
+        if let Some(f) = pending {
+            on_file(f)?;
         }
 
         Ok(())
     }
 
-    /// Diff two files directly (for git external diff mode)
-    fn diff_external_files(
+    /// Whether the file being parsed went over the line limit with its
+    /// latest changed line, dropping the lines kept so far when it did
+    fn over_parsed_limit(
         &self,
-        path: &str,
-        old_file: &str,
-        new_file: &str,
-    ) -> Result<Vec<DiffFile>> {
-        let old_content = std::fs::read_to_string(old_file).unwrap_or_default();
-        let new_content = std::fs::read_to_string(new_file).unwrap_or_default();
+        file: Option<&mut DiffFile>,
+        hunk: &mut DiffHunk,
+        changed: usize,
+    ) -> bool {
+        let Some(file) = file else {
+            return false;
+        };
+        let paths = [file.old_path.as_deref(), file.new_path.as_deref()];
+        if file.status == FileStatus::Conflicted || !self.over_file_limit(paths, changed) {
+            return false;
+        }
+        file.hunks.clear();
+        hunk.lines.clear();
+        true
+    }
+
+    /// Diff two files directly (for git external diff mode)
+    fn diff_external_file(&self, external: &ExternalFile) -> Result<DiffFile> {
+        if external.unmerged {
+            return Ok(self.conflicted_file(PathBuf::from(&external.path)));
+        }
+        let mut old_bytes = std::fs::read(&external.old_file).unwrap_or_default();
+        let mut new_bytes = std::fs::read(&external.new_file).unwrap_or_default();
 
         // Determine file status
-        let status = if old_content.is_empty() {
+        let old_path = Some(PathBuf::from(&external.path));
+        let new_path = Some(PathBuf::from(
+            external.new_path.as_ref().unwrap_or(&external.path),
+        ));
+        let status = if external.old_file == "/dev/null" {
             FileStatus::Added
-        } else if new_content.is_empty() {
+        } else if external.new_file == "/dev/null" {
             FileStatus::Deleted
+        } else if old_path != new_path {
+            FileStatus::Renamed
         } else {
             FileStatus::Modified
         };
 
-        // Use similar-style diff to create hunks
-        let hunks = self.create_diff_hunks(&old_content, &new_content)?;
+        let path = new_path.clone();
+        // git hands over the raw blobs; its attributes still decide how
+        // they are shown
+        let driver = match (Repository::open(&self.repo_path), path.as_deref()) {
+            (Ok(repo), Some(path)) => diff_driver(&repo, path),
+            _ => DiffDriver::Default,
+        };
+        if driver == DiffDriver::Binary {
+            let mut file = DiffFile::new(old_path, new_path, status);
+            file.binary = old_bytes != new_bytes;
+            file.old_size = Some(old_bytes.len() as u64).filter(|_| status != FileStatus::Added);
+            file.new_size = Some(new_bytes.len() as u64).filter(|_| status != FileStatus::Deleted);
+            return Ok(file);
+        }
+        let mut textconv = false;
+        if let DiffDriver::Textconv(command) = &driver {
+            let convert = |bytes: &[u8]| {
+                if bytes.is_empty() {
+                    Ok(Vec::new())
+                } else {
+                    run_textconv(&self.repo_path, command, bytes)
+                }
+            };
+            // A failing driver leaves both sides as they are
+            if let (Ok(old), Ok(new)) = (convert(&old_bytes), convert(&new_bytes)) {
+                (old_bytes, new_bytes) = (old, new);
+                textconv = true;
+            }
+        }
+        let mut file =
+            self.file_from_contents(old_path, new_path, &old_bytes, &new_bytes, status)?;
+        file.textconv = textconv;
+        Ok(file)
+    }
+
+    /// Build a file entry from the contents of both sides
+    fn file_from_contents(
+        &self,
+        old_path: Option<PathBuf>,
+        new_path: Option<PathBuf>,
+        old: &[u8],
+        new: &[u8],
+        status: FileStatus,
+    ) -> Result<DiffFile> {
+        let mut file = DiffFile::new(old_path, new_path, status);
+        if is_binary(old) || is_binary(new) {
+            file.binary = old != new;
+            file.old_size = Some(old.len() as u64).filter(|_| status != FileStatus::Added);
+            file.new_size = Some(new.len() as u64).filter(|_| status != FileStatus::Deleted);
+            return Ok(file);
+        }
+        let (old, old_encoding) = decode_text(old, None);
+        let (new, new_encoding) = decode_text(new, None);
+        file.hunks = self.create_diff_hunks(&old, &new)?;
+        file.encoding = new_encoding.or(old_encoding);
+        Ok(file)
+    }
+
+    /// Compare two files, or two directory trees recursively, outside any
+    /// repository. Files present on one side only are paired up as renames.
+    fn diff_no_index<F>(&self, old: &Path, new: &Path, on_file: &mut F) -> Result<()>
+    where
+        F: FnMut(DiffFile) -> Result<()>,
+    {
+        if !old.is_dir() && !new.is_dir() {
+            let old_bytes =
+                read_side(old).with_context(|| format!("Failed to read {}", old.display()))?;
+            let new_bytes =
+                read_side(new).with_context(|| format!("Failed to read {}", new.display()))?;
+            let name = |p: &Path| p.file_name().map(PathBuf::from);
+            let mut file = self.file_from_contents(
+                name(old),
+                name(new),
+                &old_bytes,
+                &new_bytes,
+                FileStatus::Modified,
+            )?;
+            file.old_mode = file_mode(old);
+            file.new_mode = file_mode(new);
+            file.classify();
+            if file.has_changes() {
+                on_file(file)?;
+            }
+            return Ok(());
+        }
+        if !old.is_dir() || !new.is_dir() {
+            anyhow::bail!("--no-index compares two files or two directories");
+        }
 
-        Ok(vec![DiffFile {
-            old_path: Some(PathBuf::from(path)),
-            new_path: Some(PathBuf::from(path)),
-            status,
-            hunks,
-        }])
+        let old_files = walk_tree(old)?;
+        let new_files = walk_tree(new)?;
+        let mut files = Vec::new();
+        let mut deleted = Vec::new();
+        for rel in &old_files {
+            let old_bytes = read_side(&old.join(rel))?;
+            if !new_files.contains(rel) {
+                deleted.push((rel.clone(), old_bytes));
+                continue;
+            }
+            let new_bytes = read_side(&new.join(rel))?;
+            let path = Some(rel.clone());
+            let mut file = self.file_from_contents(
+                path.clone(),
+                path,
+                &old_bytes,
+                &new_bytes,
+                FileStatus::Modified,
+            )?;
+            file.old_mode = file_mode(&old.join(rel));
+            file.new_mode = file_mode(&new.join(rel));
+            file.classify();
+            if file.has_changes() {
+                files.push(file);
+            }
+        }
+        let mut added = Vec::new();
+        for rel in new_files.difference(&old_files) {
+            added.push((rel.clone(), read_side(&new.join(rel))?));
+        }
+
+        let pairs = pair_renames(&deleted, &added);
+        let mut paired_old = vec![false; deleted.len()];
+        let mut paired_new = vec![false; added.len()];
+        for (old_idx, new_idx, score) in pairs {
+            paired_old[old_idx] = true;
+            paired_new[new_idx] = true;
+            let (old_rel, old_bytes) = &deleted[old_idx];
+            let (new_rel, new_bytes) = &added[new_idx];
+            let mut file = self.file_from_contents(
+                Some(old_rel.clone()),
+                Some(new_rel.clone()),
+                old_bytes,
+                new_bytes,
+                FileStatus::Renamed,
+            )?;
+            file.similarity = Some(score);
+            file.old_mode = file_mode(&old.join(old_rel));
+            file.new_mode = file_mode(&new.join(new_rel));
+            files.push(file);
+        }
+        for ((rel, bytes), _) in deleted.iter().zip(paired_old).filter(|(_, p)| !p) {
+            let path = Some(rel.clone());
+            let mut file =
+                self.file_from_contents(path.clone(), path, bytes, &[], FileStatus::Deleted)?;
+            file.old_mode = file_mode(&old.join(rel));
+            files.push(file);
+        }
+        for ((rel, bytes), _) in added.iter().zip(paired_new).filter(|(_, p)| !p) {
+            let path = Some(rel.clone());
+            let mut file =
+                self.file_from_contents(path.clone(), path, &[], bytes, FileStatus::Added)?;
+            file.new_mode = file_mode(&new.join(rel));
+            files.push(file);
+        }
+
+        files.sort_by(|a, b| {
+            let key = |f: &DiffFile| f.new_path.clone().or_else(|| f.old_path.clone());
+            key(a).cmp(&key(b))
+        });
+        for file in files {
+            on_file(file)?;
+        }
+        Ok(())
     }
 
     /// Create diff hunks from two strings using a proper diff algorithm
     fn create_diff_hunks(&self, old: &str, new: &str) -> Result<Vec<DiffHunk>> {
-        let old_lines: Vec<&str> = old.lines().collect();
-        let new_lines: Vec<&str> = new.lines().collect();
+        let (old_lines, old_endings): (Vec<&str>, Vec<LineEnding>) =
+            lines_with_endings(old).into_iter().unzip();
+        let (new_lines, new_endings): (Vec<&str>, Vec<LineEnding>) =
+            lines_with_endings(new).into_iter().unzip();
+
+        // A changed terminator is a change, as in git; a trailing CR is
+        // whitespace when spaces are ignored
+        let key = |line: &str, ending: LineEnding| {
+            let mut key = self.whitespace.normalize(line);
+            match ending {
+                LineEnding::Missing => key.push('\\'),
+                LineEnding::CrLf
+                    if !self.whitespace.ignore_all_space
+                        && !self.whitespace.ignore_space_change =>
+                {
+                    key.push('\r')
+                }
+                _ => {}
+            }
+            key
+        };
+        let old_keys: Vec<String> = old_lines
+            .iter()
+            .zip(&old_endings)
+            .map(|(l, e)| key(l, *e))
+            .collect();
+        let new_keys: Vec<String> = new_lines
+            .iter()
+            .zip(&new_endings)
+            .map(|(l, e)| key(l, *e))
+            .collect();
+        let algorithm = match self.algorithm {
+            DiffAlgorithm::Myers | DiffAlgorithm::Minimal => Algorithm::Myers,
+            // similar has no histogram; patience is the closest
+            DiffAlgorithm::Patience | DiffAlgorithm::Histogram => Algorithm::Patience,
+        };
+        let ops = capture_diff_slices(algorithm, &old_keys, &new_keys);
+        let blank_only = |op: &DiffOp| {
+            op.old_range()
+                .map(|i| old_lines[i])
+                .chain(op.new_range().map(|i| new_lines[i]))
+                .all(|l| l.trim().is_empty())
+        };
+
+        let mut hunks = Vec::new();
+        for group in group_diff_ops(ops, self.context_lines as usize) {
+            let (Some(first), Some(last)) = (group.first(), group.last()) else {
+                continue;
+            };
+            if self.whitespace.ignore_blank_lines
+                && group
+                    .iter()
+                    .filter(|op| !matches!(op, DiffOp::Equal { .. }))
+                    .all(blank_only)
+            {
+                continue;
+            }
+            let old_range = first.old_range().start..last.old_range().end;
+            let new_range = first.new_range().start..last.new_range().end;
+
+            let mut lines = Vec::new();
+            for op in &group {
+                for change in op.iter_changes(&old_lines, &new_lines) {
+                    let kind = match change.tag() {
+                        ChangeTag::Equal => LineKind::Context,
+                        ChangeTag::Delete => LineKind::Deletion,
+                        ChangeTag::Insert => LineKind::Addition,
+                    };
+                    let ending = match change.new_index() {
+                        Some(i) => new_endings[i],
+                        None => change
+                            .old_index()
+                            .map_or(LineEnding::Lf, |i| old_endings[i]),
+                    };
+                    lines.push(DiffLine {
+                        kind,
+                        old_line_no: change.old_index().map(|i| i as u32 + 1),
+                        new_line_no: change.new_index().map(|i| i as u32 + 1),
+                        content: change.value().to_string(),
+                        highlights: Vec::new(),
+                        inline_ranges: Vec::new(),
+                        conflict: None,
+                        combined: Vec::new(),
+                        moved: None,
+                        ending,
+                        raw: None,
+                    });
+                }
+            }
+
+            hunks.push(DiffHunk {
+                old_start: hunk_start(&old_range),
+                old_lines: old_range.len() as u32,
+                new_start: hunk_start(&new_range),
+                new_lines: new_range.len() as u32,
+                header: default_funcname(&old_lines, old_range.start),
+                lines,
+            });
+        }
+
+        Ok(hunks)
+    }
+}
+
+/// Unified diff start line: 1-based, or the line before an empty range
+fn hunk_start(range: &Range<usize>) -> u32 {
+    if range.is_empty() {
+        range.start as u32
+    } else {
+        range.start as u32 + 1
+    }
+}
+
+/// Git's default hunk header: the closest line above the hunk that starts
+/// with a letter, `_` or `$`, cut to 80 bytes.
+fn default_funcname(lines: &[&str], hunk_start: usize) -> Option<String> {
+    let line = lines[..hunk_start.min(lines.len())]
+        .iter()
+        .rev()
+        .find(|line| {
+            line.chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        })?;
+    let mut end = line.len().min(80);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(line[..end].trim_end().to_string())
+}
+
+/// Token-level intraline diff between a deleted and an added line.
+///
+/// Tokens are byte ranges into their line. Whitespace tokens take no part in the
+/// alignment, so a pure reflow yields no ranges, and a run of changed tokens is
+/// reported as a single range.
+pub fn token_inline_ranges(
+    old: &str,
+    old_tokens: &[Range<usize>],
+    new: &str,
+    new_tokens: &[Range<usize>],
+) -> (Vec<InlineRange>, Vec<InlineRange>) {
+    let significant = |line: &str, tokens: &[Range<usize>]| -> Vec<Range<usize>> {
+        tokens
+            .iter()
+            .filter(|t| line.get((*t).clone()).is_some_and(|s| !s.trim().is_empty()))
+            .cloned()
+            .collect()
+    };
+    let old_tokens = significant(old, old_tokens);
+    let new_tokens = significant(new, new_tokens);
+    let old_words: Vec<&str> = old_tokens.iter().map(|t| &old[t.clone()]).collect();
+    let new_words: Vec<&str> = new_tokens.iter().map(|t| &new[t.clone()]).collect();
+
+    let span = |tokens: &[Range<usize>], index: usize, len: usize| InlineRange {
+        start: tokens[index].start,
+        end: tokens[index + len - 1].end,
+    };
+
+    let mut old_ranges = Vec::new();
+    let mut new_ranges = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, &old_words, &new_words) {
+        match op {
+            DiffOp::Equal { .. } => {}
+            DiffOp::Delete {
+                old_index, old_len, ..
+            } => old_ranges.push(span(&old_tokens, old_index, old_len)),
+            DiffOp::Insert {
+                new_index, new_len, ..
+            } => new_ranges.push(span(&new_tokens, new_index, new_len)),
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => {
+                old_ranges.push(span(&old_tokens, old_index, old_len));
+                new_ranges.push(span(&new_tokens, new_index, new_len));
+            }
+        }
+    }
+
+    (old_ranges, new_ranges)
+}
+
+/// One hunk per group of nearby conflict regions, lines tagged with their
+/// side. A region written without its base takes the base of the matching
+/// region in `merged`, the stages merged again in diff3 style; those lines
+/// are not in the file and have no line numbers.
+fn conflict_hunks(text: &str, merged: Option<&str>, context: usize) -> Vec<DiffHunk> {
+    let lines = lines_with_endings(text);
+    let regions = find_conflicts(text);
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    for region in &regions {
+        let start = region.start.saturating_sub(context);
+        let end = (region.end + context).min(lines.len() - 1);
+        match spans.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = end,
+            _ => spans.push((start, end)),
+        }
+    }
+    let bases = merged
+        .map(|merged| conflict_bases(&lines, &regions, merged))
+        .unwrap_or_default();
+
+    let line = |content: &str, ending, line_no: Option<u32>, side| DiffLine {
+        kind: LineKind::Context,
+        old_line_no: line_no,
+        new_line_no: line_no,
+        content: content.to_string(),
+        highlights: Vec::new(),
+        inline_ranges: Vec::new(),
+        conflict: side,
+        combined: Vec::new(),
+        moved: None,
+        ending,
+        raw: None,
+    };
+    spans
+        .into_iter()
+        .map(|(start, end)| {
+            let mut hunk_lines = Vec::new();
+            for (idx, &(content, ending)) in lines.iter().enumerate().take(end + 1).skip(start) {
+                if let Some(base) = bases.get(&idx) {
+                    for (pos, (content, ending)) in base.iter().enumerate() {
+                        let side = if pos == 0 {
+                            ConflictSide::Marker
+                        } else {
+                            ConflictSide::Base
+                        };
+                        hunk_lines.push(line(content, *ending, None, Some(side)));
+                    }
+                }
+                let side = regions.iter().find_map(|r| r.side_of(idx));
+                hunk_lines.push(line(content, ending, Some(idx as u32 + 1), side));
+            }
+            let count = (end - start + 1) as u32;
+            DiffHunk {
+                old_start: start as u32 + 1,
+                old_lines: count,
+                new_start: start as u32 + 1,
+                new_lines: count,
+                header: None,
+                lines: hunk_lines,
+            }
+        })
+        .collect()
+}
+
+/// Base lines, from its `|||||||` marker on, for each region written without
+/// one, keyed by the index of the region's `=======` line. A region is
+/// matched to the first unused region of `merged` with the same sides.
+fn conflict_bases<'a>(
+    lines: &[(&str, LineEnding)],
+    regions: &[ConflictRegion],
+    merged: &'a str,
+) -> HashMap<usize, Vec<(&'a str, LineEnding)>> {
+    let merged_lines = lines_with_endings(merged);
+    let mut merged_regions: Vec<Option<ConflictRegion>> =
+        find_conflicts(merged).into_iter().map(Some).collect();
+    let side = |lines: &[(&str, LineEnding)], range: Range<usize>| -> Vec<String> {
+        lines[range].iter().map(|(l, _)| l.to_string()).collect()
+    };
+
+    let mut bases = HashMap::new();
+    for region in regions.iter().filter(|r| r.base.is_none()) {
+        let ours = side(lines, region.ours());
+        let theirs = side(lines, region.theirs());
+        let found = merged_regions.iter_mut().find(|m| {
+            m.is_some_and(|m| {
+                m.base.is_some()
+                    && side(&merged_lines, m.ours()) == ours
+                    && side(&merged_lines, m.theirs()) == theirs
+            })
+        });
+        if let Some(Some(m)) = found.map(Option::take) {
+            let base = m.base.unwrap_or(m.separator);
+            bases.insert(region.separator, merged_lines[base..m.separator].to_vec());
+        }
+    }
+    bases
+}
+
+/// `git diff` arguments selecting the comparison for a repository mode
+fn git_diff_args(repo_path: &Path, mode: &DiffMode) -> Result<Vec<String>> {
+    let args = match mode {
+        DiffMode::Unstaged
+        | DiffMode::ExternalDiff { .. }
+        | DiffMode::Patch { .. }
+        | DiffMode::NoIndex { .. }
+        | DiffMode::RangeDiff { .. } => Vec::new(),
+        DiffMode::Staged => vec!["--staged".to_string()],
+        DiffMode::WorkingTree { base } => vec![base.clone()],
+        DiffMode::Commits { from, to } => vec![format!("{}..{}", from, to)],
+        DiffMode::MergeBase { from, to } => vec![format!("{}...{}", from, to)],
+        DiffMode::Commit { rev } => {
+            let repo = Repository::open(repo_path).context("Failed to open repository")?;
+            let commit = resolve_commit(&repo, rev)?;
+            if commit.parent_count() > 1 {
+                // The merge result first, then its parents: a combined diff
+                let mut args = vec![commit.id().to_string()];
+                args.extend(commit.parent_ids().map(|id| id.to_string()));
+                return Ok(args);
+            }
+            let base = commit
+                .parent_id(0)
+                .map(|id| id.to_string())
+                .unwrap_or_else(|_| EMPTY_TREE.to_string());
+            vec![base, commit.id().to_string()]
+        }
+        DiffMode::Stash { index, staged } => {
+            let stash = stash_rev(*index);
+            if *staged {
+                vec![format!("{}^1", stash), format!("{}^2", stash)]
+            } else {
+                vec![format!("{}^2", stash), stash]
+            }
+        }
+    };
+    Ok(args)
+}
+
+/// One side of a file: a blob in the object database or a path on disk
+enum Side {
+    Blob(git2::Oid),
+    Disk(PathBuf),
+}
+
+impl Side {
+    fn bytes(&self, repo: &Repository) -> Option<Vec<u8>> {
+        match self {
+            Side::Blob(id) => Some(repo.find_blob(*id).ok()?.content().to_vec()),
+            Side::Disk(path) => read_side(path).ok(),
+        }
+    }
+
+    /// Size in bytes, from the object header or the file metadata
+    fn size(&self, repo: &Repository) -> Option<u64> {
+        match self {
+            Side::Blob(id) => {
+                let (size, kind) = repo.odb().ok()?.read_header(*id).ok()?;
+                (kind == git2::ObjectType::Blob).then_some(size as u64)
+            }
+            Side::Disk(path) => {
+                let meta = path.symlink_metadata().ok()?;
+                if meta.file_type().is_symlink() {
+                    // git stores a link as its target
+                    Some(std::fs::read_link(path).ok()?.to_string_lossy().len() as u64)
+                } else {
+                    Some(meta.len())
+                }
+            }
+        }
+    }
+}
+
+fn merge_base_rev(repo: &Repository, from: &str, to: &str) -> Option<String> {
+    let from_id = repo.revparse_single(from).ok()?.peel_to_commit().ok()?.id();
+    let to_id = repo.revparse_single(to).ok()?.peel_to_commit().ok()?.id();
+    Some(repo.merge_base(from_id, to_id).ok()?.to_string())
+}
+
+/// Git's heuristic: a NUL byte within the first 8000 bytes marks binary content
+pub fn is_binary(content: &[u8]) -> bool {
+    content.iter().take(8000).any(|b| *b == 0)
+}
+
+fn resolve_commit<'r>(repo: &'r Repository, spec: &str) -> Result<git2::Commit<'r>> {
+    repo.revparse_single(spec)
+        .and_then(|obj| obj.peel_to_commit())
+        .with_context(|| format!("Unknown revision: {}", spec))
+}
+
+/// Format a commit time the way `git log` does, in the committer's timezone
+fn format_git_time(time: git2::Time) -> String {
+    let format = time::macros::format_description!(
+        "[weekday repr:short] [month repr:short] [day padding:none] \
+         [hour]:[minute]:[second] [year] [offset_hour sign:mandatory][offset_minute]"
+    );
+    time::UtcOffset::from_whole_seconds(time.offset_minutes() * 60)
+        .ok()
+        .zip(time::OffsetDateTime::from_unix_timestamp(time.seconds()).ok())
+        .and_then(|(offset, at)| at.to_offset(offset).format(format).ok())
+        .unwrap_or_else(|| time.seconds().to_string())
+}
+
+fn resolve_tree<'r>(repo: &'r Repository, spec: &str) -> Result<Tree<'r>> {
+    repo.revparse_single(spec)
+        .and_then(|obj| obj.peel_to_tree())
+        .with_context(|| format!("Unknown revision: {}", spec))
+}
+
+/// The file a libgit2 patch describes. A file `deferred` for its number of
+/// changed lines gets no hunks.
+fn diff_file_from_patch(patch: &mut Patch, deferred: Option<usize>) -> Result<DiffFile> {
+    // git2 does not expose the delta's similarity, but the patch header has it
+    let similarity = match patch.delta().status() {
+        Delta::Renamed | Delta::Copied => patch_similarity(patch),
+        _ => None,
+    };
+    let num_hunks = if deferred.is_some() {
+        0
+    } else {
+        patch.num_hunks()
+    };
+    let mut hunks = Vec::with_capacity(num_hunks);
+    for hunk_idx in 0..num_hunks {
+        let (hunk, line_count) = patch.hunk(hunk_idx)?;
+        let header_text = String::from_utf8_lossy(hunk.header());
+        let header = parse_hunk_header(header_text.trim_end()).and_then(|h| h.4);
+
+        let mut lines = Vec::with_capacity(line_count);
+        for line_idx in 0..line_count {
+            let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+            let kind = match line.origin() {
+                '+' => LineKind::Addition,
+                '-' => LineKind::Deletion,
+                ' ' => LineKind::Context,
+                // "\ No newline at end of file" markers
+                _ => continue,
+            };
+            // A line without a newline is the file's last, followed by an
+            // end-of-file marker line
+            let (text, legacy) = decode_line(line.content());
+            let (content, ending) = split_line_ending(&text);
+            lines.push(DiffLine {
+                kind,
+                old_line_no: line.old_lineno(),
+                new_line_no: line.new_lineno(),
+                content: content.to_string(),
+                highlights: Vec::new(),
+                inline_ranges: Vec::new(),
+                conflict: None,
+                combined: Vec::new(),
+                moved: None,
+                ending,
+                raw: legacy.then(|| legacy_bytes(content)),
+            });
+        }
+
+        hunks.push(DiffHunk {
+            old_start: hunk.old_start(),
+            old_lines: hunk.old_lines(),
+            new_start: hunk.new_start(),
+            new_lines: hunk.new_lines(),
+            header,
+            lines,
+        });
+    }
+
+    let mut file = diff_file_from_delta(&patch.delta());
+    file.hunks = hunks;
+    file.deferred = deferred.map(Deferred::Lines);
+    file.similarity = similarity;
+    file.classify();
+    file.note_legacy_lines();
+    Ok(file)
+}
+
+/// The paths, status and modes of a libgit2 delta, without its hunks
+fn diff_file_from_delta(delta: &git2::DiffDelta) -> DiffFile {
+    let status = match delta.status() {
+        Delta::Added | Delta::Untracked => FileStatus::Added,
+        Delta::Deleted => FileStatus::Deleted,
+        Delta::Renamed => FileStatus::Renamed,
+        Delta::Copied => FileStatus::Copied,
+        Delta::Typechange => FileStatus::TypeChanged,
+        _ => FileStatus::Modified,
+    };
+    let mut file = DiffFile::new(
+        delta.old_file().path().map(Path::to_path_buf),
+        delta.new_file().path().map(Path::to_path_buf),
+        status,
+    );
+    file.binary = delta.flags().is_binary();
+    let mode = |m: git2::FileMode| Some(u32::from(m)).filter(|m| *m != 0);
+    if status != FileStatus::Added {
+        file.old_mode = mode(delta.old_file().mode());
+    }
+    if status != FileStatus::Deleted {
+        file.new_mode = mode(delta.new_file().mode());
+    }
+    file
+}
+
+fn patch_similarity(patch: &mut Patch) -> Option<u8> {
+    let buf = patch.to_buf().ok()?;
+    let text = String::from_utf8_lossy(&buf);
+    text.lines()
+        .take_while(|line| !line.starts_with("@@"))
+        .find_map(|line| line.strip_prefix("similarity index "))
+        .and_then(parse_similarity)
+}
+
+/// Emit a parsed file, folding git's deletion + addition pair for a type
+/// change back into a single entry.
+fn emit_parsed<F: FnMut(DiffFile) -> Result<()>>(
+    mut file: DiffFile,
+    pending: &mut Option<DiffFile>,
+    on_file: &mut F,
+) -> Result<()> {
+    file.classify();
+    file.note_legacy_lines();
+    if let Some(mut deleted) = pending.take() {
+        if file.status == FileStatus::Added && file.new_path == deleted.old_path {
+            deleted.status = FileStatus::TypeChanged;
+            deleted.new_path = file.new_path;
+            deleted.new_mode = file.new_mode;
+            deleted.new_size = file.new_size;
+            deleted.binary |= file.binary;
+            deleted.hunks.append(&mut file.hunks);
+            return on_file(deleted);
+        }
+        on_file(deleted)?;
+    }
+    if !file.has_changes() {
+        return Ok(());
+    }
+    if file.status == FileStatus::Deleted {
+        *pending = Some(file);
+        return Ok(());
+    }
+    on_file(file)
+}
+
+/// Leave out the hunks of a parsed file that went over the line limit
+fn defer_parsed(file: &mut DiffFile, deferred: Option<usize>) {
+    if let Some(lines) = deferred {
+        file.hunks = Vec::new();
+        file.deferred = Some(Deferred::Lines(lines));
+    }
+}
+
+/// Parse the "87%" of a "similarity index 87%" header
+fn parse_similarity(score: &str) -> Option<u8> {
+    score.trim().strip_suffix('%')?.parse().ok()
+}
+
+/// Inexact rename detection in no-index mode is skipped when either side
+/// has more unpaired files than this
+const RENAME_CANDIDATE_LIMIT: usize = 100;
+
+/// Pair deleted with added files: identical content first, then the most
+/// similar text at 50% or more, as git's rename detection does
+fn pair_renames(
+    deleted: &[(PathBuf, Vec<u8>)],
+    added: &[(PathBuf, Vec<u8>)],
+) -> Vec<(usize, usize, u8)> {
+    let mut pairs = Vec::new();
+    let mut used_old = vec![false; deleted.len()];
+    let mut used_new = vec![false; added.len()];
+
+    let mut by_content: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (idx, (_, bytes)) in deleted.iter().enumerate().rev() {
+        if !bytes.is_empty() {
+            by_content.entry(bytes.as_slice()).or_default().push(idx);
+        }
+    }
+    for (new_idx, (_, bytes)) in added.iter().enumerate() {
+        if let Some(old_idx) = by_content.get_mut(bytes.as_slice()).and_then(|c| c.pop()) {
+            used_old[old_idx] = true;
+            used_new[new_idx] = true;
+            pairs.push((old_idx, new_idx, 100));
+        }
+    }
+
+    let is_text = |bytes: &[u8]| !bytes.is_empty() && !is_binary(bytes);
+    let old_left: Vec<usize> = (0..deleted.len())
+        .filter(|&i| !used_old[i] && is_text(&deleted[i].1))
+        .collect();
+    let new_left: Vec<usize> = (0..added.len())
+        .filter(|&i| !used_new[i] && is_text(&added[i].1))
+        .collect();
+    if old_left.len() > RENAME_CANDIDATE_LIMIT || new_left.len() > RENAME_CANDIDATE_LIMIT {
+        return pairs;
+    }
+
+    let mut scored = Vec::new();
+    for &old_idx in &old_left {
+        let old = String::from_utf8_lossy(&deleted[old_idx].1);
+        for &new_idx in &new_left {
+            let new = String::from_utf8_lossy(&added[new_idx].1);
+            let ratio = TextDiff::configure()
+                .timeout(Duration::from_millis(100))
+                .diff_lines(old.as_ref(), new.as_ref())
+                .ratio();
+            let score = (ratio * 100.0) as u8;
+            if score >= 50 {
+                scored.push((score, old_idx, new_idx));
+            }
+        }
+    }
+    scored.sort_by_key(|s| std::cmp::Reverse(s.0));
+    for (score, old_idx, new_idx) in scored {
+        if !used_old[old_idx] && !used_new[new_idx] {
+            used_old[old_idx] = true;
+            used_new[new_idx] = true;
+            pairs.push((old_idx, new_idx, score));
+        }
+    }
+    pairs
+}
+
+/// Files (and symlinks) below `root`, relative to it
+fn walk_tree(root: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    let mut stack = vec![PathBuf::new()];
+    while let Some(dir) = stack.pop() {
+        let entries = std::fs::read_dir(root.join(&dir))
+            .with_context(|| format!("Failed to read {}", root.join(&dir).display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let rel = dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                stack.push(rel);
+            } else {
+                files.insert(rel);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Contents of a file as git sees it: a symlink's content is its target
+fn read_side(path: &Path) -> std::io::Result<Vec<u8>> {
+    if path.symlink_metadata()?.file_type().is_symlink() {
+        return Ok(std::fs::read_link(path)?
+            .to_string_lossy()
+            .into_owned()
+            .into_bytes());
+    }
+    std::fs::read(path)
+}
+
+/// Git file mode of a path on disk
+fn file_mode(path: &Path) -> Option<u32> {
+    let meta = path.symlink_metadata().ok()?;
+    if meta.file_type().is_symlink() {
+        return Some(MODE_SYMLINK);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if meta.permissions().mode() & 0o111 != 0 {
+            return Some(MODE_EXECUTABLE);
+        }
+    }
+    Some(MODE_FILE)
+}
+
+/// Location of a file in a no-index comparison root, which may itself be the file
+pub fn no_index_path(root: &Path, rel: &Path) -> PathBuf {
+    if root.is_dir() {
+        root.join(rel)
+    } else {
+        root.to_path_buf()
+    }
+}
+
+/// Patch carrying only a file's mode change
+pub fn build_mode_patch(file: &DiffFile) -> Option<String> {
+    if !file.mode_changed() {
+        return None;
+    }
+    let path = file.new_path.as_ref().or(file.old_path.as_ref())?;
+    Some(format!(
+        "diff --git {} {}\nold mode {:o}\nnew mode {:o}\n",
+        quote_path("a/", path),
+        quote_path("b/", path),
+        file.old_mode?,
+        file.new_mode?
+    ))
+}
+
+/// Patch applying `hunks` of a file, for `git apply` to stage or discard
+pub fn build_hunks_patch(file: &DiffFile, hunks: &[DiffHunk]) -> Vec<u8> {
+    let unknown = PathBuf::from("unknown");
+    let old_path = file
+        .old_path
+        .as_ref()
+        .or(file.new_path.as_ref())
+        .unwrap_or(&unknown);
+    let new_path = file.new_path.as_ref().unwrap_or(old_path);
+    let (old_name, new_name) = (quote_path("a/", old_path), quote_path("b/", new_path));
+
+    let mut patch = String::new();
+    patch.push_str(&format!("diff --git {} {}\n", old_name, new_name));
+
+    match file.status {
+        FileStatus::Added => {
+            patch.push_str(&format!(
+                "new file mode {:o}\n",
+                file.new_mode.unwrap_or(MODE_FILE)
+            ));
+            patch.push_str("--- /dev/null\n");
+            patch.push_str(&format!("+++ {}\n", new_name));
+        }
+        FileStatus::Deleted => {
+            if let Some(mode) = file.old_mode {
+                patch.push_str(&format!("deleted file mode {:o}\n", mode));
+            }
+            patch.push_str(&format!("--- {}\n", old_name));
+            patch.push_str("+++ /dev/null\n");
+        }
+        // Hunks leave the mode alone; a mode change is staged on its own
+        // through build_mode_patch. Symlink targets patch like file
+        // content and git apply keeps the recorded mode.
+        FileStatus::Modified
+        | FileStatus::Renamed
+        | FileStatus::Copied
+        | FileStatus::ModeChanged
+        | FileStatus::TypeChanged
+        | FileStatus::Symlink
+        | FileStatus::Submodule
+        | FileStatus::Conflicted => {
+            patch.push_str(&format!("--- {}\n", old_name));
+            patch.push_str(&format!("+++ {}\n", new_name));
+        }
+    }
+
+    // Lines go back in the bytes they had in the file
+    let mut patch = patch.into_bytes();
+    for hunk in hunks {
+        patch.extend_from_slice(
+            format!(
+                "@@ -{},{} +{},{} @@\n",
+                hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines
+            )
+            .as_bytes(),
+        );
+
+        for line in &hunk.lines {
+            patch.push(match line.kind {
+                LineKind::Context => b' ',
+                LineKind::Addition => b'+',
+                LineKind::Deletion => b'-',
+            });
+            patch.extend_from_slice(line.raw.as_deref().unwrap_or(line.content.as_bytes()));
+            patch.extend_from_slice(line.ending.patch_suffix().as_bytes());
+        }
+    }
+
+    patch
+}
+
+/// Path from a "--- a/file\t<timestamp>" header, quoted or not; None for
+/// /dev/null
+fn plain_diff_path(header: &str, prefix: &str, legacy: bool) -> Option<PathBuf> {
+    let bytes = match unquote_c_style(header) {
+        Some((bytes, _)) => bytes,
+        None => {
+            let path = header.split('\t').next().unwrap_or(header);
+            unquoted_bytes(path.trim_end_matches([' ', '\r']), legacy)
+        }
+    };
+    if bytes == b"/dev/null" {
+        return None;
+    }
+    let bytes = bytes.strip_prefix(prefix.as_bytes()).unwrap_or(&bytes);
+    Some(path_from_bytes(bytes.to_vec()))
+}
+
+/// Path from a "rename from <path>"-style header value or a `--cc` line
+fn header_path(value: &str, legacy: bool) -> PathBuf {
+    match unquote_c_style(value) {
+        Some((bytes, _)) => path_from_bytes(bytes),
+        None => path_from_bytes(unquoted_bytes(value, legacy)),
+    }
+}
+
+/// Original bytes of a bare name on a header line, which was decoded with
+/// the legacy encoding if it was not UTF-8
+fn unquoted_bytes(name: &str, legacy: bool) -> Vec<u8> {
+    if legacy {
+        legacy_bytes(name)
+    } else {
+        name.as_bytes().to_vec()
+    }
+}
+
+/// A `.gitignore` line matching exactly one file, anchored at the repo root.
+/// A name with a newline cannot be written as a pattern.
+pub fn exclude_pattern(path: &Path) -> Option<Vec<u8>> {
+    let bytes = path_bytes(path);
+    if bytes.contains(&b'\n') {
+        return None;
+    }
+    let mut pattern = vec![b'/'];
+    for byte in bytes {
+        // Wildcards and spaces (trailing ones are trimmed) match literally
+        if matches!(byte, b'*' | b'?' | b'[' | b'\\' | b' ') {
+            pattern.push(b'\\');
+        }
+        pattern.push(byte);
+    }
+    Some(pattern)
+}
 
-        // Simple diff using longest common subsequence approach
-        let mut hunks = Vec::new();
-        let mut lines = Vec::new();
+/// Whether git would quote a path byte under `core.quotePath`
+fn needs_quoting(byte: u8) -> bool {
+    !(0x20..0x7f).contains(&byte) || byte == b'"' || byte == b'\\'
+}
 
-        let mut old_idx = 0;
-        let mut new_idx = 0;
+/// A path with its prefix as git writes it in a patch: bare when plain,
+/// otherwise in double quotes with C escapes (`"a/caf\303\251.txt"`)
+pub fn quote_path(prefix: &str, path: &Path) -> String {
+    let mut bytes = prefix.as_bytes().to_vec();
+    bytes.extend_from_slice(&path_bytes(path));
+    if !bytes.iter().copied().any(needs_quoting) {
+        return String::from_utf8_lossy(&bytes).into_owned();
+    }
+    let mut out = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'\x07' => out.push_str("\\a"),
+            b'\x08' => out.push_str("\\b"),
+            b'\t' => out.push_str("\\t"),
+            b'\n' => out.push_str("\\n"),
+            b'\x0b' => out.push_str("\\v"),
+            b'\x0c' => out.push_str("\\f"),
+            b'\r' => out.push_str("\\r"),
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            byte if needs_quoting(byte) => out.push_str(&format!("\\{:03o}", byte)),
+            byte => out.push(byte as char),
+        }
+    }
+    out.push('"');
+    out
+}
 
-        while old_idx < old_lines.len() || new_idx < new_lines.len() {
-            if old_idx < old_lines.len()
-                && new_idx < new_lines.len()
-                && old_lines[old_idx] == new_lines[new_idx]
-            {
-                // Context line
-                lines.push(DiffLine {
-                    kind: LineKind::Context,
-                    old_line_no: Some(old_idx as u32 + 1),
-                    new_line_no: Some(new_idx as u32 + 1),
-                    content: old_lines[old_idx].to_string(),
-                    highlights: Vec::new(),
-                    inline_ranges: Vec::new(),
-                });
-                old_idx += 1;
-                new_idx += 1;
-            } else if new_idx < new_lines.len()
-                && (old_idx >= old_lines.len()
-                    || !old_lines[old_idx..].contains(&new_lines[new_idx]))
-            {
-                // Addition
-                lines.push(DiffLine {
-                    kind: LineKind::Addition,
-                    old_line_no: None,
-                    new_line_no: Some(new_idx as u32 + 1),
-                    content: new_lines[new_idx].to_string(),
-                    highlights: Vec::new(),
-                    inline_ranges: Vec::new(),
-                });
-                new_idx += 1;
-            } else if old_idx < old_lines.len() {
-                // Deletion
-                lines.push(DiffLine {
-                    kind: LineKind::Deletion,
-                    old_line_no: Some(old_idx as u32 + 1),
-                    new_line_no: None,
-                    content: old_lines[old_idx].to_string(),
-                    highlights: Vec::new(),
-                    inline_ranges: Vec::new(),
+/// Decode a C-quoted path at the start of `text`, returning its bytes and
+/// the text after the closing quote; None when `text` is not quoted
+fn unquote_c_style(text: &str) -> Option<(Vec<u8>, &str)> {
+    let rest = text.strip_prefix('"')?;
+    let bytes = rest.as_bytes();
+    let mut out = Vec::new();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'"' => return Some((out, &rest[idx + 1..])),
+            b'\\' => {
+                let escaped = *bytes.get(idx + 1)?;
+                idx += 2;
+                out.push(match escaped {
+                    b'a' => 0x07,
+                    b'b' => 0x08,
+                    b't' => b'\t',
+                    b'n' => b'\n',
+                    b'v' => 0x0b,
+                    b'f' => 0x0c,
+                    b'r' => b'\r',
+                    b'0'..=b'3' => {
+                        let digits = bytes.get(idx - 1..idx + 2)?;
+                        idx += 2;
+                        digits.iter().try_fold(0u8, |acc, d| match d {
+                            b'0'..=b'7' => Some(acc * 8 + (d - b'0')),
+                            _ => None,
+                        })?
+                    }
+                    other => other,
                 });
-                old_idx += 1;
+            }
+            byte => {
+                out.push(byte);
+                idx += 1;
             }
         }
+    }
+    None
+}
 
-        if !lines.is_empty() {
-            hunks.push(DiffHunk {
-                old_start: 1,
-                old_lines: old_lines.len() as u32,
-                new_start: 1,
-                new_lines: new_lines.len() as u32,
-                header: None,
-                lines,
-            });
-        }
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
 
-        Ok(hunks)
-    }
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
 }
 
-/// Parse "diff --git a/path b/path" line
-fn parse_diff_git_line(line: &str) -> (Option<String>, Option<String>) {
-    // "diff --git a/old/path b/new/path"
-    let line = line.strip_prefix("diff --git ").unwrap_or(line);
-    let parts: Vec<&str> = line.splitn(2, " b/").collect();
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
 
-    let mut old_path = parts
-        .first()
-        .and_then(|p| p.strip_prefix("a/"))
-        .map(String::from);
-    let mut new_path = parts.get(1).map(|p| p.to_string());
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().as_bytes().to_vec()
+}
 
-    if old_path.as_deref() == Some("dev/null") {
-        old_path = None;
-    }
-    if new_path.as_deref() == Some("dev/null") {
-        new_path = None;
-    }
+/// Read a patch for pager mode, dropping the colour codes git adds when it
+/// runs differ as its pager
+pub fn read_patch<R: Read>(mut reader: R) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .context("Failed to read patch")?;
+    // Left as bytes: lines and paths are decoded as the git-backed modes do
+    let ansi = regex::bytes::Regex::new(r"\x1b\[[0-9;]*[mK]").expect("valid regex");
+    Ok(ansi.replace_all(&bytes, &b""[..]).into_owned())
+}
+
+fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode.trim(), 8).ok()
+}
+
+/// Parse "diff --git a/path b/path" line. Either name may be C-quoted. Bare
+/// names are split where both halves agree, as git does; a rename whose
+/// names contain " b/" is ambiguous here and is settled by the "rename
+/// from"/"rename to" and "---"/"+++" headers that follow.
+fn parse_diff_git_line(line: &str, legacy: bool) -> (Option<PathBuf>, Option<PathBuf>) {
+    // "diff --git a/old/path b/new/path"
+    let line = line.strip_prefix("diff --git ").unwrap_or(line);
+    let (old, new): (Vec<u8>, Vec<u8>) = if let Some((old, rest)) = unquote_c_style(line) {
+        let rest = rest.strip_prefix(' ').unwrap_or(rest);
+        let new =
+            unquote_c_style(rest).map_or_else(|| unquoted_bytes(rest, legacy), |(new, _)| new);
+        (old, new)
+    } else if let Some((old, (new, _))) = line
+        .find(" \"")
+        .and_then(|idx| Some((&line[..idx], unquote_c_style(&line[idx + 1..])?)))
+    {
+        (unquoted_bytes(old, legacy), new)
+    } else {
+        let splits: Vec<usize> = line.match_indices(" b/").map(|(idx, _)| idx).collect();
+        let symmetric = splits
+            .iter()
+            .copied()
+            .find(|&idx| line[..idx].strip_prefix("a/") == Some(&line[idx + 3..]));
+        match symmetric.or(splits.first().copied()) {
+            Some(idx) => (
+                unquoted_bytes(&line[..idx], legacy),
+                unquoted_bytes(&line[idx + 1..], legacy),
+            ),
+            None => (unquoted_bytes(line, legacy), Vec::new()),
+        }
+    };
 
-    (old_path, new_path)
+    let strip = |name: &[u8], prefix: &[u8]| {
+        let name = name.strip_prefix(prefix)?;
+        (name != b"dev/null" && !name.is_empty()).then(|| path_from_bytes(name.to_vec()))
+    };
+    (strip(&old, b"a/"), strip(&new, b"b/"))
 }
 
 /// Parse a unified diff hunk header: @@ -old_start,old_count +new_start,new_count @@ <header>
@@ -698,6 +3811,35 @@ fn parse_hunk_header(line: &str) -> Option<(u32, u32, u32, u32, Option<String>)>
     Some((old_start, old_count, new_start, new_count, header))
 }
 
+/// Ranges of a combined hunk header, `@@@ -1,3 -1,4 +1,5 @@@ context`
+struct CombinedHunkHeader {
+    parents: Vec<(u32, u32)>,
+    result: (u32, u32),
+    header: Option<String>,
+}
+
+fn parse_combined_hunk_header(line: &str) -> Option<CombinedHunkHeader> {
+    let markers = line.len() - line.trim_start_matches('@').len();
+    if markers < 3 {
+        return None;
+    }
+    let mut parts = line[markers..].split_whitespace();
+    let parents = (1..markers)
+        .map(|_| parse_range(parts.next()?.strip_prefix('-')?))
+        .collect::<Option<Vec<_>>>()?;
+    let result = parse_range(parts.next()?.strip_prefix('+')?)?;
+    let closing = "@".repeat(markers);
+    if parts.next() != Some(closing.as_str()) {
+        return None;
+    }
+    let header = parts.collect::<Vec<_>>().join(" ");
+    Some(CombinedHunkHeader {
+        parents,
+        result,
+        header: Some(header).filter(|h| !h.is_empty()),
+    })
+}
+
 fn parse_range(s: &str) -> Option<(u32, u32)> {
     if let Some((start, count)) = s.split_once(',') {
         Some((start.parse().ok()?, count.parse().ok()?))
@@ -740,3 +3882,1676 @@ pub fn find_repo_root(start: &Path) -> Result<PathBuf> {
         anyhow::bail!("repo path does not exist: {}", workdir.display());
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn commit_all(repo: &Repository, message: &str) {
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+            .unwrap();
+    }
+
+    /// Run git in `dir` with a test identity; true on success
+    pub(crate) fn git_ok(dir: &Path, args: &[&str]) -> bool {
+        Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status
+            .success()
+    }
+
+    /// Inline ranges of a changed Rust line, over the tokens the syntax
+    /// highlighter splits it into
+    fn rust_inline_ranges(old: &str, new: &str) -> (Vec<InlineRange>, Vec<InlineRange>) {
+        let mut highlighter = SyntaxHighlighter::new(None).unwrap();
+        let tokens = highlighter.tokenize_lines(&[old, new], "lib.rs");
+        token_inline_ranges(old, &tokens[0], new, &tokens[1])
+    }
+
+    #[test]
+    fn test_token_inline_ranges_renamed_identifier() {
+        let old = "let total_count = compute(items);";
+        let new = "let item_total = compute(items);";
+        let mut highlighter = SyntaxHighlighter::new(None).unwrap();
+        let tokens = highlighter.tokenize_lines(&[old], "lib.rs");
+        let spans: Vec<&str> = tokens[0].iter().map(|r| &old[r.clone()]).collect();
+        assert_eq!(
+            spans,
+            vec![
+                "let",
+                " ",
+                "total_count",
+                " ",
+                "=",
+                " ",
+                "compute",
+                "(",
+                "items",
+                ")",
+                ";"
+            ]
+        );
+
+        let (old_ranges, new_ranges) = rust_inline_ranges(old, new);
+        assert_eq!(old_ranges.len(), 1);
+        assert_eq!(&old[old_ranges[0].start..old_ranges[0].end], "total_count");
+        assert_eq!(new_ranges.len(), 1);
+        assert_eq!(&new[new_ranges[0].start..new_ranges[0].end], "item_total");
+    }
+
+    #[test]
+    fn test_token_inline_ranges_ignores_whitespace_reflow() {
+        let (old_ranges, new_ranges) = rust_inline_ranges("foo(a,b,  c)", "    foo( a, b, c )");
+        assert!(old_ranges.is_empty());
+        assert!(new_ranges.is_empty());
+    }
+
+    #[test]
+    fn test_binary_files_are_kept() {
+        let engine = DiffEngine::new(PathBuf::from("."), 3);
+        let output = "diff --git a/logo.png b/logo.png\n\
+                      index 1111111..2222222 100644\n\
+                      Binary files a/logo.png and b/logo.png differ\n\
+                      diff --git a/a.txt b/a.txt\n\
+                      index 3333333..4444444 100644\n\
+                      --- a/a.txt\n\
+                      +++ b/a.txt\n\
+                      @@ -1 +1 @@\n\
+                      -old\n\
+                      +new\n";
+        let files = engine.parse_full_diff(output).unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files[0].binary);
+        assert!(files[0].hunks.is_empty());
+        assert!(!files[1].binary);
+    }
+
+    #[test]
+    fn test_mode_type_and_submodule_changes() {
+        let engine = DiffEngine::new(PathBuf::from("."), 3);
+        let output = "diff --git a/run.sh b/run.sh\n\
+                      old mode 100644\n\
+                      new mode 100755\n\
+                      diff --git a/link b/link\n\
+                      index 1111111..2222222 120000\n\
+                      --- a/link\n\
+                      +++ b/link\n\
+                      @@ -1 +1 @@\n\
+                      -old/target\n\
+                      \\ No newline at end of file\n\
+                      +new/target\n\
+                      \\ No newline at end of file\n\
+                      diff --git a/vendor/lib b/vendor/lib\n\
+                      index 3333333..4444444 160000\n\
+                      --- a/vendor/lib\n\
+                      +++ b/vendor/lib\n\
+                      @@ -1 +1 @@\n\
+                      -Subproject commit 3333333333333333333333333333333333333333\n\
+                      +Subproject commit 4444444444444444444444444444444444444444\n\
+                      diff --git a/conf b/conf\n\
+                      deleted file mode 100644\n\
+                      index 5555555..0000000\n\
+                      --- a/conf\n\
+                      +++ /dev/null\n\
+                      @@ -1 +0,0 @@\n\
+                      -key = 1\n\
+                      diff --git a/conf b/conf\n\
+                      new file mode 120000\n\
+                      index 0000000..6666666\n\
+                      --- /dev/null\n\
+                      +++ b/conf\n\
+                      @@ -0,0 +1 @@\n\
+                      +conf.d/main\n\
+                      \\ No newline at end of file\n";
+        let files = engine.parse_full_diff(output).unwrap();
+        let statuses: Vec<_> = files.iter().map(|f| f.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                FileStatus::ModeChanged,
+                FileStatus::Symlink,
+                FileStatus::Submodule,
+                FileStatus::TypeChanged,
+            ]
+        );
+        assert_eq!(files[0].old_mode, Some(MODE_FILE));
+        assert_eq!(files[0].new_mode, Some(MODE_EXECUTABLE));
+        assert!(files[0].hunks.is_empty());
+        assert_eq!(
+            files[2].submodule_commits().1.as_deref(),
+            Some("4444444444444444444444444444444444444444")
+        );
+        assert_eq!(mode_kind(files[3].old_mode.unwrap()), "file");
+        assert_eq!(mode_kind(files[3].new_mode.unwrap()), "symlink");
+        assert_eq!(files[3].hunks.len(), 2);
+    }
+
+    #[test]
+    fn test_libgit2_backend_reports_mode_change() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let script = dir.path().join("run.sh");
+        std::fs::write(&script, "echo hi\n").unwrap();
+        commit_all(&repo, "initial");
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        for engine in [engine.clone(), engine.with_backend(DiffBackend::Git)] {
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].status, FileStatus::ModeChanged);
+            assert_eq!(files[0].new_mode, Some(MODE_EXECUTABLE));
+        }
+    }
+
+    #[test]
+    fn test_copy_and_similarity_headers() {
+        let engine = DiffEngine::new(PathBuf::from("."), 3);
+        let output = "diff --git a/a.rs b/b.rs\n\
+                      similarity index 87%\n\
+                      rename from a.rs\n\
+                      rename to b.rs\n\
+                      index 1111111..2222222 100644\n\
+                      --- a/a.rs\n\
+                      +++ b/b.rs\n\
+                      @@ -1 +1 @@\n\
+                      -old\n\
+                      +new\n\
+                      diff --git a/c.rs b/d.rs\n\
+                      similarity index 100%\n\
+                      copy from c.rs\n\
+                      copy to d.rs\n";
+        let files = engine.parse_full_diff(output).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].status, FileStatus::Renamed);
+        assert_eq!(files[0].similarity, Some(87));
+        assert!(!files[0].is_pure_rename());
+        assert_eq!(files[1].status, FileStatus::Copied);
+        assert_eq!(files[1].similarity, Some(100));
+        assert_eq!(files[1].old_path, Some(PathBuf::from("c.rs")));
+    }
+
+    #[test]
+    fn test_pure_rename_similarity() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        commit_all(&repo, "initial");
+        std::fs::rename(dir.path().join("a.txt"), dir.path().join("b.txt")).unwrap();
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("a.txt")).unwrap();
+        index.write().unwrap();
+        commit_all(&repo, "rename");
+
+        let mode = DiffMode::Commits {
+            from: "HEAD~1".to_string(),
+            to: "HEAD".to_string(),
+        };
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        for engine in [engine.clone(), engine.with_backend(DiffBackend::Git)] {
+            let files = engine.diff(&mode, &[]).unwrap();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].status, FileStatus::Renamed);
+            assert_eq!(files[0].similarity, Some(100));
+            assert!(files[0].is_pure_rename());
+        }
+    }
+
+    #[test]
+    fn test_external_diff_hunks_match_git() {
+        let dir = tempdir().unwrap();
+        let old: String = (1..=40).map(|i| format!("line {}\n", i)).collect();
+        let new = old
+            .replace("line 3\n", "line three\n")
+            .replace("line 20\n", "fn twenty() {\n")
+            .replace("line 30\n", "")
+            .replace("line 40\n", "line 40\nline 41\n");
+        let old_file = dir.path().join("old.txt");
+        let new_file = dir.path().join("new.txt");
+        std::fs::write(&old_file, &old).unwrap();
+        std::fs::write(&new_file, &new).unwrap();
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        let mode = DiffMode::ExternalDiff {
+            files: vec![ExternalFile {
+                path: "file.txt".to_string(),
+                new_path: None,
+                old_file: old_file.to_string_lossy().to_string(),
+                new_file: new_file.to_string_lossy().to_string(),
+                unmerged: false,
+            }],
+        };
+        let ours = engine.diff(&mode, &[]).unwrap();
+
+        let output = Command::new("git")
+            .args(["diff", "--no-index", "-U3"])
+            .arg(&old_file)
+            .arg(&new_file)
+            .output()
+            .unwrap();
+        let theirs = engine
+            .parse_full_diff(&String::from_utf8_lossy(&output.stdout))
+            .unwrap();
+
+        let summary = |files: &[DiffFile]| -> Vec<_> {
+            files[0]
+                .hunks
+                .iter()
+                .map(|h| {
+                    // The text parser keeps the "@@" in front of the section heading
+                    let header = h
+                        .header
+                        .as_deref()
+                        .map(|t| t.trim_start_matches("@@").trim().to_string())
+                        .filter(|t| !t.is_empty());
+                    let lines: Vec<_> = h
+                        .lines
+                        .iter()
+                        .map(|l| (l.kind, l.content.clone()))
+                        .collect();
+                    (
+                        h.old_start,
+                        h.old_lines,
+                        h.new_start,
+                        h.new_lines,
+                        header,
+                        lines,
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(ours[0].hunks.len(), 4);
+        assert_eq!(summary(&ours), summary(&theirs));
+    }
+
+    #[test]
+    fn test_ignore_whitespace_options() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo  words\nthree\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "alpha\nbeta\n").unwrap();
+        commit_all(&repo, "initial");
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo words  \nthree\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "alpha\n\nbeta\n").unwrap();
+
+        let whitespace = Whitespace {
+            ignore_space_change: true,
+            ignore_blank_lines: true,
+            ..Whitespace::default()
+        };
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_whitespace(whitespace);
+        for engine in [
+            engine.clone(),
+            engine.clone().with_backend(DiffBackend::Git),
+            engine.clone().with_algorithm(DiffAlgorithm::Histogram),
+        ] {
+            assert!(engine.diff(&DiffMode::Unstaged, &[]).unwrap().is_empty());
+            let real = engine
+                .whitespace_sensitive()
+                .diff(&DiffMode::Unstaged, &[])
+                .unwrap();
+            assert_eq!(real.len(), 2);
+        }
+
+        let old = "fn a() {\n    x;\n}\n";
+        let new = "fn a() {\n\tx;\n\n}\n";
+        let ignoring = DiffEngine::new(dir.path().to_path_buf(), 3).with_whitespace(Whitespace {
+            ignore_all_space: true,
+            ignore_blank_lines: true,
+            ..Whitespace::default()
+        });
+        assert!(ignoring.create_diff_hunks(old, new).unwrap().is_empty());
+        assert_eq!(
+            ignoring
+                .whitespace_sensitive()
+                .create_diff_hunks(old, new)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_patch_mode_reads_mail_and_plain_diffs() {
+        let engine = DiffEngine::new(PathBuf::from("."), 3);
+        let mail = "From 1234 Mon Sep 17 00:00:00 2001\n\
+                    Subject: [PATCH] Fix\n\
+                    \n\
+                    ---\n \
+                    a.sql | 2 +-\n\
+                    \n\
+                    diff --git a/a.sql b/a.sql\n\
+                    index 1111111..2222222 100644\n\
+                    \x1b[1m--- a/a.sql\x1b[m\n\
+                    +++ b/a.sql\n\
+                    @@ -1,3 +1,3 @@\n\
+                    \x1b[31m--- old comment\x1b[m\n\
+                    +-- new comment\n\
+                    \n \
+                    select 1;\n\
+                    -- \n\
+                    2.39.5\n\
+                    --- old/b.txt\t2024-01-01 00:00:00\n\
+                    +++ new/b.txt\t2024-01-02 00:00:00\n\
+                    @@ -1 +1 @@\n\
+                    -x\n\
+                    +y\n";
+        let text = read_patch(mail.as_bytes()).unwrap();
+        let mode = DiffMode::Patch {
+            source: "mail".to_string(),
+            text: Arc::new(text),
+        };
+        let files = engine.diff(&mode, &[]).unwrap();
+        assert_eq!(files.len(), 2);
+        let lines: Vec<_> = files[0].hunks[0]
+            .lines
+            .iter()
+            .map(|l| (l.kind, l.content.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (LineKind::Deletion, "-- old comment"),
+                (LineKind::Addition, "-- new comment"),
+                (LineKind::Context, ""),
+                (LineKind::Context, "select 1;"),
+            ]
+        );
+        assert_eq!(files[1].old_path, Some(PathBuf::from("old/b.txt")));
+        assert_eq!(files[1].new_path, Some(PathBuf::from("new/b.txt")));
+        assert_eq!(files[1].hunks[0].lines.len(), 2);
+    }
+
+    #[test]
+    fn test_patch_mode_keeps_non_utf8_bytes() {
+        let engine = DiffEngine::new(PathBuf::from("."), 3);
+        let patch = b"diff --git a/caf\xe9.txt b/caf\xe9.txt\n\
+            --- a/caf\xe9.txt\n\
+            +++ b/caf\xe9.txt\n\
+            @@ -1 +1 @@\n\
+            \x1b[31m-na\xefve\x1b[m\n\
+            +na\xeff\n";
+        let mode = DiffMode::Patch {
+            source: "latin-1".to_string(),
+            text: Arc::new(read_patch(&patch[..]).unwrap()),
+        };
+        let files = engine.diff(&mode, &[]).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            path_bytes(files[0].new_path.as_deref().unwrap()),
+            b"caf\xe9.txt"
+        );
+        let lines: Vec<_> = files[0].hunks[0]
+            .lines
+            .iter()
+            .map(|l| l.content.as_str())
+            .collect();
+        assert_eq!(lines, vec!["na\u{ef}ve", "na\u{ef}f"]);
+    }
+
+    #[test]
+    fn test_no_index_directories_pair_renames() {
+        let dir = tempdir().unwrap();
+        let (old, new) = (dir.path().join("v1"), dir.path().join("v2"));
+        let body: String = (1..=10).map(|i| format!("line {}\n", i)).collect();
+        for root in [&old, &new] {
+            std::fs::create_dir_all(root.join("src")).unwrap();
+            std::fs::write(root.join("same.txt"), "same\n").unwrap();
+        }
+        std::fs::write(old.join("src/a.rs"), "fn a() {}\n").unwrap();
+        std::fs::write(new.join("src/a.rs"), "fn a() { b() }\n").unwrap();
+        std::fs::write(old.join("moved.txt"), &body).unwrap();
+        std::fs::write(new.join("src/moved.txt"), &body).unwrap();
+        let rows = body.replace("line", "row");
+        std::fs::write(old.join("edited.txt"), &rows).unwrap();
+        std::fs::write(new.join("renamed.txt"), rows.replace("row 5", "five")).unwrap();
+        std::fs::write(old.join("gone.txt"), "bye\n").unwrap();
+        std::fs::write(new.join("new.txt"), "hi\n").unwrap();
+
+        let engine = DiffEngine::new(new.clone(), 3);
+        let files = engine
+            .diff(
+                &DiffMode::NoIndex {
+                    old: old.clone(),
+                    new: new.clone(),
+                },
+                &[],
+            )
+            .unwrap();
+        let summary: Vec<_> = files
+            .iter()
+            .map(|f| {
+                (
+                    f.status,
+                    f.old_path.as_ref().unwrap().to_string_lossy().to_string(),
+                    f.new_path.as_ref().unwrap().to_string_lossy().to_string(),
+                    f.similarity,
+                )
+            })
+            .collect();
+        let s = |p: &str| p.to_string();
+        assert_eq!(
+            summary,
+            vec![
+                (FileStatus::Deleted, s("gone.txt"), s("gone.txt"), None),
+                (FileStatus::Added, s("new.txt"), s("new.txt"), None),
+                (
+                    FileStatus::Renamed,
+                    s("edited.txt"),
+                    s("renamed.txt"),
+                    Some(90)
+                ),
+                (FileStatus::Modified, s("src/a.rs"), s("src/a.rs"), None),
+                (
+                    FileStatus::Renamed,
+                    s("moved.txt"),
+                    s("src/moved.txt"),
+                    Some(100)
+                ),
+            ]
+        );
+        assert!(files[4].is_pure_rename());
+
+        let single = engine
+            .diff(
+                &DiffMode::NoIndex {
+                    old: old.join("src/a.rs"),
+                    new: new.join("src/a.rs"),
+                },
+                &[],
+            )
+            .unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].hunks.len(), 1);
+    }
+
+    #[test]
+    fn test_libgit2_backend_matches_git_cmd() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo\nthree\nfour\n").unwrap();
+        std::fs::write(dir.path().join("gone.txt"), "bye\n").unwrap();
+        commit_all(&repo, "initial");
+
+        std::fs::write(dir.path().join("a.txt"), "one\n2\nthree\nfour\nfive").unwrap();
+        std::fs::remove_file(dir.path().join("gone.txt")).unwrap();
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        let native = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+        let cmd = engine
+            .clone()
+            .with_backend(DiffBackend::Git)
+            .diff(&DiffMode::Unstaged, &[])
+            .unwrap();
+
+        assert_eq!(native.len(), 2);
+        assert_eq!(native.len(), cmd.len());
+        for (a, b) in native.iter().zip(cmd.iter()) {
+            assert_eq!(a.new_path, b.new_path);
+            assert_eq!(a.status, b.status);
+            assert_eq!(a.hunks.len(), b.hunks.len());
+            for (ha, hb) in a.hunks.iter().zip(b.hunks.iter()) {
+                assert_eq!(
+                    (ha.old_start, ha.old_lines, ha.new_start, ha.new_lines),
+                    (hb.old_start, hb.old_lines, hb.new_start, hb.new_lines)
+                );
+                let la: Vec<_> = ha
+                    .lines
+                    .iter()
+                    .map(|l| (l.kind, l.old_line_no, l.new_line_no, l.content.as_str()))
+                    .collect();
+                let lb: Vec<_> = hb
+                    .lines
+                    .iter()
+                    .map(|l| (l.kind, l.old_line_no, l.new_line_no, l.content.as_str()))
+                    .collect();
+                assert_eq!(la, lb);
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_files_report_sizes() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join("blob.bin"), b"a\0b").unwrap();
+        commit_all(&repo, "initial");
+
+        std::fs::write(dir.path().join("blob.bin"), b"a\0bcdef").unwrap();
+        std::fs::write(dir.path().join("new.bin"), b"\0\0").unwrap();
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        for engine in [engine.clone(), engine.with_backend(DiffBackend::Git)] {
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            assert_eq!(files.len(), 2);
+            assert!(files.iter().all(|f| f.binary && f.hunks.is_empty()));
+            assert_eq!((files[0].old_size, files[0].new_size), (Some(3), Some(7)));
+            assert_eq!((files[1].old_size, files[1].new_size), (None, Some(2)));
+        }
+    }
+
+    #[test]
+    fn test_single_commit_mode() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo\n").unwrap();
+        commit_all(&repo, "initial");
+        std::fs::write(dir.path().join("a.txt"), "one\nthree\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "new\n").unwrap();
+        commit_all(&repo, "second\n\nLonger description.\n");
+        // Uncommitted edits must not show up in a commit view
+        std::fs::write(dir.path().join("a.txt"), "dirty\n").unwrap();
+
+        for backend in [DiffBackend::Libgit2, DiffBackend::Git] {
+            let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_backend(backend);
+            let root = engine
+                .diff(
+                    &DiffMode::Commit {
+                        rev: "HEAD~1".to_string(),
+                    },
+                    &[],
+                )
+                .unwrap();
+            assert_eq!(root.len(), 1);
+            assert_eq!(root[0].status, FileStatus::Added);
+
+            let files = engine
+                .diff(
+                    &DiffMode::Commit {
+                        rev: "HEAD".to_string(),
+                    },
+                    &[],
+                )
+                .unwrap();
+            let statuses: Vec<_> = files.iter().map(|f| f.status).collect();
+            assert_eq!(statuses, vec![FileStatus::Modified, FileStatus::Added]);
+            let added: Vec<_> = files[0].hunks[0]
+                .lines
+                .iter()
+                .filter(|l| l.kind == LineKind::Addition)
+                .map(|l| l.content.as_str())
+                .collect();
+            assert_eq!(added, vec!["three"]);
+        }
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        let info = engine.commit_info("HEAD").unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(info.sha, head.id().to_string());
+        assert_eq!(info.author, "test <test@example.com>");
+        assert_eq!(info.message, "second\n\nLonger description.");
+        assert_eq!(info.parents, vec![head.parent_id(0).unwrap().to_string()]);
+        assert!(engine.commit_info("HEAD~1").unwrap().parents.is_empty());
+
+        let commits = engine.list_commits("HEAD~1", "HEAD").unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].summary(), "second");
+    }
+
+    #[test]
+    fn test_stash_parts() {
+        let dir = tempdir().unwrap();
+        let mut repo = Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b\n").unwrap();
+        commit_all(&repo, "initial");
+        std::fs::write(dir.path().join("a.txt"), "a staged\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a.txt")).unwrap();
+        index.write().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a staged\na later\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b changed\n").unwrap();
+        std::fs::write(dir.path().join("new.txt"), "untracked\n").unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.stash_save(&sig, "wip", Some(git2::StashFlags::INCLUDE_UNTRACKED))
+            .unwrap();
+
+        for backend in [DiffBackend::Libgit2, DiffBackend::Git] {
+            let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_backend(backend);
+            let changes = |staged| {
+                engine
+                    .diff(&DiffMode::Stash { index: 0, staged }, &[])
+                    .unwrap()
+                    .into_iter()
+                    .map(|f| {
+                        let lines: Vec<String> = f
+                            .hunks
+                            .iter()
+                            .flat_map(|h| &h.lines)
+                            .filter(|l| l.kind != LineKind::Context)
+                            .map(|l| l.content.clone())
+                            .collect();
+                        (f.new_path.unwrap(), f.status, lines)
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let change = |path: &str, status, lines: &[&str]| {
+                let lines = lines.iter().map(|l| l.to_string()).collect();
+                (PathBuf::from(path), status, lines)
+            };
+            // The staged change to a.txt belongs to the index part only
+            assert_eq!(
+                changes(false),
+                vec![
+                    change("a.txt", FileStatus::Modified, &["a later"]),
+                    change("b.txt", FileStatus::Modified, &["b", "b changed"]),
+                    change("new.txt", FileStatus::Added, &["untracked"]),
+                ]
+            );
+            assert_eq!(
+                changes(true),
+                vec![change("a.txt", FileStatus::Modified, &["a", "a staged"])]
+            );
+        }
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        let stashes = engine.list_stashes().unwrap();
+        assert_eq!(stashes.len(), 1);
+        assert!(stashes[0].message.ends_with("wip"));
+    }
+
+    #[test]
+    fn test_find_and_resolve_conflicts() {
+        let text = "top\n<<<<<<< HEAD\nours\n||||||| base\nbase\n=======\ntheirs\n>>>>>>> topic\nmid\r\n<<<<<<< HEAD\n=======\nonly theirs\n>>>>>>> topic\nend";
+        let regions = find_conflicts(text);
+        assert_eq!(
+            regions,
+            vec![
+                ConflictRegion {
+                    start: 1,
+                    base: Some(3),
+                    separator: 5,
+                    end: 7,
+                },
+                ConflictRegion {
+                    start: 9,
+                    base: None,
+                    separator: 10,
+                    end: 12,
+                },
+            ]
+        );
+        assert_eq!(regions[0].side_of(4), Some(ConflictSide::Base));
+        assert_eq!(regions[0].side_of(8), None);
+
+        let ours = resolve_conflict(text.as_bytes(), &regions[0], ConflictChoice::Ours);
+        assert!(ours.starts_with(b"top\nours\nmid\r\n<<<<<<< HEAD\n"));
+        let both = resolve_conflict(text.as_bytes(), &regions[1], ConflictChoice::Both);
+        assert!(both.ends_with(b"mid\r\nonly theirs\nend"));
+        assert_eq!(find_conflicts_in(&both).len(), 1);
+
+        // Latin-1 sides come through byte for byte
+        let latin1 = b"<<<<<<< HEAD\ncaf\xe9\n=======\nna\xefve\n>>>>>>> topic\n";
+        let regions = find_conflicts_in(latin1);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(
+            resolve_conflict(latin1, &regions[0], ConflictChoice::Theirs),
+            b"na\xefve\n"
+        );
+
+        // Seven characters exactly, then a label or nothing
+        assert!(find_conflicts("<<<<<<<< no\n=======\n>>>>>>>\n").is_empty());
+    }
+
+    #[test]
+    fn test_unmerged_paths_show_conflicts() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        assert!(git(&["init", "-q", "-b", "main"]));
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "base"]));
+        assert!(git(&["checkout", "-qb", "topic"]));
+        std::fs::write(dir.path().join("a.txt"), "one\ntheirs\n").unwrap();
+        assert!(git(&["commit", "-qam", "topic"]));
+        assert!(git(&["checkout", "-q", "main"]));
+        std::fs::write(dir.path().join("a.txt"), "one\nours\n").unwrap();
+        assert!(git(&["commit", "-qam", "main"]));
+        assert!(!git(&["merge", "-q", "topic"]));
+        std::fs::write(dir.path().join("b.txt"), "b changed\n").unwrap();
+
+        for backend in [DiffBackend::Libgit2, DiffBackend::Git] {
+            let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_backend(backend);
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            let statuses: Vec<_> = files
+                .iter()
+                .map(|f| (f.new_path.clone().unwrap(), f.status))
+                .collect();
+            assert_eq!(
+                statuses,
+                vec![
+                    (PathBuf::from("a.txt"), FileStatus::Conflicted),
+                    (PathBuf::from("b.txt"), FileStatus::Modified),
+                ]
+            );
+            // The worktree has no base markers; the base comes from stage 1
+            let sides: Vec<_> = files[0].hunks[0]
+                .lines
+                .iter()
+                .map(|l| (l.new_line_no, l.conflict, l.content.as_str()))
+                .collect();
+            assert_eq!(sides[0], (Some(1), None, "one"));
+            assert_eq!(sides[2], (Some(3), Some(ConflictSide::Ours), "ours"));
+            assert_eq!(
+                sides[3..5],
+                [
+                    (None, Some(ConflictSide::Marker), "||||||| base"),
+                    (None, Some(ConflictSide::Base), "two"),
+                ]
+            );
+            assert_eq!(sides[5], (Some(4), Some(ConflictSide::Marker), "======="));
+            assert_eq!(sides[6], (Some(5), Some(ConflictSide::Theirs), "theirs"));
+            assert_eq!(files[0].hunks[0].new_lines, 6);
+        }
+    }
+
+    #[test]
+    fn test_parse_combined_diff() {
+        let engine = DiffEngine::new(PathBuf::from("."), 3);
+        let output = concat!(
+            "diff --cc f\n",
+            "index 4f213f7,7be73ce..a04d52f\n",
+            "--- a/f\n",
+            "+++ b/f\n",
+            "@@@ -1,3 -1,3 +1,4 @@@ fn main\n",
+            " -a\n",
+            " +A\n",
+            "- b\n",
+            "+ B\n",
+            "  c\n",
+            "++evil\n",
+            "diff --git a/g b/g\n",
+            "--- a/g\n",
+            "+++ b/g\n",
+            "@@ -1 +1 @@\n",
+            "-old\n",
+            "+new\n",
+        );
+        let files = engine.parse_full_diff(output).unwrap();
+        assert_eq!(files.len(), 2);
+        let hunk = &files[0].hunks[0];
+        assert_eq!((hunk.old_start, hunk.new_start, hunk.new_lines), (1, 1, 4));
+        assert_eq!(hunk.header.as_deref(), Some("fn main"));
+        let lines: Vec<_> = hunk
+            .lines
+            .iter()
+            .map(|l| (l.kind, l.old_line_no, l.new_line_no, l.content.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (LineKind::Deletion, Some(1), None, "a"),
+                (LineKind::Addition, None, Some(1), "A"),
+                (LineKind::Deletion, Some(2), None, "b"),
+                (LineKind::Addition, None, Some(2), "B"),
+                (LineKind::Context, Some(3), Some(3), "c"),
+                (LineKind::Addition, None, Some(4), "evil"),
+            ]
+        );
+        assert_eq!(
+            hunk.lines[2].combined,
+            vec![LineKind::Deletion, LineKind::Context]
+        );
+        assert_eq!(
+            hunk.lines[5].combined,
+            vec![LineKind::Addition, LineKind::Addition]
+        );
+        assert!(files[1].hunks[0]
+            .lines
+            .iter()
+            .all(|l| l.combined.is_empty()));
+    }
+
+    #[test]
+    fn test_merge_commit_is_combined() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        assert!(git(&["init", "-q", "-b", "main"]));
+        std::fs::write(dir.path().join("f"), "a\nb\nc\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "base"]));
+        assert!(git(&["checkout", "-qb", "topic"]));
+        std::fs::write(dir.path().join("f"), "a\nB\nc\n").unwrap();
+        assert!(git(&["commit", "-qam", "topic"]));
+        assert!(git(&["checkout", "-q", "main"]));
+        std::fs::write(dir.path().join("f"), "A\nb\nc\n").unwrap();
+        assert!(git(&["commit", "-qam", "main"]));
+        assert!(!git(&["merge", "-q", "topic"]));
+        // An evil merge: a line neither parent has
+        std::fs::write(dir.path().join("f"), "A\nB\nc\nevil\n").unwrap();
+        assert!(git(&["commit", "-qam", "merge"]));
+
+        for backend in [DiffBackend::Libgit2, DiffBackend::Git] {
+            let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_backend(backend);
+            let mode = DiffMode::Commit {
+                rev: "HEAD".to_string(),
+            };
+            let files = engine.diff(&mode, &[]).unwrap();
+            assert_eq!(files.len(), 1);
+            let evil: Vec<_> = files[0].hunks[0]
+                .lines
+                .iter()
+                .filter(|l| !l.combined.contains(&LineKind::Context))
+                .map(|l| l.content.as_str())
+                .collect();
+            assert_eq!(evil, vec!["evil"]);
+        }
+    }
+
+    #[test]
+    fn test_detect_moves_across_files() {
+        let engine = DiffEngine::new(PathBuf::from("."), 3);
+        let output = concat!(
+            "diff --git a/a.rs b/a.rs\n",
+            "--- a/a.rs\n",
+            "+++ b/a.rs\n",
+            "@@ -1,4 +1,1 @@\n",
+            " fn keep() {}\n",
+            "-fn moved_helper(value: u32) -> u32 {\n",
+            "-    value * 2\n",
+            "-}\n",
+            "diff --git a/b.rs b/b.rs\n",
+            "--- a/b.rs\n",
+            "+++ b/b.rs\n",
+            "@@ -1,2 +1,5 @@\n",
+            " fn other() {}\n",
+            "+fn moved_helper(value: u32) -> u32 {\n",
+            "+    value * 2\n",
+            "+}\n",
+            "-}\n",
+            "+}\n",
+        );
+        let mut files = engine.parse_full_diff(output).unwrap();
+        assert_eq!(detect_moves(&mut files), 3);
+        let deleted = &files[0].hunks[0].lines[1];
+        assert_eq!(
+            deleted.moved,
+            Some(MovedLine {
+                file: 1,
+                hunk: 0,
+                line: 1,
+            })
+        );
+        assert_eq!(
+            files[1].hunks[0].lines[1].moved,
+            Some(MovedLine {
+                file: 0,
+                hunk: 0,
+                line: 1,
+            })
+        );
+        // A lone brace is too short to count as a move
+        assert_eq!(files[1].hunks[0].lines[4].moved, None);
+        assert_eq!(files[1].hunks[0].lines[5].moved, None);
+        // A blank line is not looked up but joins the block after it; a
+        // line added over and over is not looked up at all
+        let repeated = "+    log_value(value_common);\n".repeat(MOVED_MAX_CANDIDATES + 1);
+        let output = format!(
+            "--- a/c.rs\n+++ b/c.rs\n@@ -1,4 +0,0 @@\n-\n-    let total = compute(values);\n-    total\n-    log_value(value_common);\n--- a/d.rs\n+++ b/d.rs\n@@ -1 +1,{} @@\n+\n+    let total = compute(values);\n+    total\n }}\n{}",
+            MOVED_MAX_CANDIDATES + 5,
+            repeated
+        );
+        let mut files = engine.parse_full_diff(&output).unwrap();
+        assert_eq!(detect_moves(&mut files), 3);
+        let moved: Vec<bool> = files[0].hunks[0]
+            .lines
+            .iter()
+            .map(|l| l.moved.is_some())
+            .collect();
+        assert_eq!(moved, vec![true, true, true, false]);
+    }
+
+    #[test]
+    fn test_line_endings_round_trip() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join("crlf.txt"), "one\r\ntwo\r\nthree\r\n").unwrap();
+        std::fs::write(dir.path().join("tail.txt"), "a\nb").unwrap();
+        commit_all(&repo, "initial");
+        std::fs::write(dir.path().join("crlf.txt"), "one\r\n2\r\nthree\n").unwrap();
+        std::fs::write(dir.path().join("tail.txt"), "a\nb\nc").unwrap();
+
+        // Hunk bodies as a patch would carry them, headers left out
+        let body = |files: &[DiffFile]| -> String {
+            let mut out = String::new();
+            for line in files.iter().flat_map(|f| &f.hunks).flat_map(|h| &h.lines) {
+                out.push(match line.kind {
+                    LineKind::Context => ' ',
+                    LineKind::Addition => '+',
+                    LineKind::Deletion => '-',
+                });
+                out.push_str(&line.content);
+                out.push_str(line.ending.patch_suffix());
+            }
+            out
+        };
+        let git_body = |output: &[u8]| -> String {
+            String::from_utf8_lossy(output)
+                .split_inclusive('\n')
+                .filter(|l| {
+                    let header = [
+                        "diff ", "index ", "--- ", "+++ ", "@@", "new file", "deleted",
+                    ];
+                    !header.iter().any(|h| l.starts_with(h))
+                })
+                .collect()
+        };
+
+        let output = Command::new("git")
+            .args(["diff", "--no-color", "--no-ext-diff"])
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        let expected = git_body(&output.stdout);
+        assert!(expected.contains("-two\r\n-three\r\n+2\r\n+three\n"));
+        assert!(expected.contains("-b\n\\ No newline at end of file\n"));
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        for engine in [engine.clone(), engine.with_backend(DiffBackend::Git)] {
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            assert_eq!(body(&files), expected);
+        }
+
+        // Same for the in-process diff of two files
+        let old_file = dir.path().join("old.txt");
+        std::fs::write(&old_file, "a\nb").unwrap();
+        let new_file = dir.path().join("tail.txt");
+        let mode = DiffMode::ExternalDiff {
+            files: vec![ExternalFile {
+                path: "tail.txt".to_string(),
+                new_path: None,
+                old_file: old_file.to_string_lossy().to_string(),
+                new_file: new_file.to_string_lossy().to_string(),
+                unmerged: false,
+            }],
+        };
+        let files = DiffEngine::new(dir.path().to_path_buf(), 3)
+            .diff(&mode, &[])
+            .unwrap();
+        let output = Command::new("git")
+            .args(["diff", "--no-index", "--no-color"])
+            .arg(&old_file)
+            .arg(&new_file)
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        assert_eq!(body(&files), git_body(&output.stdout));
+        let last = files[0].hunks[0].lines.last().unwrap();
+        assert_eq!(
+            (last.content.as_str(), last.ending),
+            ("c", LineEnding::Missing)
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted_and_spaced_paths() {
+        let cafe = "diff --git \"a/caf\\303\\251.txt\" \"b/caf\\303\\251.txt\"";
+        let expected = Some(PathBuf::from("café.txt"));
+        assert_eq!(
+            parse_diff_git_line(cafe, false),
+            (expected.clone(), expected)
+        );
+        let spaced = Some(PathBuf::from("x b/y.txt"));
+        assert_eq!(
+            parse_diff_git_line("diff --git a/x b/y.txt b/x b/y.txt", false),
+            (spaced.clone(), spaced)
+        );
+
+        let engine = DiffEngine::new(PathBuf::from("."), 3);
+        let patch = concat!(
+            "diff --git a/old b/n.txt b/new b/n.txt\n",
+            "similarity index 100%\n",
+            "rename from old b/n.txt\n",
+            "rename to new b/n.txt\n",
+            "diff --git \"a/tab\\there\" \"b/tab\\there\"\n",
+            "index 1111111..2222222 100644\n",
+            "--- \"a/tab\\there\"\n",
+            "+++ \"b/tab\\there\"\n",
+            "@@ -1 +1 @@\n",
+            "-one\n",
+            "+two\n",
+        );
+        let files = engine.parse_full_diff(patch).unwrap();
+        assert_eq!(files[0].old_path, Some(PathBuf::from("old b/n.txt")));
+        assert_eq!(files[0].new_path, Some(PathBuf::from("new b/n.txt")));
+        assert_eq!(files[1].new_path, Some(PathBuf::from("tab\there")));
+
+        for name in [
+            "plain.txt",
+            "quo\"te\\d",
+            "new\nline",
+            "caf\u{e9}",
+            "bell\u{7}",
+        ] {
+            let quoted = quote_path("a/", Path::new(name));
+            let unquoted = unquote_c_style(&quoted).map_or(quoted.clone().into_bytes(), |u| u.0);
+            assert_eq!(unquoted, format!("a/{}", name).into_bytes(), "{}", quoted);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_nasty_filenames_stage_and_discard() {
+        use std::io::Write;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let names: Vec<PathBuf> = [
+            &b"with space.txt"[..],
+            b"x b/y.txt",
+            b"tab\there.txt",
+            b"quo\"te.txt",
+            b"back\\slash.txt",
+            "caf\u{e9}.txt".as_bytes(),
+            b"latin1-\xe9.txt",
+            b"new\nline.txt",
+            b"glob[1]*.txt",
+        ]
+        .iter()
+        .map(|name| PathBuf::from(std::ffi::OsStr::from_bytes(name)))
+        .collect();
+        std::fs::create_dir(dir.path().join("x b")).unwrap();
+        for name in &names {
+            std::fs::write(dir.path().join(name), "one\n").unwrap();
+        }
+        commit_all(&repo, "initial");
+        for name in &names {
+            std::fs::write(dir.path().join(name), "two\n").unwrap();
+        }
+        let untracked = PathBuf::from(std::ffi::OsStr::from_bytes(b"new \xff\n.txt"));
+        std::fs::write(dir.path().join(&untracked), "new\n").unwrap();
+
+        let apply = |patch: &[u8], args: &[&str]| {
+            let mut child = Command::new("git")
+                .arg("apply")
+                .args(args)
+                .current_dir(dir.path())
+                .stdin(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(patch).unwrap();
+            let text = String::from_utf8_lossy(patch);
+            assert!(child.wait().unwrap().success(), "{}", text);
+        };
+        let paths = |files: &[DiffFile]| -> BTreeSet<PathBuf> {
+            files
+                .iter()
+                .filter(|f| f.status == FileStatus::Modified)
+                .filter_map(|f| f.new_path.clone())
+                .collect()
+        };
+        let expected: BTreeSet<PathBuf> = names.iter().cloned().collect();
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        for engine in [engine.clone(), engine.with_backend(DiffBackend::Git)] {
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            assert_eq!(paths(&files), expected);
+            assert!(files
+                .iter()
+                .any(|f| f.new_path.as_ref() == Some(&untracked)));
+            for file in files.iter().filter(|f| f.status == FileStatus::Modified) {
+                assert_eq!(file.old_path, file.new_path);
+                apply(&build_hunks_patch(file, &file.hunks), &["--cached"]);
+            }
+            let staged = engine.diff(&DiffMode::Staged, &[]).unwrap();
+            assert_eq!(paths(&staged), expected);
+            for file in &staged {
+                apply(&build_hunks_patch(file, &file.hunks), &["--cached", "-R"]);
+            }
+        }
+
+        // Discarding restores every file to its committed content
+        let files = DiffEngine::new(dir.path().to_path_buf(), 3)
+            .diff(&DiffMode::Unstaged, &[])
+            .unwrap();
+        for file in files.iter().filter(|f| f.status == FileStatus::Modified) {
+            apply(&build_hunks_patch(file, &file.hunks), &["-R"]);
+        }
+        for name in &names {
+            assert_eq!(std::fs::read(dir.path().join(name)).unwrap(), b"one\n");
+        }
+    }
+
+    #[test]
+    fn test_encodings_decode_for_display_and_patch_in_bytes() {
+        use std::io::Write;
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let write =
+            |name: &str, bytes: &[u8]| std::fs::write(dir.path().join(name), bytes).unwrap();
+        let utf16 =
+            |text: &str| -> Vec<u8> { text.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+        let apply = |patch: &[u8], args: &[&str]| {
+            let mut child = Command::new("git")
+                .arg("apply")
+                .args(args)
+                .current_dir(dir.path())
+                .stdin(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(patch).unwrap();
+            assert!(child.wait().unwrap().success());
+        };
+        assert!(git(&["init", "-q"]));
+        write(
+            ".gitattributes",
+            b"*16.txt working-tree-encoding=UTF-16LE\n",
+        );
+        write("latin1.txt", b"caf\xe9\nna\xefve\n");
+        write("utf16.txt", &utf16("hello\nworld\n"));
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        write("latin1.txt", b"caf\xe9 cr\xe8me\nna\xefve\n");
+        write("utf16.txt", &utf16("hello\nthere\n"));
+        write("new16.txt", &utf16("hi\n"));
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        for engine in [engine.clone(), engine.with_backend(DiffBackend::Git)] {
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            let file = |name: &str| {
+                files
+                    .iter()
+                    .find(|f| f.new_path == Some(PathBuf::from(name)))
+                    .unwrap()
+            };
+            let added = |f: &DiffFile| -> Vec<String> {
+                f.hunks
+                    .iter()
+                    .flat_map(|h| &h.lines)
+                    .filter(|l| l.kind == LineKind::Addition)
+                    .map(|l| l.content.clone())
+                    .collect()
+            };
+            let latin1 = file("latin1.txt");
+            assert_eq!(latin1.encoding, Some(WINDOWS_1252));
+            assert_eq!(added(latin1), vec!["café crème"]);
+            let wide = file("utf16.txt");
+            assert!(!wide.binary);
+            assert_eq!(wide.encoding.map(|e| e.name()), Some("UTF-16LE"));
+            assert_eq!(added(wide), vec!["there"]);
+            assert_eq!(added(file("new16.txt")), vec!["hi"]);
+
+            // Staged in the bytes each file keeps in the index
+            for name in ["latin1.txt", "utf16.txt"] {
+                apply(
+                    &build_hunks_patch(file(name), &file(name).hunks),
+                    &["--cached"],
+                );
+            }
+            let show = |spec: &str| {
+                Command::new("git")
+                    .args(["show", spec])
+                    .current_dir(dir.path())
+                    .output()
+                    .unwrap()
+                    .stdout
+            };
+            assert_eq!(show(":latin1.txt"), b"caf\xe9 cr\xe8me\nna\xefve\n");
+            assert_eq!(show(":utf16.txt"), b"hello\nthere\n");
+            assert!(git(&["reset", "-q"]));
+        }
+
+        // Discarding writes the working tree back in its own encoding
+        let files = DiffEngine::new(dir.path().to_path_buf(), 3)
+            .diff(&DiffMode::Unstaged, &[])
+            .unwrap();
+        for file in files.iter().filter(|f| f.status == FileStatus::Modified) {
+            apply(&build_hunks_patch(file, &file.hunks), &["-R"]);
+        }
+        let read = |name: &str| std::fs::read(dir.path().join(name)).unwrap();
+        assert_eq!(read("latin1.txt"), b"caf\xe9\nna\xefve\n");
+        assert_eq!(read("utf16.txt"), utf16("hello\nworld\n"));
+    }
+
+    #[test]
+    fn test_map_line() {
+        let old = "a\nb\nc\nd\n";
+        let new = "new\na\nB\nc\nd\n";
+        assert_eq!(map_line(old, new, 1), Some(2));
+        assert_eq!(map_line(old, new, 2), None);
+        assert_eq!(map_line(old, new, 4), Some(5));
+        assert_eq!(map_line(old, new, 0), None);
+    }
+
+    #[test]
+    fn test_range_pairs() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let write = |name: &str, text: &str| std::fs::write(dir.path().join(name), text).unwrap();
+        assert!(git(&["init", "-q", "-b", "main"]));
+        write("base.txt", "base\n");
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "base"]));
+        assert!(git(&["tag", "v1-base"]));
+        write("a.txt", "a\n");
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "add a"]));
+        write("b.txt", "b\nmore\n");
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "add b"]));
+        assert!(git(&["tag", "v1"]));
+
+        // v2: rebased onto a new base, b reworked, a new commit on top
+        assert!(git(&["checkout", "-q", "v1-base"]));
+        write("base.txt", "base moved on\n");
+        assert!(git(&["commit", "-qam", "upstream"]));
+        assert!(git(&["tag", "v2-base"]));
+        assert!(git(&["cherry-pick", "v1~1"]));
+        write("b.txt", "b\nchanged\n");
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "add b"]));
+        write("c.txt", "c\n");
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "add c"]));
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        let pairs = engine
+            .range_pairs(("v1-base", "v1"), ("v2-base", "HEAD"), &[])
+            .unwrap();
+        let labels: Vec<_> = pairs
+            .iter()
+            .map(|p| p.label().split(' ').nth(2).unwrap().to_string())
+            .collect();
+        assert_eq!(labels, vec!["=", "!", ">"]);
+        assert_eq!(pairs[1].old.as_ref().unwrap().0, 2);
+
+        let files = engine
+            .diff(
+                &DiffMode::RangeDiff {
+                    old_from: "v1-base".to_string(),
+                    old_to: "v1".to_string(),
+                    new_from: "v2-base".to_string(),
+                    new_to: "HEAD".to_string(),
+                },
+                &[],
+            )
+            .unwrap();
+        let statuses: Vec<_> = files.iter().map(|f| f.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                FileStatus::Modified,
+                FileStatus::Modified,
+                FileStatus::Added
+            ]
+        );
+        assert!(files[0].hunks.is_empty());
+        let changed: Vec<_> = files[1].hunks[0]
+            .lines
+            .iter()
+            .filter(|l| l.kind != LineKind::Context)
+            .map(|l| (l.kind, l.content.as_str()))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                (LineKind::Deletion, "+more"),
+                (LineKind::Addition, "+changed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_git_time() {
+        let time = git2::Time::new(1_700_000_000, 60);
+        assert_eq!(format_git_time(time), "Tue Nov 14 23:13:20 2023 +0100");
+        let time = git2::Time::new(951_802_200, -330);
+        assert_eq!(format_git_time(time), "Tue Feb 29 00:00:00 2000 -0530");
+    }
+
+    #[test]
+    fn test_oversized_files_are_not_diffed() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let write = |name: &str, text: &str| std::fs::write(dir.path().join(name), text).unwrap();
+        assert!(git(&["init", "-q"]));
+        write("big.txt", &"old\n".repeat(100));
+        write("small.txt", "old\n");
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        write("big.txt", &"new\n".repeat(100));
+        write("small.txt", "new\n");
+        assert!(git(&["commit", "-qam", "change"]));
+        write("big.txt", &"newer\n".repeat(100));
+
+        let limited = |bytes| {
+            DiffEngine::new(dir.path().to_path_buf(), 3).with_limits(DiffLimits {
+                file_bytes: bytes,
+                ..DiffLimits::default()
+            })
+        };
+        let commits = DiffMode::Commits {
+            from: "HEAD~".to_string(),
+            to: "HEAD".to_string(),
+        };
+        // Sizes come from the index and the working tree...
+        let files = limited(900).diff(&DiffMode::Unstaged, &[]).unwrap();
+        assert_eq!(files[0].deferred, Some(Deferred::Bytes(1000)));
+        assert!(files[0].hunks.is_empty());
+        // ...or from the headers of blobs in trees
+        let files = limited(900).diff(&commits, &[]).unwrap();
+        assert_eq!(files[0].deferred, None);
+        assert_eq!(files[0].changed_lines(), 200);
+        let files = limited(700).diff(&commits, &[]).unwrap();
+        assert_eq!(files[0].deferred, Some(Deferred::Bytes(800)));
+        assert_eq!(files[1].changed_lines(), 2);
+
+        let mut engine = limited(900);
+        let big = &engine.diff(&DiffMode::Unstaged, &[]).unwrap()[0];
+        let loaded = engine
+            .load_in_full(&DiffMode::Unstaged, &[], big)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.changed_lines(), 200);
+    }
+
+    #[test]
+    fn test_limits_defer_large_files_until_loaded() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let write = |name: &str, text: String| std::fs::write(dir.path().join(name), text).unwrap();
+        let numbered = |n: usize, tag: &str| -> String {
+            (0..n).map(|i| format!("{} {}\n", tag, i)).collect()
+        };
+        assert!(git(&["init", "-q"]));
+        write("big.txt", numbered(40, "old"));
+        write("small.txt", numbered(3, "old"));
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        write("big.txt", numbered(40, "new"));
+        write("small.txt", numbered(3, "new"));
+        write("huge.log", numbered(100, "log"));
+
+        let limits = DiffLimits {
+            file_lines: 20,
+            total_lines: 0,
+            untracked_bytes: 200,
+            file_bytes: 0,
+        };
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_limits(limits);
+        for mut engine in [engine.clone(), engine.with_backend(DiffBackend::Git)] {
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            let file = |name: &str| {
+                files
+                    .iter()
+                    .find(|f| f.new_path == Some(PathBuf::from(name)))
+                    .unwrap()
+                    .clone()
+            };
+            let big = file("big.txt");
+            assert_eq!(big.deferred, Some(Deferred::Lines(80)));
+            assert!(big.hunks.is_empty());
+            assert!(big.whole_file_only());
+            assert_eq!(file("small.txt").deferred, None);
+            assert_eq!(file("small.txt").changed_lines(), 6);
+            let huge = file("huge.log");
+            assert!(matches!(huge.deferred, Some(Deferred::Bytes(size)) if size > 200));
+            assert!(huge.hunks.is_empty());
+
+            let loaded = engine
+                .load_in_full(&DiffMode::Unstaged, &[], &big)
+                .unwrap()
+                .unwrap();
+            assert_eq!(loaded.deferred, None);
+            assert_eq!(loaded.changed_lines(), 80);
+            let loaded = engine
+                .load_in_full(&DiffMode::Unstaged, &[], &huge)
+                .unwrap()
+                .unwrap();
+            assert_eq!(loaded.changed_lines(), 100);
+
+            // Loaded files stay whole when the diff is taken again
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            assert!(files.iter().all(|f| f.deferred.is_none()));
+        }
+
+        // A patch is cut off while it is read, and the file after it kept
+        let text = format!(
+            "--- a/big.txt\n+++ b/big.txt\n@@ -1,30 +0,0 @@\n{}--- a/small.txt\n+++ b/small.txt\n@@ -1 +1 @@\n-one\n+two\n",
+            numbered(30, "-old")
+        );
+        let mode = DiffMode::Patch {
+            source: "test".to_string(),
+            text: Arc::new(text.into_bytes()),
+        };
+        let files = DiffEngine::new(dir.path().to_path_buf(), 3)
+            .with_limits(limits)
+            .diff(&mode, &[])
+            .unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].deferred, Some(Deferred::Lines(30)));
+        assert!(files[0].hunks.is_empty());
+        assert_eq!(files[1].changed_lines(), 2);
+
+        // The total budget stubs whatever comes after it is spent
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_limits(DiffLimits {
+            file_lines: 0,
+            total_lines: 50,
+            untracked_bytes: 0,
+            file_bytes: 0,
+        });
+        let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+        let deferred: Vec<_> = files.iter().filter(|f| f.deferred.is_some()).collect();
+        let kept: usize = files.iter().map(DiffFile::changed_lines).sum();
+        assert!(!deferred.is_empty());
+        assert!(kept <= 50);
+    }
+
+    #[test]
+    fn test_untracked_policies_and_exclude() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let write = |name: &str, text: &str| std::fs::write(dir.path().join(name), text).unwrap();
+        assert!(git(&["init", "-q"]));
+        write("tracked.txt", "one\n");
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        write("tracked.txt", "two\n");
+        write("a*b.txt", "glob\n");
+        write("axb.txt", "plain\n");
+        write("trailing ", "space\n");
+
+        let untracked = |engine: &DiffEngine| -> Vec<DiffFile> {
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            assert!(files
+                .iter()
+                .any(|f| !f.untracked && f.new_path == Some(PathBuf::from("tracked.txt"))));
+            files.into_iter().filter(|f| f.untracked).collect()
+        };
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        let shown = untracked(&engine);
+        assert_eq!(shown.len(), 3);
+        assert!(shown.iter().all(|f| f.changed_lines() == 1));
+
+        for policy in [UntrackedFiles::Names, UntrackedFiles::Group] {
+            let names = untracked(&engine.clone().with_untracked(policy));
+            assert_eq!(names.len(), 3);
+            assert!(names
+                .iter()
+                .all(|f| f.hunks.is_empty() && matches!(f.deferred, Some(Deferred::Bytes(_)))));
+        }
+        assert!(untracked(&engine.clone().with_untracked(UntrackedFiles::Hide)).is_empty());
+
+        // Excluded names are matched literally, never as patterns
+        engine.exclude_untracked(Path::new("a*b.txt")).unwrap();
+        engine.exclude_untracked(Path::new("trailing ")).unwrap();
+        let left: Vec<_> = untracked(&engine)
+            .into_iter()
+            .filter_map(|f| f.new_path)
+            .collect();
+        assert_eq!(left, vec![PathBuf::from("axb.txt")]);
+        assert!(exclude_pattern(Path::new("new\nline")).is_none());
+    }
+
+    #[test]
+    fn test_rename_and_submodule_options() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let body: String = (0..20).map(|i| format!("line {}\n", i)).collect();
+        assert!(git(&["init", "-q"]));
+        std::fs::write(dir.path().join("old.txt"), &body).unwrap();
+        let sub = dir.path().join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        assert!(git_ok(&sub, &["init", "-q"]));
+        std::fs::write(sub.join("tracked.txt"), "one\n").unwrap();
+        assert!(git_ok(&sub, &["add", "."]));
+        assert!(git_ok(&sub, &["commit", "-qm", "sub"]));
+        std::fs::write(
+            dir.path().join(".gitmodules"),
+            "[submodule \"sub\"]\n\tpath = sub\n\turl = ./sub\n",
+        )
+        .unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        assert!(git(&["mv", "old.txt", "new.txt"]));
+
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        for engine in [engine.clone(), engine.with_backend(DiffBackend::Git)] {
+            let statuses = |renames: RenameDetection| -> Vec<FileStatus> {
+                let engine = engine.clone().with_renames(renames);
+                let files = engine.diff(&DiffMode::Staged, &[]).unwrap();
+                files.iter().map(|f| f.status).collect()
+            };
+            assert_eq!(
+                statuses(RenameDetection::Renames),
+                vec![FileStatus::Renamed]
+            );
+            let mut off = statuses(RenameDetection::Off);
+            off.sort_by_key(|s| format!("{:?}", s));
+            assert_eq!(off, vec![FileStatus::Added, FileStatus::Deleted]);
+
+            let submodules = |ignore: IgnoreSubmodules| -> usize {
+                let engine = engine.clone().with_ignore_submodules(ignore);
+                let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+                files
+                    .iter()
+                    .filter(|f| f.status == FileStatus::Submodule)
+                    .count()
+            };
+            std::fs::write(sub.join("scratch.txt"), "x\n").unwrap();
+            assert_eq!(submodules(IgnoreSubmodules::None), 1);
+            assert_eq!(submodules(IgnoreSubmodules::Untracked), 0);
+            std::fs::write(sub.join("tracked.txt"), "two\n").unwrap();
+            assert_eq!(submodules(IgnoreSubmodules::Untracked), 1);
+            assert_eq!(submodules(IgnoreSubmodules::Dirty), 0);
+            assert_eq!(submodules(IgnoreSubmodules::All), 0);
+            std::fs::write(sub.join("tracked.txt"), "one\n").unwrap();
+            std::fs::remove_file(sub.join("scratch.txt")).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_textconv_and_binary_attributes_in_every_mode() {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let write = |name: &str, text: &str| std::fs::write(dir.path().join(name), text).unwrap();
+        assert!(git(&["init", "-q"]));
+        assert!(git(&["config", "diff.upper.textconv", "tr a-z A-Z <"]));
+        write(".gitattributes", "*.up diff=upper\n*.nd -diff\n");
+        write("doc.up", "first\nsecond\n");
+        write("data.nd", "one\n");
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        write("doc.up", "first\nchanged\n");
+        write("data.nd", "two\n");
+        write("new.up", "fresh\n");
+
+        let contents = |file: &DiffFile| -> Vec<String> {
+            file.hunks
+                .iter()
+                .flat_map(|h| &h.lines)
+                .filter(|l| l.kind != LineKind::Context)
+                .map(|l| l.content.clone())
+                .collect()
+        };
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        for engine in [
+            engine.clone(),
+            engine.clone().with_backend(DiffBackend::Git),
+        ] {
+            let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+            let file = |name: &str| {
+                files
+                    .iter()
+                    .find(|f| f.new_path == Some(PathBuf::from(name)))
+                    .unwrap()
+            };
+            assert!(file("doc.up").textconv && file("doc.up").whole_file_only());
+            assert_eq!(contents(file("doc.up")), vec!["SECOND", "CHANGED"]);
+            assert!(file("new.up").untracked && file("new.up").textconv);
+            assert_eq!(contents(file("new.up")), vec!["FRESH"]);
+            assert!(file("data.nd").binary && file("data.nd").hunks.is_empty());
+        }
+
+        // git hands an external diff the raw blobs
+        let old_file = dir.path().join("old.tmp");
+        std::fs::write(&old_file, "first\nsecond\n").unwrap();
+        let mode = DiffMode::ExternalDiff {
+            files: vec![ExternalFile {
+                path: "doc.up".to_string(),
+                new_path: None,
+                old_file: old_file.to_string_lossy().to_string(),
+                new_file: dir.path().join("doc.up").to_string_lossy().to_string(),
+                unmerged: false,
+            }],
+        };
+        let files = engine.diff(&mode, &[]).unwrap();
+        assert!(files[0].textconv);
+        assert_eq!(contents(&files[0]), vec!["SECOND", "CHANGED"]);
+
+        assert_eq!(
+            read_worktree_text(dir.path(), Path::new("doc.up")).as_deref(),
+            Some("FIRST\nCHANGED\n")
+        );
+
+        // A failing driver shows the file as it is instead of failing the diff
+        assert!(git(&["config", "diff.upper.textconv", "false"]));
+        let files = engine.diff(&DiffMode::Unstaged, &[]).unwrap();
+        let broken: Vec<_> = files
+            .iter()
+            .filter(|f| {
+                f.new_path
+                    .as_ref()
+                    .is_some_and(|p| p.extension() == Some("up".as_ref()))
+            })
+            .map(|f| (f.textconv, contents(f)))
+            .collect();
+        assert_eq!(
+            broken,
+            vec![
+                (false, vec!["second".to_string(), "changed".to_string()]),
+                (false, vec!["fresh".to_string()]),
+            ]
+        );
+        let files = engine.diff(&mode, &[]).unwrap();
+        assert!(!files[0].textconv);
+        assert_eq!(
+            read_worktree_text(dir.path(), Path::new("doc.up")).as_deref(),
+            Some("first\nchanged\n")
+        );
+
+        // The content is handed over in a file only we can read
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            run_textconv(dir.path(), "stat -c %a", b"secret").unwrap(),
+            b"600\n"
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! External diff sessions
+//!
+//! git runs `diff.external` once per file and waits for each run to exit, so
+//! no single run sees the whole diff. Each run leaves its file pair in a
+//! session directory inside `$GIT_DIR`, copying the sides because git deletes
+//! its temporary blobs as soon as the run returns. The run git numbers last
+//! picks up every pair and shows them together. Each `git diff` process has
+//! a session of its own, so diffs running side by side stay apart.
+
+use anyhow::{Context, Result};
+use git2::Repository;
+use std::path::{Path, PathBuf};
+
+use crate::diff::ExternalFile;
+
+/// Where the pairs of an external diff in progress are kept, followed by
+/// the id of the git process running it
+const SESSION_DIR: &str = "differ-external";
+
+/// This run's place among git's external diff runs, as
+/// `GIT_DIFF_PATH_COUNTER` and `GIT_DIFF_PATH_TOTAL` (both 1-based)
+pub fn run_position() -> Option<(usize, usize)> {
+    let var = |name: &str| std::env::var(name).ok()?.parse::<usize>().ok();
+    let counter = var("GIT_DIFF_PATH_COUNTER")?;
+    let total = var("GIT_DIFF_PATH_TOTAL")?;
+    (counter >= 1 && counter <= total).then_some((counter, total))
+}
+
+/// The file pairs gathered so far for one `git diff` run
+pub struct Session {
+    dir: PathBuf,
+}
+
+impl Session {
+    /// The session of the `git diff` running this one, in the repository
+    /// at `repo_path`. The first run of a diff starts it afresh, dropping
+    /// whatever an interrupted one with the same process id left.
+    pub fn open(repo_path: &Path, first: bool) -> Result<Self> {
+        Self::open_for(repo_path, git_process_id(), first)
+    }
+
+    fn open_for(repo_path: &Path, git_pid: u32, first: bool) -> Result<Self> {
+        let repo = Repository::open(repo_path).context("Failed to open repository")?;
+        let dir = repo.path().join(format!("{}-{}", SESSION_DIR, git_pid));
+        if first && dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to clear {}", dir.display()))?;
+        }
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Keep the pair of run `counter`, with copies of both sides
+    pub fn add(&self, counter: usize, file: &ExternalFile) -> Result<()> {
+        let entry = self.dir.join(counter.to_string());
+        std::fs::create_dir_all(&entry)
+            .with_context(|| format!("Failed to create {}", entry.display()))?;
+        std::fs::write(entry.join("path"), &file.path)?;
+        if let Some(new_path) = &file.new_path {
+            std::fs::write(entry.join("new_path"), new_path)?;
+        }
+        if file.unmerged {
+            std::fs::write(entry.join("unmerged"), "")?;
+        }
+        for (side, source) in [("old", &file.old_file), ("new", &file.new_file)] {
+            if source != "/dev/null" {
+                std::fs::copy(source, entry.join(side))
+                    .with_context(|| format!("Failed to copy {}", source))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every pair kept so far, in the order git ran them
+    pub fn files(&self) -> Result<Vec<ExternalFile>> {
+        let mut entries: Vec<(usize, PathBuf)> = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let counter = entry.file_name().to_str()?.parse().ok()?;
+                Some((counter, entry.path()))
+            })
+            .collect();
+        entries.sort();
+
+        let side = |entry: &Path, name: &str| {
+            let path = entry.join(name);
+            if path.exists() {
+                path.to_string_lossy().to_string()
+            } else {
+                "/dev/null".to_string()
+            }
+        };
+        let mut files = Vec::new();
+        for (_, entry) in entries {
+            let Ok(path) = std::fs::read_to_string(entry.join("path")) else {
+                continue;
+            };
+            files.push(ExternalFile {
+                path,
+                new_path: std::fs::read_to_string(entry.join("new_path")).ok(),
+                old_file: side(&entry, "old"),
+                new_file: side(&entry, "new"),
+                unmerged: entry.join("unmerged").exists(),
+            });
+        }
+        Ok(files)
+    }
+
+    /// Drop the session and its copies once they have been shown
+    pub fn close(self) -> Result<()> {
+        std::fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("Failed to remove {}", self.dir.display()))
+    }
+}
+
+/// git runs the external diff itself, so the parent is the `git diff`
+#[cfg(unix)]
+fn git_process_id() -> u32 {
+    std::os::unix::process::parent_id()
+}
+
+#[cfg(not(unix))]
+fn git_process_id() -> u32 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{DiffEngine, DiffMode, FileStatus};
+    use tempfile::tempdir;
+
+    fn git_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_session_gathers_every_run() {
+        let dir = tempdir().unwrap();
+        Repository::init(dir.path()).unwrap();
+        let blobs = tempdir().unwrap();
+        let blob = |name: &str, text: &str| {
+            let path = blobs.path().join(name);
+            std::fs::write(&path, text).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let oid = "0ff3bbb9c8bba2291654cd64067fa417ff54c508";
+        let runs = [
+            git_args(&[
+                "a.txt",
+                &blob("a-old", "one\n"),
+                oid,
+                "100644",
+                &blob("a-new", "two\n"),
+                oid,
+                "100644",
+            ]),
+            git_args(&[
+                "b.txt",
+                &blob("b-old", "same\n"),
+                oid,
+                "100644",
+                &blob("b-new", "same\n"),
+                oid,
+                "100644",
+                "c.txt",
+                "similarity index 100%\n",
+            ]),
+            git_args(&["e.txt"]),
+            git_args(&[
+                "d.txt",
+                "/dev/null",
+                ".",
+                ".",
+                &blob("d-new", "new\n"),
+                oid,
+                "100644",
+            ]),
+        ];
+        assert!(ExternalFile::from_git_args(&git_args(&["path", "old", "new"])).is_none());
+
+        std::fs::write(
+            dir.path().join("e.txt"),
+            "<<<<<<< ours\nmine\n=======\nyours\n>>>>>>> theirs\n",
+        )
+        .unwrap();
+        for (idx, args) in runs.iter().enumerate() {
+            let session = Session::open(dir.path(), idx == 0).unwrap();
+            let file = ExternalFile::from_git_args(args)
+                .unwrap_or_else(|| ExternalFile::unmerged(&args[0]));
+            session.add(idx + 1, &file).unwrap();
+        }
+        // git removes its temporary blobs after each run
+        drop(blobs);
+
+        let session = Session::open(dir.path(), false).unwrap();
+        let files = session.files().unwrap();
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3);
+        let diffed = engine.diff(&DiffMode::ExternalDiff { files }, &[]).unwrap();
+        let summary: Vec<_> = diffed
+            .iter()
+            .map(|f| (f.new_path.clone().unwrap(), f.status, f.hunks.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (PathBuf::from("a.txt"), FileStatus::Modified, 1),
+                (PathBuf::from("c.txt"), FileStatus::Renamed, 0),
+                (PathBuf::from("e.txt"), FileStatus::Conflicted, 1),
+                (PathBuf::from("d.txt"), FileStatus::Added, 1),
+            ]
+        );
+        let session_dir = session.dir.clone();
+        session.close().unwrap();
+        assert!(!session_dir.exists());
+
+        // A new diff drops what an interrupted one left behind
+        let stale = Session::open(dir.path(), false).unwrap();
+        std::fs::create_dir(stale.dir.join("7")).unwrap();
+        let fresh = Session::open(dir.path(), true).unwrap();
+        assert!(fresh.files().unwrap().is_empty());
+
+        // Diffs run by different git processes keep their own pairs
+        let one = Session::open_for(dir.path(), 1, true).unwrap();
+        one.add(1, &ExternalFile::unmerged("one.txt")).unwrap();
+        let two = Session::open_for(dir.path(), 2, true).unwrap();
+        two.add(1, &ExternalFile::unmerged("two.txt")).unwrap();
+        assert_eq!(one.files().unwrap()[0].path, "one.txt");
+        assert_eq!(two.files().unwrap()[0].path, "two.txt");
+    }
+}
@@ -7,46 +7,35 @@
 mod config;
 mod diff;
 mod export;
+mod external;
 mod storage;
+mod structural;
 mod syntax;
 mod tui;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{CommandFactory, Parser, Subcommand};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 const REATTACH_CONTEXT_LINES: usize = 2;
 
-use crate::config::Config;
-use crate::diff::{find_repo_root, DiffEngine, DiffMode};
+use crate::config::{Config, DiffAlgorithm};
+use crate::diff::{find_repo_root, read_patch, DiffEngine, DiffMode, ExternalFile};
 use crate::export::{export, ExportFormat};
+use crate::external::Session;
 use crate::storage::{AnnotationType, Side, Storage};
-
-/// Parsed git diff arguments
-#[derive(Debug)]
-struct DiffArgs {
-    mode: DiffMode,
-    paths: Vec<String>,
-}
-
-/// Check if args look like git external diff format:
-/// path old-file old-hex old-mode new-file new-hex new-mode
-fn is_git_external_diff_args(args: &[String]) -> bool {
-    // Git external diff passes 7 arguments
-    args.len() == 7 && args[2].len() == 40 && args[5].len() == 40
-}
+use crate::tui::DiffArgs;
 
 /// Parse git diff-style arguments
 fn parse_diff_args(args: &[String], staged: bool) -> DiffArgs {
     // Check if this is git external diff format
-    if is_git_external_diff_args(args) {
+    if let Some(file) = ExternalFile::from_git_args(args) {
         return DiffArgs {
-            mode: DiffMode::ExternalDiff {
-                path: args[0].clone(),
-                old_file: args[1].clone(),
-                new_file: args[4].clone(),
-            },
+            mode: DiffMode::ExternalDiff { files: vec![file] },
             paths: Vec::new(),
+            log_commits: Vec::new(),
         };
     }
 
@@ -95,7 +84,8 @@ fn parse_diff_args(args: &[String], staged: bool) -> DiffArgs {
 
     DiffArgs {
         mode,
-        paths: paths.into_iter().map(String::from).collect(),
+        paths: paths.into_iter().collect(),
+        log_commits: Vec::new(),
     }
 }
 
@@ -111,8 +101,9 @@ fn parse_diff_args(args: &[String], staged: bool) -> DiffArgs {
 )]
 #[command(version)]
 struct Cli {
+    /// Without a subcommand, a diff piped on stdin is shown (pager mode)
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
@@ -126,11 +117,16 @@ enum Commands {
     ///   differ diff main..feature      # between two branches
     ///   differ diff abc123 def456      # between two commits
     ///   differ diff HEAD -- src/       # only files in src/
+    ///   differ diff --no-index a/ b/   # two paths, no repository needed
     Diff {
         /// Show staged changes (index vs HEAD)
         #[arg(long, visible_alias = "cached")]
         staged: bool,
 
+        /// Compare two files or directories on disk instead of git states
+        #[arg(long)]
+        no_index: bool,
+
         /// Enable side-by-side view
         #[arg(short = 's', long)]
         side_by_side: bool,
@@ -139,11 +135,146 @@ enum Commands {
         #[arg(short = 'c', long)]
         context_lines: Option<u32>,
 
+        /// Diff algorithm to compare lines with
+        #[arg(long, value_enum)]
+        diff_algorithm: Option<DiffAlgorithm>,
+
+        /// Ignore whitespace when comparing lines
+        #[arg(short = 'w', long)]
+        ignore_all_space: bool,
+
+        /// Ignore changes in amount of whitespace
+        #[arg(short = 'b', long)]
+        ignore_space_change: bool,
+
+        /// Ignore changes whose lines are all blank
+        #[arg(long)]
+        ignore_blank_lines: bool,
+
         /// Git diff arguments: [<commit>] [<commit>] [-- <path>...]
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
 
+    /// Show a single commit with its author, date, message and parents
+    ///
+    /// Annotations made in this view are recorded against the commit.
+    ///
+    /// Examples:
+    ///   differ show                    # HEAD
+    ///   differ show abc123
+    ///   differ show HEAD~2 -- src/     # only files in src/
+    Show {
+        /// Revision to show
+        #[arg(default_value = "HEAD")]
+        rev: String,
+
+        /// Enable side-by-side view
+        #[arg(short = 's', long)]
+        side_by_side: bool,
+
+        /// Number of context lines around changes
+        #[arg(short = 'c', long)]
+        context_lines: Option<u32>,
+
+        /// Only show these paths
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+
+    /// Review the commits of a range one at a time
+    ///
+    /// Each commit is shown with its metadata; annotations and a "reviewed"
+    /// flag are kept per commit.
+    ///
+    /// Examples:
+    ///   differ log main..feature
+    ///   differ log origin/main         # origin/main..HEAD
+    ///   differ log HEAD~5.. -- src/
+    Log {
+        /// Commit range <from>..<to>; a single revision means <rev>..HEAD
+        range: String,
+
+        /// Enable side-by-side view
+        #[arg(short = 's', long)]
+        side_by_side: bool,
+
+        /// Number of context lines around changes
+        #[arg(short = 'c', long)]
+        context_lines: Option<u32>,
+
+        /// Only show these paths
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+
+    /// Compare two versions of a patch series, e.g. before and after a rebase
+    ///
+    /// Commits are matched across the versions and each pair shows the diff
+    /// of their patches. Open annotations on old commits are copied to the
+    /// matching lines of the new ones.
+    ///
+    /// Examples:
+    ///   differ range-diff main..feature@{1} main..feature
+    ///   differ range-diff v1-base..v1 v2-base..v2 -- src/
+    RangeDiff {
+        /// Old version of the series, <base>..<tip>
+        old: String,
+
+        /// New version of the series, <base>..<tip>
+        new: String,
+
+        /// Enable side-by-side view
+        #[arg(short = 's', long)]
+        side_by_side: bool,
+
+        /// Number of context lines around changes
+        #[arg(short = 'c', long)]
+        context_lines: Option<u32>,
+
+        /// Only compare changes to these paths
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+
+    /// Browse a stash entry; press z in the TUI to pick another
+    ///
+    /// Examples:
+    ///   differ stash                   # stash@{0}
+    ///   differ stash 2                 # stash@{2}
+    ///   differ stash --staged          # the stashed index
+    Stash {
+        /// Stash index
+        #[arg(default_value_t = 0)]
+        index: usize,
+
+        /// Show the stashed index instead of the worktree changes
+        #[arg(long, visible_alias = "cached")]
+        staged: bool,
+
+        /// Enable side-by-side view
+        #[arg(short = 's', long)]
+        side_by_side: bool,
+
+        /// Number of context lines around changes
+        #[arg(short = 'c', long)]
+        context_lines: Option<u32>,
+    },
+
+    /// View a unified diff from a file or stdin without applying it
+    ///
+    /// The TUI runs read-only: stage and discard are disabled, annotations
+    /// still work and are keyed to the patch's paths.
+    ///
+    /// Examples:
+    ///   differ view change.patch
+    ///   git diff | differ
+    ///   git config core.pager differ
+    View {
+        /// Patch file ("-" or omitted reads stdin)
+        file: Option<PathBuf>,
+    },
+
     /// List all annotations for the current repository
     List {
         /// Filter by file path
@@ -194,9 +325,30 @@ enum Commands {
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    // Find repo root and initialize storage
+    let command = match cli.command {
+        Some(command) => command,
+        None if !std::io::stdin().is_terminal() => Commands::View { file: None },
+        None => {
+            Cli::command().print_help()?;
+            return Ok(());
+        }
+    };
+
+    // Find repo root and initialize storage. Patches can be viewed anywhere;
+    // outside a repository their annotations are kept under the cwd.
     let cwd = std::env::current_dir().context("Failed to get current directory")?;
-    let repo_path = find_repo_root(&cwd)?;
+    let repo_path = match find_repo_root(&cwd) {
+        Ok(path) => path,
+        Err(_)
+            if matches!(
+                command,
+                Commands::View { .. } | Commands::Diff { no_index: true, .. }
+            ) =>
+        {
+            cwd.clone()
+        }
+        Err(err) => return Err(err),
+    };
     let storage = Storage::open_default()?;
 
     // Get display name from repo directory name
@@ -204,27 +356,141 @@ fn main() -> Result<()> {
         .file_name()
         .map(|n| n.to_string_lossy().to_string());
 
-    let repo_id = storage.get_or_create_repo(&repo_path, display_name.as_deref())?;
+    // Created on first use: a --no-index diff keeps its annotations under
+    // an identity of its own and leaves the cwd without a row
+    let repo_id = || storage.get_or_create_repo(&repo_path, display_name.as_deref());
 
-    // Load config and apply CLI overrides
-    let config = Config::load().unwrap_or_default();
+    // Load config (git config filling what it leaves out), then apply CLI overrides
+    let config = Config::load()
+        .unwrap_or_default()
+        .with_git_config(&repo_path);
 
-    match cli.command {
+    match command {
         Commands::Diff {
             staged,
+            no_index,
             side_by_side,
             context_lines,
+            diff_algorithm,
+            ignore_all_space,
+            ignore_space_change,
+            ignore_blank_lines,
             args,
         } => {
-            let config =
-                config.with_overrides(if side_by_side { Some(true) } else { None }, context_lines);
+            let config = config
+                .with_overrides(if side_by_side { Some(true) } else { None }, context_lines)
+                .with_diff_overrides(
+                    diff_algorithm,
+                    ignore_all_space,
+                    ignore_space_change,
+                    ignore_blank_lines,
+                );
+
+            if no_index {
+                cmd_diff_no_index(&storage, &args, config)?;
+                return Ok(());
+            }
+
+            // git runs an external diff once per file: gather them all.
+            // Unmerged paths come with their name alone.
+            if let Some(position) = external::run_position() {
+                if ExternalFile::from_git_args(&args).is_some() || args.len() == 1 {
+                    cmd_external_diff(&storage, &repo_path, repo_id()?, &args, position, config)?;
+                    return Ok(());
+                }
+            }
 
             // Parse git diff-style arguments
             let diff_args = parse_diff_args(&args, staged);
-            cmd_diff(&storage, &repo_path, repo_id, diff_args, config)?;
+            cmd_diff(&storage, &repo_path, repo_id()?, diff_args, config)?;
+        }
+        Commands::Show {
+            rev,
+            side_by_side,
+            context_lines,
+            paths,
+        } => {
+            let config =
+                config.with_overrides(if side_by_side { Some(true) } else { None }, context_lines);
+            // Pin the revision so a moving ref does not change the view
+            let engine = DiffEngine::new(repo_path.clone(), config.context_lines);
+            let sha = engine.commit_info(&rev)?.sha;
+            let diff_args = DiffArgs {
+                mode: DiffMode::Commit { rev: sha },
+                paths,
+                log_commits: Vec::new(),
+            };
+            cmd_diff(&storage, &repo_path, repo_id()?, diff_args, config)?;
+        }
+        Commands::Log {
+            range,
+            side_by_side,
+            context_lines,
+            paths,
+        } => {
+            let config =
+                config.with_overrides(if side_by_side { Some(true) } else { None }, context_lines);
+            cmd_log(&storage, &repo_path, repo_id()?, &range, paths, config)?;
+        }
+        Commands::RangeDiff {
+            old,
+            new,
+            side_by_side,
+            context_lines,
+            paths,
+        } => {
+            let config =
+                config.with_overrides(if side_by_side { Some(true) } else { None }, context_lines);
+            cmd_range_diff(
+                &storage,
+                &repo_path,
+                repo_id()?,
+                (&old, &new),
+                paths,
+                config,
+            )?;
+        }
+        Commands::Stash {
+            index,
+            staged,
+            side_by_side,
+            context_lines,
+        } => {
+            let config =
+                config.with_overrides(if side_by_side { Some(true) } else { None }, context_lines);
+            let engine = DiffEngine::new(repo_path.clone(), config.context_lines);
+            let count = engine.list_stashes()?.len();
+            if index >= count {
+                anyhow::bail!("No stash entry stash@{{{}}} ({} stashes)", index, count);
+            }
+            let diff_args = DiffArgs {
+                mode: DiffMode::Stash { index, staged },
+                paths: Vec::new(),
+                log_commits: Vec::new(),
+            };
+            cmd_diff(&storage, &repo_path, repo_id()?, diff_args, config)?;
+        }
+        Commands::View { file } => {
+            let (source, text) = match file {
+                Some(path) if path.as_os_str() != "-" => {
+                    let file = std::fs::File::open(&path)
+                        .with_context(|| format!("Failed to open {}", path.display()))?;
+                    (path.display().to_string(), read_patch(file)?)
+                }
+                _ => ("stdin".to_string(), read_patch(std::io::stdin().lock())?),
+            };
+            let diff_args = DiffArgs {
+                mode: DiffMode::Patch {
+                    source,
+                    text: Arc::new(text),
+                },
+                paths: Vec::new(),
+                log_commits: Vec::new(),
+            };
+            cmd_diff(&storage, &repo_path, repo_id()?, diff_args, config)?;
         }
         Commands::List { file } => {
-            cmd_list(&storage, repo_id, file.as_deref())?;
+            cmd_list(&storage, repo_id()?, file.as_deref())?;
         }
         Commands::Add {
             file,
@@ -236,7 +502,7 @@ fn main() -> Result<()> {
             cmd_add(
                 &storage,
                 &repo_path,
-                repo_id,
+                repo_id()?,
                 &file,
                 line,
                 end_line,
@@ -245,10 +511,10 @@ fn main() -> Result<()> {
             )?;
         }
         Commands::Export { format, output } => {
-            cmd_export(&storage, repo_id, &format, output)?;
+            cmd_export(&storage, repo_id()?, &format, output)?;
         }
         Commands::Clear => {
-            cmd_clear(&storage, repo_id)?;
+            cmd_clear(&storage, repo_id()?)?;
         }
         Commands::Config => {
             cmd_config()?;
@@ -260,12 +526,20 @@ fn main() -> Result<()> {
 
 fn cmd_diff(
     _storage: &Storage,
-    repo_path: &PathBuf,
+    repo_path: &Path,
     repo_id: i64,
     args: DiffArgs,
     config: Config,
 ) -> Result<()> {
-    let diff_engine = DiffEngine::new(repo_path.clone(), config.context_lines);
+    let diff_engine = DiffEngine::new(repo_path.to_path_buf(), config.context_lines)
+        .with_backend(config.diff_backend)
+        .with_structural(config.structural_diff)
+        .with_algorithm(config.diff_algorithm)
+        .with_renames(config.renames)
+        .with_ignore_submodules(config.ignore_submodules)
+        .with_whitespace(config.whitespace())
+        .with_limits(config.limits())
+        .with_untracked(config.untracked_files);
 
     // Clone storage for TUI (it needs ownership)
     let tui_storage = Storage::open_default()?;
@@ -273,15 +547,143 @@ fn cmd_diff(
     tui::run(
         tui_storage,
         diff_engine,
-        repo_path.clone(),
+        repo_path.to_path_buf(),
         repo_id,
-        Vec::new(),
         config,
-        args.mode.clone(),
-        args.paths.clone(),
+        args,
     )
 }
 
+/// One run of `diff.external` within a `git diff`. Every run adds its file
+/// to the session; the last one shows them all.
+fn cmd_external_diff(
+    storage: &Storage,
+    repo_path: &Path,
+    repo_id: i64,
+    args: &[String],
+    (counter, total): (usize, usize),
+    config: Config,
+) -> Result<()> {
+    let session = Session::open(repo_path, counter == 1)?;
+    let file = match args {
+        [path] => Some(ExternalFile::unmerged(path)),
+        _ => ExternalFile::from_git_args(args),
+    };
+    if let Some(file) = file {
+        session.add(counter, &file)?;
+    }
+    if counter < total {
+        return Ok(());
+    }
+    let files = session.files()?;
+    let result = if files.is_empty() {
+        Ok(())
+    } else {
+        let diff_args = DiffArgs {
+            mode: DiffMode::ExternalDiff { files },
+            paths: Vec::new(),
+            log_commits: Vec::new(),
+        };
+        cmd_diff(storage, repo_path, repo_id, diff_args, config)
+    };
+    let closed = session.close();
+    result.and(closed)
+}
+
+fn cmd_log(
+    storage: &Storage,
+    repo_path: &Path,
+    repo_id: i64,
+    range: &str,
+    paths: Vec<String>,
+    config: Config,
+) -> Result<()> {
+    if range.contains("...") {
+        anyhow::bail!("Symmetric ranges are not supported, use <from>..<to>");
+    }
+    let (from, to) = match range.split_once("..") {
+        Some((from, to)) => (from, if to.is_empty() { "HEAD" } else { to }),
+        None => (range, "HEAD"),
+    };
+    let engine = DiffEngine::new(repo_path.to_path_buf(), config.context_lines);
+    let commits = engine.list_commits(from, to)?;
+    let Some(first) = commits.first() else {
+        anyhow::bail!("No commits in {}..{}", from, to);
+    };
+    let diff_args = DiffArgs {
+        mode: DiffMode::Commit {
+            rev: first.sha.clone(),
+        },
+        paths,
+        log_commits: commits,
+    };
+    cmd_diff(storage, repo_path, repo_id, diff_args, config)
+}
+
+fn cmd_range_diff(
+    storage: &Storage,
+    repo_path: &Path,
+    repo_id: i64,
+    ranges: (&str, &str),
+    paths: Vec<String>,
+    config: Config,
+) -> Result<()> {
+    let engine = DiffEngine::new(repo_path.to_path_buf(), config.context_lines);
+    // Pin both ranges so a force-push during review does not move them
+    let resolve = |range: &str| -> Result<(String, String)> {
+        let Some((base, tip)) = range.split_once("..").filter(|_| !range.contains("...")) else {
+            anyhow::bail!("Expected a range <base>..<tip>, got {}", range);
+        };
+        let tip = if tip.is_empty() { "HEAD" } else { tip };
+        Ok((engine.commit_info(base)?.sha, engine.commit_info(tip)?.sha))
+    };
+    let (old_from, old_to) = resolve(ranges.0)?;
+    let (new_from, new_to) = resolve(ranges.1)?;
+    let diff_args = DiffArgs {
+        mode: DiffMode::RangeDiff {
+            old_from,
+            old_to,
+            new_from,
+            new_to,
+        },
+        paths,
+        log_commits: Vec::new(),
+    };
+    cmd_diff(storage, repo_path, repo_id, diff_args, config)
+}
+
+fn cmd_diff_no_index(storage: &Storage, args: &[String], config: Config) -> Result<()> {
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--").collect();
+    let [old, new] = paths.as_slice() else {
+        anyhow::bail!("Usage: differ diff --no-index <path> <path>");
+    };
+    let old = std::fs::canonicalize(old).with_context(|| format!("Cannot open {}", old))?;
+    let new = std::fs::canonicalize(new).with_context(|| format!("Cannot open {}", new))?;
+
+    // Not a repository: annotations are keyed to the pair of roots instead
+    let identity = PathBuf::from(format!("no-index:{}:{}", old.display(), new.display()));
+    let name_of = |p: &PathBuf| {
+        p.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| p.display().to_string())
+    };
+    let display_name = format!("{} ↔ {}", name_of(&old), name_of(&new));
+    let repo_id = storage.get_or_create_repo(&identity, Some(&display_name))?;
+
+    // Relative paths in the diff resolve against the new side
+    let root = if new.is_dir() {
+        new.clone()
+    } else {
+        new.parent().map(PathBuf::from).unwrap_or_default()
+    };
+    let diff_args = DiffArgs {
+        mode: DiffMode::NoIndex { old, new },
+        paths: Vec::new(),
+        log_commits: Vec::new(),
+    };
+    cmd_diff(storage, &root, repo_id, diff_args, config)
+}
+
 fn cmd_list(storage: &Storage, repo_id: i64, file: Option<&str>) -> Result<()> {
     let annotations = storage.list_annotations(repo_id, file)?;
 
@@ -326,9 +728,10 @@ fn cmd_list(storage: &Storage, repo_id: i64, file: Option<&str>) -> Result<()> {
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn cmd_add(
     storage: &Storage,
-    repo_path: &PathBuf,
+    repo_path: &Path,
     repo_id: i64,
     file: &str,
     line: u32,
@@ -362,7 +765,7 @@ fn cmd_add(
 }
 
 fn build_anchor_from_file(
-    repo_path: &PathBuf,
+    repo_path: &Path,
     file: &str,
     line: u32,
 ) -> (u32, String, String, String) {
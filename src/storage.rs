@@ -5,6 +5,7 @@
 use anyhow::{Context, Result};
 use rusqlite::{params, Connection, OptionalExtension};
 use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::path::Path;
 
 const SCHEMA: &str = r#"
@@ -39,6 +40,15 @@ CREATE TABLE IF NOT EXISTS annotations (
 
 CREATE INDEX IF NOT EXISTS idx_repo_file ON annotations(repo_id, file_path);
 CREATE INDEX IF NOT EXISTS idx_unresolved ON annotations(resolved_at) WHERE resolved_at IS NULL;
+
+-- Commits marked as reviewed in commit-by-commit review
+CREATE TABLE IF NOT EXISTS reviewed_commits (
+    repo_id INTEGER NOT NULL REFERENCES repos(id),
+    commit_sha TEXT NOT NULL,
+    reviewed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (repo_id, commit_sha)
+);
 "#;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -226,6 +236,7 @@ impl Storage {
     }
 
     /// Adds a new annotation
+    #[allow(clippy::too_many_arguments)]
     pub fn add_annotation(
         &self,
         repo_id: i64,
@@ -402,6 +413,39 @@ impl Storage {
         )?;
         Ok(())
     }
+
+    /// Set or clear the reviewed flag of a commit
+    pub fn set_commit_reviewed(
+        &self,
+        repo_id: i64,
+        commit_sha: &str,
+        reviewed: bool,
+    ) -> Result<()> {
+        if reviewed {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO reviewed_commits (repo_id, commit_sha) VALUES (?1, ?2)",
+                params![repo_id, commit_sha],
+            )?;
+        } else {
+            self.conn.execute(
+                "DELETE FROM reviewed_commits WHERE repo_id = ?1 AND commit_sha = ?2",
+                params![repo_id, commit_sha],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Commits of a repo marked as reviewed
+    pub fn reviewed_commits(&self, repo_id: i64) -> Result<HashSet<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT commit_sha FROM reviewed_commits WHERE repo_id = ?1")?;
+        let shas = stmt
+            .query_map(params![repo_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<HashSet<String>>>()
+            .context("Failed to fetch reviewed commits")?;
+        Ok(shas)
+    }
 }
 
 // Need hex encoding for the hash
@@ -491,4 +535,35 @@ mod tests {
         let annotations = storage.list_annotations(repo_id, None).unwrap();
         assert_eq!(annotations.len(), 0);
     }
+
+    #[test]
+    fn test_reviewed_commits() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        let storage = Storage::open(&db_path).unwrap();
+
+        let repo_id = storage
+            .get_or_create_repo(Path::new("/test/repo"), None)
+            .unwrap();
+        let other_id = storage
+            .get_or_create_repo(Path::new("/test/other"), None)
+            .unwrap();
+
+        storage
+            .set_commit_reviewed(repo_id, "abc123", true)
+            .unwrap();
+        storage
+            .set_commit_reviewed(repo_id, "abc123", true)
+            .unwrap();
+        storage
+            .set_commit_reviewed(repo_id, "def456", true)
+            .unwrap();
+        storage
+            .set_commit_reviewed(repo_id, "def456", false)
+            .unwrap();
+
+        let reviewed = storage.reviewed_commits(repo_id).unwrap();
+        assert_eq!(reviewed, HashSet::from(["abc123".to_string()]));
+        assert!(storage.reviewed_commits(other_id).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,285 @@
+//! Structural diff
+//!
+//! Compares the syntax token streams of both sides of a file instead of their
+//! lines, so changes that only move tokens between lines or alter whitespace
+//! disappear. The result is mapped back onto `DiffHunk`/`DiffLine`: lines that
+//! hold a changed token become deletions/additions with the changed tokens as
+//! inline ranges, and every other line becomes context. A line that only
+//! moved or was reflowed is context on the one side it is on, so each hunk
+//! still covers a contiguous run of lines on both sides.
+
+use crate::diff::{DiffHunk, DiffLine, InlineRange, LineEnding, LineKind};
+use crate::syntax::SyntaxHighlighter;
+use similar::{capture_diff_slices, capture_diff_slices_deadline, Algorithm, DiffOp};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Upper bound on token alignment time per file before falling back to an
+/// approximate alignment.
+const TOKEN_DIFF_DEADLINE: Duration = Duration::from_millis(500);
+
+/// Build hunks for a file from a token-level comparison of both sides.
+pub fn structural_hunks(
+    highlighter: &mut SyntaxHighlighter,
+    file_path: &str,
+    old: &str,
+    new: &str,
+    context_lines: u32,
+) -> Vec<DiffHunk> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let old_tokens = highlighter.tokenize_lines(&old_lines, file_path);
+    let new_tokens = highlighter.tokenize_lines(&new_lines, file_path);
+    let (old_changed, new_changed) =
+        changed_tokens(&old_lines, &old_tokens, &new_lines, &new_tokens);
+
+    // Order output lines by a line-level alignment; unchanged lines inside a
+    // changed block only moved or were reflowed, so they stay as context that
+    // carries just their own side's line number.
+    let mut entries: Vec<DiffLine> = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines) {
+        match op {
+            DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } => {
+                for offset in 0..len {
+                    entries.push(line(
+                        LineKind::Context,
+                        Some(old_index + offset),
+                        Some(new_index + offset),
+                        old_lines[old_index + offset],
+                        Vec::new(),
+                    ));
+                }
+            }
+            op => {
+                for old_idx in op.old_range() {
+                    let kind = if old_changed[old_idx].is_empty() {
+                        LineKind::Context
+                    } else {
+                        LineKind::Deletion
+                    };
+                    entries.push(line(
+                        kind,
+                        Some(old_idx),
+                        None,
+                        old_lines[old_idx],
+                        old_changed[old_idx].clone(),
+                    ));
+                }
+                for new_idx in op.new_range() {
+                    let kind = if new_changed[new_idx].is_empty() {
+                        LineKind::Context
+                    } else {
+                        LineKind::Addition
+                    };
+                    entries.push(line(
+                        kind,
+                        None,
+                        Some(new_idx),
+                        new_lines[new_idx],
+                        new_changed[new_idx].clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    group_hunks(entries, context_lines as usize)
+}
+
+/// Align significant tokens of both sides and return the changed byte ranges
+/// per line, with adjacent changed tokens on a line merged into one range.
+fn changed_tokens(
+    old_lines: &[&str],
+    old_tokens: &[Vec<Range<usize>>],
+    new_lines: &[&str],
+    new_tokens: &[Vec<Range<usize>>],
+) -> (Vec<Vec<InlineRange>>, Vec<Vec<InlineRange>>) {
+    let old_flat = flatten(old_lines, old_tokens);
+    let new_flat = flatten(new_lines, new_tokens);
+    let old_words: Vec<&str> = old_flat
+        .iter()
+        .map(|(l, r)| &old_lines[*l][r.clone()])
+        .collect();
+    let new_words: Vec<&str> = new_flat
+        .iter()
+        .map(|(l, r)| &new_lines[*l][r.clone()])
+        .collect();
+
+    let mut old_changed = vec![Vec::new(); old_lines.len()];
+    let mut new_changed = vec![Vec::new(); new_lines.len()];
+    let deadline = Some(Instant::now() + TOKEN_DIFF_DEADLINE);
+    for op in capture_diff_slices_deadline(Algorithm::Myers, &old_words, &new_words, deadline) {
+        if matches!(op, DiffOp::Equal { .. }) {
+            continue;
+        }
+        mark(&mut old_changed, &old_flat[op.old_range()]);
+        mark(&mut new_changed, &new_flat[op.new_range()]);
+    }
+    (old_changed, new_changed)
+}
+
+fn flatten(lines: &[&str], tokens: &[Vec<Range<usize>>]) -> Vec<(usize, Range<usize>)> {
+    let mut flat = Vec::new();
+    for (line_idx, line_tokens) in tokens.iter().enumerate() {
+        for token in line_tokens {
+            let significant = lines[line_idx]
+                .get(token.clone())
+                .is_some_and(|s| !s.trim().is_empty());
+            if significant {
+                flat.push((line_idx, token.clone()));
+            }
+        }
+    }
+    flat
+}
+
+fn mark(changed: &mut [Vec<InlineRange>], tokens: &[(usize, Range<usize>)]) {
+    let mut prev_line = None;
+    for (line_idx, range) in tokens {
+        let ranges = &mut changed[*line_idx];
+        match ranges.last_mut() {
+            Some(last) if prev_line == Some(*line_idx) => last.end = range.end,
+            _ => ranges.push(InlineRange {
+                start: range.start,
+                end: range.end,
+            }),
+        }
+        prev_line = Some(*line_idx);
+    }
+}
+
+fn line(
+    kind: LineKind,
+    old_idx: Option<usize>,
+    new_idx: Option<usize>,
+    content: &str,
+    inline_ranges: Vec<InlineRange>,
+) -> DiffLine {
+    DiffLine {
+        kind,
+        old_line_no: old_idx.map(|i| i as u32 + 1),
+        new_line_no: new_idx.map(|i| i as u32 + 1),
+        content: content.to_string(),
+        highlights: Vec::new(),
+        inline_ranges,
+        conflict: None,
+        combined: Vec::new(),
+        moved: None,
+        ending: LineEnding::Lf,
+        raw: None,
+    }
+}
+
+/// Cut the line sequence into hunks with `context` lines around changes.
+fn group_hunks(entries: Vec<DiffLine>, context: usize) -> Vec<DiffHunk> {
+    let changed: Vec<usize> = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| l.kind != LineKind::Context)
+        .map(|(i, _)| i)
+        .collect();
+    let Some(&first) = changed.first() else {
+        return Vec::new();
+    };
+
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    let mut start = first.saturating_sub(context);
+    let mut end = first;
+    for &idx in &changed[1..] {
+        if idx.saturating_sub(end) > context * 2 + 1 {
+            spans.push((start, (end + context).min(entries.len() - 1)));
+            start = idx.saturating_sub(context);
+        }
+        end = idx;
+    }
+    spans.push((start, (end + context).min(entries.len() - 1)));
+
+    spans
+        .into_iter()
+        .map(|(start, end)| {
+            let lines = entries[start..=end].to_vec();
+            let old_start = lines.iter().find_map(|l| l.old_line_no);
+            let new_start = lines.iter().find_map(|l| l.new_line_no);
+            DiffHunk {
+                old_start: old_start.unwrap_or(0),
+                old_lines: lines.iter().filter(|l| l.old_line_no.is_some()).count() as u32,
+                new_start: new_start.unwrap_or(0),
+                new_lines: lines.iter().filter(|l| l.new_line_no.is_some()).count() as u32,
+                header: None,
+                lines,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reformat_only_has_no_hunks() {
+        let mut highlighter = SyntaxHighlighter::new(None).unwrap();
+        let old = "fn main() {\n    let x = foo(a, b);\n}\n";
+        let new = "fn main() {\n    let x = foo(\n        a,\n        b\n    );\n}\n";
+        let hunks = structural_hunks(&mut highlighter, "main.rs", old, new, 3);
+        assert!(hunks.is_empty());
+    }
+
+    #[test]
+    fn test_changed_token_marks_only_its_lines() {
+        let mut highlighter = SyntaxHighlighter::new(None).unwrap();
+        let old = "fn a() {}\nfn b() {\n    call(one);\n}\n";
+        let new = "fn a() {}\n\nfn b() {\n    call(two);\n}\n";
+        let hunks = structural_hunks(&mut highlighter, "lib.rs", old, new, 0);
+        assert_eq!(hunks.len(), 1);
+        let changed: Vec<_> = hunks[0]
+            .lines
+            .iter()
+            .map(|l| (l.kind, l.content.as_str()))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                (LineKind::Deletion, "    call(one);"),
+                (LineKind::Addition, "    call(two);"),
+            ]
+        );
+        let del = &hunks[0].lines[0];
+        assert_eq!(
+            &del.content[del.inline_ranges[0].start..del.inline_ranges[0].end],
+            "one"
+        );
+    }
+
+    #[test]
+    fn test_hunks_cover_contiguous_lines() {
+        let mut highlighter = SyntaxHighlighter::new(None).unwrap();
+        let old = "fn main() {\n    let x = foo(a, b);\n    bar();\n}\n";
+        let new = "fn main() {\n    let x = foo(\n        a,\n        b\n    );\n    baz();\n}\n";
+        let hunks = structural_hunks(&mut highlighter, "main.rs", old, new, 3);
+        assert_eq!(hunks.len(), 1);
+        let hunk = &hunks[0];
+        let old_nos: Vec<u32> = hunk.lines.iter().filter_map(|l| l.old_line_no).collect();
+        let new_nos: Vec<u32> = hunk.lines.iter().filter_map(|l| l.new_line_no).collect();
+        assert_eq!(
+            old_nos,
+            (hunk.old_start..hunk.old_start + hunk.old_lines).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            new_nos,
+            (hunk.new_start..hunk.new_start + hunk.new_lines).collect::<Vec<_>>()
+        );
+        assert_eq!((hunk.old_lines, hunk.new_lines), (4, 7));
+        let changed: Vec<_> = hunk
+            .lines
+            .iter()
+            .filter(|l| l.kind != LineKind::Context)
+            .map(|l| l.content.as_str())
+            .collect();
+        assert_eq!(changed, vec!["    bar();", "    baz();"]);
+    }
+}
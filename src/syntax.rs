@@ -3,11 +3,12 @@
 //! Highlights full files and returns per-line highlight ranges.
 
 use anyhow::Result;
+use std::ops::Range;
 use std::str::FromStr;
 use syntect::easy::HighlightLines;
 use syntect::highlighting::HighlightIterator;
 use syntect::highlighting::{FontStyle, HighlightState, Highlighter, ScopeSelectors};
-use syntect::parsing::{ParseState, ScopeStack};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet};
 use syntect::util::LinesWithEndings;
 use syntect_assets::assets::HighlightingAssets;
 
@@ -58,17 +59,7 @@ impl SyntaxHighlighter {
         let Ok(syntax_set) = self.assets.get_syntax_set() else {
             return Vec::new();
         };
-        let syntax = syntax_set
-            .find_syntax_for_file(file_path)
-            .ok()
-            .flatten()
-            .or_else(|| {
-                std::path::Path::new(file_path)
-                    .extension()
-                    .and_then(|ext| ext.to_str())
-                    .and_then(|ext| syntax_set.find_syntax_by_extension(ext))
-            })
-            .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+        let syntax = find_syntax(syntax_set, file_path);
 
         let mut highlighter = HighlightLines::new(syntax, theme);
 
@@ -114,17 +105,7 @@ impl SyntaxHighlighter {
         let Ok(syntax_set) = self.assets.get_syntax_set() else {
             return (Vec::new(), false);
         };
-        let syntax = syntax_set
-            .find_syntax_for_file(file_path)
-            .ok()
-            .flatten()
-            .or_else(|| {
-                std::path::Path::new(file_path)
-                    .extension()
-                    .and_then(|ext| ext.to_str())
-                    .and_then(|ext| syntax_set.find_syntax_by_extension(ext))
-            })
-            .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+        let syntax = find_syntax(syntax_set, file_path);
 
         let highlighter = Highlighter::new(theme);
         let mut highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
@@ -182,6 +163,48 @@ impl SyntaxHighlighter {
         (per_line, ends_in_string)
     }
 
+    /// Split lines into syntax tokens, returned as byte ranges per line.
+    ///
+    /// Lines are parsed as one continuous stream so multi-line constructs keep
+    /// their state. Token boundaries come from scope changes in the syntax
+    /// definition, refined by character class so identifiers stay whole while
+    /// punctuation and whitespace become their own tokens.
+    pub fn tokenize_lines(&mut self, lines: &[&str], file_path: &str) -> Vec<Vec<Range<usize>>> {
+        let Ok(syntax_set) = self.assets.get_syntax_set() else {
+            return lines
+                .iter()
+                .map(|line| split_by_class(line, 0..line.len()))
+                .collect();
+        };
+        let syntax = find_syntax(syntax_set, file_path);
+        let mut parse_state = ParseState::new(syntax);
+
+        let mut per_line = Vec::with_capacity(lines.len());
+        for line in lines {
+            let mut text = String::with_capacity(line.len() + 1);
+            text.push_str(line);
+            text.push('\n');
+
+            let mut boundaries = vec![0, line.len()];
+            if let Ok(ops) = parse_state.parse_line(&text, syntax_set) {
+                boundaries.extend(
+                    ops.iter()
+                        .map(|(offset, _)| *offset)
+                        .filter(|o| *o < line.len()),
+                );
+            }
+            boundaries.sort_unstable();
+            boundaries.dedup();
+
+            let mut tokens = Vec::new();
+            for window in boundaries.windows(2) {
+                tokens.extend(split_by_class(line, window[0]..window[1]));
+            }
+            per_line.push(tokens);
+        }
+        per_line
+    }
+
     fn to_text_style(style: syntect::highlighting::Style) -> TextStyle {
         let fg = (style.foreground.r, style.foreground.g, style.foreground.b);
         TextStyle {
@@ -192,3 +215,58 @@ impl SyntaxHighlighter {
         }
     }
 }
+
+fn find_syntax<'a>(syntax_set: &'a SyntaxSet, file_path: &str) -> &'a SyntaxReference {
+    syntax_set
+        .find_syntax_for_file(file_path)
+        .ok()
+        .flatten()
+        .or_else(|| {
+            std::path::Path::new(file_path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(|ext| syntax_set.find_syntax_by_extension(ext))
+        })
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
+}
+
+#[derive(PartialEq)]
+enum CharClass {
+    Word,
+    Space,
+    Punct,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else if c.is_whitespace() {
+        CharClass::Space
+    } else {
+        CharClass::Punct
+    }
+}
+
+/// Split a scope segment into word runs, whitespace runs and single punctuation chars.
+fn split_by_class(line: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut tokens = Vec::new();
+    let mut start = range.start;
+    let mut prev: Option<CharClass> = None;
+    for (idx, c) in line[range.clone()].char_indices() {
+        let idx = range.start + idx;
+        let class = char_class(c);
+        let split = match &prev {
+            Some(p) => *p != class || class == CharClass::Punct,
+            None => false,
+        };
+        if split {
+            tokens.push(start..idx);
+            start = idx;
+        }
+        prev = Some(class);
+    }
+    if start < range.end {
+        tokens.push(start..range.end);
+    }
+    tokens
+}
@@ -2,10 +2,13 @@
 //!
 //! Provides interactive diff viewing with annotation support.
 
-use crate::config::{AiTarget, Config};
+use crate::config::{AiTarget, Config, DiffAlgorithm, UntrackedFiles};
 use crate::diff::{
-    DiffEngine, DiffFile, DiffHunk, DiffLine, DiffMode, FileStatus, HighlightRange, InlineRange,
-    LineKind,
+    build_hunks_patch, build_mode_patch, decode_text, detect_moves, find_conflicts_in, map_line,
+    mode_kind, no_index_path, read_worktree_text, resolve_conflict, stash_rev, token_inline_ranges,
+    CommitInfo, ConflictChoice, ConflictSide, Deferred, DiffEngine, DiffFile, DiffHunk, DiffLine,
+    DiffMode, ExternalFile, FileStatus, HighlightRange, InlineRange, LineEnding, LineKind,
+    RangePair, StashEntry, MODE_FILE,
 };
 use crate::storage::{Annotation, AnnotationType, Side, Storage};
 use crate::syntax::SyntaxHighlighter;
@@ -29,12 +32,13 @@ use ratatui::{
     widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap},
     Frame, Terminal,
 };
-use similar::{ChangeTag, TextDiff};
+use similar::TextDiff;
 use std::cmp::Ordering;
 use std::collections::VecDeque;
 use std::collections::{HashMap, HashSet};
 use std::io::{self, BufRead, Stdout, Write};
 use std::mem;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::mpsc::{self, Receiver, Sender};
@@ -75,16 +79,36 @@ const HELP_TEXT: &[&str] = &[
     "    c         Collapse/expand current file",
     "    B         Toggle sidebar",
     "    b         Focus sidebar",
+    "    N / I / D Sidebar: git add -N, exclude or delete an untracked file",
     "    v         Toggle side-by-side view",
     "    s         Stage/unstage current hunk",
     "    D         Discard current hunk (unstaged)",
-    "    u         Toggle staged/unstaged view",
+    "    u         Toggle staged/unstaged view (stash: index/worktree)",
+    "    S         Toggle structural/line diff",
     "    R         Reload diff",
     "    Ctrl+r    Reload diff (global)",
     "    @         Send annotation to AI",
     "    P         Toggle AI pane",
     "    A         Annotation list",
     "",
+    "  Commits (differ show / differ log):",
+    "    ] / [     Next / previous commit in the range",
+    "    m         Mark/unmark commit as reviewed",
+    "",
+    "  Stashes:",
+    "    z         Stash list (Enter: view, a: apply, p: pop, D: drop)",
+    "    s         Apply current stash hunk to the worktree",
+    "",
+    "  Moved code (shown in its own colours):",
+    "    M         Jump to the other end of a moved block",
+    "",
+    "  Merge conflicts (unstaged view, status U):",
+    "    <         Take ours for the conflict under the cursor",
+    "    >         Take theirs for the conflict under the cursor",
+    "    =         Take both, ours first",
+    "    E         Edit the file in $EDITOR at the current line",
+    "    s         Mark the file resolved (git add)",
+    "",
     "  Annotations:",
     "    a         Add annotation at current line",
     "    e         Edit annotation at current line",
@@ -138,6 +162,10 @@ pub enum DisplayLine {
     },
     /// End of hunk marker for spacing
     HunkEnd { file_idx: usize, hunk_idx: usize },
+    /// Placeholder for files without line content (e.g. binary)
+    FileNotice { file_idx: usize, text: String },
+    /// Fold holding the untracked files, when they are grouped
+    UntrackedGroup { count: usize, open: bool },
     /// A diff line (addition, deletion, or context)
     Diff {
         line: DiffLine,
@@ -155,6 +183,15 @@ pub enum DisplayLine {
     },
 }
 
+/// Parsed git diff arguments: what a session shows
+#[derive(Debug)]
+pub struct DiffArgs {
+    pub mode: DiffMode,
+    pub paths: Vec<String>,
+    /// Commits to step through one at a time (`differ log`), oldest first
+    pub log_commits: Vec<CommitInfo>,
+}
+
 /// Application state
 #[allow(dead_code)]
 pub struct App {
@@ -165,6 +202,12 @@ pub struct App {
     config: Config,
     diff_mode: DiffMode,
     diff_paths: Vec<String>,
+    /// Metadata of the commit being shown, for a single-commit view
+    commit_info: Option<CommitInfo>,
+    /// Commits of a `differ log` range, oldest first
+    log_commits: Vec<CommitInfo>,
+    log_idx: usize,
+    reviewed_commits: HashSet<String>,
 
     // Diff state
     files: Vec<DiffFile>,
@@ -220,11 +263,17 @@ pub struct App {
     sidebar_focused: bool,
     sidebar_index: usize,
     sidebar_scroll: usize,
+    /// Whether the "N untracked files" group is unfolded
+    untracked_group_open: bool,
+    /// File waiting for a second D before it is deleted or discarded
+    pending_delete: Option<PathBuf>,
     expanded_file: Option<usize>, // When Some, only show this file
     collapsed_files: HashSet<String>,
     collapsed_files_unstaged: HashSet<String>,
     collapsed_files_staged: HashSet<String>,
     collapsed_files_other: HashSet<String>,
+    /// Pure renames collapsed on first sight, so a manual expand sticks
+    auto_collapsed_renames: HashSet<String>,
     selection_active: bool,
     selection_start: Option<usize>,
     selection_file_idx: Option<usize>,
@@ -235,6 +284,15 @@ pub struct App {
     pre_expand_file_line_ranges: Option<Vec<Option<(usize, usize)>>>,
     pre_expand_diff_generation: Option<u64>,
     annotation_list_idx: usize,
+    stash_entries: Vec<StashEntry>,
+    stash_list_idx: usize,
+    /// Pop or drop waiting for the same key again before it runs
+    pending_stash_action: Option<char>,
+    // File and line to open in $EDITOR once the terminal is released
+    pending_editor: Option<(PathBuf, u32)>,
+    // Range-diff annotations copied to the new series, and those whose line
+    // changed; None until the first range-diff has loaded
+    carried_annotations: Option<(usize, usize)>,
     goto_line_input: String,
     command_query: String,
     command_selected_idx: usize,
@@ -261,6 +319,7 @@ enum Mode {
     GotoLine,
     CommandPalette,
     CommitMessage,
+    StashList,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -273,6 +332,12 @@ enum CommandId {
     FocusSidebar,
     ToggleSideBySide,
     ToggleDiffView,
+    ToggleStructuralDiff,
+    ToggleIgnoreAllSpace,
+    ToggleIgnoreSpaceChange,
+    ToggleIgnoreBlankLines,
+    CycleDiffAlgorithm,
+    CycleUntrackedFiles,
     StageHunk,
     DiscardHunk,
     CollapseFile,
@@ -283,6 +348,12 @@ enum CommandId {
     ResolveAnnotation,
     ToggleAnnotationType,
     AnnotationList,
+    StashList,
+    JumpToMove,
+    TakeOurs,
+    TakeTheirs,
+    TakeBoth,
+    EditConflict,
     SendAnnotationToAi,
     CopySelection,
     ToggleSelection,
@@ -318,6 +389,17 @@ enum DiffStreamEvent {
         generation: u64,
         error: Option<String>,
     },
+    /// The commits of a range-diff matched up, sent once its files are
+    RangePairs {
+        generation: u64,
+        pairs: Vec<RangePair>,
+    },
+    /// A file left out over the limits, diffed again in full
+    Loaded {
+        generation: u64,
+        file_key: String,
+        result: Result<Option<DiffFile>, String>,
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -397,6 +479,12 @@ struct Theme {
     deleted_fg: Color,
     intraline_added_bg: Color,
     intraline_deleted_bg: Color,
+    conflict_ours_bg: Color,
+    conflict_base_bg: Color,
+    conflict_theirs_bg: Color,
+    conflict_marker_fg: Color,
+    moved_from_bg: Color,
+    moved_to_bg: Color,
     context_fg: Color,
     line_num: Color,
     annotation_bg: Color,
@@ -441,6 +529,12 @@ impl Default for Theme {
             deleted_fg: Color::Rgb(238, 170, 170),
             intraline_added_bg: Color::Rgb(28, 66, 38),
             intraline_deleted_bg: Color::Rgb(84, 34, 34),
+            conflict_ours_bg: Color::Rgb(24, 44, 64),
+            conflict_base_bg: Color::Rgb(44, 44, 48),
+            conflict_theirs_bg: Color::Rgb(56, 36, 64),
+            conflict_marker_fg: Color::Rgb(255, 150, 90),
+            moved_from_bg: Color::Rgb(52, 30, 58),
+            moved_to_bg: Color::Rgb(20, 46, 58),
             context_fg: Color::Rgb(220, 224, 230),
             line_num: Color::Rgb(120, 130, 140),
             annotation_bg: Color::Rgb(40, 76, 78),
@@ -467,11 +561,14 @@ impl App {
         diff_engine: DiffEngine,
         repo_path: PathBuf,
         repo_id: i64,
-        files: Vec<DiffFile>,
         config: Config,
-        diff_mode: DiffMode,
-        diff_paths: Vec<String>,
+        args: DiffArgs,
     ) -> Result<Self> {
+        let DiffArgs {
+            mode: diff_mode,
+            paths: diff_paths,
+            log_commits,
+        } = args;
         let show_annotations = config.show_annotations;
         let side_by_side = config.side_by_side;
         let (ai_tx, ai_rx) = mpsc::channel();
@@ -480,6 +577,11 @@ impl App {
         let (fs_tx, fs_rx) = mpsc::channel();
 
         let syntax_theme = config.syntax_theme.clone();
+        let commit_info = match &diff_mode {
+            DiffMode::Commit { rev } => Some(diff_engine.commit_info(rev)?),
+            _ => None,
+        };
+        let reviewed_commits = storage.reviewed_commits(repo_id)?;
 
         Ok(Self {
             storage,
@@ -489,7 +591,11 @@ impl App {
             config,
             diff_mode,
             diff_paths,
-            files,
+            commit_info,
+            log_commits,
+            log_idx: 0,
+            reviewed_commits,
+            files: Vec::new(),
             diff_file_index: HashMap::new(),
             display_lines: Vec::new(),
             file_line_ranges: Vec::new(),
@@ -536,11 +642,14 @@ impl App {
             sidebar_focused: false,
             sidebar_index: 0,
             sidebar_scroll: 0,
+            untracked_group_open: false,
+            pending_delete: None,
             expanded_file: None,
             collapsed_files: HashSet::new(),
             collapsed_files_unstaged: HashSet::new(),
             collapsed_files_staged: HashSet::new(),
             collapsed_files_other: HashSet::new(),
+            auto_collapsed_renames: HashSet::new(),
             selection_active: false,
             selection_start: None,
             selection_file_idx: None,
@@ -551,6 +660,11 @@ impl App {
             pre_expand_diff_generation: None,
             search: None,
             annotation_list_idx: 0,
+            stash_entries: Vec::new(),
+            stash_list_idx: 0,
+            pending_stash_action: None,
+            pending_editor: None,
+            carried_annotations: None,
             goto_line_input: String::new(),
             command_query: String::new(),
             command_selected_idx: 0,
@@ -570,6 +684,100 @@ impl App {
         Ok(())
     }
 
+    /// Whether an annotation shows on a line reading `content`. One made on
+    /// another commit only shows where its line still reads the same.
+    fn annotation_shown_on(&self, annotation: &Annotation, content: &str) -> bool {
+        annotation.commit_sha.is_none()
+            || annotation.commit_sha.as_deref() == self.annotation_commit_sha()
+            || annotation.anchor_text.is_empty()
+            || annotation.anchor_text.trim() == content.trim()
+    }
+
+    fn made_on_other_commit(&self, annotation: &Annotation) -> bool {
+        annotation.commit_sha.is_some()
+            && annotation.commit_sha.as_deref() != self.annotation_commit_sha()
+    }
+
+    /// Copy open annotations made on commits of the old series of a
+    /// range-diff to the matching lines of their counterparts in the new
+    /// series, so reviewing v2 with `differ log` starts from the v1 notes
+    fn carry_over_annotations(&mut self, pairs: &[RangePair]) -> Result<()> {
+        let annotations = self.storage.list_annotations(self.repo_id, None)?;
+        let mut carried = 0;
+        let mut lost = 0;
+        for pair in pairs {
+            let (Some((_, old)), Some((_, new))) = (&pair.old, &pair.new) else {
+                continue;
+            };
+            if old.sha == new.sha {
+                continue;
+            }
+            let from_old = annotations.iter().filter(|a| {
+                a.commit_sha.as_deref() == Some(old.sha.as_str()) && a.resolved_at.is_none()
+            });
+            for annotation in from_old {
+                let (old_rev, new_rev) = match annotation.side {
+                    Side::New => (old.sha.clone(), new.sha.clone()),
+                    Side::Old => (format!("{}^", old.sha), format!("{}^", new.sha)),
+                };
+                let show = |rev: &str| {
+                    git_show_at(
+                        &self.repo_path,
+                        &format!("{}:{}", rev, annotation.file_path),
+                    )
+                    .unwrap_or_default()
+                };
+                let (old_text, new_text) = (show(&old_rev), show(&new_rev));
+                let Some(start_line) = map_line(&old_text, &new_text, annotation.start_line) else {
+                    lost += 1;
+                    continue;
+                };
+                let end_line = annotation
+                    .end_line
+                    .map(|end| {
+                        map_line(&old_text, &new_text, end)
+                            .unwrap_or(start_line + end.saturating_sub(annotation.start_line))
+                    })
+                    .filter(|end| *end != start_line);
+                // Running the range-diff again must not copy twice
+                let exists = annotations.iter().any(|a| {
+                    a.commit_sha.as_deref() == Some(new.sha.as_str())
+                        && a.file_path == annotation.file_path
+                        && a.start_line == start_line
+                        && a.content == annotation.content
+                });
+                if exists {
+                    continue;
+                }
+                let lines: Vec<String> = new_text.lines().map(str::to_string).collect();
+                let (anchor_line, anchor_text, context_before, context_after) =
+                    Self::build_anchor(&lines, start_line);
+                self.storage.add_annotation(
+                    self.repo_id,
+                    &annotation.file_path,
+                    Some(&new.sha),
+                    annotation.side.clone(),
+                    start_line,
+                    end_line,
+                    annotation.annotation_type.clone(),
+                    &annotation.content,
+                    anchor_line,
+                    &anchor_text,
+                    &context_before,
+                    &context_after,
+                )?;
+                carried += 1;
+            }
+        }
+        self.carried_annotations = Some((carried, lost));
+        Ok(())
+    }
+
+    /// Commit recorded with new annotations, when viewing a single commit
+    fn annotation_commit_sha(&self) -> Option<&str> {
+        self.commit_info.as_ref().map(|info| info.sha.as_str())
+    }
+
     fn file_highlight_keys(file: &DiffFile) -> (String, String, String) {
         let file_key = file
             .new_path
@@ -637,7 +845,7 @@ impl App {
                 .unwrap_or_else(|| "<unknown>".to_string());
 
             let include_spacer = has_any && expanded_file.is_none();
-            let file_lines = self.build_file_lines(file_idx, &file, &file_path, include_spacer);
+            let file_lines = self.build_file_lines(file_idx, file, &file_path, include_spacer);
             let start = self.display_lines.len();
             self.display_lines.extend(file_lines);
             let len = self.display_lines.len().saturating_sub(start);
@@ -656,12 +864,29 @@ impl App {
         include_spacer: bool,
     ) -> Vec<DisplayLine> {
         let mut lines = Vec::new();
+        let grouped = file.untracked
+            && self.config.untracked_files == UntrackedFiles::Group
+            && self.expanded_file.is_none();
+        let first_untracked = self.files.iter().position(|f| f.untracked);
+        if grouped && first_untracked != Some(file_idx) && !self.untracked_group_open {
+            return lines;
+        }
 
         if include_spacer {
             lines.push(DisplayLine::Spacer);
             lines.push(DisplayLine::Spacer);
         }
 
+        if grouped && first_untracked == Some(file_idx) {
+            lines.push(DisplayLine::UntrackedGroup {
+                count: self.files.iter().filter(|f| f.untracked).count(),
+                open: self.untracked_group_open,
+            });
+            if !self.untracked_group_open {
+                return lines;
+            }
+        }
+
         lines.push(DisplayLine::FileHeader {
             path: file_path.to_string(),
             file_idx,
@@ -671,6 +896,18 @@ impl App {
             return lines;
         }
 
+        let prefixes = self.config.path_prefixes(&self.diff_mode);
+        let notices = file_notices(file, prefixes);
+        if !notices.is_empty() {
+            lines.push(DisplayLine::Spacer);
+        }
+        for text in notices {
+            lines.push(DisplayLine::FileNotice { file_idx, text });
+        }
+        if file.binary || file.status == FileStatus::Submodule {
+            return lines;
+        }
+
         if self.expanded_file.is_some() {
             self.build_expanded_file_lines_into(&mut lines, file_idx, file, file_path);
         } else {
@@ -682,30 +919,19 @@ impl App {
     }
 
     fn git_show(&self, spec: &str) -> Option<String> {
-        let output = Command::new("git")
-            .arg("show")
-            .arg(spec)
-            .current_dir(&self.repo_path)
-            .output()
-            .ok()?;
-        if output.status.success() {
-            Some(String::from_utf8_lossy(&output.stdout).to_string())
-        } else {
-            None
-        }
+        git_show_at(&self.repo_path, spec)
     }
 
     fn read_working_file(&self, path: &str) -> Option<String> {
-        let full_path = self.repo_path.join(path);
-        std::fs::read(&full_path)
-            .ok()
-            .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+        read_worktree_text(&self.repo_path, Path::new(path))
     }
 
     fn read_file_lines(&self, file_path: &str) -> Option<Vec<String>> {
-        let full_path = self.repo_path.join(file_path);
-        let content = std::fs::read(&full_path).ok()?;
-        let text = String::from_utf8_lossy(&content);
+        if let Some(sha) = self.annotation_commit_sha() {
+            let text = self.git_show(&format!("{}:{}", sha, file_path))?;
+            return Some(text.lines().map(|l| l.to_string()).collect());
+        }
+        let text = self.read_working_file(file_path)?;
         Some(text.lines().map(|l| l.to_string()).collect())
     }
 
@@ -824,12 +1050,7 @@ impl App {
                     Ok(h) => h,
                     Err(_) => return,
                 };
-                let content = load_new_file_content_for_highlight(
-                    &repo_path,
-                    &diff_mode,
-                    &file,
-                    &file_key_clone,
-                );
+                let content = load_new_file_content(&repo_path, &diff_mode, &file, &file_key_clone);
                 let map = content
                     .as_ref()
                     .map(|c| build_highlight_map_with(&mut highlighter, c, &file_key_clone))
@@ -940,10 +1161,54 @@ impl App {
                     }
                 }
             }
-            _ => {
+            DiffMode::Commit { ref rev } => {
+                if need_old {
+                    if let Some(path) = old_path {
+                        old_content = self.git_show(&format!("{}^:{}", rev, path));
+                    }
+                }
+                if need_new {
+                    if let Some(path) = new_path {
+                        new_content = self.git_show(&format!("{}:{}", rev, path));
+                    }
+                }
+            }
+            DiffMode::Stash { index, staged } => {
+                if need_old {
+                    if let Some(path) = old_path {
+                        let base = if staged { "^1" } else { "^2" };
+                        old_content =
+                            self.git_show(&format!("{}{}:{}", stash_rev(index), base, path));
+                    }
+                }
+                if need_new {
+                    if let Some(path) = new_path {
+                        new_content = stash_file_content(&self.repo_path, index, staged, path);
+                    }
+                }
+            }
+            DiffMode::Patch { .. } | DiffMode::RangeDiff { .. } => {}
+            DiffMode::NoIndex { ref old, ref new } => {
+                if need_old {
+                    if let Some(path) = old_path {
+                        old_content = read_text(&no_index_path(old, Path::new(path)));
+                    }
+                }
+                if need_new {
+                    if let Some(path) = new_path {
+                        new_content = read_text(&no_index_path(new, Path::new(path)));
+                    }
+                }
+            }
+            DiffMode::ExternalDiff { ref files } => {
+                if need_old {
+                    if let Some(path) = old_path {
+                        old_content = external_side(&self.repo_path, files, path, Side::Old);
+                    }
+                }
                 if need_new {
                     if let Some(path) = new_path.or(old_path) {
-                        new_content = self.read_working_file(path);
+                        new_content = external_side(&self.repo_path, files, path, Side::New);
                     }
                 }
             }
@@ -968,6 +1233,49 @@ impl App {
         (old_map, new_map)
     }
 
+    /// Token-level intraline ranges for a hunk, tokenized with the file's syntax.
+    /// Old and new sides are tokenized as separate streams, as they appear in
+    /// their respective files.
+    fn intraline_ranges(&mut self, file_path: &str, lines: &[DiffLine]) -> Vec<Vec<InlineRange>> {
+        let has_pairs = lines.iter().any(|l| l.kind == LineKind::Deletion)
+            && lines.iter().any(|l| l.kind == LineKind::Addition);
+        if !has_pairs {
+            return vec![Vec::new(); lines.len()];
+        }
+        let old_side: Vec<&str> = lines
+            .iter()
+            .filter(|l| l.kind != LineKind::Addition)
+            .map(|l| l.content.as_str())
+            .collect();
+        let new_side: Vec<&str> = lines
+            .iter()
+            .filter(|l| l.kind != LineKind::Deletion)
+            .map(|l| l.content.as_str())
+            .collect();
+        let mut old_tokens = self
+            .syntax_highlighter
+            .tokenize_lines(&old_side, file_path)
+            .into_iter();
+        let mut new_tokens = self
+            .syntax_highlighter
+            .tokenize_lines(&new_side, file_path)
+            .into_iter();
+
+        let mut tokens = Vec::with_capacity(lines.len());
+        for line in lines {
+            let line_tokens = match line.kind {
+                LineKind::Deletion => old_tokens.next(),
+                LineKind::Addition => new_tokens.next(),
+                LineKind::Context => {
+                    old_tokens.next();
+                    new_tokens.next()
+                }
+            };
+            tokens.push(line_tokens.unwrap_or_default());
+        }
+        compute_intraline_ranges(lines, &tokens)
+    }
+
     /// Build display lines for diff hunks (normal mode)
     fn build_diff_hunk_lines_into(
         &mut self,
@@ -983,7 +1291,7 @@ impl App {
             let inline_ranges = if self.diff_loading {
                 vec![Vec::new(); hunk.lines.len()]
             } else {
-                compute_intraline_ranges(&hunk.lines)
+                self.intraline_ranges(file_path, &hunk.lines)
             };
             let (hunk_highlights, hunk_needs_full) =
                 self.highlight_hunk_lines(file_path, &hunk.lines);
@@ -1042,7 +1350,7 @@ impl App {
 
             for (line_idx, line) in hunk.lines.iter().enumerate() {
                 let mut highlighted_line = line.clone();
-                if let Some(ranges) = inline_ranges.get(line_idx) {
+                if let Some(ranges) = inline_ranges.get(line_idx).filter(|r| !r.is_empty()) {
                     highlighted_line.inline_ranges = ranges.clone();
                 }
                 let mut applied = false;
@@ -1101,7 +1409,7 @@ impl App {
             let inline_ranges = if self.diff_loading {
                 vec![Vec::new(); hunk.lines.len()]
             } else {
-                compute_intraline_ranges(&hunk.lines)
+                self.intraline_ranges(file_path, &hunk.lines)
             };
             let old_range = hunk.old_start
                 ..=hunk
@@ -1119,10 +1427,12 @@ impl App {
                         if let Some(n) = line.new_line_no {
                             additions.insert(n, line.content.clone());
                             last_new_line = n;
-                            if let Some(ranges) = inline_ranges.get(line_idx) {
-                                if !ranges.is_empty() {
-                                    inline_additions.insert(n, ranges.clone());
-                                }
+                            let ranges = inline_ranges
+                                .get(line_idx)
+                                .filter(|r| !r.is_empty())
+                                .unwrap_or(&line.inline_ranges);
+                            if !ranges.is_empty() {
+                                inline_additions.insert(n, ranges.clone());
                             }
                         }
                     }
@@ -1140,9 +1450,9 @@ impl App {
             }
         }
 
-        // Read the full file from disk
-        let full_path = self.repo_path.join(file_path);
-        let file_content = std::fs::read_to_string(&full_path).unwrap_or_default();
+        // The whole new side, wherever the mode keeps it
+        let file_content = load_new_file_content(&self.repo_path, &self.diff_mode, file, file_path)
+            .unwrap_or_default();
         let file_lines: Vec<&str> = file_content.lines().collect();
         let (file_key, old_key, _new_key) = Self::file_highlight_keys(file);
         let old_path = file
@@ -1199,6 +1509,11 @@ impl App {
                             content: del_content.clone(),
                             highlights: Vec::new(),
                             inline_ranges: Vec::new(),
+                            conflict: None,
+                            combined: Vec::new(),
+                            moved: None,
+                            ending: LineEnding::Lf,
+                            raw: None,
                         };
                         self.highlight_from_maps(&mut del_line, &old_map, &[]);
                         let hunk_idx = hunk_ranges
@@ -1228,6 +1543,11 @@ impl App {
                 content: content.to_string(),
                 highlights: Vec::new(),
                 inline_ranges: Vec::new(),
+                conflict: None,
+                combined: Vec::new(),
+                moved: None,
+                ending: LineEnding::Lf,
+                raw: None,
             };
             if let Some(ranges) = inline_additions.get(&line_no) {
                 diff_line.inline_ranges = ranges.clone();
@@ -1269,6 +1589,11 @@ impl App {
                         content: del_content.clone(),
                         highlights: Vec::new(),
                         inline_ranges: Vec::new(),
+                        conflict: None,
+                        combined: Vec::new(),
+                        moved: None,
+                        ending: LineEnding::Lf,
+                        raw: None,
                     };
                     self.highlight_from_maps(&mut del_line, &old_map, &[]);
                     let hunk_idx = hunk_ranges
@@ -1312,7 +1637,9 @@ impl App {
                     .end_line
                     .map_or(annotation.start_line == line_no, |e| e >= line_no)
             {
-                if annotation.start_line != line_no {
+                if annotation.start_line != line_no
+                    || !self.annotation_shown_on(annotation, &line.content)
+                {
                     continue;
                 }
                 let orphaned = annotation.side == Side::New
@@ -1331,8 +1658,9 @@ impl App {
         &self,
         file_path: &str,
         side: Side,
-        line_no: u32,
+        line: &DiffLine,
     ) -> Option<char> {
+        let line_no = line.new_line_no.or(line.old_line_no).unwrap_or(0);
         let mut has_range = false;
         for a in &self.all_annotations {
             if a.file_path != file_path || a.side != side {
@@ -1342,9 +1670,12 @@ impl App {
                 && a.end_line.map_or(a.start_line == line_no, |e| e >= line_no)
             {
                 if a.start_line == line_no {
-                    return Some('●');
+                    if self.annotation_shown_on(a, &line.content) {
+                        return Some('●');
+                    }
+                } else if !self.made_on_other_commit(a) {
+                    has_range = true;
                 }
-                has_range = true;
             }
         }
         if has_range {
@@ -1447,9 +1778,7 @@ impl App {
     }
 
     fn find_line_in_expanded(&self, line_no: u32) -> Option<usize> {
-        if self.expanded_file.is_none() {
-            return None;
-        }
+        self.expanded_file?;
         for (idx, line) in self.display_lines.iter().enumerate() {
             if let DisplayLine::Diff { line, .. } = line {
                 if line.new_line_no == Some(line_no) {
@@ -1461,9 +1790,7 @@ impl App {
     }
 
     fn find_old_line_in_expanded(&self, line_no: u32) -> Option<usize> {
-        if self.expanded_file.is_none() {
-            return None;
-        }
+        self.expanded_file?;
         for (idx, line) in self.display_lines.iter().enumerate() {
             if let DisplayLine::Diff { line, .. } = line {
                 if line.old_line_no == Some(line_no) {
@@ -1505,6 +1832,12 @@ impl App {
 
     /// Get the current file info (path and index) based on current position
     fn current_file_info(&self) -> Option<(String, usize)> {
+        if matches!(
+            self.current_display_line(),
+            Some(DisplayLine::UntrackedGroup { .. })
+        ) {
+            return None;
+        }
         // Search backwards to find the most recent file header
         for i in (0..=self.current_line_idx).rev() {
             if let Some(DisplayLine::FileHeader { path, file_idx }) = self.display_lines.get(i) {
@@ -1604,6 +1937,11 @@ impl App {
                     && a.side == side
                     && a.start_line <= line_no
                     && a.end_line.map_or(a.start_line == line_no, |e| e >= line_no)
+                    && if a.start_line == line_no {
+                        self.annotation_shown_on(a, &line.content)
+                    } else {
+                        !self.made_on_other_commit(a)
+                    }
             });
         }
         None
@@ -1647,10 +1985,11 @@ impl App {
             };
 
             let content = self.annotation_text();
+            let commit_sha = self.annotation_commit_sha().map(str::to_string);
             let id = self.storage.add_annotation(
                 self.repo_id,
                 &file_path,
-                None, // commit_sha
+                commit_sha.as_deref(),
                 side.clone(),
                 start_line,
                 if start_line == end_line {
@@ -1672,7 +2011,7 @@ impl App {
                 id,
                 repo_id: self.repo_id,
                 file_path: file_path.clone(),
-                commit_sha: None,
+                commit_sha,
                 side,
                 start_line,
                 end_line: if start_line == end_line {
@@ -1952,14 +2291,14 @@ impl App {
             return Ok(false);
         }
 
-        if key.code == KeyCode::Char('?') {
-            if matches!(self.mode, Mode::Normal | Mode::AnnotationList) {
-                self.show_help = !self.show_help;
-                if self.show_help {
-                    self.help_scroll = 0;
-                }
-                return Ok(false);
+        if key.code == KeyCode::Char('?')
+            && matches!(self.mode, Mode::Normal | Mode::AnnotationList)
+        {
+            self.show_help = !self.show_help;
+            if self.show_help {
+                self.help_scroll = 0;
             }
+            return Ok(false);
         }
 
         if self.show_help {
@@ -1986,10 +2325,14 @@ impl App {
             Mode::GotoLine => self.handle_goto_line_input(key),
             Mode::CommandPalette => self.handle_command_palette_input(key),
             Mode::CommitMessage => self.handle_commit_input(key),
+            Mode::StashList => self.handle_stash_list_input(key),
         }
     }
 
     fn handle_normal_input(&mut self, key: KeyEvent) -> Result<bool> {
+        if key.code != KeyCode::Char('D') {
+            self.pending_delete = None;
+        }
         // Handle Ctrl+key combinations first
         if key.modifiers.contains(KeyModifiers::CONTROL) {
             match key.code {
@@ -2037,16 +2380,12 @@ impl App {
             }
         }
 
-        match key.code {
-            KeyCode::Char('q') => return Ok(true), // Quit
-            _ => {}
+        if let KeyCode::Char('q') = key.code {
+            return Ok(true); // Quit
         }
-        match key.code {
-            KeyCode::Char('V') => {
-                self.toggle_selection();
-                return Ok(false);
-            }
-            _ => {}
+        if let KeyCode::Char('V') = key.code {
+            self.toggle_selection();
+            return Ok(false);
         }
         match key.code {
             // Navigation
@@ -2116,7 +2455,14 @@ impl App {
 
             // Toggle views
             KeyCode::Char('c') => {
-                self.toggle_collapse_current_file();
+                if matches!(
+                    self.current_display_line(),
+                    Some(DisplayLine::UntrackedGroup { .. })
+                ) {
+                    self.toggle_untracked_group();
+                } else {
+                    self.toggle_collapse_current_file();
+                }
             }
             KeyCode::Char('h') => {
                 self.toggle_old_in_expanded();
@@ -2145,6 +2491,15 @@ impl App {
             KeyCode::Char('P') => {
                 self.show_ai_pane = !self.show_ai_pane;
             }
+            KeyCode::Char(']') => self.step_commit(1)?,
+            KeyCode::Char('[') => self.step_commit(-1)?,
+            KeyCode::Char('m') => self.toggle_commit_reviewed()?,
+            KeyCode::Char('z') => self.open_stash_list()?,
+            KeyCode::Char('<') => self.resolve_current_conflict(ConflictChoice::Ours)?,
+            KeyCode::Char('>') => self.resolve_current_conflict(ConflictChoice::Theirs)?,
+            KeyCode::Char('=') => self.resolve_current_conflict(ConflictChoice::Both)?,
+            KeyCode::Char('E') => self.edit_current_conflict(),
+            KeyCode::Char('M') => self.jump_to_move_counterpart(),
             KeyCode::Char('s') => {
                 self.toggle_stage_current_hunk()?;
             }
@@ -2154,6 +2509,9 @@ impl App {
             KeyCode::Char('u') => {
                 self.toggle_diff_view()?;
             }
+            KeyCode::Char('S') => {
+                self.toggle_structural_diff()?;
+            }
             KeyCode::Char('R') => {
                 self.reload_diff()?;
                 self.message = Some("Diff reloaded".to_string());
@@ -2280,19 +2638,86 @@ impl App {
                 self.focus_file_from_sidebar(&entries);
             }
             KeyCode::Enter => {
-                self.sidebar_focused = false;
+                if entries.get(self.sidebar_index).is_some_and(|e| e.group) {
+                    self.toggle_untracked_group();
+                } else {
+                    self.sidebar_focused = false;
+                }
             }
             KeyCode::Esc => {
                 self.sidebar_focused = false;
             }
+            KeyCode::Char(c @ ('N' | 'I' | 'D')) => {
+                if let Some(entry) = entries.get(self.sidebar_index) {
+                    self.untracked_file_action(entry, c)?;
+                }
+            }
             _ => {}
         }
+        if key.code != KeyCode::Char('D') {
+            self.pending_delete = None;
+        }
         Ok(false)
     }
 
+    /// Sidebar actions on an untracked file: N marks it intent-to-add,
+    /// I lists it in `info/exclude`, D deletes it (pressed twice)
+    fn untracked_file_action(&mut self, entry: &SidebarEntry, action: char) -> Result<()> {
+        if entry.group {
+            self.message = Some("Unfold the group (Enter) to pick a file".to_string());
+            return Ok(());
+        }
+        let Some(path) = self
+            .files
+            .get(entry.file_idx)
+            .filter(|f| f.untracked)
+            .and_then(|f| f.new_path.clone())
+        else {
+            self.message = Some("Not an untracked file".to_string());
+            return Ok(());
+        };
+        let shown = path.to_string_lossy().to_string();
+        let result = match action {
+            'N' => self
+                .run_git_for_paths(&["add", "-N"], std::slice::from_ref(&path))
+                .map(|()| format!("Intent to add {}", shown)),
+            'I' => self
+                .diff_engine
+                .exclude_untracked(&path)
+                .map(|()| format!("Excluded {} in info/exclude", shown))
+                .map_err(|e| e.to_string()),
+            _ if self.pending_delete.as_ref() != Some(&path) => {
+                self.message = Some(format!("Press D again to delete {}", shown));
+                self.pending_delete = Some(path);
+                return Ok(());
+            }
+            _ => {
+                self.pending_delete = None;
+                std::fs::remove_file(self.repo_path.join(&path))
+                    .map(|()| format!("Deleted {}", shown))
+                    .map_err(|e| e.to_string())
+            }
+        };
+        match result {
+            Ok(msg) => {
+                self.reload_diff()?;
+                self.message = Some(msg);
+            }
+            Err(err) => self.message = Some(format!("Failed: {}", err)),
+        }
+        Ok(())
+    }
+
     fn focus_file_from_sidebar(&mut self, entries: &[SidebarEntry]) {
         if let Some(entry) = entries.get(self.sidebar_index) {
-            if let Some(idx) = self.find_file_header_idx(entry.file_idx) {
+            let line = if entry.group {
+                self.display_lines
+                    .iter()
+                    .position(|l| matches!(l, DisplayLine::UntrackedGroup { .. }))
+            } else {
+                self.find_file_header_idx(entry.file_idx)
+            };
+            if let Some(idx) = line {
                 self.current_line_idx = idx;
                 self.ensure_cursor_on_navigable();
                 self.adjust_scroll();
@@ -2400,11 +2825,7 @@ impl App {
         if self.display_lines.is_empty() {
             return;
         }
-        let max_offset = self
-            .display_lines
-            .len()
-            .saturating_sub(self.visible_height)
-            .max(0);
+        let max_offset = self.display_lines.len().saturating_sub(self.visible_height);
         let mut offset = self.scroll_offset as i32 + delta;
         if offset < 0 {
             offset = 0;
@@ -2507,7 +2928,7 @@ impl App {
         if self.sidebar_open {
             self.sidebar_focused = true;
             if let Some((_, file_idx)) = self.current_file_info() {
-                self.sidebar_index = file_idx.min(self.files.len().saturating_sub(1));
+                self.sidebar_index = self.sidebar_index_for_file(file_idx);
             } else {
                 self.sidebar_index = 0;
             }
@@ -2555,6 +2976,21 @@ impl App {
         });
     }
 
+    fn open_stash_list(&mut self) -> Result<()> {
+        self.stash_entries = self.diff_engine.list_stashes()?;
+        if self.stash_entries.is_empty() {
+            self.message = Some("No stashes".to_string());
+            return Ok(());
+        }
+        self.stash_list_idx = match self.diff_mode {
+            DiffMode::Stash { index, .. } => index.min(self.stash_entries.len() - 1),
+            _ => 0,
+        };
+        self.pending_stash_action = None;
+        self.mode = Mode::StashList;
+        Ok(())
+    }
+
     fn open_annotation_list(&mut self) {
         self.mode = Mode::AnnotationList;
         self.annotation_list_idx = 0;
@@ -2579,6 +3015,53 @@ impl App {
         }
     }
 
+    /// Fetch the hunks of a file that was left out over the size limits,
+    /// in the background like the rest of the diff
+    fn load_deferred_file(&mut self, file_idx: usize) {
+        let Some(file) = self.files.get(file_idx).cloned() else {
+            return;
+        };
+        self.diff_engine.keep_in_full(&file);
+        let (file_key, _, _) = Self::file_highlight_keys(&file);
+        let mut diff_engine = self.diff_engine.clone();
+        let diff_mode = self.diff_mode.clone();
+        let diff_paths = self.diff_paths.clone();
+        let generation = self.diff_generation;
+        let tx = self.diff_tx.clone();
+        thread::spawn(move || {
+            let result = diff_engine
+                .load_in_full(&diff_mode, &diff_paths, &file)
+                .map_err(|e| e.to_string());
+            let _ = tx.send(DiffStreamEvent::Loaded {
+                generation,
+                file_key,
+                result,
+            });
+        });
+        self.message = Some("Loading file...".to_string());
+    }
+
+    fn handle_loaded_file(&mut self, file_key: &str, result: Result<Option<DiffFile>, String>) {
+        let Some(file_idx) = self.diff_file_index.get(file_key).copied() else {
+            return;
+        };
+        match result {
+            Ok(Some(loaded)) => {
+                let lines = loaded.changed_lines();
+                self.files[file_idx] = loaded;
+                // Its lines can be moved from or to any other file
+                detect_moves(&mut self.files);
+                self.invalidate_pre_expand_cache();
+                self.build_display_lines();
+                self.ensure_cursor_on_navigable();
+                self.adjust_scroll();
+                self.message = Some(format!("Loaded {} changed lines", format_count(lines)));
+            }
+            Ok(None) => self.message = Some("File is no longer in the diff".to_string()),
+            Err(err) => self.message = Some(format!("Failed to load file: {}", err)),
+        }
+    }
+
     fn toggle_expanded_view(&mut self) {
         if self.expanded_file.is_some() {
             // Collapse back to all files - restore previous position
@@ -2625,8 +3108,20 @@ impl App {
             self.adjust_scroll();
             self.message = Some("Showing all files".to_string());
         } else {
+            if matches!(self.diff_mode, DiffMode::Patch { .. }) {
+                self.message = Some("Full-file view is not available for patches".to_string());
+                return;
+            }
             // Expand current file - save position first
             if let Some((_, file_idx)) = self.current_file_info() {
+                if self
+                    .files
+                    .get(file_idx)
+                    .is_some_and(|f| f.deferred.is_some())
+                {
+                    self.load_deferred_file(file_idx);
+                    return;
+                }
                 if !self.diff_loading {
                     self.pre_expand_display_lines = Some(self.display_lines.clone());
                     self.pre_expand_file_line_ranges = Some(self.file_line_ranges.clone());
@@ -2773,6 +3268,36 @@ impl App {
                 label: "Toggle staged/unstaged",
                 keywords: "staged unstaged view",
             },
+            CommandEntry {
+                id: CommandId::ToggleStructuralDiff,
+                label: "Toggle structural diff",
+                keywords: "structural syntax token line diff",
+            },
+            CommandEntry {
+                id: CommandId::ToggleIgnoreAllSpace,
+                label: "Toggle ignore all whitespace (-w)",
+                keywords: "whitespace ignore all space",
+            },
+            CommandEntry {
+                id: CommandId::ToggleIgnoreSpaceChange,
+                label: "Toggle ignore whitespace changes (-b)",
+                keywords: "whitespace ignore space change amount",
+            },
+            CommandEntry {
+                id: CommandId::ToggleIgnoreBlankLines,
+                label: "Toggle ignore blank lines",
+                keywords: "whitespace ignore blank empty lines",
+            },
+            CommandEntry {
+                id: CommandId::CycleDiffAlgorithm,
+                label: "Cycle diff algorithm",
+                keywords: "algorithm myers patience histogram minimal",
+            },
+            CommandEntry {
+                id: CommandId::CycleUntrackedFiles,
+                label: "Cycle untracked files (show/names/group/hide)",
+                keywords: "untracked files show names group hide",
+            },
             CommandEntry {
                 id: CommandId::StageHunk,
                 label: "Stage/unstage hunk",
@@ -2798,6 +3323,36 @@ impl App {
                 label: "Annotation list",
                 keywords: "annotations list",
             },
+            CommandEntry {
+                id: CommandId::StashList,
+                label: "Stash list",
+                keywords: "stash apply pop drop",
+            },
+            CommandEntry {
+                id: CommandId::JumpToMove,
+                label: "Jump to other end of moved code",
+                keywords: "moved move jump",
+            },
+            CommandEntry {
+                id: CommandId::TakeOurs,
+                label: "Conflict: take ours",
+                keywords: "conflict merge resolve ours",
+            },
+            CommandEntry {
+                id: CommandId::TakeTheirs,
+                label: "Conflict: take theirs",
+                keywords: "conflict merge resolve theirs",
+            },
+            CommandEntry {
+                id: CommandId::TakeBoth,
+                label: "Conflict: take both",
+                keywords: "conflict merge resolve both union",
+            },
+            CommandEntry {
+                id: CommandId::EditConflict,
+                label: "Conflict: edit in $EDITOR",
+                keywords: "conflict merge resolve edit editor",
+            },
             CommandEntry {
                 id: CommandId::AddAnnotation,
                 label: "Add annotation",
@@ -2866,13 +3421,29 @@ impl App {
         match id {
             CommandId::GotoLine | CommandId::ToggleOldDeletions => self.expanded_file.is_some(),
             CommandId::FocusSidebar => self.sidebar_open,
+            CommandId::CycleUntrackedFiles => matches!(self.diff_mode, DiffMode::Unstaged),
             CommandId::StageHunk => {
-                matches!(self.diff_mode, DiffMode::Unstaged | DiffMode::Staged)
-                    && self.current_hunk_ref().is_some()
+                matches!(
+                    self.diff_mode,
+                    DiffMode::Unstaged | DiffMode::Staged | DiffMode::Stash { .. }
+                ) && !self.config.structural_diff
+                    && (self.current_hunk_ref().is_some()
+                        || self.current_file_level_target().is_some())
             }
             CommandId::DiscardHunk => {
-                matches!(self.diff_mode, DiffMode::Unstaged) && self.current_hunk_ref().is_some()
-            }
+                matches!(self.diff_mode, DiffMode::Unstaged)
+                    && !self.config.structural_diff
+                    && (self.current_hunk_ref().is_some()
+                        || self.current_file_level_target().is_some())
+            }
+            CommandId::JumpToMove => matches!(
+                self.current_display_line(),
+                Some(DisplayLine::Diff { line, .. }) if line.moved.is_some()
+            ),
+            CommandId::TakeOurs
+            | CommandId::TakeTheirs
+            | CommandId::TakeBoth
+            | CommandId::EditConflict => self.current_conflicted_file().is_some(),
             CommandId::AddAnnotation => self.can_add_annotation(),
             CommandId::EditAnnotation
             | CommandId::DeleteAnnotation
@@ -3048,6 +3619,28 @@ impl App {
             CommandId::ToggleDiffView => {
                 self.toggle_diff_view()?;
             }
+            CommandId::ToggleStructuralDiff => {
+                self.toggle_structural_diff()?;
+            }
+            CommandId::ToggleIgnoreAllSpace => {
+                self.config.ignore_all_space = !self.config.ignore_all_space;
+                self.apply_diff_options()?;
+            }
+            CommandId::ToggleIgnoreSpaceChange => {
+                self.config.ignore_space_change = !self.config.ignore_space_change;
+                self.apply_diff_options()?;
+            }
+            CommandId::ToggleIgnoreBlankLines => {
+                self.config.ignore_blank_lines = !self.config.ignore_blank_lines;
+                self.apply_diff_options()?;
+            }
+            CommandId::CycleDiffAlgorithm => {
+                self.config.diff_algorithm = self.config.diff_algorithm.next();
+                self.apply_diff_options()?;
+            }
+            CommandId::CycleUntrackedFiles => {
+                self.cycle_untracked_files()?;
+            }
             CommandId::StageHunk => {
                 self.toggle_stage_current_hunk()?;
             }
@@ -3063,6 +3656,14 @@ impl App {
             CommandId::AnnotationList => {
                 self.open_annotation_list();
             }
+            CommandId::StashList => {
+                self.open_stash_list()?;
+            }
+            CommandId::JumpToMove => self.jump_to_move_counterpart(),
+            CommandId::TakeOurs => self.resolve_current_conflict(ConflictChoice::Ours)?,
+            CommandId::TakeTheirs => self.resolve_current_conflict(ConflictChoice::Theirs)?,
+            CommandId::TakeBoth => self.resolve_current_conflict(ConflictChoice::Both)?,
+            CommandId::EditConflict => self.edit_current_conflict(),
             CommandId::AddAnnotation => {
                 if self.selection_active {
                     if let Some((_, side, start, end)) = self.selection_range_for_annotation() {
@@ -3368,24 +3969,117 @@ impl App {
         Ok(false)
     }
 
-    fn handle_ai_event(&mut self, evt: AiEvent) -> Result<()> {
-        match evt {
-            AiEvent::Output { job_id, chunk } => {
-                if let Some(job) = self.ai_jobs.iter_mut().find(|j| j.id == job_id) {
-                    match job.target {
-                        AiTarget::Claude => {
-                            if let Some(text) = extract_claude_text(&chunk) {
-                                job.output.push_str(&text);
-                            }
-                        }
-                        AiTarget::Codex => {
-                            job.output.push_str(&chunk);
-                        }
-                    }
-                }
-            }
-            AiEvent::Done { job_id, ok } => {
-                let mut resolve_target: Option<(i64, String)> = None;
+    fn handle_stash_list_input(&mut self, key: KeyEvent) -> Result<bool> {
+        let Some(index) = self
+            .stash_entries
+            .get(self.stash_list_idx)
+            .map(|entry| entry.index)
+        else {
+            self.mode = Mode::Normal;
+            return Ok(false);
+        };
+
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('q') => {
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Char('j') | KeyCode::Down => {
+                self.stash_list_idx =
+                    (self.stash_list_idx + 1).min(self.stash_entries.len().saturating_sub(1));
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                self.stash_list_idx = self.stash_list_idx.saturating_sub(1);
+            }
+            KeyCode::Enter => {
+                self.mode = Mode::Normal;
+                self.switch_diff_mode(DiffMode::Stash {
+                    index,
+                    staged: false,
+                })?;
+            }
+            KeyCode::Char('a') => self.run_stash_action("apply", index)?,
+            // Both throw the stash away, so they want a second press
+            KeyCode::Char(c @ ('p' | 'D')) => {
+                if self.pending_stash_action == Some(c) {
+                    self.pending_stash_action = None;
+                    let action = if c == 'p' { "pop" } else { "drop" };
+                    self.run_stash_action(action, index)?;
+                } else {
+                    self.pending_stash_action = Some(c);
+                }
+                return Ok(false);
+            }
+            _ => {}
+        }
+
+        self.pending_stash_action = None;
+        Ok(false)
+    }
+
+    /// `git stash apply|pop|drop stash@{index}`, then refresh the list and view
+    fn run_stash_action(&mut self, action: &str, index: usize) -> Result<()> {
+        let output = Command::new("git")
+            .arg("stash")
+            .arg(action)
+            .arg(stash_rev(index))
+            .current_dir(&self.repo_path)
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            self.message = Some(format!("git stash {} failed: {}", action, stderr));
+            return Ok(());
+        }
+
+        self.stash_entries = self.diff_engine.list_stashes()?;
+        self.stash_list_idx = self
+            .stash_list_idx
+            .min(self.stash_entries.len().saturating_sub(1));
+        if self.stash_entries.is_empty() {
+            self.mode = Mode::Normal;
+        }
+        let removed = action != "apply";
+        match self.diff_mode {
+            // Later stashes moved up by one; keep showing a stash that still exists
+            DiffMode::Stash { index: shown, .. } if removed => {
+                if self.stash_entries.is_empty() {
+                    self.switch_diff_mode(DiffMode::Unstaged)?;
+                } else {
+                    let shown = if shown > index { shown - 1 } else { shown };
+                    self.switch_diff_mode(DiffMode::Stash {
+                        index: shown.min(self.stash_entries.len() - 1),
+                        staged: false,
+                    })?;
+                }
+            }
+            DiffMode::Stash { .. } => {}
+            _ => {
+                self.cached_unstaged = None;
+                self.cached_staged = None;
+                self.reload_diff()?;
+            }
+        }
+        self.message = Some(format!("git stash {} {}", action, stash_rev(index)));
+        Ok(())
+    }
+
+    fn handle_ai_event(&mut self, evt: AiEvent) -> Result<()> {
+        match evt {
+            AiEvent::Output { job_id, chunk } => {
+                if let Some(job) = self.ai_jobs.iter_mut().find(|j| j.id == job_id) {
+                    match job.target {
+                        AiTarget::Claude => {
+                            if let Some(text) = extract_claude_text(&chunk) {
+                                job.output.push_str(&text);
+                            }
+                        }
+                        AiTarget::Codex => {
+                            job.output.push_str(&chunk);
+                        }
+                    }
+                }
+            }
+            AiEvent::Done { job_id, ok } => {
+                let mut resolve_target: Option<(i64, String)> = None;
                 if let Some(job) = self.ai_jobs.iter_mut().find(|j| j.id == job_id) {
                     job.status = AiStatus::Done { ok };
                     if ok {
@@ -3636,7 +4330,7 @@ impl App {
                 hunk_idx: Some(h), ..
             }) = self.display_lines.get(idx)
             {
-                if current_hunk.map_or(true, |ch| ch != *h) {
+                if current_hunk != Some(*h) {
                     self.jump_to_line(idx);
                     return;
                 }
@@ -3671,7 +4365,7 @@ impl App {
                 hunk_idx: Some(h), ..
             }) = self.display_lines.get(idx)
             {
-                if current_hunk.map_or(true, |ch| ch != *h) {
+                if current_hunk != Some(*h) {
                     // Move to the first line of this hunk
                     let mut start = idx;
                     while start > 0 {
@@ -3738,63 +4432,578 @@ impl App {
         }
     }
 
+    /// File under the cursor when stage/discard applies to the file rather
+    /// than a hunk: on a notice line, or anywhere in a whole-file-only file.
+    fn current_file_level_target(&self) -> Option<usize> {
+        match self.current_display_line() {
+            Some(DisplayLine::FileNotice { file_idx, .. }) => Some(*file_idx),
+            Some(DisplayLine::FileHeader { file_idx, .. }) => self
+                .files
+                .get(*file_idx)
+                .filter(|f| f.whole_file_only() || f.hunks.is_empty())
+                .map(|_| *file_idx),
+            Some(DisplayLine::Diff { file_idx, .. }) => self
+                .files
+                .get(*file_idx)
+                .filter(|f| f.whole_file_only())
+                .map(|_| *file_idx),
+            _ => None,
+        }
+    }
+
+    fn stage_file_level(&mut self, file_idx: usize, reverse: bool) -> Result<()> {
+        let Some(file) = self.files.get(file_idx) else {
+            self.message = Some("File not found".to_string());
+            return Ok(());
+        };
+        if file.whole_file_only() {
+            return self.stage_whole_file(file_idx, reverse);
+        }
+        let Some(patch) = build_mode_patch(file) else {
+            self.message = Some("Move to a diff hunk to stage/unstage".to_string());
+            return Ok(());
+        };
+        match self.apply_patch_to_index(patch.as_bytes(), reverse, file.status) {
+            Ok(()) => {
+                self.invalidate_pre_expand_cache();
+                self.reload_diff()?;
+                self.message = Some(if reverse {
+                    "Unstaged mode change".to_string()
+                } else {
+                    "Staged mode change".to_string()
+                });
+            }
+            Err(err) => {
+                self.message = Some(format!("Stage/unstage failed: {}", err));
+            }
+        }
+        Ok(())
+    }
+
+    fn discard_file_level(&mut self, file_idx: usize) -> Result<()> {
+        let Some(file) = self.files.get(file_idx) else {
+            self.message = Some("File not found".to_string());
+            return Ok(());
+        };
+        if file.status == FileStatus::Submodule {
+            self.message =
+                Some("Use `git submodule update` to reset a submodule checkout".to_string());
+            return Ok(());
+        }
+        if file.status == FileStatus::Conflicted {
+            self.message = Some("Resolve conflicts with <, >, = or E".to_string());
+            return Ok(());
+        }
+        // Changes nobody has looked at yet are not thrown away
+        if file.deferred.is_some() {
+            self.message = Some("Load the file with x before discarding it".to_string());
+            return Ok(());
+        }
+        // A textconv diff hides the real change, and a line of it can only
+        // be discarded by restoring the whole file
+        if file.textconv {
+            let path = file
+                .new_path
+                .clone()
+                .or_else(|| file.old_path.clone())
+                .unwrap_or_default();
+            if self.pending_delete.as_ref() != Some(&path) {
+                self.message = Some(format!(
+                    "Press D again to discard all changes to {}",
+                    path.display()
+                ));
+                self.pending_delete = Some(path);
+                return Ok(());
+            }
+            self.pending_delete = None;
+        }
+        if file.whole_file_only() {
+            return self.discard_whole_file(file_idx);
+        }
+        let Some(patch) = build_mode_patch(file) else {
+            self.message = Some("Move to a diff hunk to discard".to_string());
+            return Ok(());
+        };
+        match self.apply_patch_to_worktree(patch.as_bytes(), true) {
+            Ok(()) => {
+                self.invalidate_pre_expand_cache();
+                self.reload_diff()?;
+                self.message = Some("Discarded mode change".to_string());
+            }
+            Err(err) => {
+                self.message = Some(format!("Discard failed: {}", err));
+            }
+        }
+        Ok(())
+    }
+
+    fn file_paths_for_git(file: &DiffFile) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for path in [file.old_path.as_ref(), file.new_path.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if !paths.contains(path) {
+                paths.push(path.clone());
+            }
+        }
+        paths
+    }
+
+    fn stage_whole_file(&mut self, file_idx: usize, reverse: bool) -> Result<()> {
+        let Some(file) = self.files.get(file_idx) else {
+            self.message = Some("File not found".to_string());
+            return Ok(());
+        };
+        let paths = Self::file_paths_for_git(file);
+        // Adding a conflicted file marks it resolved, markers and all
+        if file.status == FileStatus::Conflicted && !reverse {
+            if let Some(path) = paths.first() {
+                let remaining = self.conflicts_left(path);
+                if remaining > 0 {
+                    self.message = Some(format!(
+                        "{} conflict{} left in {}; resolve before staging",
+                        remaining,
+                        if remaining == 1 { "" } else { "s" },
+                        path.display()
+                    ));
+                    return Ok(());
+                }
+            }
+        }
+        let result = if reverse {
+            self.run_git_for_paths(&["reset", "-q"], &paths)
+        } else {
+            self.run_git_for_paths(&["add", "-A"], &paths)
+        };
+        match result {
+            Ok(()) => {
+                self.invalidate_pre_expand_cache();
+                self.reload_diff()?;
+                self.message = Some(if reverse {
+                    "Unstaged file".to_string()
+                } else {
+                    "Staged file".to_string()
+                });
+            }
+            Err(err) => {
+                self.message = Some(format!("Stage/unstage failed: {}", err));
+            }
+        }
+        Ok(())
+    }
+
+    fn discard_whole_file(&mut self, file_idx: usize) -> Result<()> {
+        let Some(file) = self.files.get(file_idx) else {
+            self.message = Some("File not found".to_string());
+            return Ok(());
+        };
+        if file.status == FileStatus::Added {
+            self.message = Some("Untracked file has nothing to restore".to_string());
+            return Ok(());
+        }
+        let paths = Self::file_paths_for_git(file);
+        match self.run_git_for_paths(&["checkout"], &paths) {
+            Ok(()) => {
+                self.invalidate_pre_expand_cache();
+                self.reload_diff()?;
+                self.message = Some("Discarded file".to_string());
+            }
+            Err(err) => {
+                self.message = Some(format!("Discard failed: {}", err));
+            }
+        }
+        Ok(())
+    }
+
+    fn run_git_for_paths(&self, args: &[&str], paths: &[PathBuf]) -> Result<(), String> {
+        // File names, not patterns: "*" or ":" in a name is taken as is
+        let output = Command::new("git")
+            .args(args)
+            .arg("--")
+            .args(paths)
+            .env("GIT_LITERAL_PATHSPECS", "1")
+            .current_dir(&self.repo_path)
+            .output()
+            .map_err(|e| e.to_string())?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            if stderr.is_empty() {
+                Err(format!("git {} failed", args.first().unwrap_or(&"")))
+            } else {
+                Err(stderr)
+            }
+        }
+    }
+
     fn toggle_stage_current_hunk(&mut self) -> Result<()> {
+        if self.config.structural_diff {
+            self.message = Some("Staging is disabled in structural diff (press S)".to_string());
+            return Ok(());
+        }
         let reverse = match self.diff_mode {
             DiffMode::Unstaged => false,
             DiffMode::Staged => true,
+            DiffMode::Stash { .. } => return self.apply_stash_hunk(),
             _ => {
                 self.message = Some("Staging only works for unstaged/staged diffs".to_string());
                 return Ok(());
             }
         };
-
-        let Some((file_idx, hunk_idx)) = self.current_hunk_ref() else {
-            self.message = Some("Move to a diff hunk to stage/unstage".to_string());
-            return Ok(());
+
+        if let Some(file_idx) = self.current_file_level_target() {
+            return self.stage_file_level(file_idx, reverse);
+        }
+
+        let Some((file_idx, hunk_idx)) = self.current_hunk_ref() else {
+            self.message = Some("Move to a diff hunk to stage/unstage".to_string());
+            return Ok(());
+        };
+
+        let Some(file) = self.files.get(file_idx) else {
+            self.message = Some("File not found".to_string());
+            return Ok(());
+        };
+        let _file_path = file
+            .new_path
+            .as_ref()
+            .or(file.old_path.as_ref())
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let Some(hunk) = file.hunks.get(hunk_idx) else {
+            self.message = Some("Hunk not found".to_string());
+            return Ok(());
+        };
+
+        if self.diff_engine.whitespace().is_ignoring() {
+            let status = file.status;
+            let Some(patch) = self.whitespace_sensitive_patch(file_idx, hunk_idx)? else {
+                self.message = Some("No whitespace-sensitive change under this hunk".to_string());
+                return Ok(());
+            };
+            match self.apply_patch_to_index(&patch, reverse, status) {
+                Ok(()) => {
+                    self.invalidate_pre_expand_cache();
+                    self.reload_diff()?;
+                    self.message = Some(if reverse {
+                        "Unstaged hunk (including whitespace)".to_string()
+                    } else {
+                        "Staged hunk (including whitespace)".to_string()
+                    });
+                }
+                Err(err) => {
+                    self.message = Some(format!("Stage/unstage failed: {}", err));
+                }
+            }
+            return Ok(());
+        }
+
+        let patch = Self::build_hunk_patch(file, hunk);
+
+        match self.apply_patch_to_index(&patch, reverse, file.status) {
+            Ok(()) => {
+                self.message = Some(if reverse {
+                    "Unstaged hunk".to_string()
+                } else {
+                    "Staged hunk".to_string()
+                });
+                self.invalidate_pre_expand_cache();
+                self.apply_stage_local(file_idx, hunk_idx)?;
+            }
+            Err(err) => {
+                self.message = Some(format!("Stage/unstage failed: {}", err));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the cursor to where the moved line under it came from or went to
+    fn jump_to_move_counterpart(&mut self) {
+        let Some(DisplayLine::Diff { line, .. }) = self.current_display_line() else {
+            self.message = Some("Move to a moved line to jump".to_string());
+            return;
+        };
+        let Some(target) = line.moved else {
+            self.message = Some("Not a moved line".to_string());
+            return;
+        };
+        // Both ends of a move hold the same text; anything else means the
+        // files changed (e.g. a hunk was staged) since moves were detected
+        let Some(target_line) = self
+            .files
+            .get(target.file)
+            .and_then(|f| f.hunks.get(target.hunk))
+            .and_then(|h| h.lines.get(target.line))
+            .filter(|l| l.kind != line.kind && l.content == line.content)
+            .cloned()
+        else {
+            self.message = Some("Moved lines are out of date, press R to reload".to_string());
+            return;
+        };
+        let target_path = self.files[target.file]
+            .new_path
+            .as_ref()
+            .or(self.files[target.file].old_path.as_ref())
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if self.collapsed_files.remove(&target_path) {
+            self.build_display_lines();
+        }
+        let found = self.display_lines.iter().position(|d| {
+            matches!(d, DisplayLine::Diff { line, file_idx, hunk_idx, .. }
+                if *file_idx == target.file
+                    && *hunk_idx == Some(target.hunk)
+                    && line.kind == target_line.kind
+                    && line.old_line_no == target_line.old_line_no
+                    && line.new_line_no == target_line.new_line_no)
+        });
+        match found {
+            Some(idx) => {
+                self.jump_to_line(idx);
+                self.message = Some(format!(
+                    "Moved {} {}:{}",
+                    if target_line.kind == LineKind::Addition {
+                        "to"
+                    } else {
+                        "from"
+                    },
+                    target_path,
+                    target_line
+                        .new_line_no
+                        .or(target_line.old_line_no)
+                        .unwrap_or(0)
+                ));
+            }
+            None => {
+                self.message = Some(format!("Other end of the move is in {}", target_path));
+            }
+        }
+    }
+
+    /// Conflicted file under the cursor, in the unstaged view
+    fn current_conflicted_file(&self) -> Option<usize> {
+        if !matches!(self.diff_mode, DiffMode::Unstaged) {
+            return None;
+        }
+        let file_idx = self.file_idx_for_line(self.current_line_idx)?;
+        self.files
+            .get(file_idx)
+            .filter(|f| f.status == FileStatus::Conflicted)
+            .map(|_| file_idx)
+    }
+
+    /// Resolve the conflict region under the cursor, or the whole file when
+    /// it has no markers to pick from (binary, or deleted on one side)
+    fn resolve_current_conflict(&mut self, choice: ConflictChoice) -> Result<()> {
+        let Some(file_idx) = self.current_conflicted_file() else {
+            self.message = Some("Move to a conflicted file to resolve it".to_string());
+            return Ok(());
+        };
+        let file = &self.files[file_idx];
+        let paths = Self::file_paths_for_git(file);
+        let Some(path) = paths.first().cloned() else {
+            return Ok(());
+        };
+        let full_path = self.repo_path.join(&path);
+        let bytes = if file.binary {
+            None
+        } else {
+            std::fs::read(&full_path).ok()
+        };
+        let line_no = self.conflict_line_no();
+
+        let regions = bytes.as_deref().map(find_conflicts_in).unwrap_or_default();
+        let mut deleted = false;
+        let region = line_no.and_then(|n| {
+            let idx = n.saturating_sub(1) as usize;
+            regions.iter().find(|r| (r.start..=r.end).contains(&idx))
+        });
+        let remaining = match (bytes.as_deref(), region) {
+            (Some(bytes), Some(region)) => {
+                let resolved = resolve_conflict(bytes, region, choice);
+                if let Err(err) = std::fs::write(&full_path, &resolved) {
+                    self.message = Some(format!("Resolve failed: {}", err));
+                    return Ok(());
+                }
+                regions.len() - 1
+            }
+            // Taking a whole side would drop the other side of every region
+            _ if !regions.is_empty() => {
+                self.message = Some("Move to a conflict region to resolve it".to_string());
+                return Ok(());
+            }
+            _ => {
+                let side = match choice {
+                    ConflictChoice::Ours => "--ours",
+                    ConflictChoice::Theirs => "--theirs",
+                    ConflictChoice::Both => {
+                        self.message =
+                            Some("Move to a conflict region to take both sides".to_string());
+                        return Ok(());
+                    }
+                };
+                // The side taken deleted the file: there is nothing to check out
+                deleted = self.diff_engine.conflict_side_deleted(&path, choice);
+                let result = if deleted {
+                    self.run_git_for_paths(&["rm", "-q"], &paths)
+                } else {
+                    self.run_git_for_paths(&["checkout", side], &paths)
+                };
+                if let Err(err) = result {
+                    self.message = Some(format!("Resolve failed: {}", err));
+                    return Ok(());
+                }
+                0
+            }
+        };
+
+        let taken = match choice {
+            ConflictChoice::Ours => "ours",
+            ConflictChoice::Theirs => "theirs",
+            ConflictChoice::Both => "both",
+        };
+        self.message = Some(if remaining > 0 {
+            format!(
+                "Took {}; {} conflict{} left",
+                taken,
+                remaining,
+                if remaining == 1 { "" } else { "s" }
+            )
+        } else if deleted {
+            format!("Took {}; {} deleted", taken, path.display())
+        } else {
+            match self.run_git_for_paths(&["add"], &paths) {
+                Ok(()) => format!("Took {}; {} marked resolved", taken, path.display()),
+                Err(err) => format!("Took {}, but git add failed: {}", taken, err),
+            }
+        });
+        self.invalidate_pre_expand_cache();
+        self.reload_diff()?;
+        Ok(())
+    }
+
+    /// Worktree line number of the conflict line under the cursor. Base
+    /// lines filled in from the index are not in the file; they take the
+    /// number of the file line above them.
+    fn conflict_line_no(&self) -> Option<u32> {
+        self.display_lines
+            .get(..=self.current_line_idx)
+            .unwrap_or_default()
+            .iter()
+            .rev()
+            .map_while(|l| match l {
+                DisplayLine::Diff { line, .. } => Some(line),
+                _ => None,
+            })
+            .find_map(|line| line.new_line_no)
+    }
+
+    /// Queue the conflicted file for $EDITOR at the line under the cursor
+    fn edit_current_conflict(&mut self) {
+        let Some(file_idx) = self.current_conflicted_file() else {
+            self.message = Some("Move to a conflicted file to edit it".to_string());
+            return;
+        };
+        let Some(path) = Self::file_paths_for_git(&self.files[file_idx])
+            .first()
+            .map(|p| self.repo_path.join(p))
+        else {
+            return;
+        };
+        let line = match self.current_display_line() {
+            Some(DisplayLine::Diff { .. }) => self.conflict_line_no().unwrap_or(1),
+            _ => self.files[file_idx]
+                .hunks
+                .iter()
+                .flat_map(|h| &h.lines)
+                .find(|l| l.conflict == Some(ConflictSide::Marker))
+                .and_then(|l| l.new_line_no)
+                .unwrap_or(1),
         };
+        self.pending_editor = Some((path, line));
+    }
+
+    /// Conflict regions left in the worktree copy of a file
+    fn conflicts_left(&self, rel_path: &Path) -> usize {
+        std::fs::read(self.repo_path.join(rel_path))
+            .map(|bytes| find_conflicts_in(&bytes).len())
+            .unwrap_or(0)
+    }
+
+    /// After editing a conflicted file, stage it once no markers are left
+    fn finish_conflict_edit(&mut self, path: &Path) -> Result<()> {
+        let rel_path = path.strip_prefix(&self.repo_path).unwrap_or(path);
+        let rel = rel_path.to_string_lossy().to_string();
+        let remaining = self.conflicts_left(rel_path);
+        self.message = Some(if remaining > 0 {
+            format!(
+                "{} conflict{} left in {}",
+                remaining,
+                if remaining == 1 { "" } else { "s" },
+                rel
+            )
+        } else {
+            match self.run_git_for_paths(&["add"], &[rel_path.to_path_buf()]) {
+                Ok(()) => format!("{} marked resolved", rel),
+                Err(err) => format!("git add failed: {}", err),
+            }
+        });
+        self.invalidate_pre_expand_cache();
+        self.reload_diff()
+    }
 
-        let Some(file) = self.files.get(file_idx) else {
-            self.message = Some("File not found".to_string());
+    /// Apply the hunk under the cursor from a stash to the worktree
+    fn apply_stash_hunk(&mut self) -> Result<()> {
+        let Some((file_idx, hunk_idx)) = self.current_hunk_ref() else {
+            self.message = Some("Move to a stash hunk to apply it".to_string());
             return Ok(());
         };
-        let _file_path = file
-            .new_path
-            .as_ref()
-            .or(file.old_path.as_ref())
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_default();
-
-        let Some(hunk) = file.hunks.get(hunk_idx) else {
-            self.message = Some("Hunk not found".to_string());
-            return Ok(());
+        let patch = if self.diff_engine.whitespace().is_ignoring() {
+            let Some(patch) = self.whitespace_sensitive_patch(file_idx, hunk_idx)? else {
+                self.message = Some("No whitespace-sensitive change under this hunk".to_string());
+                return Ok(());
+            };
+            patch
+        } else {
+            let Some(file) = self.files.get(file_idx) else {
+                return Ok(());
+            };
+            let Some(hunk) = file.hunks.get(hunk_idx) else {
+                return Ok(());
+            };
+            Self::build_hunk_patch(file, hunk)
         };
-
-        let patch = Self::build_hunk_patch(file, hunk);
-
-        match self.apply_patch_to_index(&patch, reverse, file.status) {
+        match self.apply_patch_to_worktree(&patch, false) {
             Ok(()) => {
-                self.message = Some(if reverse {
-                    "Unstaged hunk".to_string()
-                } else {
-                    "Staged hunk".to_string()
-                });
                 self.invalidate_pre_expand_cache();
-                self.apply_stage_local(file_idx, hunk_idx)?;
+                self.reload_diff()?;
+                self.message = Some("Applied hunk to the worktree".to_string());
             }
             Err(err) => {
-                self.message = Some(format!("Stage/unstage failed: {}", err));
+                self.message = Some(format!("Apply failed: {}", err));
             }
         }
-
         Ok(())
     }
 
     fn discard_current_hunk(&mut self) -> Result<()> {
+        if self.config.structural_diff {
+            self.message = Some("Discard is disabled in structural diff (press S)".to_string());
+            return Ok(());
+        }
         if !matches!(self.diff_mode, DiffMode::Unstaged) {
             self.message = Some("Discard only works for unstaged changes".to_string());
             return Ok(());
         }
 
+        if let Some(file_idx) = self.current_file_level_target() {
+            return self.discard_file_level(file_idx);
+        }
+
         let Some((file_idx, hunk_idx)) = self.current_hunk_ref() else {
             self.message = Some("Move to a diff hunk to discard".to_string());
             return Ok(());
@@ -3809,6 +5018,24 @@ impl App {
             return Ok(());
         };
 
+        if self.diff_engine.whitespace().is_ignoring() {
+            let Some(patch) = self.whitespace_sensitive_patch(file_idx, hunk_idx)? else {
+                self.message = Some("No whitespace-sensitive change under this hunk".to_string());
+                return Ok(());
+            };
+            match self.apply_patch_to_worktree(&patch, true) {
+                Ok(()) => {
+                    self.invalidate_pre_expand_cache();
+                    self.reload_diff()?;
+                    self.message = Some("Discarded hunk (including whitespace)".to_string());
+                }
+                Err(err) => {
+                    self.message = Some(format!("Discard failed: {}", err));
+                }
+            }
+            return Ok(());
+        }
+
         let patch = Self::build_hunk_patch(file, hunk);
 
         match self.apply_patch_to_worktree(&patch, true) {
@@ -3952,12 +5179,9 @@ impl App {
                 .unwrap_or(file.hunks.len());
             file.hunks.insert(insert_at, hunk);
         } else {
-            files.push(DiffFile {
-                old_path,
-                new_path,
-                status,
-                hunks: vec![hunk],
-            });
+            let mut file = DiffFile::new(old_path, new_path, status);
+            file.hunks.push(hunk);
+            files.push(file);
         }
     }
 
@@ -4043,6 +5267,10 @@ impl App {
         let target = match self.diff_mode {
             DiffMode::Unstaged => DiffMode::Staged,
             DiffMode::Staged => DiffMode::Unstaged,
+            DiffMode::Stash { index, staged } => DiffMode::Stash {
+                index,
+                staged: !staged,
+            },
             _ => {
                 self.message = Some("Only unstaged/staged views can be toggled".to_string());
                 return Ok(());
@@ -4052,6 +5280,115 @@ impl App {
         self.switch_diff_mode(target)
     }
 
+    fn toggle_structural_diff(&mut self) -> Result<()> {
+        self.config.structural_diff = !self.config.structural_diff;
+        self.diff_engine.set_structural(self.config.structural_diff);
+        self.reload_diff()?;
+        self.message = Some(format!(
+            "Diff: {}",
+            if self.config.structural_diff {
+                "structural"
+            } else {
+                "line"
+            }
+        ));
+        Ok(())
+    }
+
+    /// Push algorithm/whitespace settings from config to the engine and reload
+    fn apply_diff_options(&mut self) -> Result<()> {
+        self.diff_engine.set_algorithm(self.config.diff_algorithm);
+        self.diff_engine.set_whitespace(self.config.whitespace());
+        self.cached_unstaged = None;
+        self.cached_staged = None;
+        self.reload_diff()?;
+        self.message = Some(format!(
+            "Diff options: {}",
+            diff_options_label(&self.config)
+        ));
+        Ok(())
+    }
+
+    /// Move to the next (`1`) or previous (`-1`) commit of a `differ log` range
+    fn step_commit(&mut self, delta: isize) -> Result<()> {
+        if self.log_commits.is_empty() {
+            self.message = Some("Not reviewing a commit range".to_string());
+            return Ok(());
+        }
+        let Some(idx) = self
+            .log_idx
+            .checked_add_signed(delta)
+            .filter(|idx| *idx < self.log_commits.len())
+        else {
+            self.message = Some(if delta > 0 {
+                "Already at the last commit".to_string()
+            } else {
+                "Already at the first commit".to_string()
+            });
+            return Ok(());
+        };
+        self.select_commit(idx)
+    }
+
+    fn select_commit(&mut self, idx: usize) -> Result<()> {
+        let info = self.log_commits[idx].clone();
+        self.log_idx = idx;
+        // Paths collapsed in one commit say nothing about the next
+        self.collapsed_files.clear();
+        self.auto_collapsed_renames.clear();
+        self.expanded_file = None;
+        self.diff_mode = DiffMode::Commit {
+            rev: info.sha.clone(),
+        };
+        self.commit_info = Some(info);
+        self.start_diff_stream(None)?;
+        self.message = Some(self.commit_position_label());
+        Ok(())
+    }
+
+    /// "Commit 2/5 abc1234 Summary" for the commit being shown
+    fn commit_position_label(&self) -> String {
+        let Some(info) = self.commit_info.as_ref() else {
+            return String::new();
+        };
+        let position = if self.log_commits.is_empty() {
+            String::new()
+        } else {
+            format!("{}/{} ", self.log_idx + 1, self.log_commits.len())
+        };
+        format!(
+            "Commit {}{} {}",
+            position,
+            short_sha(&info.sha),
+            info.summary()
+        )
+    }
+
+    fn toggle_commit_reviewed(&mut self) -> Result<()> {
+        let Some(sha) = self.commit_info.as_ref().map(|info| info.sha.clone()) else {
+            self.message = Some("Only commits can be marked as reviewed".to_string());
+            return Ok(());
+        };
+        let reviewed = !self.reviewed_commits.contains(&sha);
+        self.storage
+            .set_commit_reviewed(self.repo_id, &sha, reviewed)?;
+        if reviewed {
+            self.reviewed_commits.insert(sha.clone());
+        } else {
+            self.reviewed_commits.remove(&sha);
+        }
+        self.message = Some(format!(
+            "{} {}",
+            short_sha(&sha),
+            if reviewed {
+                "marked reviewed"
+            } else {
+                "no longer reviewed"
+            }
+        ));
+        Ok(())
+    }
+
     fn switch_diff_mode(&mut self, target: DiffMode) -> Result<()> {
         self.save_collapsed_state();
         self.diff_mode = target;
@@ -4061,69 +5398,58 @@ impl App {
         Ok(())
     }
 
-    fn build_hunk_patch(file: &DiffFile, hunk: &DiffHunk) -> String {
-        let old_path = file
-            .old_path
-            .as_ref()
-            .or(file.new_path.as_ref())
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_else(|| "unknown".to_string());
-        let new_path = file
-            .new_path
-            .as_ref()
-            .or(file.old_path.as_ref())
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_else(|| old_path.clone());
-
-        let mut patch = String::new();
-        patch.push_str(&format!("diff --git a/{} b/{}\n", old_path, new_path));
-
-        match file.status {
-            FileStatus::Added => {
-                patch.push_str("new file mode 100644\n");
-                patch.push_str("--- /dev/null\n");
-                patch.push_str(&format!("+++ b/{}\n", new_path));
-            }
-            FileStatus::Deleted => {
-                patch.push_str(&format!("--- a/{}\n", old_path));
-                patch.push_str("+++ /dev/null\n");
-            }
-            FileStatus::Modified | FileStatus::Renamed => {
-                patch.push_str(&format!("--- a/{}\n", old_path));
-                patch.push_str(&format!("+++ b/{}\n", new_path));
-            }
-        }
+    fn build_hunk_patch(file: &DiffFile, hunk: &DiffHunk) -> Vec<u8> {
+        build_hunks_patch(file, std::slice::from_ref(hunk))
+    }
 
-        patch.push_str(&format!(
-            "@@ -{},{} +{},{} @@\n",
-            hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines
-        ));
+    /// Patch of the real (whitespace-sensitive) hunks under a hunk shown with
+    /// whitespace ignored. Hunks overlapping its new-side lines are included.
+    fn whitespace_sensitive_patch(
+        &self,
+        file_idx: usize,
+        hunk_idx: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(file) = self.files.get(file_idx) else {
+            return Ok(None);
+        };
+        let Some(hunk) = file.hunks.get(hunk_idx) else {
+            return Ok(None);
+        };
+        let Some(real) = self.diff_engine.whitespace_sensitive().diff_file_again(
+            &self.diff_mode,
+            &self.diff_paths,
+            file,
+        )?
+        else {
+            return Ok(None);
+        };
 
-        for line in &hunk.lines {
-            let prefix = match line.kind {
-                LineKind::Context => ' ',
-                LineKind::Addition => '+',
-                LineKind::Deletion => '-',
-            };
-            patch.push(prefix);
-            patch.push_str(&line.content);
-            patch.push('\n');
+        let start = hunk.new_start;
+        let end = hunk.new_start + hunk.new_lines.max(1);
+        let hunks: Vec<DiffHunk> = real
+            .hunks
+            .iter()
+            .filter(|h| h.new_start < end && start < h.new_start + h.new_lines.max(1))
+            .cloned()
+            .collect();
+        if hunks.is_empty() {
+            return Ok(None);
         }
-
-        patch
+        Ok(Some(build_hunks_patch(&real, &hunks)))
     }
 
     fn apply_patch_to_index(
         &self,
-        patch: &str,
+        patch: &[u8],
         reverse: bool,
         _status: FileStatus,
     ) -> Result<(), String> {
         let mut cmd = Command::new("git");
         cmd.arg("apply").arg("--cached");
+        let text = String::from_utf8_lossy(patch);
         if reverse {
             cmd.arg("-R");
-        } else if patch.contains("--- /dev/null\n") && !patch.contains("new file mode") {
+        } else if text.contains("--- /dev/null\n") && !text.contains("new file mode") {
             // Allow staging hunks for new files not yet in the index when
             // the patch doesn't already declare a new file.
             cmd.arg("--intent-to-add");
@@ -4135,9 +5461,7 @@ impl App {
 
         let mut child = cmd.spawn().map_err(|e| e.to_string())?;
         if let Some(mut stdin) = child.stdin.take() {
-            stdin
-                .write_all(patch.as_bytes())
-                .map_err(|e| e.to_string())?;
+            stdin.write_all(patch).map_err(|e| e.to_string())?;
         }
         let output = child.wait_with_output().map_err(|e| e.to_string())?;
 
@@ -4153,7 +5477,7 @@ impl App {
         }
     }
 
-    fn apply_patch_to_worktree(&self, patch: &str, reverse: bool) -> Result<(), String> {
+    fn apply_patch_to_worktree(&self, patch: &[u8], reverse: bool) -> Result<(), String> {
         let mut cmd = Command::new("git");
         cmd.arg("apply");
         if reverse {
@@ -4166,9 +5490,7 @@ impl App {
 
         let mut child = cmd.spawn().map_err(|e| e.to_string())?;
         if let Some(mut stdin) = child.stdin.take() {
-            stdin
-                .write_all(patch.as_bytes())
-                .map_err(|e| e.to_string())?;
+            stdin.write_all(patch).map_err(|e| e.to_string())?;
         }
         let output = child.wait_with_output().map_err(|e| e.to_string())?;
 
@@ -4249,7 +5571,21 @@ impl App {
                 let _ = tx.send(DiffStreamEvent::File { generation, file });
                 Ok(())
             };
-            let result = diff_engine.diff_stream(&diff_mode, &diff_paths, &mut on_file);
+            let result = match &diff_mode {
+                DiffMode::RangeDiff {
+                    old_from,
+                    old_to,
+                    new_from,
+                    new_to,
+                } => diff_engine
+                    .range_pairs((old_from, old_to), (new_from, new_to), &diff_paths)
+                    .and_then(|pairs| {
+                        let result = diff_engine.range_diff_stream(&pairs, &mut on_file);
+                        let _ = tx.send(DiffStreamEvent::RangePairs { generation, pairs });
+                        result
+                    }),
+                _ => diff_engine.diff_stream(&diff_mode, &diff_paths, &mut on_file),
+            };
             let error = result.err().map(|e| e.to_string());
             let _ = tx.send(DiffStreamEvent::Done { generation, error });
         });
@@ -4264,6 +5600,24 @@ impl App {
 
     fn handle_diff_event(&mut self, evt: DiffStreamEvent) -> Result<()> {
         match evt {
+            DiffStreamEvent::Loaded {
+                generation,
+                file_key,
+                result,
+            } => {
+                // A newer diff already has the file in full
+                if generation == self.diff_generation {
+                    self.handle_loaded_file(&file_key, result);
+                }
+            }
+            DiffStreamEvent::RangePairs { generation, pairs } => {
+                // Notes are carried once, from the range-diff first shown
+                if generation == self.diff_generation && self.carried_annotations.is_none() {
+                    self.carry_over_annotations(&pairs)?;
+                    self.load_all_annotations()?;
+                    self.build_display_lines();
+                }
+            }
             DiffStreamEvent::File { generation, file } => {
                 if generation != self.diff_generation {
                     return Ok(());
@@ -4298,6 +5652,10 @@ impl App {
                 }
                 self.flush_streamed_files();
                 self.diff_loading = false;
+                // Moves can span files, so they are found once all are in
+                if detect_moves(&mut self.files) > 0 {
+                    self.build_display_lines();
+                }
                 if let Some(err) = error {
                     self.message = Some(format!("Diff load failed: {}", err));
                 } else if self.files.is_empty() {
@@ -4310,10 +5668,8 @@ impl App {
                 } else {
                     self.message = None;
                 }
-                if !self.files.is_empty() {
-                    if self.display_lines.is_empty() {
-                        self.build_display_lines();
-                    }
+                if !self.files.is_empty() && self.display_lines.is_empty() {
+                    self.build_display_lines();
                 }
                 if let Some(search) = &mut self.search {
                     if !search.query.is_empty() {
@@ -4353,6 +5709,9 @@ impl App {
         let files = mem::take(&mut self.pending_stream_files);
         for file in files {
             let (file_key, _, _) = Self::file_highlight_keys(&file);
+            if file.is_pure_rename() && self.auto_collapsed_renames.insert(file_key.clone()) {
+                self.collapsed_files.insert(file_key.clone());
+            }
             if let Some(idx) = self.diff_file_index.get(&file_key).copied() {
                 if let Some(slot) = self.files.get_mut(idx) {
                     *slot = file;
@@ -4366,6 +5725,15 @@ impl App {
             }
         }
 
+        // The group line sits on the first untracked file and counts them all
+        if self.config.untracked_files == UntrackedFiles::Group {
+            if let Some(first) = self.files.iter().position(|f| f.untracked) {
+                if !updated_indices.contains(&first) {
+                    updated_indices.push(first);
+                }
+            }
+        }
+
         for idx in updated_indices {
             self.update_display_lines_for_file(idx);
         }
@@ -4534,21 +5902,47 @@ impl App {
     }
 
     fn sidebar_entries(&self) -> Vec<SidebarEntry> {
+        let folded =
+            self.config.untracked_files == UntrackedFiles::Group && !self.untracked_group_open;
+        let untracked = self.files.iter().filter(|f| f.untracked).count();
+        let mut group_listed = false;
         self.files
             .iter()
             .enumerate()
-            .map(|(idx, file)| {
+            .filter_map(|(idx, file)| {
+                if folded && file.untracked {
+                    if std::mem::replace(&mut group_listed, true) {
+                        return None;
+                    }
+                    return Some(SidebarEntry {
+                        file_idx: idx,
+                        path: format!(
+                            "{} untracked file{}",
+                            untracked,
+                            if untracked == 1 { "" } else { "s" }
+                        ),
+                        status: file.status,
+                        similarity: None,
+                        binary: false,
+                        untracked: true,
+                        group: true,
+                    });
+                }
                 let path = file
                     .new_path
                     .as_ref()
                     .or(file.old_path.as_ref())
                     .map(|p| p.to_string_lossy().to_string())
                     .unwrap_or_else(|| "<unknown>".to_string());
-                SidebarEntry {
+                Some(SidebarEntry {
                     file_idx: idx,
                     path,
                     status: file.status,
-                }
+                    similarity: file.similarity,
+                    binary: file.binary,
+                    untracked: file.untracked,
+                    group: false,
+                })
             })
             .collect()
     }
@@ -4592,6 +5986,31 @@ impl App {
         best.map(|(idx, _)| idx)
     }
 
+    fn toggle_untracked_group(&mut self) {
+        self.untracked_group_open = !self.untracked_group_open;
+        self.build_display_lines();
+        self.ensure_cursor_on_navigable();
+        self.adjust_scroll();
+        self.message = Some(if self.untracked_group_open {
+            "Showing untracked files".to_string()
+        } else {
+            "Folded untracked files".to_string()
+        });
+    }
+
+    /// Show, list, group or hide untracked files in the unstaged view
+    fn cycle_untracked_files(&mut self) -> Result<()> {
+        self.config.untracked_files = self.config.untracked_files.next();
+        self.diff_engine.set_untracked(self.config.untracked_files);
+        self.cached_unstaged = None;
+        self.reload_diff()?;
+        self.message = Some(format!(
+            "Untracked files: {}",
+            self.config.untracked_files.as_str()
+        ));
+        Ok(())
+    }
+
     fn toggle_collapse_current_file(&mut self) {
         let current_header_idx = self.find_current_file_header_idx();
         let Some((file_path, file_idx)) = self.current_file_info() else {
@@ -4698,9 +6117,11 @@ impl App {
             }
         }
 
+        // Annotations of other commits are listed where they show
         let mut entries: Vec<AnnotationListEntry> = self
             .all_annotations
             .iter()
+            .filter(|a| visible.contains_key(&a.id) || !self.made_on_other_commit(a))
             .map(|a| {
                 let display_idx = visible.get(&a.id).map(|(idx, _)| *idx);
                 let orphaned = if let Some((_, o)) = visible.get(&a.id) {
@@ -4880,7 +6301,9 @@ impl App {
             DisplayLine::Spacer
             | DisplayLine::FileHeader { .. }
             | DisplayLine::HunkHeader { .. }
-            | DisplayLine::HunkEnd { .. } => 1,
+            | DisplayLine::HunkEnd { .. }
+            | DisplayLine::FileNotice { .. }
+            | DisplayLine::UntrackedGroup { .. } => 1,
             DisplayLine::HunkContext { .. } => {
                 if content_width < 4 {
                     1
@@ -4895,7 +6318,7 @@ impl App {
                 if width == 0 {
                     1
                 } else {
-                    (width + content_width - 1) / content_width
+                    width.div_ceil(content_width)
                 }
             }
             DisplayLine::Annotation {
@@ -4957,7 +6380,7 @@ impl App {
                     let lines = if width == 0 {
                         1
                     } else {
-                        (width + content_width - 1) / content_width
+                        width.div_ceil(content_width)
                     };
                     total = total.saturating_add(lines);
                 }
@@ -4985,10 +6408,19 @@ impl App {
             return;
         }
         if let Some((_, file_idx)) = self.current_file_info() {
-            self.sidebar_index = file_idx.min(self.files.len().saturating_sub(1));
+            self.sidebar_index = self.sidebar_index_for_file(file_idx);
         }
     }
 
+    /// Sidebar row of a file, or of the group folding it
+    fn sidebar_index_for_file(&self, file_idx: usize) -> usize {
+        let untracked = self.files.get(file_idx).is_some_and(|f| f.untracked);
+        self.sidebar_entries()
+            .iter()
+            .position(|e| e.file_idx == file_idx || (e.group && untracked))
+            .unwrap_or(0)
+    }
+
     fn max_scroll_offset(&self) -> usize {
         if self.display_lines.is_empty() || self.visible_height == 0 {
             return 0;
@@ -5022,10 +6454,8 @@ pub fn run(
     diff_engine: DiffEngine,
     repo_path: PathBuf,
     repo_id: i64,
-    files: Vec<DiffFile>,
     config: Config,
-    diff_mode: DiffMode,
-    diff_paths: Vec<String>,
+    args: DiffArgs,
 ) -> Result<()> {
     // Setup terminal
     enable_raw_mode()?;
@@ -5035,27 +6465,27 @@ pub fn run(
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let mut app = App::new(
-        storage,
-        diff_engine,
-        repo_path,
-        repo_id,
-        files,
-        config,
-        diff_mode,
-        diff_paths,
-    )?;
+    let mut app = App::new(storage, diff_engine, repo_path, repo_id, config, args)?;
     app.start_diff_stream(None)?;
-    let _watcher = match start_fs_watcher(
-        app.repo_path.clone(),
-        app.fs_tx.clone(),
-        app.config.watch_ignore_paths.clone(),
-    ) {
-        Ok(watcher) => Some(watcher),
-        Err(err) => {
-            app.message = Some(format!("Watcher disabled: {}", err));
-            None
+    // A patch or a commit does not change with the working tree
+    let watch = !matches!(
+        app.diff_mode,
+        DiffMode::Patch { .. } | DiffMode::Commit { .. } | DiffMode::RangeDiff { .. }
+    );
+    let _watcher = if watch {
+        match start_fs_watcher(
+            app.repo_path.clone(),
+            app.fs_tx.clone(),
+            app.config.watch_ignore_paths.clone(),
+        ) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                app.message = Some(format!("Watcher disabled: {}", err));
+                None
+            }
         }
+    } else {
+        None
     };
 
     // Skip to first navigable line
@@ -5088,7 +6518,7 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
         while let Ok(evt) = app.diff_rx.try_recv() {
             app.handle_diff_event(evt)?;
         }
-        while let Ok(_) = app.fs_rx.try_recv() {
+        while app.fs_rx.try_recv().is_ok() {
             app.fs_pending = true;
             app.fs_last_event = Some(Instant::now());
         }
@@ -5100,6 +6530,13 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
                     if app.handle_input(key)? {
                         return Ok(());
                     }
+                    if let Some((path, line)) = app.pending_editor.take() {
+                        if let Err(err) = run_editor(terminal, &path, line) {
+                            app.message = Some(format!("Editor failed: {}", err));
+                        } else {
+                            app.finish_conflict_edit(&path)?;
+                        }
+                    }
                 }
                 Event::Mouse(mouse) => match mouse.kind {
                     MouseEventKind::ScrollUp => {
@@ -5124,12 +6561,35 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
     }
 }
 
+/// Hand the terminal to $EDITOR for one file, then take it back
+fn run_editor(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    path: &Path,
+    line: u32,
+) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), DisableMouseCapture)?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let status = Command::new(&editor)
+        .arg(format!("+{}", line))
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to open editor: {}", editor));
+
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen)?;
+    execute!(terminal.backend_mut(), EnableMouseCapture)?;
+    terminal.clear()?;
+    status.map(|_| ())
+}
+
 fn start_fs_watcher(
     repo_path: PathBuf,
     tx: Sender<FsEvent>,
     ignore_paths: Vec<String>,
 ) -> Result<RecommendedWatcher> {
-    let ignore_paths = ignore_paths;
     let mut watcher = notify::recommended_watcher(move |res: notify::Result<FsEventRaw>| {
         if let Ok(event) = res {
             if should_ignore_fs_event(&event, &ignore_paths) {
@@ -5182,6 +6642,20 @@ fn ui(f: &mut Frame, app: &mut App) {
         _ => 1,
     };
 
+    // Commit metadata, capped so the diff keeps most of the screen
+    let commit_lines = app.commit_info.as_ref().map(commit_header_lines);
+    let commit_height = commit_lines
+        .as_ref()
+        .map(|lines| (lines.len() as u16 + 1).min(f.area().height / 3))
+        .unwrap_or(0);
+
+    let outer = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(commit_height), // Commit metadata
+            Constraint::Min(0),
+        ])
+        .split(f.area());
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
@@ -5189,11 +6663,15 @@ fn ui(f: &mut Frame, app: &mut App) {
             Constraint::Min(0),               // Diff content
             Constraint::Length(input_height), // Status/input
         ])
-        .split(f.area());
+        .split(outer[1]);
 
     // Update visible height for page navigation
     app.visible_height = chunks[1].height as usize;
 
+    if let Some(lines) = commit_lines {
+        render_commit_header(f, lines, outer[0], theme);
+    }
+
     // Sticky file header
     render_sticky_file_header(f, app, chunks[0], theme);
 
@@ -5245,6 +6723,10 @@ fn ui(f: &mut Frame, app: &mut App) {
         render_command_palette(f, app, theme);
     }
 
+    if matches!(app.mode, Mode::StashList) {
+        render_stash_list(f, app, theme);
+    }
+
     if matches!(app.mode, Mode::CommitMessage) {
         render_commit_popup(f, app, theme);
     }
@@ -5254,6 +6736,69 @@ fn ui(f: &mut Frame, app: &mut App) {
     }
 }
 
+/// `git show`-style header: sha, parents, author, date and the full message
+fn commit_header_lines(info: &CommitInfo) -> Vec<String> {
+    let parents = if info.parents.is_empty() {
+        "(root commit)".to_string()
+    } else {
+        info.parents
+            .iter()
+            .map(|p| short_sha(p))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let mut lines = vec![
+        format!("commit {}", info.sha),
+        if info.parents.len() > 1 {
+            // Numbered like the +/- columns of the combined diff
+            let numbered: Vec<String> = info
+                .parents
+                .iter()
+                .enumerate()
+                .map(|(i, p)| format!("{} {}", i + 1, short_sha(p)))
+                .collect();
+            format!("Merge:   {}", numbered.join("  "))
+        } else {
+            format!("Parents: {}", parents)
+        },
+        format!("Author:  {}", info.author),
+        format!("Date:    {}", info.date),
+        String::new(),
+    ];
+    lines.extend(info.message.lines().map(|l| format!("    {}", l)));
+    lines
+}
+
+fn render_commit_header(f: &mut Frame, lines: Vec<String>, area: Rect, theme: Theme) {
+    if area.height == 0 {
+        return;
+    }
+    let text: Vec<Line> = lines
+        .into_iter()
+        .enumerate()
+        .map(|(idx, line)| {
+            if idx == 0 {
+                Line::styled(
+                    line,
+                    Style::default()
+                        .fg(theme.annotation_marker)
+                        .add_modifier(Modifier::BOLD),
+                )
+            } else {
+                Line::raw(line)
+            }
+        })
+        .collect();
+    let block = Block::default()
+        .borders(Borders::BOTTOM)
+        .border_style(Style::default().fg(theme.border));
+    let paragraph = Paragraph::new(text)
+        .style(Style::default().fg(theme.context_fg).bg(theme.surface_alt))
+        .block(block);
+    f.render_widget(Clear, area);
+    f.render_widget(paragraph, area);
+}
+
 fn render_sticky_file_header(f: &mut Frame, app: &App, area: Rect, theme: Theme) {
     // Find the current file's header index
     let current_file_header_idx = app.find_current_file_header_idx();
@@ -5339,11 +6884,21 @@ fn render_sticky_file_header(f: &mut Frame, app: &App, area: Rect, theme: Theme)
 
     let collapse_indicator = if is_collapsed { " ▶" } else { "" };
 
+    let badge = file.and_then(file_badge);
+    let stats = match badge {
+        Some(badge) if additions == 0 && deletions == 0 => badge,
+        Some(badge) => format!("+{} -{} {}", additions, deletions, badge),
+        None => format!("+{} -{}", additions, deletions),
+    };
+    let similarity = file
+        .filter(|f| matches!(f.status, FileStatus::Renamed | FileStatus::Copied))
+        .map(|f| format!("{}  ", similarity_label(f.status, f.similarity)))
+        .unwrap_or_default();
     let header = format!(
-        " Δ {}  [+{} -{}]  ({}/{}){}{}",
+        " Δ {}  {}[{}]  ({}/{}){}{}",
         file_path,
-        additions,
-        deletions,
+        similarity,
+        stats,
         file_idx + 1,
         app.files.len(),
         expanded_indicator,
@@ -5436,17 +6991,24 @@ fn render_diff_unified(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
                         ""
                     };
 
-                    ListItem::new(Line::from(vec![
-                        Span::styled(format!(" Δ {}{}  ", path, collapse_indicator), style),
-                        Span::styled(
-                            format!("+{} ", adds),
-                            Style::default().fg(theme.added_fg).bg(stats_bg),
-                        ),
-                        Span::styled(
-                            format!("-{} ", dels),
-                            Style::default().fg(theme.deleted_fg).bg(stats_bg),
-                        ),
-                    ]))
+                    let mut spans = vec![Span::styled(
+                        format!(" Δ {}{}  ", path, collapse_indicator),
+                        style,
+                    )];
+                    spans.extend(file_header_stats(file, adds, dels, stats_bg, theme));
+                    ListItem::new(Line::from(spans))
+                }
+                DisplayLine::FileNotice { text, .. } => {
+                    let mut style = Style::default()
+                        .fg(theme.hunk_fg)
+                        .add_modifier(Modifier::ITALIC);
+                    if is_current {
+                        style = style.bg(theme.current_line_bg);
+                    }
+                    ListItem::new(Line::from(Span::styled(format!("    {}", text), style)))
+                }
+                DisplayLine::UntrackedGroup { count, open } => {
+                    ListItem::new(untracked_group_line(*count, *open, is_current, theme))
                 }
                 DisplayLine::HunkContext {
                     text,
@@ -5512,7 +7074,7 @@ fn render_diff_unified(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
                     };
 
                     let annotation_marker = app
-                        .annotation_marker_for_line(file_path, side, line_no)
+                        .annotation_marker_for_line(file_path, side, line)
                         .unwrap_or(' ')
                         .to_string();
 
@@ -5533,16 +7095,24 @@ fn render_diff_unified(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
                     };
                     let line_num = format!("{:>4}", line_no);
 
-                    let (prefix, mut content_style, prefix_style) = match line.kind {
+                    let (prefix, content_style, prefix_style) = match line.kind {
                         LineKind::Addition => (
                             "+",
-                            Style::default().fg(theme.context_fg).bg(theme.added_bg),
-                            Style::default().fg(theme.added_fg).bg(theme.added_bg),
+                            Style::default()
+                                .fg(theme.context_fg)
+                                .bg(change_bg(theme, line)),
+                            Style::default()
+                                .fg(theme.added_fg)
+                                .bg(change_bg(theme, line)),
                         ),
                         LineKind::Deletion => (
                             "-",
-                            Style::default().fg(theme.context_fg).bg(theme.deleted_bg),
-                            Style::default().fg(theme.deleted_fg).bg(theme.deleted_bg),
+                            Style::default()
+                                .fg(theme.context_fg)
+                                .bg(change_bg(theme, line)),
+                            Style::default()
+                                .fg(theme.deleted_fg)
+                                .bg(change_bg(theme, line)),
                         ),
                         LineKind::Context => (
                             " ",
@@ -5550,6 +7120,27 @@ fn render_diff_unified(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
                             Style::default().fg(theme.context_fg),
                         ),
                     };
+                    let (prefix, mut content_style, prefix_style) = match line.conflict {
+                        Some(ConflictSide::Marker) => {
+                            let style = Style::default()
+                                .fg(theme.conflict_marker_fg)
+                                .add_modifier(Modifier::BOLD);
+                            ("!", style, style)
+                        }
+                        Some(conflict) => {
+                            let (label, bg) = match conflict {
+                                ConflictSide::Ours => ("<", theme.conflict_ours_bg),
+                                ConflictSide::Base => ("|", theme.conflict_base_bg),
+                                _ => (">", theme.conflict_theirs_bg),
+                            };
+                            (
+                                label,
+                                content_style.bg(bg),
+                                Style::default().fg(theme.conflict_marker_fg).bg(bg),
+                            )
+                        }
+                        None => (prefix, content_style, prefix_style),
+                    };
                     if is_selected && !is_current {
                         content_style = content_style.bg(theme.selection_bg);
                     }
@@ -5567,19 +7158,32 @@ fn render_diff_unified(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
                         Style::default().fg(theme.annotation_marker)
                     };
 
-                    let prefix_spans = vec![
+                    let mut prefix_spans = vec![
                         Span::styled(annotation_marker, marker_style),
                         Span::styled(line_num, line_num_style),
                         Span::raw(" "),
-                        Span::styled(format!("{} ", prefix), prefix_style),
                     ];
+                    if line.combined.is_empty() {
+                        prefix_spans.push(Span::styled(format!("{} ", prefix), prefix_style));
+                    } else {
+                        // One column per parent, in parent order, as git prints them
+                        for column in &line.combined {
+                            let (mark, fg) = match column {
+                                LineKind::Addition => ("+", theme.added_fg),
+                                LineKind::Deletion => ("-", theme.deleted_fg),
+                                LineKind::Context => (" ", theme.context_fg),
+                            };
+                            prefix_spans.push(Span::styled(mark, prefix_style.fg(fg)));
+                        }
+                        prefix_spans.push(Span::styled(" ", prefix_style));
+                    }
 
                     let inline_bg = match line.kind {
                         LineKind::Addition => Some(theme.intraline_added_bg),
                         LineKind::Deletion => Some(theme.intraline_deleted_bg),
                         _ => None,
                     };
-                    let content_spans = build_highlighted_spans(
+                    let mut content_spans = build_highlighted_spans(
                         &line.content,
                         &line.highlights,
                         &line.inline_ranges,
@@ -5592,6 +7196,7 @@ fn render_diff_unified(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
                         },
                         inline_bg,
                     );
+                    content_spans.extend(ending_marker(line, content_style, theme));
 
                     let lines = wrap_spans_with_prefix(
                         prefix_spans,
@@ -5703,6 +7308,33 @@ fn render_diff_unified(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
     f.render_widget(diff_list, area);
 }
 
+/// Background of an added or deleted line; moved lines get their own
+fn change_bg(theme: Theme, line: &DiffLine) -> Color {
+    match (line.kind, line.moved.is_some()) {
+        (LineKind::Deletion, true) => theme.moved_from_bg,
+        (LineKind::Deletion, false) => theme.deleted_bg,
+        (_, true) => theme.moved_to_bg,
+        (_, false) => theme.added_bg,
+    }
+}
+
+/// Marker after a changed line whose terminator matters to the patch: a CR
+/// of a CRLF line, or a last line without a newline
+fn ending_marker(line: &DiffLine, style: Style, theme: Theme) -> Option<Span<'static>> {
+    if line.kind == LineKind::Context {
+        return None;
+    }
+    let marker = match line.ending {
+        LineEnding::Lf => return None,
+        LineEnding::CrLf => "␍",
+        LineEnding::Missing => " ∖ no newline at end of file",
+    };
+    Some(Span::styled(
+        marker,
+        style.fg(theme.line_num).add_modifier(Modifier::ITALIC),
+    ))
+}
+
 fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
     // Split the area into two columns with a small gap
     let columns = Layout::default()
@@ -5830,18 +7462,32 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                     ""
                 };
 
-                let header = Line::from(vec![
-                    Span::styled(format!(" Δ {}{} ", path, collapse_indicator), style),
-                    Span::styled(
-                        format!("+{} ", adds),
-                        Style::default().fg(theme.added_fg).bg(stats_bg),
-                    ),
-                    Span::styled(
-                        format!("-{} ", dels),
-                        Style::default().fg(theme.deleted_fg).bg(stats_bg),
-                    ),
-                ]);
-                push_pair(vec![header], blank_lines(1), None);
+                let mut spans = vec![Span::styled(
+                    format!(" Δ {}{} ", path, collapse_indicator),
+                    style,
+                )];
+                spans.extend(file_header_stats(file, adds, dels, stats_bg, theme));
+                push_pair(vec![Line::from(spans)], blank_lines(1), None);
+            }
+            DisplayLine::FileNotice { text, .. } => {
+                let mut style = Style::default()
+                    .fg(theme.hunk_fg)
+                    .add_modifier(Modifier::ITALIC);
+                if is_current {
+                    style = style.bg(theme.current_line_bg);
+                }
+                push_pair(
+                    vec![Line::from(Span::styled(format!("    {}", text), style))],
+                    blank_lines(1),
+                    None,
+                );
+            }
+            DisplayLine::UntrackedGroup { count, open } => {
+                push_pair(
+                    vec![untracked_group_line(*count, *open, is_current, theme)],
+                    blank_lines(1),
+                    None,
+                );
             }
             DisplayLine::HunkContext {
                 text,
@@ -5903,7 +7549,6 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                 let is_selected = selection_range
                     .map(|(s, e)| idx >= s && idx <= e)
                     .unwrap_or(false);
-                let line_no = line.new_line_no.or(line.old_line_no).unwrap_or(0);
                 let side = if line.new_line_no.is_some() {
                     Side::New
                 } else {
@@ -5911,7 +7556,7 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                 };
 
                 let marker_char = app
-                    .annotation_marker_for_line(file_path, side, line_no)
+                    .annotation_marker_for_line(file_path, side, line)
                     .unwrap_or(' ');
                 let marker_style = if is_current {
                     Style::default()
@@ -5931,10 +7576,12 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                         let old_no = line
                             .old_line_no
                             .map_or("    ".to_string(), |n| format!("{:>4}", n));
-                        let content_style =
-                            Style::default().fg(theme.context_fg).bg(theme.deleted_bg);
-                        let prefix_style =
-                            Style::default().fg(theme.deleted_fg).bg(theme.deleted_bg);
+                        let content_style = Style::default()
+                            .fg(theme.context_fg)
+                            .bg(change_bg(theme, line));
+                        let prefix_style = Style::default()
+                            .fg(theme.deleted_fg)
+                            .bg(change_bg(theme, line));
                         let line_num_style = if is_current {
                             Style::default()
                                 .fg(theme.deleted_fg)
@@ -5954,7 +7601,7 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                             Span::styled(old_no, line_num_style),
                             Span::styled("- ", prefix_style),
                         ];
-                        let content_spans = build_highlighted_spans(
+                        let mut content_spans = build_highlighted_spans(
                             &line.content,
                             &line.highlights,
                             &line.inline_ranges,
@@ -5967,6 +7614,7 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                             },
                             Some(theme.intraline_deleted_bg),
                         );
+                        content_spans.extend(ending_marker(line, content_style, theme));
                         let lines = wrap_spans_with_prefix(
                             left_spans,
                             content_spans,
@@ -5979,9 +7627,12 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                         let new_no = line
                             .new_line_no
                             .map_or("    ".to_string(), |n| format!("{:>4}", n));
-                        let content_style =
-                            Style::default().fg(theme.context_fg).bg(theme.added_bg);
-                        let prefix_style = Style::default().fg(theme.added_fg).bg(theme.added_bg);
+                        let content_style = Style::default()
+                            .fg(theme.context_fg)
+                            .bg(change_bg(theme, line));
+                        let prefix_style = Style::default()
+                            .fg(theme.added_fg)
+                            .bg(change_bg(theme, line));
                         let line_num_style = if is_current {
                             Style::default()
                                 .fg(theme.added_fg)
@@ -6001,7 +7652,7 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                             Span::styled(new_no, line_num_style),
                             Span::styled("+ ", prefix_style),
                         ];
-                        let content_spans = build_highlighted_spans(
+                        let mut content_spans = build_highlighted_spans(
                             &line.content,
                             &line.highlights,
                             &line.inline_ranges,
@@ -6014,6 +7665,7 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
                             },
                             Some(theme.intraline_added_bg),
                         );
+                        content_spans.extend(ending_marker(line, content_style, theme));
                         let lines = wrap_spans_with_prefix(
                             right_spans,
                             content_spans,
@@ -6232,11 +7884,52 @@ fn render_diff_side_by_side(f: &mut Frame, app: &mut App, area: Rect, theme: The
 fn render_status(f: &mut Frame, app: &App, area: Rect, theme: Theme) {
     match &app.mode {
         Mode::Normal => {
-            let mode_label = match app.diff_mode {
-                DiffMode::Unstaged => "[unstaged] ",
-                DiffMode::Staged => "[staged] ",
-                _ => "",
+            let mode_label = match &app.diff_mode {
+                DiffMode::Unstaged => "[unstaged] ".to_string(),
+                DiffMode::Staged => "[staged] ".to_string(),
+                DiffMode::Patch { source, .. } => format!("[patch: {} · read-only] ", source),
+                DiffMode::RangeDiff { .. } => match app.carried_annotations.unwrap_or_default() {
+                    (0, 0) => "[range-diff] ".to_string(),
+                    (carried, 0) => format!("[range-diff · {} notes carried] ", carried),
+                    (carried, lost) => format!(
+                        "[range-diff · {} notes carried, {} on changed lines] ",
+                        carried, lost
+                    ),
+                },
+                DiffMode::Stash { index, staged } => format!(
+                    "[{} {}] ",
+                    stash_rev(*index),
+                    if *staged { "index" } else { "worktree" }
+                ),
+                DiffMode::Commit { .. } => app
+                    .commit_info
+                    .as_ref()
+                    .map(|info| {
+                        let position = if app.log_commits.is_empty() {
+                            String::new()
+                        } else {
+                            format!(" {}/{}", app.log_idx + 1, app.log_commits.len())
+                        };
+                        let reviewed = if app.reviewed_commits.contains(&info.sha) {
+                            " ✓"
+                        } else {
+                            ""
+                        };
+                        format!("[commit {}{}{}] ", short_sha(&info.sha), position, reviewed)
+                    })
+                    .unwrap_or_default(),
+                _ => String::new(),
             };
+            let mut mode_label = if app.config.structural_diff {
+                format!("{}[structural] ", mode_label)
+            } else {
+                mode_label
+            };
+            if app.config.diff_algorithm != DiffAlgorithm::default()
+                || app.config.whitespace().is_ignoring()
+            {
+                mode_label.push_str(&format!("[{}] ", diff_options_label(&app.config)));
+            }
             let ai_running = app
                 .ai_jobs
                 .iter()
@@ -6352,10 +8045,41 @@ fn render_status(f: &mut Frame, app: &App, area: Rect, theme: Theme) {
                     .style(Style::default().fg(theme.status_fg).bg(theme.status_bg));
             f.render_widget(status, area);
         }
+        Mode::StashList => {
+            let text = match (
+                app.pending_stash_action,
+                app.stash_entries.get(app.stash_list_idx),
+            ) {
+                (Some(c), Some(entry)) => format!(
+                    " Press {} again to {} {}, any other key cancels",
+                    c,
+                    if c == 'p' { "pop" } else { "drop" },
+                    stash_rev(entry.index)
+                ),
+                _ => {
+                    " Stashes: j/k, Enter: view, a: apply, p: pop, D: drop, Esc: close".to_string()
+                }
+            };
+            let status = Paragraph::new(text)
+                .style(Style::default().fg(theme.status_fg).bg(theme.status_bg));
+            f.render_widget(status, area);
+        }
     }
 }
 
 fn render_sidebar(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
+    // A commit range gets its commit list above the files
+    let area = if app.log_commits.is_empty() {
+        area
+    } else {
+        let height = (app.log_commits.len() as u16 + 2).min(area.height / 3);
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(height), Constraint::Min(0)])
+            .split(area);
+        render_commit_list(f, app, rows[0], theme);
+        rows[1]
+    };
     let entries = app.sidebar_entries();
     if entries.is_empty() {
         let empty = Paragraph::new(" No changes")
@@ -6389,15 +8113,28 @@ fn render_sidebar(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
         .take(visible_height)
         .map(|(idx, entry)| {
             let status_char = match entry.status {
-                FileStatus::Added => "A",
-                FileStatus::Deleted => "D",
-                FileStatus::Renamed => "R",
-                _ => "M",
+                _ if entry.untracked => "?".to_string(),
+                FileStatus::Added => "A".to_string(),
+                FileStatus::Deleted => "D".to_string(),
+                FileStatus::Renamed | FileStatus::Copied => {
+                    similarity_label(entry.status, entry.similarity)
+                }
+                FileStatus::ModeChanged => "P".to_string(),
+                FileStatus::TypeChanged => "T".to_string(),
+                FileStatus::Symlink => "L".to_string(),
+                FileStatus::Submodule => "S".to_string(),
+                FileStatus::Conflicted => "U".to_string(),
+                _ => "M".to_string(),
             };
             let status_color = match entry.status {
                 FileStatus::Added => theme.added_fg,
-                FileStatus::Deleted => theme.deleted_fg,
-                FileStatus::Renamed => theme.hunk_fg,
+                FileStatus::Deleted | FileStatus::Conflicted => theme.deleted_fg,
+                FileStatus::Renamed
+                | FileStatus::Copied
+                | FileStatus::ModeChanged
+                | FileStatus::TypeChanged
+                | FileStatus::Symlink
+                | FileStatus::Submodule => theme.hunk_fg,
                 _ => theme.header_fg,
             };
             let is_selected = idx == app.sidebar_index;
@@ -6412,14 +8149,22 @@ fn render_sidebar(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
             if !app.sidebar_focused {
                 style = style.add_modifier(Modifier::DIM);
             }
-            let line = Line::from(vec![
+            let mut spans = vec![
                 Span::styled(
                     format!(" {} ", status_char),
                     Style::default().fg(status_color),
                 ),
                 Span::styled(entry.path.clone(), style),
-            ]);
-            ListItem::new(line)
+            ];
+            if entry.binary {
+                spans.push(Span::styled(
+                    " [binary]",
+                    Style::default()
+                        .fg(theme.hunk_fg)
+                        .add_modifier(Modifier::DIM),
+                ));
+            }
+            ListItem::new(Line::from(spans))
         })
         .collect();
 
@@ -6452,6 +8197,49 @@ fn render_sidebar(f: &mut Frame, app: &mut App, area: Rect, theme: Theme) {
     f.render_widget(list, area);
 }
 
+fn render_commit_list(f: &mut Frame, app: &App, area: Rect, theme: Theme) {
+    let visible = area.height.saturating_sub(2) as usize;
+    // Keep the current commit in view
+    let scroll = app.log_idx.saturating_sub(visible.saturating_sub(1));
+    let items: Vec<ListItem> = app
+        .log_commits
+        .iter()
+        .enumerate()
+        .skip(scroll)
+        .take(visible)
+        .map(|(idx, info)| {
+            let reviewed = app.reviewed_commits.contains(&info.sha);
+            let mut style = Style::default().fg(theme.status_fg);
+            if idx == app.log_idx {
+                style = style.bg(theme.selection_bg).add_modifier(Modifier::BOLD);
+            }
+            ListItem::new(Line::from(vec![
+                Span::styled(
+                    if reviewed { " ✓ " } else { "   " },
+                    Style::default().fg(theme.added_fg),
+                ),
+                Span::styled(
+                    format!("{} ", short_sha(&info.sha)),
+                    Style::default().fg(theme.annotation_marker),
+                ),
+                Span::styled(info.summary().to_string(), style),
+            ]))
+        })
+        .collect();
+    let title = format!(
+        " Commits {}/{} ([/]) ",
+        app.log_idx + 1,
+        app.log_commits.len()
+    );
+    let list = List::new(items).block(
+        Block::default()
+            .borders(Borders::RIGHT | Borders::BOTTOM)
+            .border_style(Style::default().fg(theme.border))
+            .title(title),
+    );
+    f.render_widget(list, area);
+}
+
 fn build_context_box(
     prefix_spans: Vec<Span<'static>>,
     content_spans: Vec<Span<'static>>,
@@ -6647,28 +8435,71 @@ fn extract_claude_text(line: &str) -> Option<String> {
     None
 }
 
-fn read_working_file_at(repo_path: &PathBuf, path: &str) -> Option<String> {
-    let full_path = repo_path.join(path);
-    std::fs::read(&full_path)
+fn read_working_file_at(repo_path: &Path, path: &str) -> Option<String> {
+    read_worktree_text(repo_path, Path::new(path))
+}
+
+/// One side of a file in an external diff session, from the copy the
+/// session kept of it. Unmerged paths have no copies; their conflicts are
+/// in the working tree.
+fn external_side(
+    repo_path: &Path,
+    files: &[ExternalFile],
+    path: &str,
+    side: Side,
+) -> Option<String> {
+    let file = files.iter().find(|f| match side {
+        Side::Old => f.path == path,
+        Side::New => f.new_path.as_deref().unwrap_or(&f.path) == path,
+    })?;
+    if file.unmerged {
+        return read_working_file_at(repo_path, path);
+    }
+    let copy = match side {
+        Side::Old => &file.old_file,
+        Side::New => &file.new_file,
+    };
+    (copy != "/dev/null").then(|| read_text(Path::new(copy)))?
+}
+
+/// A file outside any repository decoded for display
+fn read_text(path: &Path) -> Option<String> {
+    std::fs::read(path)
         .ok()
-        .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+        .map(|bytes| decode_text(&bytes, None).0)
 }
 
+/// A blob as the diff shows it, through any textconv driver
 fn git_show_at(repo_path: &PathBuf, spec: &str) -> Option<String> {
     let output = Command::new("git")
-        .arg("show")
+        .args(["cat-file", "--textconv"])
         .arg(spec)
         .current_dir(repo_path)
         .output()
         .ok()?;
     if output.status.success() {
-        Some(String::from_utf8_lossy(&output.stdout).to_string())
+        Some(decode_text(&output.stdout, None).0)
     } else {
         None
     }
 }
 
-fn load_new_file_content_for_highlight(
+/// New side of a file in a stash; untracked files live in its third parent
+fn stash_file_content(
+    repo_path: &PathBuf,
+    index: usize,
+    staged: bool,
+    path: &str,
+) -> Option<String> {
+    let stash = stash_rev(index);
+    if staged {
+        return git_show_at(repo_path, &format!("{}^2:{}", stash, path));
+    }
+    git_show_at(repo_path, &format!("{}:{}", stash, path))
+        .or_else(|| git_show_at(repo_path, &format!("{}^3:{}", stash, path)))
+}
+
+fn load_new_file_content(
     repo_path: &PathBuf,
     diff_mode: &DiffMode,
     file: &DiffFile,
@@ -6690,7 +8521,13 @@ fn load_new_file_content_for_highlight(
         DiffMode::WorkingTree { .. } => read_working_file_at(repo_path, &path),
         DiffMode::Commits { to, .. } => git_show_at(repo_path, &format!("{}:{}", to, path)),
         DiffMode::MergeBase { to, .. } => git_show_at(repo_path, &format!("{}:{}", to, path)),
-        DiffMode::ExternalDiff { .. } => read_working_file_at(repo_path, &path),
+        DiffMode::Commit { rev } => git_show_at(repo_path, &format!("{}:{}", rev, path)),
+        DiffMode::Stash { index, staged } => stash_file_content(repo_path, *index, *staged, &path),
+        DiffMode::ExternalDiff { files } => external_side(repo_path, files, &path, Side::New),
+        // The patch's sides need not exist anywhere on disk, and a range-diff
+        // compares patches rather than files
+        DiffMode::Patch { .. } | DiffMode::RangeDiff { .. } => None,
+        DiffMode::NoIndex { new, .. } => read_text(&no_index_path(new, Path::new(&path))),
     }
 }
 
@@ -6733,6 +8570,11 @@ struct SidebarEntry {
     file_idx: usize,
     path: String,
     status: FileStatus,
+    similarity: Option<u8>,
+    binary: bool,
+    untracked: bool,
+    /// Stands for all untracked files while their group is folded
+    group: bool,
 }
 
 fn render_annotation_list(f: &mut Frame, app: &mut App, theme: Theme) {
@@ -6820,6 +8662,43 @@ fn render_annotation_list(f: &mut Frame, app: &mut App, theme: Theme) {
     f.render_widget(list, area);
 }
 
+fn render_stash_list(f: &mut Frame, app: &App, theme: Theme) {
+    let area = centered_rect(70, 50, f.area());
+    let visible_height = area.height.saturating_sub(2) as usize;
+    let start = app.stash_list_idx.saturating_sub(visible_height / 2);
+    let end = (start + visible_height).min(app.stash_entries.len());
+
+    let lines: Vec<Line> = app.stash_entries[start..end]
+        .iter()
+        .enumerate()
+        .map(|(offset, entry)| {
+            let mut style = Style::default().fg(theme.help_fg);
+            if start + offset == app.stash_list_idx {
+                style = style.bg(theme.header_focus_bg).add_modifier(Modifier::BOLD);
+            }
+            Line::from(vec![
+                Span::styled(
+                    format!("{}  ", stash_rev(entry.index)),
+                    style.fg(theme.annotation_marker),
+                ),
+                Span::styled(entry.message.clone(), style),
+            ])
+        })
+        .collect();
+
+    let list = Paragraph::new(lines)
+        .style(Style::default().bg(theme.help_bg))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(" Stashes (Enter: view, a: apply, p: pop, D: drop, Esc) ")
+                .border_style(Style::default().fg(theme.border)),
+        );
+
+    f.render_widget(Clear, area);
+    f.render_widget(list, area);
+}
+
 fn render_command_palette(f: &mut Frame, app: &mut App, theme: Theme) {
     let matches = app.command_matches();
     let area = centered_rect(50, 35, f.area());
@@ -7005,6 +8884,262 @@ fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
         .split(popup_layout[1])[1]
 }
 
+/// Stats shown after the path in a file header: "+a -d" and/or a badge
+fn file_header_stats(
+    file: Option<&DiffFile>,
+    adds: usize,
+    dels: usize,
+    stats_bg: Color,
+    theme: Theme,
+) -> Vec<Span<'static>> {
+    let badge = file.and_then(file_badge);
+    let mut spans = Vec::new();
+    if adds > 0 || dels > 0 || badge.is_none() {
+        spans.push(Span::styled(
+            format!("+{} ", adds),
+            Style::default().fg(theme.added_fg).bg(stats_bg),
+        ));
+        spans.push(Span::styled(
+            format!("-{} ", dels),
+            Style::default().fg(theme.deleted_fg).bg(stats_bg),
+        ));
+    }
+    if let Some(badge) = badge {
+        spans.push(Span::styled(
+            format!("{} ", badge),
+            Style::default().fg(theme.hunk_fg).bg(stats_bg),
+        ));
+    }
+    spans
+}
+
+/// Short summary of algorithm and whitespace settings, e.g. "patience -w"
+fn diff_options_label(config: &Config) -> String {
+    let mut parts = vec![config.diff_algorithm.as_str()];
+    if config.ignore_all_space {
+        parts.push("-w");
+    }
+    if config.ignore_space_change {
+        parts.push("-b");
+    }
+    if config.ignore_blank_lines {
+        parts.push("--ignore-blank-lines");
+    }
+    parts.join(" ")
+}
+
+/// "R87%" / "C100%" status for renames and copies
+fn similarity_label(status: FileStatus, similarity: Option<u8>) -> String {
+    let letter = if status == FileStatus::Copied {
+        'C'
+    } else {
+        'R'
+    };
+    match similarity {
+        Some(score) => format!("{}{}%", letter, score),
+        None => letter.to_string(),
+    }
+}
+
+/// File-level lines shown above the hunks (renames, binary content, mode and type changes).
+/// Both paths of a rename carry the side prefixes git would print.
+fn file_notices(file: &DiffFile, prefixes: (&str, &str)) -> Vec<String> {
+    let mut notices = Vec::new();
+    if let (FileStatus::Renamed | FileStatus::Copied, Some(old_path), Some(new_path)) =
+        (file.status, file.old_path.as_ref(), file.new_path.as_ref())
+    {
+        let verb = if file.status == FileStatus::Copied {
+            "Copied"
+        } else {
+            "Renamed"
+        };
+        let similarity = file
+            .similarity
+            .map(|s| format!(" ({}% similar)", s))
+            .unwrap_or_default();
+        notices.push(format!(
+            "{} from {}{} to {}{}{}",
+            verb,
+            prefixes.0,
+            old_path.to_string_lossy(),
+            prefixes.1,
+            new_path.to_string_lossy(),
+            similarity
+        ));
+    }
+    if file.binary {
+        notices.push(binary_notice(file));
+    }
+    match file.deferred {
+        Some(Deferred::Lines(lines)) => notices.push(format!(
+            "{} lines changed, press x to load",
+            format_count(lines)
+        )),
+        Some(Deferred::Bytes(bytes)) if file.untracked => notices.push(format!(
+            "Untracked file of {}, press x to load",
+            format_size(bytes)
+        )),
+        Some(Deferred::Bytes(bytes)) => {
+            notices.push(format!("{} to diff, press x to load", format_size(bytes)))
+        }
+        None => {}
+    }
+    let parents = file
+        .hunks
+        .first()
+        .and_then(|h| h.lines.first())
+        .map_or(0, |l| l.combined.len());
+    if parents > 1 {
+        notices.push(format!(
+            "Combined diff against {} parents: column N marks a change from parent N",
+            parents
+        ));
+    }
+    match file.status {
+        FileStatus::TypeChanged => notices.push(format!(
+            "Type changed: {} → {}",
+            mode_kind(file.old_mode.unwrap_or(MODE_FILE)),
+            mode_kind(file.new_mode.unwrap_or(MODE_FILE))
+        )),
+        FileStatus::Symlink => notices.push("Symlink target changed".to_string()),
+        FileStatus::Submodule => {
+            let short = |sha: Option<String>| {
+                sha.map(|s| s.chars().take(10).collect::<String>())
+                    .unwrap_or_else(|| "?".to_string())
+            };
+            let (old, new) = file.submodule_commits();
+            notices.push(format!("Submodule commit: {} → {}", short(old), short(new)));
+        }
+        FileStatus::Conflicted if file.binary => {
+            notices.push("Unmerged: take a side with < or >, or resolve it and press s".to_string())
+        }
+        FileStatus::Conflicted if file.hunks.is_empty() => notices
+            .push("Unmerged, no conflict markers left: press s to mark it resolved".to_string()),
+        FileStatus::Conflicted => {
+            let regions: usize = file
+                .hunks
+                .iter()
+                .flat_map(|h| &h.lines)
+                .filter(|l| {
+                    l.conflict == Some(ConflictSide::Marker) && l.content.starts_with("<<<<<<<")
+                })
+                .count();
+            notices.push(format!(
+                "{} conflict{}: < ours, > theirs, = both, E edit",
+                regions,
+                if regions == 1 { "" } else { "s" }
+            ));
+        }
+        _ => {}
+    }
+    if file.mode_changed() {
+        notices.push(format!(
+            "Mode changed: {:o} → {:o}",
+            file.old_mode.unwrap_or_default(),
+            file.new_mode.unwrap_or_default()
+        ));
+    }
+    notices
+}
+
+/// Header of the fold holding the untracked files
+fn untracked_group_line(count: usize, open: bool, is_current: bool, theme: Theme) -> Line<'static> {
+    let mut style = Style::default()
+        .fg(theme.header_fg)
+        .bg(theme.header_bg)
+        .add_modifier(Modifier::BOLD);
+    if is_current {
+        style = style
+            .bg(theme.header_focus_bg)
+            .add_modifier(Modifier::UNDERLINED);
+    }
+    let (marker, hint) = if open {
+        ("▼", "c to fold")
+    } else {
+        ("▶", "c to show")
+    };
+    Line::from(Span::styled(
+        format!(
+            " {} {} untracked file{} ({}) ",
+            marker,
+            count,
+            if count == 1 { "" } else { "s" },
+            hint
+        ),
+        style,
+    ))
+}
+
+/// Short label for non-content changes, shown in file headers
+fn file_badge(file: &DiffFile) -> Option<String> {
+    if file.binary {
+        return Some("binary".to_string());
+    }
+    let kind = match file.status {
+        FileStatus::ModeChanged => Some("mode"),
+        FileStatus::TypeChanged => Some("type"),
+        FileStatus::Symlink => Some("symlink"),
+        FileStatus::Submodule => Some("submodule"),
+        FileStatus::Conflicted => Some("conflict"),
+        _ if file.mode_changed() => Some("mode"),
+        _ => None,
+    };
+    // Text not in UTF-8 is shown decoded; the badge names what it was
+    let encoding = if file.textconv {
+        Some("textconv")
+    } else {
+        file.encoding.map(|e| e.name())
+    };
+    match (kind, encoding) {
+        (Some(kind), Some(encoding)) => Some(format!("{} {}", kind, encoding)),
+        (kind, encoding) => kind.or(encoding).map(str::to_string),
+    }
+}
+
+fn binary_notice(file: &DiffFile) -> String {
+    match (file.old_size, file.new_size) {
+        (Some(old), Some(new)) => format!(
+            "Binary file changed: {} → {}",
+            format_size(old),
+            format_size(new)
+        ),
+        (None, Some(new)) => format!("Binary file added ({})", format_size(new)),
+        (Some(old), None) => format!("Binary file deleted ({})", format_size(old)),
+        (None, None) => "Binary file".to_string(),
+    }
+}
+
+fn short_sha(sha: &str) -> &str {
+    sha.get(..7).unwrap_or(sha)
+}
+
+/// A count with thousands separators, e.g. 12,431
+fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut out = String::new();
+    for (idx, ch) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
 /// Get the color for a syntax token type
 /// Build spans with syntax highlighting for a diff line
 fn build_highlighted_spans(
@@ -7107,46 +9242,6 @@ fn span_width(span: &Span) -> usize {
     span.content.width()
 }
 
-fn compute_inline_ranges(old: &str, new: &str) -> (Vec<InlineRange>, Vec<InlineRange>) {
-    let diff = TextDiff::from_chars(old, new);
-    let mut old_pos = 0usize;
-    let mut new_pos = 0usize;
-    let mut old_ranges = Vec::new();
-    let mut new_ranges = Vec::new();
-
-    for change in diff.iter_all_changes() {
-        let len = change.value().len();
-        if len == 0 {
-            continue;
-        }
-        match change.tag() {
-            ChangeTag::Delete => {
-                old_ranges.push(InlineRange {
-                    start: old_pos,
-                    end: old_pos + len,
-                });
-                old_pos += len;
-            }
-            ChangeTag::Insert => {
-                new_ranges.push(InlineRange {
-                    start: new_pos,
-                    end: new_pos + len,
-                });
-                new_pos += len;
-            }
-            ChangeTag::Equal => {
-                old_pos += len;
-                new_pos += len;
-            }
-        }
-    }
-
-    (
-        merge_inline_ranges(old_ranges),
-        merge_inline_ranges(new_ranges),
-    )
-}
-
 fn line_similarity(old: &str, new: &str) -> f32 {
     TextDiff::from_chars(old, new).ratio()
 }
@@ -7169,26 +9264,10 @@ fn fuzzy_score(query: &str, text: &str) -> Option<usize> {
     Some(score)
 }
 
-fn merge_inline_ranges(mut ranges: Vec<InlineRange>) -> Vec<InlineRange> {
-    if ranges.is_empty() {
-        return ranges;
-    }
-    ranges.sort_by_key(|r| r.start);
-    let mut merged = Vec::new();
-    let mut current = ranges.remove(0);
-    for r in ranges {
-        if r.start <= current.end {
-            current.end = current.end.max(r.end);
-        } else {
-            merged.push(current);
-            current = r;
-        }
-    }
-    merged.push(current);
-    merged
-}
-
-fn compute_intraline_ranges(lines: &[DiffLine]) -> Vec<Vec<InlineRange>> {
+fn compute_intraline_ranges(
+    lines: &[DiffLine],
+    tokens: &[Vec<Range<usize>>],
+) -> Vec<Vec<InlineRange>> {
     let mut per_line: Vec<Vec<InlineRange>> = vec![Vec::new(); lines.len()];
     let mut i = 0usize;
     while i < lines.len() {
@@ -7208,10 +9287,10 @@ fn compute_intraline_ranges(lines: &[DiffLine]) -> Vec<Vec<InlineRange>> {
             }
 
             let mut candidates: Vec<(f32, usize, usize, usize)> = Vec::new();
-            for d in del_start..add_start {
-                let old_line = lines[d].content.as_str();
-                for a in add_start..i {
-                    let new_line = lines[a].content.as_str();
+            for (d, old) in lines.iter().enumerate().take(add_start).skip(del_start) {
+                let old_line = old.content.as_str();
+                for (a, new) in lines.iter().enumerate().take(i).skip(add_start) {
+                    let new_line = new.content.as_str();
                     let score = line_similarity(old_line, new_line);
                     if score >= INTRALINE_PAIR_RATIO_THRESHOLD {
                         let rel_d = d - del_start;
@@ -7239,8 +9318,12 @@ fn compute_intraline_ranges(lines: &[DiffLine]) -> Vec<Vec<InlineRange>> {
                 if used_del[rel_d] || used_add[rel_a] {
                     continue;
                 }
-                let (del_ranges, add_ranges) =
-                    compute_inline_ranges(&lines[d].content, &lines[a].content);
+                let (del_ranges, add_ranges) = token_inline_ranges(
+                    &lines[d].content,
+                    &tokens[d],
+                    &lines[a].content,
+                    &tokens[a],
+                );
                 per_line[d] = del_ranges;
                 per_line[a] = add_ranges;
                 used_del[rel_d] = true;
@@ -7349,7 +9432,7 @@ fn wrapped_line_count(text: &str, max_width: usize) -> usize {
         let lines = if width == 0 {
             1
         } else {
-            (width + max_width - 1) / max_width
+            width.div_ceil(max_width)
         };
         count += lines;
     }
@@ -7359,3 +9442,462 @@ fn wrapped_line_count(text: &str, max_width: usize) -> usize {
         count
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::tests::git_ok;
+    use crate::diff::{DiffLimits, Whitespace};
+    use tempfile::{tempdir, TempDir};
+
+    /// An app over `repo` with the diff for `mode` loaded, keeping its
+    /// database in `db`
+    fn app_for(repo: &Path, db: &TempDir, mode: DiffMode) -> App {
+        app_with(repo, DiffEngine::new(repo.to_path_buf(), 3), db, mode)
+    }
+
+    fn app_with(repo: &Path, engine: DiffEngine, db: &TempDir, mode: DiffMode) -> App {
+        let storage = Storage::open(&db.path().join("differ.db")).unwrap();
+        let repo_id = storage.get_or_create_repo(repo, None).unwrap();
+        let files = engine.diff(&mode, &[]).unwrap();
+        let args = DiffArgs {
+            mode,
+            paths: Vec::new(),
+            log_commits: Vec::new(),
+        };
+        let mut app = App::new(
+            storage,
+            engine,
+            repo.to_path_buf(),
+            repo_id,
+            Config::default(),
+            args,
+        )
+        .unwrap();
+        for (idx, file) in files.iter().enumerate() {
+            let (file_key, _, _) = App::file_highlight_keys(file);
+            app.diff_file_index.insert(file_key, idx);
+        }
+        app.files = files;
+        app.build_display_lines();
+        app
+    }
+
+    fn press(app: &mut App, c: char) {
+        app.handle_normal_input(KeyEvent::from(KeyCode::Char(c)))
+            .unwrap();
+    }
+
+    /// Put the cursor on the first diff line of `path`
+    fn move_to_line_of(app: &mut App, path: &str) {
+        app.current_line_idx = app
+            .display_lines
+            .iter()
+            .position(|l| matches!(l, DisplayLine::Diff { file_path, .. } if file_path == path))
+            .unwrap();
+    }
+
+    fn unmerged(repo: &Path) -> String {
+        let output = Command::new("git")
+            .args(["diff", "--name-only", "--diff-filter=U"])
+            .current_dir(repo)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// A merge of `topic` into `main` that leaves `a.txt` conflicted
+    fn conflicted_repo(ours: &[u8], theirs: &[u8]) -> TempDir {
+        let dir = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let path = dir.path().join("a.txt");
+        assert!(git(&["init", "-q", "-b", "main"]));
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "base"]));
+        assert!(git(&["checkout", "-qb", "topic"]));
+        std::fs::write(&path, [b"one\n", theirs].concat()).unwrap();
+        assert!(git(&["commit", "-qam", "topic"]));
+        assert!(git(&["checkout", "-q", "main"]));
+        std::fs::write(&path, [b"one\n", ours].concat()).unwrap();
+        assert!(git(&["commit", "-qam", "main"]));
+        assert!(!git(&["merge", "-q", "topic"]));
+        dir
+    }
+
+    #[test]
+    fn test_stage_keeps_conflict_markers_unresolved() {
+        let dir = conflicted_repo(b"ours\n", b"theirs\n");
+        let db = tempdir().unwrap();
+
+        let mut app = app_for(dir.path(), &db, DiffMode::Unstaged);
+        move_to_line_of(&mut app, "a.txt");
+        press(&mut app, 's');
+        assert_eq!(unmerged(dir.path()), "a.txt\n");
+        assert!(app.message.as_deref().unwrap().contains("1 conflict left"));
+
+        std::fs::write(dir.path().join("a.txt"), "one\nboth\n").unwrap();
+        press(&mut app, 's');
+        assert_eq!(unmerged(dir.path()), "");
+    }
+
+    #[test]
+    fn test_resolve_keeps_other_regions_and_legacy_bytes() {
+        let dir = conflicted_repo(b"caf\xe9\n", b"na\xefve\n");
+        let db = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        let before = std::fs::read(&path).unwrap();
+
+        // From the header, taking a side would drop the other side of every region
+        let mut app = app_for(dir.path(), &db, DiffMode::Unstaged);
+        app.current_line_idx = app
+            .display_lines
+            .iter()
+            .position(|l| matches!(l, DisplayLine::FileHeader { .. }))
+            .unwrap();
+        press(&mut app, '>');
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+
+        // The base shown from the index belongs to the region around it
+        let base = app
+            .display_lines
+            .iter()
+            .position(|l| {
+                matches!(l, DisplayLine::Diff { line, .. } if line.conflict == Some(ConflictSide::Base))
+            })
+            .unwrap();
+        app.current_line_idx = base;
+        press(&mut app, '>');
+        assert_eq!(std::fs::read(&path).unwrap(), b"one\nna\xefve\n");
+        assert_eq!(unmerged(dir.path()), "");
+    }
+
+    #[test]
+    fn test_resolve_modify_delete_by_taking_the_deletion() {
+        let dir = tempdir().unwrap();
+        let db = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let path = dir.path().join("a.txt");
+        assert!(git(&["init", "-q", "-b", "main"]));
+        std::fs::write(&path, "one\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "base"]));
+        assert!(git(&["checkout", "-qb", "topic"]));
+        assert!(git(&["rm", "-q", "a.txt"]));
+        assert!(git(&["commit", "-qm", "topic"]));
+        assert!(git(&["checkout", "-q", "main"]));
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        assert!(git(&["commit", "-qam", "main"]));
+        assert!(!git(&["merge", "-q", "topic"]));
+        assert_eq!(unmerged(dir.path()), "a.txt\n");
+
+        let mut app = app_for(dir.path(), &db, DiffMode::Unstaged);
+        app.current_line_idx = app
+            .display_lines
+            .iter()
+            .position(|l| matches!(l, DisplayLine::FileHeader { .. }))
+            .unwrap();
+        press(&mut app, '>');
+        assert_eq!(unmerged(dir.path()), "");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_external_diff_reads_both_sides_from_session_copies() {
+        let dir = tempdir().unwrap();
+        let db = tempdir().unwrap();
+        let copies = tempdir().unwrap();
+        assert!(git_ok(dir.path(), &["init", "-q"]));
+        std::fs::write(dir.path().join("a.txt"), "worktree\n").unwrap();
+        let old_file = copies.path().join("old");
+        let new_file = copies.path().join("new");
+        std::fs::write(&old_file, "one\ntwo\nthree\n").unwrap();
+        std::fs::write(&new_file, "one\nTWO\nthree\n").unwrap();
+        let files = vec![ExternalFile {
+            path: "a.txt".to_string(),
+            new_path: None,
+            old_file: old_file.to_string_lossy().to_string(),
+            new_file: new_file.to_string_lossy().to_string(),
+            unmerged: false,
+        }];
+        assert_eq!(
+            external_side(dir.path(), &files, "a.txt", Side::Old).as_deref(),
+            Some("one\ntwo\nthree\n")
+        );
+
+        let mut app = app_for(dir.path(), &db, DiffMode::ExternalDiff { files });
+        app.expanded_file = Some(0);
+        app.build_display_lines();
+        let shown: Vec<&str> = app
+            .display_lines
+            .iter()
+            .filter_map(|l| match l {
+                DisplayLine::Diff { line, .. } => Some(line.content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(shown, ["one", "two", "TWO", "three"]);
+    }
+
+    #[test]
+    fn test_discard_skips_files_not_loaded() {
+        let dir = tempdir().unwrap();
+        let db = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let path = dir.path().join("big.txt");
+        let numbered =
+            |tag: &str| -> String { (0..50).map(|i| format!("{} {}\n", tag, i)).collect() };
+        assert!(git(&["init", "-q"]));
+        std::fs::write(&path, numbered("old")).unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        std::fs::write(&path, numbered("new")).unwrap();
+
+        let limits = DiffLimits {
+            file_lines: 10,
+            ..DiffLimits::default()
+        };
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_limits(limits);
+        let mut app = app_with(dir.path(), engine, &db, DiffMode::Unstaged);
+        assert!(app.files[0].deferred.is_some());
+        app.current_line_idx = app
+            .display_lines
+            .iter()
+            .position(|l| matches!(l, DisplayLine::FileNotice { .. }))
+            .unwrap();
+        press(&mut app, 'D');
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), numbered("new"));
+
+        // x loads it in the background
+        press(&mut app, 'x');
+        let event = app.diff_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        app.handle_diff_event(event).unwrap();
+        assert_eq!(app.files[0].deferred, None);
+        assert_eq!(app.files[0].changed_lines(), 100);
+    }
+
+    #[test]
+    fn test_discard_textconv_file_needs_confirmation() {
+        let dir = tempdir().unwrap();
+        let db = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let path = dir.path().join("notes.up");
+        assert!(git(&["init", "-q"]));
+        assert!(git(&["config", "diff.upper.textconv", "tr a-z A-Z <"]));
+        std::fs::write(dir.path().join(".gitattributes"), "*.up diff=upper\n").unwrap();
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        std::fs::write(&path, "one\nthree\n").unwrap();
+
+        let mut app = app_for(dir.path(), &db, DiffMode::Unstaged);
+        let file_idx = app.files.iter().position(|f| f.textconv).unwrap();
+        app.current_line_idx = app
+            .display_lines
+            .iter()
+            .position(|l| matches!(l, DisplayLine::Diff { file_idx: f, line, .. } if *f == file_idx && line.kind == LineKind::Addition))
+            .unwrap();
+        press(&mut app, 'D');
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\nthree\n");
+        // Any other key cancels
+        press(&mut app, 'j');
+        press(&mut app, 'k');
+        press(&mut app, 'D');
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\nthree\n");
+        press(&mut app, 'D');
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+    }
+
+    #[test]
+    fn test_stash_pop_and_drop_need_confirmation() {
+        let dir = tempdir().unwrap();
+        let db = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let path = dir.path().join("a.txt");
+        assert!(git(&["init", "-q"]));
+        std::fs::write(&path, "a\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        for text in ["first\n", "second\n"] {
+            std::fs::write(&path, text).unwrap();
+            assert!(git(&["stash", "-q"]));
+        }
+
+        let mut app = app_for(dir.path(), &db, DiffMode::Unstaged);
+        let stash_key = |app: &mut App, c: char| {
+            app.handle_stash_list_input(KeyEvent::from(KeyCode::Char(c)))
+                .unwrap();
+        };
+        let stashes = |app: &App| app.diff_engine.list_stashes().unwrap().len();
+        app.open_stash_list().unwrap();
+        stash_key(&mut app, 'D');
+        stash_key(&mut app, 'j');
+        stash_key(&mut app, 'p');
+        stash_key(&mut app, 'D');
+        assert_eq!(stashes(&app), 2);
+        stash_key(&mut app, 'D');
+        assert_eq!(stashes(&app), 1);
+        stash_key(&mut app, 'p');
+        assert_eq!(stashes(&app), 1);
+        stash_key(&mut app, 'p');
+        assert_eq!(stashes(&app), 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
+    }
+
+    #[test]
+    fn test_range_diff_carries_notes_with_the_pairs_it_loaded() {
+        let dir = tempdir().unwrap();
+        let db = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let path = dir.path().join("a.txt");
+        let head = || {
+            let out = Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .current_dir(dir.path())
+                .output()
+                .unwrap();
+            String::from_utf8(out.stdout).unwrap().trim().to_string()
+        };
+        assert!(git(&["init", "-q", "-b", "main"]));
+        std::fs::write(dir.path().join("base.txt"), "base\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "base"]));
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "add a"]));
+        let v1 = head();
+        assert!(git(&["checkout", "-q", "HEAD~1"]));
+        std::fs::write(&path, "zero\none\ntwo\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "add a"]));
+        let v2 = head();
+
+        let mode = DiffMode::RangeDiff {
+            old_from: format!("{}~1", v1),
+            old_to: v1.clone(),
+            new_from: format!("{}~1", v2),
+            new_to: v2.clone(),
+        };
+        let mut app = app_for(dir.path(), &db, mode);
+        app.storage
+            .add_annotation(
+                app.repo_id,
+                "a.txt",
+                Some(&v1),
+                Side::New,
+                2,
+                None,
+                AnnotationType::Comment,
+                "check two",
+                2,
+                "two",
+                "",
+                "",
+            )
+            .unwrap();
+        app.start_diff_stream(None).unwrap();
+        loop {
+            let event = app.diff_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            let done = matches!(event, DiffStreamEvent::Done { .. });
+            app.handle_diff_event(event).unwrap();
+            if done {
+                break;
+            }
+        }
+        assert_eq!(app.carried_annotations, Some((1, 0)));
+        let carried: Vec<(Option<&str>, u32)> = app
+            .all_annotations
+            .iter()
+            .filter(|a| a.content == "check two")
+            .map(|a| (a.commit_sha.as_deref(), a.start_line))
+            .collect();
+        assert_eq!(carried, [(Some(v1.as_str()), 2), (Some(v2.as_str()), 3)]);
+    }
+
+    #[test]
+    fn test_commit_annotations_show_where_their_code_is() {
+        let dir = tempdir().unwrap();
+        let db = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let path = dir.path().join("a.txt");
+        assert!(git(&["init", "-q"]));
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+
+        let mut app = app_for(dir.path(), &db, DiffMode::Unstaged);
+        for (commit_sha, start_line, anchor_text, content) in [
+            (Some("0123abcd"), 2, "two", "same code"),
+            (Some("0123abcd"), 1, "changed since", "other code"),
+            (None, 3, "three", "worktree"),
+        ] {
+            app.storage
+                .add_annotation(
+                    app.repo_id,
+                    "a.txt",
+                    commit_sha,
+                    Side::New,
+                    start_line,
+                    None,
+                    AnnotationType::Comment,
+                    content,
+                    start_line,
+                    anchor_text,
+                    "",
+                    "",
+                )
+                .unwrap();
+        }
+        app.load_all_annotations().unwrap();
+        app.build_display_lines();
+
+        let mut shown: Vec<String> = app
+            .display_lines
+            .iter()
+            .filter_map(|l| match l {
+                DisplayLine::Annotation { annotation, .. } => Some(annotation.content.clone()),
+                _ => None,
+            })
+            .collect();
+        shown.sort();
+        assert_eq!(shown, vec!["same code", "worktree"]);
+        assert_eq!(app.annotation_list_entries().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stage_ignoring_whitespace_with_non_utf8_name() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = tempdir().unwrap();
+        let db = tempdir().unwrap();
+        let git = |args: &[&str]| git_ok(dir.path(), args);
+        let path = dir.path().join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        assert!(git(&["init", "-q"]));
+        std::fs::write(&path, "a\n").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "initial"]));
+        std::fs::write(&path, "  a\nb\n").unwrap();
+
+        let whitespace = Whitespace {
+            ignore_all_space: true,
+            ..Whitespace::default()
+        };
+        let engine = DiffEngine::new(dir.path().to_path_buf(), 3).with_whitespace(whitespace);
+        let mut app = app_with(dir.path(), engine, &db, DiffMode::Unstaged);
+        app.current_line_idx = app
+            .display_lines
+            .iter()
+            .position(
+                |l| matches!(l, DisplayLine::Diff { line, .. } if line.kind == LineKind::Addition),
+            )
+            .unwrap();
+        press(&mut app, 's');
+        let cached = Command::new("git")
+            .args(["diff", "--cached", "--numstat"])
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&cached.stdout).starts_with("2\t1\t"));
+    }
+}